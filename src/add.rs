@@ -0,0 +1,210 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{copy, error::Error, mount::MountedImage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TarCompression {
+    None,
+    Gzip,
+    Xz,
+}
+
+pub fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Recognizes tar archives from their file name, plain or compressed.
+fn tar_compression(path: &Path) -> Option<TarCompression> {
+    let name = path.file_name()?.to_str()?;
+
+    if name.ends_with(".tar") {
+        Some(TarCompression::None)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(TarCompression::Gzip)
+    } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+        Some(TarCompression::Xz)
+    } else {
+        None
+    }
+}
+
+/// Checks a file against a `sha256:<hex>` checksum.
+fn verify_checksum(path: &Path, checksum: &str) -> Result<(), Error> {
+    let expected = checksum
+        .strip_prefix("sha256:")
+        .ok_or_else(|| Error::Usage(format!("Unsupported checksum {}", checksum)))?;
+    let digest = sha256::try_digest(path)?;
+
+    if !digest.eq_ignore_ascii_case(expected) {
+        return Err(Error::Other(format!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            path.display(),
+            expected,
+            digest
+        )));
+    }
+
+    Ok(())
+}
+
+/// Downloads `url` into `dir`, named after the last segment of its path.
+fn download(url: &str, dir: &Path) -> Result<PathBuf, Error> {
+    let parsed = url::Url::parse(url)?;
+    let name = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("download");
+    let path = dir.join(name);
+
+    let mut response = reqwest::blocking::get(url)?.error_for_status()?;
+    io::copy(&mut response, &mut File::create(&path)?)?;
+
+    Ok(path)
+}
+
+/// Unpacks the tar archive at `archive` into the `target` directory of the
+/// image mounted at `mount_point`.
+fn extract_into(
+    mount_point: &Path,
+    archive: &Path,
+    compression: TarCompression,
+    target: &Path,
+) -> Result<(), Error> {
+    let file = File::open(archive)?;
+    let reader: Box<dyn Read> = match compression {
+        TarCompression::None => Box::new(file),
+        TarCompression::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+        TarCompression::Xz => Box::new(xz2::read::XzDecoder::new(file)),
+    };
+
+    let target = copy::resolve_in_root(mount_point, target)?;
+    fs::create_dir_all(&target)?;
+
+    let mut archive = tar::Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.unpack(&target)?;
+
+    Ok(())
+}
+
+fn add_into(mount_point: &Path, source: &Path, target: &Path) -> Result<(), Error> {
+    match tar_compression(source) {
+        Some(compression) => extract_into(mount_point, source, compression, target),
+        None => copy::copy_into(mount_point, source, target),
+    }
+}
+
+impl MountedImage {
+    /// Copies a file like `COPY` does, unless it is a tar archive which gets
+    /// extracted into `target` instead.
+    pub fn add(&self, label: &str, source: &Path, target: &Path) -> Result<(), Error> {
+        add_into(&self.get_mount_point(label)?, source, target)
+    }
+    /// Extracts the tar archive `source` into `target`, failing on any other
+    /// kind of file.
+    pub fn extract(&self, label: &str, source: &Path, target: &Path) -> Result<(), Error> {
+        let compression = tar_compression(source)
+            .ok_or_else(|| Error::Usage(format!("{} is not a tar archive", source.display())))?;
+        extract_into(&self.get_mount_point(label)?, source, compression, target)
+    }
+    /// Downloads `url`, verifying it against `checksum` when given, before
+    /// adding it like a local file.
+    pub fn add_url(
+        &self,
+        label: &str,
+        url: &str,
+        checksum: Option<&str>,
+        target: &Path,
+    ) -> Result<(), Error> {
+        let download_dir = tempdir::TempDir::new("baker")?;
+        let path = download(url, download_dir.path())?;
+
+        if let Some(checksum) = checksum {
+            verify_checksum(&path, checksum)?;
+        }
+
+        self.add(label, &path, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_url() {
+        assert!(is_url("https://example.com/app.tar.gz"));
+        assert!(is_url("http://example.com/app"));
+        assert!(!is_url("files/app.tar.gz"));
+    }
+
+    #[test]
+    fn test_tar_compression() {
+        assert_eq!(
+            tar_compression(Path::new("app.tar")),
+            Some(TarCompression::None)
+        );
+        assert_eq!(
+            tar_compression(Path::new("dir/app.tgz")),
+            Some(TarCompression::Gzip)
+        );
+        assert_eq!(
+            tar_compression(Path::new("app.tar.xz")),
+            Some(TarCompression::Xz)
+        );
+        assert_eq!(tar_compression(Path::new("app.gz")), None);
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("file");
+        fs::write(&path, b"hello").unwrap();
+
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(verify_checksum(&path, &format!("sha256:{}", digest)).is_ok());
+        assert!(verify_checksum(&path, &format!("sha256:{}", digest.to_uppercase())).is_ok());
+        assert!(matches!(
+            verify_checksum(&path, "sha256:00"),
+            Err(Error::Other(_))
+        ));
+        assert!(matches!(
+            verify_checksum(&path, &format!("md5:{}", digest)),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_add_into_extracts_archives() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+
+        let archive_path = tmp_dir.path().join("app.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            File::create(&archive_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o755);
+        builder
+            .append_data(&mut header, "bin/app", &b"hello"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        add_into(&root, &archive_path, Path::new("/opt/app")).unwrap();
+        assert_eq!(fs::read(root.join("opt/app/bin/app")).unwrap(), b"hello");
+
+        let plain_path = tmp_dir.path().join("notes.txt");
+        fs::write(&plain_path, b"notes").unwrap();
+        add_into(&root, &plain_path, Path::new("/opt/")).unwrap();
+        assert_eq!(fs::read(root.join("opt/notes.txt")).unwrap(), b"notes");
+    }
+}
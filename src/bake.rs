@@ -0,0 +1,234 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    error::Error,
+    images::{self, BakerImage, BuildOptions},
+    progress,
+};
+
+/// A `bake.toml`, declaring images to build together.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BakeFile {
+    #[serde(default)]
+    targets: BTreeMap<String, BakeTarget>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BakeTarget {
+    /// Build context, relative to the bake file
+    #[serde(default = "default_context")]
+    context: PathBuf,
+    /// Bakerfile, relative to the context
+    file: Option<PathBuf>,
+    /// `NAME[:TAG]` references, the image getting the first one and then
+    /// tagged with the others
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    args: BTreeMap<String, String>,
+    /// Stage to stop at
+    target: Option<String>,
+}
+
+fn default_context() -> PathBuf {
+    PathBuf::from(".")
+}
+
+pub fn parse_bake_file(contents: &str) -> Result<BakeFile, Error> {
+    toml::from_str(contents).map_err(|err| Error::Usage(format!("Invalid bake file: {}", err)))
+}
+
+pub fn load_bake_file(path: &Path) -> Result<BakeFile, Error> {
+    parse_bake_file(&fs::read_to_string(path)?)
+}
+
+/// Names of the targets to build, every one when none is asked for.
+fn select_targets<'a>(bake_file: &'a BakeFile, names: &'a [String]) -> Result<Vec<&'a str>, Error> {
+    if names.is_empty() {
+        return Ok(bake_file.targets.keys().map(String::as_str).collect());
+    }
+
+    names
+        .iter()
+        .map(|name| match bake_file.targets.contains_key(name) {
+            true => Ok(name.as_str()),
+            false => Err(Error::Usage(format!("No target named {} to bake", name))),
+        })
+        .collect()
+}
+
+fn bake_target(
+    dir: &Path,
+    target: &BakeTarget,
+    options: &BuildOptions,
+) -> Result<Vec<BakerImage>, Error> {
+    let context = dir.join(&target.context);
+    let file = context.join(target.file.as_deref().unwrap_or(Path::new("Bakerfile")));
+    let (name, tag) = match target.tags.first() {
+        Some(reference) => {
+            let (name, tag) = images::parse_reference(reference)?;
+            (Some(name), Some(tag))
+        }
+        None => (None, None),
+    };
+
+    let mut build_args = options.build_args.clone();
+    build_args.extend(target.args.clone());
+    let options = BuildOptions {
+        context,
+        build_args,
+        target: target.target.clone(),
+        ..options.clone()
+    };
+    let build = |platform: Option<&str>| {
+        let built = images::build(
+            file.clone(),
+            name.clone(),
+            tag.clone(),
+            BuildOptions {
+                platform: platform.map(str::to_string),
+                ..options.clone()
+            },
+        )?;
+        for reference in target.tags.iter().skip(1) {
+            let (new_name, new_tag) = images::parse_reference(reference)?;
+            images::tag(
+                built.platform(),
+                built.name(),
+                built.tag(),
+                &new_name,
+                &new_tag,
+            )?;
+        }
+        Ok(built)
+    };
+
+    match target.platforms.as_slice() {
+        [] => Ok(vec![build(None)?]),
+        platforms => images::build_platforms(platforms, |platform| build(Some(platform))),
+    }
+}
+
+/// Builds the targets of a bake file, `jobs` of them at once, carrying on
+/// past failed targets. Bases are pulled once and shared by the targets.
+pub fn bake(
+    path: &Path,
+    names: &[String],
+    options: &BuildOptions,
+) -> Result<Vec<BakerImage>, Error> {
+    let bake_file = load_bake_file(path)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let selected = select_targets(&bake_file, names)?;
+
+    let mut built = Vec::new();
+    let mut failed = Vec::new();
+    for names in selected.chunks(options.jobs.max(1)) {
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = names
+                .iter()
+                .map(|name| {
+                    let target = &bake_file.targets[*name];
+                    scope.spawn(move || {
+                        progress::message(&format!("Baking {}", name));
+                        bake_target(dir, target, options)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(Error::Other("Baking a target panicked".to_string()))
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+
+        for (name, result) in names.iter().zip(results) {
+            match result {
+                Ok(images) => built.extend(images),
+                Err(err) => {
+                    eprintln!("{}: {}", name, err);
+                    failed.push(*name);
+                }
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(Error::Other(format!(
+            "Failed to bake {} of {} targets: {}",
+            failed.len(),
+            selected.len(),
+            failed.join(", ")
+        )));
+    }
+
+    Ok(built)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bake_file() {
+        let bake_file = parse_bake_file(
+            r#"
+            [targets.kiosk]
+            context = "kiosk"
+            tags = ["kiosk:1.2", "kiosk:latest"]
+            platforms = ["arm64", "armhf"]
+            args = { VERSION = "1.2" }
+
+            [targets.sensor]
+            file = "Bakerfile.sensor"
+            target = "runtime"
+            "#,
+        )
+        .unwrap();
+
+        let kiosk = &bake_file.targets["kiosk"];
+        assert_eq!(kiosk.context, PathBuf::from("kiosk"));
+        assert_eq!(kiosk.tags, vec!["kiosk:1.2", "kiosk:latest"]);
+        assert_eq!(kiosk.args["VERSION"], "1.2");
+
+        let sensor = &bake_file.targets["sensor"];
+        assert_eq!(sensor.context, PathBuf::from("."));
+        assert!(sensor.platforms.is_empty());
+        assert_eq!(sensor.target.as_deref(), Some("runtime"));
+
+        assert!(matches!(
+            parse_bake_file("[targets.kiosk]\ntag = \"kiosk\"\n"),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_select_targets() {
+        let bake_file = parse_bake_file("[targets.kiosk]\n[targets.gateway]\n").unwrap();
+
+        assert_eq!(
+            select_targets(&bake_file, &[]).unwrap(),
+            vec!["gateway", "kiosk"]
+        );
+        assert_eq!(
+            select_targets(&bake_file, &["kiosk".to_string()]).unwrap(),
+            vec!["kiosk"]
+        );
+        assert!(matches!(
+            select_targets(&bake_file, &["sensor".to_string()]),
+            Err(Error::Usage(_))
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, Read, Write},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+};
+
+use crate::{devices, error::Error, images::BakerImage, progress};
+
+const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+fn check_device(device: &Path) -> Result<(), Error> {
+    if !device.metadata()?.file_type().is_block_device() {
+        return Err(Error::Usage(format!(
+            "{} is not a block device",
+            device.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Refuses devices in use, whose filesystems writing would corrupt.
+fn check_not_mounted(device: &Path, mount_points: &[String]) -> Result<(), Error> {
+    if !mount_points.is_empty() {
+        return Err(Error::Usage(format!(
+            "{} is mounted on {}, unmount it first",
+            device.display(),
+            mount_points.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Asks before overwriting the device, anything but an explicit yes aborts.
+fn confirm<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    device: &Path,
+) -> Result<bool, Error> {
+    write!(
+        output,
+        "All data on {} will be erased. Continue? [y/N] ",
+        device.display()
+    )?;
+    output.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Copies the image in large chunks, returning the number of bytes written.
+fn write_image<R: Read, W: Write>(image: &mut R, device: &mut W) -> Result<u64, Error> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut written = 0;
+
+    loop {
+        let read = match image.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        device.write_all(&buffer[..read])?;
+        written += read as u64;
+    }
+
+    device.flush()?;
+
+    Ok(written)
+}
+
+pub fn burn(image: &BakerImage, device: &Path, assume_yes: bool) -> Result<(), Error> {
+    check_device(device)?;
+    check_not_mounted(device, &devices::mounted(device)?)?;
+
+    if !assume_yes && !confirm(&mut io::stdin().lock(), &mut io::stderr(), device)? {
+        return Err(Error::Other("Burn aborted".to_string()));
+    }
+
+    if !progress::is_quiet() {
+        println!("Burning {} to {}", image.full_name(), device.display());
+    }
+
+    let mut source = File::open(image.path()?)?;
+    let mut target = OpenOptions::new().write(true).open(device)?;
+
+    let written = write_image(&mut source, &mut target)?;
+    target.sync_all()?;
+
+    if !progress::is_quiet() {
+        println!(
+            "Wrote {} to {}",
+            crate::size::format_size(written),
+            device.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirm() {
+        let device = Path::new("/dev/sdz");
+
+        for (answer, expected) in [
+            ("y\n", true),
+            ("YES\n", true),
+            ("n\n", false),
+            ("\n", false),
+        ] {
+            let mut output = Vec::new();
+            let confirmed = confirm(&mut answer.as_bytes(), &mut output, device).unwrap();
+
+            assert_eq!(confirmed, expected);
+            assert!(String::from_utf8(output).unwrap().contains("/dev/sdz"));
+        }
+    }
+
+    #[test]
+    fn test_write_image() {
+        let image: Vec<u8> = (0..BUFFER_SIZE + 1000).map(|i| i as u8).collect();
+        let mut device = Vec::new();
+
+        let written = write_image(&mut image.as_slice(), &mut device).unwrap();
+
+        assert_eq!(written, image.len() as u64);
+        assert_eq!(device, image);
+    }
+
+    #[test]
+    fn test_check_not_mounted() {
+        let device = Path::new("/dev/sdz");
+
+        assert!(check_not_mounted(device, &[]).is_ok());
+        assert!(matches!(
+            check_not_mounted(device, &["/media/boot".to_string()]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_device_rejects_regular_file() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("disk.img");
+        std::fs::write(&path, b"").unwrap();
+
+        assert!(matches!(check_device(&path), Err(Error::Usage(_))));
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::error::Error;
+
+type Teardown = Box<dyn FnOnce() + Send>;
+
+pub struct Registry {
+    next_id: AtomicU64,
+    teardowns: Mutex<BTreeMap<u64, Teardown>>,
+}
+
+static REGISTRY: Registry = Registry::new();
+
+impl Registry {
+    pub const fn new() -> Registry {
+        Registry {
+            next_id: AtomicU64::new(0),
+            teardowns: Mutex::new(BTreeMap::new()),
+        }
+    }
+    fn register(&self, teardown: Teardown) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.teardowns
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(id, teardown);
+        id
+    }
+    /// Removes a teardown without running it. Returns `false` if it has
+    /// already been taken, meaning someone else is cleaning up.
+    fn release(&self, id: u64) -> bool {
+        self.teardowns
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&id)
+            .is_some()
+    }
+    /// Runs every registered teardown once, most recent first.
+    pub fn run_all(&self) -> usize {
+        let teardowns =
+            std::mem::take(&mut *self.teardowns.lock().unwrap_or_else(|err| err.into_inner()));
+        let count = teardowns.len();
+
+        for (_, teardown) in teardowns.into_iter().rev() {
+            teardown();
+        }
+
+        count
+    }
+}
+
+/// Keeps a teardown registered for as long as it is alive.
+pub struct Registration {
+    id: u64,
+    registry: &'static Registry,
+}
+
+impl Registration {
+    /// Unregisters the teardown so the owner can clean up itself. Returns
+    /// `false` if the signal handler already took care of it.
+    pub fn release(self) -> bool {
+        let released = self.registry.release(self.id);
+        std::mem::forget(self);
+        released
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.release(self.id);
+    }
+}
+
+/// Registers a best-effort teardown to run if the process is interrupted.
+pub fn register<F>(teardown: F) -> Registration
+where
+    F: FnOnce() + Send + 'static,
+{
+    Registration {
+        id: REGISTRY.register(Box::new(teardown)),
+        registry: &REGISTRY,
+    }
+}
+
+pub fn install_signal_handler() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        eprintln!("Interrupted, cleaning up");
+        REGISTRY.run_all();
+        std::process::exit(130);
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{atomic::AtomicUsize, Arc};
+
+    #[test]
+    fn test_run_all_invokes_teardown_exactly_once() {
+        static TEST_REGISTRY: Registry = Registry::new();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let counter = count.clone();
+        let id = TEST_REGISTRY.register(Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        assert_eq!(TEST_REGISTRY.run_all(), 1);
+        assert_eq!(TEST_REGISTRY.run_all(), 0);
+        assert!(!TEST_REGISTRY.release(id));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_released_teardown_is_not_run() {
+        static TEST_REGISTRY: Registry = Registry::new();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let counter = count.clone();
+        let registration = Registration {
+            id: TEST_REGISTRY.register(Box::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            registry: &TEST_REGISTRY,
+        };
+
+        assert!(registration.release());
+        assert_eq!(TEST_REGISTRY.run_all(), 0);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_run_all_runs_most_recent_first() {
+        static TEST_REGISTRY: Registry = Registry::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for index in 0..3 {
+            let order = order.clone();
+            TEST_REGISTRY.register(Box::new(move || order.lock().unwrap().push(index)));
+        }
+
+        TEST_REGISTRY.run_all();
+        assert_eq!(*order.lock().unwrap(), vec![2, 1, 0]);
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use glob::{glob, MatchOptions, Pattern};
+use path_absolutize::*;
+
+use crate::{error::Error, size};
+
+mod git;
+
+pub use git::{GitContext, GitUrl};
+
+pub const DEFAULT_MAX_SIZE: &str = "2G";
+pub const DEFAULT_MAX_SIZE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+pub const DEFAULT_MAX_FILES: usize = 10_000;
+/// File at the root of the build context listing what COPY must leave out.
+pub const IGNORE_FILE: &str = ".bakerignore";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Follow symlinks wherever they point
+    Follow,
+    /// Follow symlinks only when they stay inside the build context
+    Contained,
+    /// Copy symlinks themselves instead of their targets
+    Copy,
+    /// Refuse to copy symlinks
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File(PathBuf),
+    Symlink(PathBuf),
+    Directory(PathBuf),
+}
+
+impl Source {
+    pub fn path(&self) -> &Path {
+        match self {
+            Source::File(path) | Source::Symlink(path) | Source::Directory(path) => path,
+        }
+    }
+}
+
+/// Line of a `.bakerignore`, `!` re-including what earlier lines excluded.
+#[derive(Debug)]
+struct IgnoreRule {
+    pattern: Pattern,
+    negated: bool,
+}
+
+/// Reads `.bakerignore` lines, patterns relative to the context root like in
+/// a `.dockerignore`.
+fn parse_ignore(content: &str) -> Result<Vec<IgnoreRule>, Error> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (line, negated) = match line.strip_prefix('!') {
+                Some(line) => (line.trim(), true),
+                None => (line, false),
+            };
+            let line = line.trim_start_matches('/').trim_end_matches('/');
+            Ok(IgnoreRule {
+                pattern: Pattern::new(line)?,
+                negated,
+            })
+        })
+        .collect()
+}
+
+/// Expands COPY sources inside the build context while keeping track of how
+/// much of it has been ingested.
+pub struct BuildContext {
+    root: PathBuf,
+    ignore: Vec<IgnoreRule>,
+    symlinks: SymlinkPolicy,
+    max_size: u64,
+    max_files: usize,
+    size: u64,
+    files: usize,
+}
+
+impl BuildContext {
+    pub fn new(
+        root: &Path,
+        symlinks: SymlinkPolicy,
+        max_size: u64,
+        max_files: usize,
+    ) -> Result<BuildContext, Error> {
+        let ignore = match fs::read_to_string(root.join(IGNORE_FILE)) {
+            Ok(content) => parse_ignore(&content)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(BuildContext {
+            root: root.canonicalize()?,
+            ignore,
+            symlinks,
+            max_size,
+            max_files,
+            size: 0,
+            files: 0,
+        })
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    /// Whether `.bakerignore` excludes `path`, directly or through one of the
+    /// directories it is in. Directories are walked anyway when a `!` rule
+    /// may re-include something below them.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        if path.is_dir() && self.ignore.iter().any(|rule| rule.negated) {
+            return false;
+        }
+        let absolute = match path.absolutize() {
+            Ok(absolute) => absolute,
+            Err(_) => return false,
+        };
+        let relative = match absolute.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        self.ignore.iter().fold(false, |ignored, rule| {
+            let matches = relative
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| rule.pattern.matches_path_with(ancestor, options));
+            if matches {
+                !rule.negated
+            } else {
+                ignored
+            }
+        })
+    }
+    fn check_symlink(&self, path: &Path) -> Result<Option<Source>, Error> {
+        let is_symlink = fs::symlink_metadata(path)?.file_type().is_symlink();
+
+        match self.symlinks {
+            SymlinkPolicy::Follow => Ok(None),
+            SymlinkPolicy::Copy if is_symlink => Ok(Some(Source::Symlink(path.to_path_buf()))),
+            SymlinkPolicy::Reject if is_symlink => Err(Error::Usage(format!(
+                "COPY source {} is a symlink, which the symlink policy rejects",
+                path.display()
+            ))),
+            _ => {
+                // A symlink anywhere in the path must not lead out of the context
+                let inside = path.absolutize()?.starts_with(&self.root);
+                if inside && !path.canonicalize()?.starts_with(&self.root) {
+                    return Err(Error::Usage(format!(
+                        "COPY source {} links outside the build context",
+                        path.display()
+                    )));
+                }
+                Ok(None)
+            }
+        }
+    }
+    fn account(&mut self, path: &Path, size: u64) -> Result<(), Error> {
+        self.size += size;
+        self.files += 1;
+
+        if self.files > self.max_files {
+            return Err(Error::Usage(format!(
+                "Build context exceeds {} files while adding {}, raise --max-context-files if this is intended",
+                self.max_files,
+                path.display()
+            )));
+        }
+
+        if self.size > self.max_size {
+            return Err(Error::Usage(format!(
+                "Build context exceeds {} while adding {}, raise --max-context-size if this is intended",
+                size::format_size(self.max_size),
+                path.display()
+            )));
+        }
+
+        Ok(())
+    }
+    /// Accounts for everything inside a copied directory. Nested symlinks are
+    /// copied as symlinks, so only the reject policy applies to them.
+    fn account_tree(&mut self, dir: &Path) -> Result<(), Error> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let metadata = fs::symlink_metadata(&path)?;
+
+            if self.is_ignored(&path) {
+                continue;
+            }
+
+            if metadata.file_type().is_symlink() {
+                if self.symlinks == SymlinkPolicy::Reject {
+                    return Err(Error::Usage(format!(
+                        "COPY source {} is a symlink, which the symlink policy rejects",
+                        path.display()
+                    )));
+                }
+                self.account(&path, 0)?;
+            } else if metadata.is_dir() {
+                self.account_tree(&path)?;
+            } else {
+                self.account(&path, metadata.len())?;
+            }
+        }
+
+        Ok(())
+    }
+    /// Expands a pattern relative to the context root, skipping what
+    /// `.bakerignore` excludes.
+    pub fn expand(&mut self, pattern: &str) -> Result<Vec<Source>, Error> {
+        let mut sources = Vec::new();
+        let pattern = self.root.join(pattern);
+        let pattern = pattern
+            .to_str()
+            .ok_or_else(|| Error::Usage("Invalid pattern".to_string()))?;
+
+        for path in glob(pattern)?.collect::<Result<Vec<_>, _>>()? {
+            if !path.absolutize()?.starts_with(&self.root) {
+                return Err(Error::Usage(format!(
+                    "COPY source {} is outside the build context",
+                    path.display()
+                )));
+            }
+            if self.is_ignored(&path) {
+                continue;
+            }
+
+            let source = match self.check_symlink(&path)? {
+                Some(source) => {
+                    self.account(&path, 0)?;
+                    source
+                }
+                None if path.is_dir() => {
+                    self.account_tree(&path)?;
+                    Source::Directory(path)
+                }
+                None => {
+                    self.account(&path, fs::metadata(&path)?.len())?;
+                    Source::File(path)
+                }
+            };
+            sources.push(source);
+        }
+
+        Ok(sources)
+    }
+    /// Appends a line per file of the tree at `path` with its digest, to
+    /// notice any change to it.
+    fn digest_tree(&self, path: &Path, lines: &mut Vec<String>) -> Result<(), Error> {
+        if self.is_ignored(path) {
+            return Ok(());
+        }
+
+        let relative = path.strip_prefix(&self.root).unwrap_or(path).display();
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.file_type().is_symlink() {
+            lines.push(format!(
+                "{} -> {}",
+                relative,
+                fs::read_link(path)?.display()
+            ));
+            if self.symlinks == SymlinkPolicy::Copy || !path.exists() {
+                return Ok(());
+            }
+        }
+
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>, Error>>()?;
+            entries.sort();
+            for entry in entries {
+                self.digest_tree(&entry, lines)?;
+            }
+        } else {
+            lines.push(format!("{} {}", relative, sha256::try_digest(path)?));
+        }
+
+        Ok(())
+    }
+    /// Digest of everything a pattern matches, which changes whenever one of
+    /// the files it would copy does.
+    pub fn digest(&self, pattern: &str) -> Result<String, Error> {
+        let pattern = self.root.join(pattern);
+        let pattern = pattern
+            .to_str()
+            .ok_or_else(|| Error::Usage("Invalid pattern".to_string()))?;
+
+        let mut lines = Vec::new();
+        for path in glob(pattern)?.collect::<Result<Vec<_>, _>>()? {
+            self.digest_tree(&path, &mut lines)?;
+        }
+
+        Ok(sha256::digest(lines.join("\n")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn setup() -> (tempdir::TempDir, PathBuf) {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let root = dir.path().join("context");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("a.conf"), vec![0; 600]).unwrap();
+        fs::write(root.join("b.conf"), vec![0; 600]).unwrap();
+        fs::write(dir.path().join("secret"), "secret").unwrap();
+        (dir, root)
+    }
+
+    fn pattern(root: &Path, name: &str) -> String {
+        root.join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_default_max_size() {
+        assert_eq!(
+            size::parse_size(DEFAULT_MAX_SIZE).unwrap(),
+            DEFAULT_MAX_SIZE_BYTES
+        );
+    }
+
+    #[test]
+    fn test_expand_within_limits() {
+        let (_dir, root) = setup();
+        let mut context = BuildContext::new(&root, SymlinkPolicy::Contained, 2048, 10).unwrap();
+
+        assert_eq!(context.expand(&pattern(&root, "*.conf")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_expand_size_limit() {
+        let (_dir, root) = setup();
+        let mut context = BuildContext::new(&root, SymlinkPolicy::Contained, 1000, 10).unwrap();
+
+        let err = context.expand(&pattern(&root, "*.conf")).unwrap_err();
+        assert!(err.to_string().contains("--max-context-size"));
+    }
+
+    #[test]
+    fn test_expand_file_count_limit() {
+        let (_dir, root) = setup();
+        let mut context = BuildContext::new(&root, SymlinkPolicy::Contained, 2048, 1).unwrap();
+
+        let err = context.expand(&pattern(&root, "*.conf")).unwrap_err();
+        assert!(err.to_string().contains("--max-context-files"));
+    }
+
+    #[test]
+    fn test_expand_directory() {
+        let (_dir, root) = setup();
+        fs::create_dir_all(root.join("overlay/etc")).unwrap();
+        fs::write(root.join("overlay/etc/hostname"), vec![0; 600]).unwrap();
+        symlink("hostname", root.join("overlay/etc/link")).unwrap();
+
+        let mut context = BuildContext::new(&root, SymlinkPolicy::Contained, 4096, 10).unwrap();
+        assert_eq!(
+            context.expand(&pattern(&root, "overlay")).unwrap(),
+            vec![Source::Directory(root.join("overlay"))]
+        );
+        assert_eq!((context.size, context.files), (600, 2));
+
+        let mut limited = BuildContext::new(&root, SymlinkPolicy::Contained, 500, 10).unwrap();
+        assert!(limited.expand(&pattern(&root, "overlay")).is_err());
+
+        let mut reject = BuildContext::new(&root, SymlinkPolicy::Reject, 4096, 10).unwrap();
+        assert!(reject.expand(&pattern(&root, "overlay")).is_err());
+    }
+
+    #[test]
+    fn test_expand_relative_to_root() {
+        let (_dir, root) = setup();
+        let mut context = BuildContext::new(&root, SymlinkPolicy::Contained, 2048, 10).unwrap();
+
+        assert_eq!(
+            context.expand("a.conf").unwrap(),
+            vec![Source::File(root.join("a.conf"))]
+        );
+        assert!(matches!(context.expand("../secret"), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn test_expand_honors_ignore_file() {
+        let (_dir, root) = setup();
+        fs::create_dir_all(root.join("build/cache")).unwrap();
+        fs::write(root.join("build/app"), vec![0; 100]).unwrap();
+        fs::write(root.join("build/cache/blob"), vec![0; 100]).unwrap();
+        fs::write(root.join("build/cache/keep"), vec![0; 100]).unwrap();
+        fs::write(
+            root.join(IGNORE_FILE),
+            "# Secrets
+b.conf
+/build/cache
+!build/cache/keep
+",
+        )
+        .unwrap();
+
+        let mut context = BuildContext::new(&root, SymlinkPolicy::Contained, 4096, 10).unwrap();
+        assert_eq!(
+            context.expand("*.conf").unwrap(),
+            vec![Source::File(root.join("a.conf"))]
+        );
+        assert_eq!(
+            context.expand("build").unwrap(),
+            vec![Source::Directory(root.join("build"))]
+        );
+        assert_eq!(context.files, 3);
+
+        assert!(context.is_ignored(&root.join("build/cache/blob")));
+        assert!(!context.is_ignored(&root.join("build/cache/keep")));
+        assert!(!context.is_ignored(&root.join("build/app")));
+    }
+
+    #[test]
+    fn test_digest_follows_contents() {
+        let (_dir, root) = setup();
+        let context = BuildContext::new(&root, SymlinkPolicy::Contained, 2048, 10).unwrap();
+
+        let digest = context.digest("*.conf").unwrap();
+        assert_eq!(context.digest("*.conf").unwrap(), digest);
+        assert_ne!(context.digest("a.conf").unwrap(), digest);
+
+        fs::write(root.join("b.conf"), "changed").unwrap();
+        assert_ne!(context.digest("*.conf").unwrap(), digest);
+    }
+
+    #[test]
+    fn test_expand_symlink_policies() {
+        let (dir, root) = setup();
+        symlink(dir.path().join("secret"), root.join("escape.conf")).unwrap();
+        symlink(root.join("a.conf"), root.join("inside.conf")).unwrap();
+
+        let mut contained = BuildContext::new(&root, SymlinkPolicy::Contained, 4096, 10).unwrap();
+        assert!(contained.expand(&pattern(&root, "inside.conf")).is_ok());
+        assert!(contained.expand(&pattern(&root, "escape.conf")).is_err());
+
+        let mut follow = BuildContext::new(&root, SymlinkPolicy::Follow, 4096, 10).unwrap();
+        assert_eq!(
+            follow.expand(&pattern(&root, "escape.conf")).unwrap(),
+            vec![Source::File(root.join("escape.conf"))]
+        );
+
+        let mut copy = BuildContext::new(&root, SymlinkPolicy::Copy, 4096, 10).unwrap();
+        assert_eq!(
+            copy.expand(&pattern(&root, "escape.conf")).unwrap(),
+            vec![Source::Symlink(root.join("escape.conf"))]
+        );
+
+        let mut reject = BuildContext::new(&root, SymlinkPolicy::Reject, 4096, 10).unwrap();
+        assert!(reject.expand(&pattern(&root, "inside.conf")).is_err());
+        assert!(reject.expand(&pattern(&root, "a.conf")).is_ok());
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use crate::{cleanup::Registration, error::Error};
+
+/// A `URL#REF:DIR` build context, like Docker takes, the ref and the
+/// directory of the repository being optional.
+#[derive(Debug, PartialEq, Eq)]
+pub struct GitUrl {
+    url: String,
+    reference: Option<String>,
+    subdir: Option<String>,
+}
+
+impl GitUrl {
+    /// Reads `context` as a git URL, `None` meaning it is a local directory.
+    pub fn parse(context: &str) -> Option<GitUrl> {
+        let (url, fragment) = match context.split_once('#') {
+            Some((url, fragment)) => (url, Some(fragment)),
+            None => (context, None),
+        };
+        let is_git = url.starts_with("git@")
+            || url.starts_with("git://")
+            || url.starts_with("ssh://")
+            || ((url.starts_with("https://") || url.starts_with("http://"))
+                && url.ends_with(".git"));
+        if !is_git {
+            return None;
+        }
+
+        let (reference, subdir) = match fragment.map(|fragment| fragment.split_once(':')) {
+            Some(Some((reference, subdir))) => (reference, Some(subdir)),
+            Some(None) => (fragment.unwrap_or_default(), None),
+            None => ("", None),
+        };
+        let non_empty = |value: &str| Some(value.to_string()).filter(|value| !value.is_empty());
+
+        Some(GitUrl {
+            url: url.to_string(),
+            reference: non_empty(reference),
+            subdir: subdir.and_then(non_empty),
+        })
+    }
+
+    /// Rejects refs git would take for options and directories outside of
+    /// the repository.
+    fn check(&self) -> Result<(), Error> {
+        if let Some(reference) = self.reference.as_deref().filter(|r| r.starts_with('-')) {
+            return Err(Error::Usage(format!("Invalid git ref: {}", reference)));
+        }
+        if let Some(subdir) = &self.subdir {
+            if !Path::new(subdir)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(Error::Usage(format!(
+                    "{} is not a directory inside of {}",
+                    subdir, self.url
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Subcommand git runs with `args`, past the `-C DIR` option.
+fn subcommand<'a>(args: &[&'a str]) -> &'a str {
+    match args {
+        ["-C", _, subcommand, ..] | [subcommand, ..] => subcommand,
+        [] => "",
+    }
+}
+
+fn git(args: &[&str]) -> Result<(), Error> {
+    let status = Command::new("git").args(args).status()?;
+    if !status.success() {
+        return Err(Error::Other(format!("git {} failed", subcommand(args))));
+    }
+    Ok(())
+}
+
+/// A clone of a repository used as the build context, deleted when dropped.
+pub struct GitContext {
+    root: PathBuf,
+    _dir: tempdir::TempDir,
+    _registration: Registration,
+}
+
+impl GitContext {
+    pub fn clone(url: &GitUrl) -> Result<GitContext, Error> {
+        url.check()?;
+
+        let dir = tempdir::TempDir::new("baker-git")?;
+        let cleanup_path = dir.path().to_path_buf();
+        let registration = crate::cleanup::register(move || {
+            let _ = fs::remove_dir_all(cleanup_path);
+        });
+        let path = dir.path().join("repository");
+        let path_str = path
+            .to_str()
+            .ok_or_else(|| Error::Other("Invalid clone path".to_string()))?;
+
+        // Only branches and tags can be cloned shallowly, a commit needs the
+        // whole history
+        let shallow = match &url.reference {
+            Some(reference) => git(&[
+                "clone", "--quiet", "--depth", "1", "--branch", reference, &url.url, path_str,
+            ]),
+            None => git(&["clone", "--quiet", "--depth", "1", &url.url, path_str]),
+        };
+        match (shallow, &url.reference) {
+            (Err(_), Some(reference)) => {
+                let _ = fs::remove_dir_all(&path);
+                git(&["clone", "--quiet", &url.url, path_str])?;
+                git(&["-C", path_str, "checkout", "--quiet", reference])?;
+            }
+            (Err(err), None) => return Err(err),
+            (Ok(()), _) => {}
+        }
+        git(&[
+            "-C",
+            path_str,
+            "submodule",
+            "update",
+            "--quiet",
+            "--init",
+            "--recursive",
+        ])?;
+
+        let root = match &url.subdir {
+            Some(subdir) => path.join(subdir),
+            None => path.clone(),
+        };
+        // Symlinks of the repository could point the directory outside of it
+        let inside = match (root.canonicalize(), path.canonicalize()) {
+            (Ok(root), Ok(path)) => root.is_dir() && root.starts_with(path),
+            _ => false,
+        };
+        if !inside {
+            return Err(Error::Usage(format!(
+                "{} is not a directory of {}",
+                url.subdir.as_deref().unwrap_or_default(),
+                url.url
+            )));
+        }
+
+        Ok(GitContext {
+            root,
+            _dir: dir,
+            _registration: registration,
+        })
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_git_url() {
+        assert_eq!(
+            GitUrl::parse("https://github.com/me/pi-kiosk.git#main"),
+            Some(GitUrl {
+                url: "https://github.com/me/pi-kiosk.git".to_string(),
+                reference: Some("main".to_string()),
+                subdir: None,
+            })
+        );
+        assert_eq!(
+            GitUrl::parse("git@github.com:me/pi-kiosk.git#v1.2:images/kiosk"),
+            Some(GitUrl {
+                url: "git@github.com:me/pi-kiosk.git".to_string(),
+                reference: Some("v1.2".to_string()),
+                subdir: Some("images/kiosk".to_string()),
+            })
+        );
+        assert_eq!(
+            GitUrl::parse("https://github.com/me/pi-kiosk.git#:kiosk"),
+            Some(GitUrl {
+                url: "https://github.com/me/pi-kiosk.git".to_string(),
+                reference: None,
+                subdir: Some("kiosk".to_string()),
+            })
+        );
+        assert_eq!(GitUrl::parse("."), None);
+        assert_eq!(GitUrl::parse("https://example.com/context.tar"), None);
+    }
+
+    #[test]
+    fn test_check_git_url() {
+        let check = |context| GitUrl::parse(context).unwrap().check();
+
+        assert!(check("https://github.com/me/pi-kiosk.git#v1.2:images/./kiosk").is_ok());
+        assert!(matches!(
+            check("https://github.com/me/pi-kiosk.git#--upload-pack=touch"),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            check("https://github.com/me/pi-kiosk.git#main:../../etc"),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            check("https://github.com/me/pi-kiosk.git#:/etc"),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_subcommand() {
+        assert_eq!(subcommand(&["clone", "--quiet"]), "clone");
+        assert_eq!(
+            subcommand(&["-C", "/tmp/repository", "checkout", "main"]),
+            "checkout"
+        );
+    }
+}
@@ -1,33 +1,508 @@
-use std::{fs, path::PathBuf};
+use std::{
+    ffi::{CString, OsString},
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, MetadataExt},
+    },
+    path::{Component, Path, PathBuf},
+};
 
-use crate::mount::MountedImage;
+use crate::{error::Error, mount::MountedImage};
 use path_absolutize::*;
 
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_os_string()),
+            Component::ParentDir => pending.push(OsString::from("..")),
+            _ => {}
+        }
+    }
+}
+
+/// Resolves `path` as if `root` were `/`: symlinks are followed, absolute
+/// ones from `root`, and `..` never climbs above `root`. The result is
+/// always inside `root`, whatever symlinks the image contains.
+pub(crate) fn resolve_in_root(root: &Path, path: &Path) -> Result<PathBuf, Error> {
+    let mut resolved = PathBuf::new();
+    let mut pending = Vec::new();
+    let mut follows = 0;
+
+    push_components(&mut pending, path);
+
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+
+        let candidate = resolved.join(&name);
+        let mounted_candidate = root.join(&candidate);
+
+        match fs::symlink_metadata(&mounted_candidate) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                follows += 1;
+                if follows > MAX_SYMLINK_FOLLOWS {
+                    return Err(Error::Usage(
+                        "Too many levels of symbolic links".to_string(),
+                    ));
+                }
+
+                let link = fs::read_link(&mounted_candidate)?;
+                if link.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                push_components(&mut pending, &link);
+            }
+            _ => resolved = candidate,
+        }
+    }
+
+    Ok(root.join(resolved))
+}
+
+/// Resolves `target` inside `mount_point`. A target ending with `/` or naming
+/// an existing directory receives the source under its own file name.
+fn resolve_target(mount_point: &Path, source: &Path, target: &Path) -> Result<PathBuf, Error> {
+    let mount_point_string = mount_point
+        .to_str()
+        .ok_or_else(|| Error::Other("Failed to convert path to string".to_string()))?;
+
+    let target_str = target
+        .to_str()
+        .ok_or_else(|| Error::Other("Failed to convert path to string".to_string()))?;
+
+    let mounted_target = PathBuf::from(mount_point_string.to_string() + "/" + target_str);
+
+    if !mounted_target.absolutize()?.starts_with(mount_point) {
+        return Err(Error::Usage("Invalid target path".to_string()));
+    }
+
+    let mut absolute_mounted_target = resolve_in_root(mount_point, target)?;
+
+    if target_str.ends_with('/') || absolute_mounted_target.is_dir() {
+        absolute_mounted_target = resolve_in_root(
+            mount_point,
+            &target.join(
+                source
+                    .file_name()
+                    .ok_or_else(|| Error::Other("Invalid source path".to_string()))?,
+            ),
+        )?;
+    }
+
+    if !absolute_mounted_target.starts_with(mount_point) {
+        return Err(Error::Usage("Invalid target path".to_string()));
+    }
+
+    Ok(absolute_mounted_target)
+}
+
+pub(crate) fn copy_into(mount_point: &Path, source: &Path, target: &Path) -> Result<(), Error> {
+    let absolute_mounted_target = resolve_target(mount_point, source, target)?;
+
+    let parent = absolute_mounted_target
+        .parent()
+        .ok_or_else(|| Error::Other("Invalid target path".to_string()))?;
+
+    if !parent.starts_with(mount_point) {
+        return Err(Error::Usage("Invalid target path".to_string()));
+    }
+
+    fs::create_dir_all(parent)?;
+
+    fs::copy(source, absolute_mounted_target)?;
+
+    Ok(())
+}
+
+/// Recreates the `source` symlink itself at `target` instead of copying the
+/// file it points to.
+fn copy_symlink_into(mount_point: &Path, source: &Path, target: &Path) -> Result<(), Error> {
+    let absolute_mounted_target = resolve_target(mount_point, source, target)?;
+
+    let parent = absolute_mounted_target
+        .parent()
+        .ok_or_else(|| Error::Other("Invalid target path".to_string()))?;
+
+    if !parent.starts_with(mount_point) {
+        return Err(Error::Usage("Invalid target path".to_string()));
+    }
+
+    fs::create_dir_all(parent)?;
+
+    if fs::symlink_metadata(&absolute_mounted_target).is_ok() {
+        fs::remove_file(&absolute_mounted_target)?;
+    }
+
+    std::os::unix::fs::symlink(fs::read_link(source)?, absolute_mounted_target)?;
+
+    Ok(())
+}
+
+/// Gives `target` the owner, mode and timestamps of `source`, without
+/// following symlinks.
+fn preserve_metadata(source: &Path, target: &Path) -> Result<(), Error> {
+    let metadata = fs::symlink_metadata(source)?;
+
+    lchown(target, Some(metadata.uid()), Some(metadata.gid()))?;
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(target, metadata.permissions())?;
+    }
+
+    let path = CString::new(target.as_os_str().as_bytes())
+        .map_err(|_| Error::Other("Invalid target path".to_string()))?;
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    let result = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+/// Copies the content of the `source` directory into `target`, like
+/// `COPY dir/ /target` does, leaving out the entries `ignored` matches.
+/// Directories already in the image keep their metadata, everything copied
+/// keeps the one it had in the build context.
+fn copy_tree_into(
+    mount_point: &Path,
+    source: &Path,
+    target: &Path,
+    ignored: &dyn Fn(&Path) -> bool,
+) -> Result<(), Error> {
+    let mounted_target = resolve_in_root(mount_point, target)?;
+    let created = !mounted_target.exists();
+    fs::create_dir_all(&mounted_target)?;
+
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if ignored(&entry.path()) {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            copy_tree_into(
+                mount_point,
+                &entry.path(),
+                &target.join(entry.file_name()),
+                ignored,
+            )?;
+            continue;
+        }
+
+        let mounted_entry = mounted_target.join(entry.file_name());
+        if let Ok(metadata) = fs::symlink_metadata(&mounted_entry) {
+            if !metadata.is_dir() {
+                fs::remove_file(&mounted_entry)?;
+            }
+        }
+
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &mounted_entry)?;
+        } else {
+            fs::copy(entry.path(), &mounted_entry)?;
+        }
+        preserve_metadata(&entry.path(), &mounted_entry)?;
+    }
+
+    if created {
+        preserve_metadata(source, &mounted_target)?;
+    }
+
+    Ok(())
+}
+
+/// Copies the files matching `pattern` inside `source_root` to `target`
+/// inside `target_root`. Symlinks of the source are resolved within its own
+/// root rather than on the host.
+fn copy_between_roots(
+    source_root: &Path,
+    pattern: &str,
+    target_root: &Path,
+    target: &Path,
+) -> Result<usize, Error> {
+    let source_pattern = source_root.join(pattern.trim_start_matches('/'));
+    let source_pattern = source_pattern
+        .to_str()
+        .ok_or_else(|| Error::Other("Failed to convert path to string".to_string()))?;
+
+    let mut copied = 0;
+    for path in glob::glob(source_pattern)?.collect::<Result<Vec<_>, _>>()? {
+        let relative = path
+            .strip_prefix(source_root)
+            .map_err(|_| Error::Other("Invalid source path".to_string()))?;
+        let source = resolve_in_root(source_root, relative)?;
+
+        if !source.is_file() {
+            return Err(Error::Usage(format!(
+                "COPY source {} is not a file",
+                relative.display()
+            )));
+        }
+
+        copy_into(target_root, &source, target)?;
+        copied += 1;
+    }
+
+    if copied == 0 {
+        return Err(Error::Usage(format!(
+            "COPY source {} matched no file",
+            pattern
+        )));
+    }
+
+    Ok(copied)
+}
+
 impl MountedImage {
-    pub fn copy(
+    /// Copies files from another mounted image, used by `COPY --from`.
+    pub fn copy_from(
         &self,
         label: &str,
-        source: &PathBuf,
-        target: &PathBuf,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        source: &MountedImage,
+        source_label: &str,
+        pattern: &str,
+        target: &Path,
+    ) -> Result<(), Error> {
+        copy_between_roots(
+            &source.get_mount_point(source_label)?,
+            pattern,
+            &self.get_mount_point(label)?,
+            target,
+        )?;
+
+        Ok(())
+    }
+    pub fn copy_symlink(&self, label: &str, source: &Path, target: &Path) -> Result<(), Error> {
         let mount_point = self.get_mount_point(label)?;
 
-        let mount_point_string = mount_point
-            .to_str()
-            .ok_or("Failed to convert path to string")?;
+        copy_symlink_into(&mount_point, source, target)
+    }
+    pub fn copy(&self, label: &str, source: &Path, target: &Path) -> Result<(), Error> {
+        let mount_point = self.get_mount_point(label)?;
 
-        let target_str = target.to_str().ok_or("Failed to convert path to string")?;
+        copy_into(&mount_point, source, target)
+    }
+    pub fn copy_tree(
+        &self,
+        label: &str,
+        source: &Path,
+        target: &Path,
+        ignored: &dyn Fn(&Path) -> bool,
+    ) -> Result<(), Error> {
+        let mount_point = self.get_mount_point(label)?;
 
-        let mounted_target = PathBuf::from(mount_point_string.to_string() + "/" + target_str);
+        copy_tree_into(&mount_point, source, target, ignored)
+    }
+}
 
-        let absolute_mounted_target = mounted_target.absolutize()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if !absolute_mounted_target.starts_with(mount_point) {
-            return Err("Invalid target path".into());
-        }
+    fn setup() -> (tempdir::TempDir, PathBuf, PathBuf) {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let mount_point = dir.path().join("rootfs");
+        fs::create_dir_all(&mount_point).unwrap();
+        let source = dir.path().join("app.conf");
+        fs::write(&source, "content").unwrap();
+        (dir, mount_point, source)
+    }
 
-        fs::copy(source, absolute_mounted_target)?;
+    #[test]
+    fn test_copy_into_nonexistent_nested_directory() {
+        let (_dir, mount_point, source) = setup();
 
-        Ok(())
+        copy_into(&mount_point, &source, Path::new("/opt/myapp/app.conf")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(mount_point.join("opt/myapp/app.conf")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn test_copy_tree_into_overlays_rootfs() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let (dir, mount_point, _source) = setup();
+        fs::create_dir_all(mount_point.join("usr/lib")).unwrap();
+        symlink("usr/lib", mount_point.join("lib")).unwrap();
+        fs::set_permissions(&mount_point, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let overlay = dir.path().join("overlay");
+        fs::create_dir_all(overlay.join("lib/firmware")).unwrap();
+        fs::create_dir_all(overlay.join("opt/app")).unwrap();
+        fs::write(overlay.join("lib/firmware/blob.bin"), "blob").unwrap();
+        fs::write(overlay.join("opt/app/run.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(
+            overlay.join("opt/app/run.sh"),
+            fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+        symlink("run.sh", overlay.join("opt/app/start")).unwrap();
+        fs::set_permissions(&overlay, fs::Permissions::from_mode(0o700)).unwrap();
+
+        copy_tree_into(&mount_point, &overlay, Path::new("/"), &|_| false).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(mount_point.join("usr/lib/firmware/blob.bin")).unwrap(),
+            "blob"
+        );
+        let script = fs::metadata(mount_point.join("opt/app/run.sh")).unwrap();
+        assert_eq!(script.permissions().mode() & 0o777, 0o750);
+        assert_eq!(
+            script.mtime(),
+            fs::metadata(overlay.join("opt/app/run.sh"))
+                .unwrap()
+                .mtime()
+        );
+        assert_eq!(
+            fs::read_link(mount_point.join("opt/app/start")).unwrap(),
+            Path::new("run.sh")
+        );
+        let root = fs::metadata(&mount_point).unwrap();
+        assert_eq!(root.permissions().mode() & 0o777, 0o755);
+    }
+
+    #[test]
+    fn test_copy_into_trailing_slash_destination() {
+        let (_dir, mount_point, source) = setup();
+
+        copy_into(&mount_point, &source, Path::new("/opt/myapp/")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(mount_point.join("opt/myapp/app.conf")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn test_copy_into_existing_directory() {
+        let (_dir, mount_point, source) = setup();
+        fs::create_dir_all(mount_point.join("etc")).unwrap();
+
+        copy_into(&mount_point, &source, Path::new("/etc")).unwrap();
+
+        assert!(mount_point.join("etc/app.conf").is_file());
+    }
+
+    #[test]
+    fn test_copy_into_absolute_symlink_stays_in_mount() {
+        let (dir, mount_point, source) = setup();
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, mount_point.join("opt")).unwrap();
+
+        copy_into(&mount_point, &source, Path::new("/opt/app.conf")).unwrap();
+
+        assert!(!outside.join("app.conf").exists());
+        assert!(mount_point
+            .join(outside.strip_prefix("/").unwrap())
+            .join("app.conf")
+            .is_file());
+    }
+
+    #[test]
+    fn test_copy_into_relative_symlink_stays_in_mount() {
+        let (dir, mount_point, source) = setup();
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink("../outside", mount_point.join("escape")).unwrap();
+
+        copy_into(&mount_point, &source, Path::new("/escape/app.conf")).unwrap();
+
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        assert_eq!(
+            fs::read_to_string(mount_point.join("outside/app.conf")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn test_copy_into_symlinked_file_stays_in_mount() {
+        let (dir, mount_point, source) = setup();
+        let outside = dir.path().join("host.conf");
+        fs::write(&outside, "host").unwrap();
+        fs::create_dir_all(mount_point.join("etc")).unwrap();
+        std::os::unix::fs::symlink(&outside, mount_point.join("etc/app.conf")).unwrap();
+
+        copy_into(&mount_point, &source, Path::new("/etc/app.conf")).unwrap();
+
+        assert_eq!(fs::read_to_string(&outside).unwrap(), "host");
+    }
+
+    #[test]
+    fn test_copy_symlink_into() {
+        let (_dir, mount_point, source) = setup();
+        let link = source.with_file_name("link.conf");
+        std::os::unix::fs::symlink("app.conf", &link).unwrap();
+
+        copy_symlink_into(&mount_point, &link, Path::new("/etc/")).unwrap();
+
+        assert_eq!(
+            fs::read_link(mount_point.join("etc/link.conf")).unwrap(),
+            PathBuf::from("app.conf")
+        );
+    }
+
+    #[test]
+    fn test_copy_between_roots() {
+        let (dir, mount_point, _source) = setup();
+        let stage_root = dir.path().join("stage");
+        fs::create_dir_all(stage_root.join("usr/bin")).unwrap();
+        fs::write(stage_root.join("usr/bin/app"), "binary").unwrap();
+        std::os::unix::fs::symlink("/usr/bin/app", stage_root.join("usr/bin/link")).unwrap();
+
+        let copied =
+            copy_between_roots(&stage_root, "/usr/bin/*", &mount_point, Path::new("/opt/"))
+                .unwrap();
+
+        assert_eq!(copied, 2);
+        assert_eq!(
+            fs::read_to_string(mount_point.join("opt/app")).unwrap(),
+            "binary"
+        );
+        assert!(
+            copy_between_roots(&stage_root, "/missing", &mount_point, Path::new("/opt/")).is_err()
+        );
+    }
+
+    #[test]
+    fn test_resolve_in_root_symlink_loop() {
+        let (_dir, mount_point, _source) = setup();
+        std::os::unix::fs::symlink("/b", mount_point.join("a")).unwrap();
+        std::os::unix::fs::symlink("/a", mount_point.join("b")).unwrap();
+
+        assert!(resolve_in_root(&mount_point, Path::new("/a/file")).is_err());
+    }
+
+    #[test]
+    fn test_copy_into_rejects_escaping_target() {
+        let (dir, mount_point, source) = setup();
+
+        assert!(copy_into(&mount_point, &source, Path::new("/../escaped/app.conf")).is_err());
+        assert!(!dir.path().join("escaped").exists());
     }
 }
@@ -0,0 +1,226 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::error::Error;
+
+const SYS_BLOCK: &str = "/sys/block";
+const PROC_MOUNTS: &str = "/proc/mounts";
+const VIRTUAL_DEVICES: &[&str] = &["loop", "ram", "zram", "dm-", "md"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlockDevice {
+    path: PathBuf,
+    model: String,
+    size: u64,
+    bus: String,
+    removable: bool,
+    mount_points: Vec<String>,
+}
+
+impl BlockDevice {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn bus(&self) -> &str {
+        &self.bus
+    }
+    pub fn mount_points(&self) -> &[String] {
+        &self.mount_points
+    }
+}
+
+fn read_attribute(path: &Path) -> String {
+    fs::read_to_string(path)
+        .map(|value| value.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Guesses the bus from where the device sits in the sysfs device tree.
+fn bus(device_dir: &Path) -> String {
+    let resolved = fs::canonicalize(device_dir).unwrap_or_else(|_| device_dir.to_path_buf());
+    let resolved = resolved.to_string_lossy();
+
+    ["usb", "mmc", "nvme", "ata", "virtio"]
+        .iter()
+        .find(|bus| resolved.contains(&format!("/{}", bus)))
+        .map_or("unknown".to_string(), |bus| bus.to_string())
+}
+
+/// Mount points of the device and of its partitions, taken from the
+/// contents of `/proc/mounts`.
+fn mount_points(name: &str, mounts: &str) -> Vec<String> {
+    let device = format!("/dev/{}", name);
+
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .filter(|(source, _)| {
+            source
+                .strip_prefix(&device)
+                .map(|rest| {
+                    rest.trim_start_matches('p')
+                        .chars()
+                        .all(|ch| ch.is_ascii_digit())
+                })
+                .unwrap_or(false)
+        })
+        .map(|(_, target)| target.to_string())
+        .collect()
+}
+
+/// Mount points of `device`, a disk or a partition, and of its partitions,
+/// the device being resolved when it is a link like `/dev/disk/by-id/...`.
+fn mounted_in(device: &Path, mounts: &str) -> Vec<String> {
+    let device = device.canonicalize().unwrap_or(device.to_path_buf());
+    match device.file_name() {
+        Some(name) => mount_points(&name.to_string_lossy(), mounts),
+        None => Vec::new(),
+    }
+}
+
+/// Where `device` or one of its partitions is mounted.
+pub fn mounted(device: &Path) -> Result<Vec<String>, Error> {
+    Ok(mounted_in(device, &fs::read_to_string(PROC_MOUNTS)?))
+}
+
+fn list_devices_in(sys_block: &Path, mounts: &str, all: bool) -> Result<Vec<BlockDevice>, Error> {
+    let mut devices = Vec::new();
+
+    for entry in fs::read_dir(sys_block)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if VIRTUAL_DEVICES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+
+        let device_dir = entry.path();
+        let removable = read_attribute(&device_dir.join("removable")) == "1";
+        let bus = bus(&device_dir);
+        if !all && !removable && bus != "usb" && bus != "mmc" {
+            continue;
+        }
+
+        let model = [
+            read_attribute(&device_dir.join("device/vendor")),
+            read_attribute(&device_dir.join("device/model")),
+            read_attribute(&device_dir.join("device/name")),
+        ]
+        .into_iter()
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+        devices.push(BlockDevice {
+            path: PathBuf::from("/dev").join(&name),
+            model,
+            size: read_attribute(&device_dir.join("size"))
+                .parse::<u64>()
+                .unwrap_or(0)
+                * 512,
+            bus,
+            removable,
+            mount_points: mount_points(&name, mounts),
+        });
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(devices)
+}
+
+/// Lists the block devices that look like burn targets, every disk if `all`.
+pub fn list_devices(all: bool) -> Result<Vec<BlockDevice>, Error> {
+    list_devices_in(
+        Path::new(SYS_BLOCK),
+        &fs::read_to_string(PROC_MOUNTS).unwrap_or_default(),
+        all,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(sys_block: &Path, name: &str, bus: &str, removable: bool, model: &str) {
+        let device_dir = sys_block
+            .parent()
+            .unwrap()
+            .join("devices")
+            .join(bus)
+            .join(name);
+        fs::create_dir_all(device_dir.join("device")).unwrap();
+        fs::write(
+            device_dir.join("removable"),
+            if removable { "1\n" } else { "0\n" },
+        )
+        .unwrap();
+        fs::write(device_dir.join("size"), "62333952\n").unwrap();
+        fs::write(device_dir.join("device/model"), format!("{}\n", model)).unwrap();
+        std::os::unix::fs::symlink(&device_dir, sys_block.join(name)).unwrap();
+    }
+
+    #[test]
+    fn test_mount_points() {
+        let mounts = "/dev/sda1 /media/boot vfat rw 0 0\n\
+                      /dev/sda2 /media/rootfs ext4 rw 0 0\n\
+                      /dev/sdab1 /other ext4 rw 0 0\n\
+                      /dev/mmcblk0p1 /boot vfat rw 0 0\n";
+
+        assert_eq!(
+            mount_points("sda", mounts),
+            vec!["/media/boot", "/media/rootfs"]
+        );
+        assert_eq!(mount_points("mmcblk0", mounts), vec!["/boot"]);
+        assert!(mount_points("sdb", mounts).is_empty());
+    }
+
+    #[test]
+    fn test_mounted_in() {
+        let mounts = "/dev/sda1 /media/boot vfat rw 0 0\n\
+                      /dev/mmcblk0p2 / ext4 rw 0 0\n";
+
+        assert_eq!(mounted_in(Path::new("/dev/sda"), mounts), ["/media/boot"]);
+        assert_eq!(mounted_in(Path::new("/dev/sda1"), mounts), ["/media/boot"]);
+        assert_eq!(mounted_in(Path::new("/dev/mmcblk0"), mounts), ["/"]);
+        assert!(mounted_in(Path::new("/dev/sdb"), mounts).is_empty());
+    }
+
+    #[test]
+    fn test_list_devices_in() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let sys_block = tmp_dir.path().join("block");
+        fs::create_dir_all(&sys_block).unwrap();
+        device(&sys_block, "sda", "usb1", true, "Card Reader");
+        device(&sys_block, "nvme0n1", "nvme", false, "Samsung SSD");
+        device(&sys_block, "loop0", "virtual", false, "");
+
+        let mounts = "/dev/sda1 /media/boot vfat rw 0 0\n";
+
+        let devices = list_devices_in(&sys_block, mounts, false).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].path(), Path::new("/dev/sda"));
+        assert_eq!(devices[0].model(), "Card Reader");
+        assert_eq!(devices[0].bus(), "usb");
+        assert_eq!(devices[0].size(), 62333952 * 512);
+        assert_eq!(devices[0].mount_points(), ["/media/boot"]);
+
+        let devices = list_devices_in(&sys_block, mounts, true).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].bus(), "nvme");
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{error::Error, privileges};
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    name: String,
+    passed: bool,
+    critical: bool,
+    detail: String,
+}
+
+impl Check {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn passed(&self) -> bool {
+        self.passed
+    }
+    pub fn critical(&self) -> bool {
+        self.critical
+    }
+    pub fn detail(&self) -> &str {
+        &self.detail
+    }
+}
+
+pub fn find_in_path(binary: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(binary))
+        .find(|path| {
+            fs::metadata(path)
+                .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+}
+
+fn check_binary<F>(binary: &str, critical: bool, lookup: F) -> Check
+where
+    F: Fn(&str) -> Option<PathBuf>,
+{
+    let (passed, detail) = match lookup(binary) {
+        Some(path) => (true, path.display().to_string()),
+        None => (false, "not found in PATH".to_string()),
+    };
+
+    Check {
+        name: binary.to_string(),
+        passed,
+        critical,
+        detail,
+    }
+}
+
+fn check_root(euid: u32) -> Check {
+    Check {
+        name: "root".to_string(),
+        passed: euid == 0,
+        critical: true,
+        detail: format!("effective uid {}", euid),
+    }
+}
+
+fn check_loop_control(path: &Path) -> Check {
+    let (passed, detail) = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => (true, format!("{} is accessible", path.display())),
+        Err(err) => (false, format!("{}: {}", path.display(), err)),
+    };
+
+    Check {
+        name: "loop devices".to_string(),
+        passed,
+        critical: true,
+        detail,
+    }
+}
+
+fn check_binfmt(binfmt_dir: &Path) -> Check {
+    let registered = ["qemu-aarch64", "qemu-arm"]
+        .iter()
+        .filter(|name| {
+            fs::read_to_string(binfmt_dir.join(name))
+                .map(|content| content.lines().next() == Some("enabled"))
+                .unwrap_or(false)
+        })
+        .cloned()
+        .collect::<Vec<&str>>();
+
+    Check {
+        name: "qemu binfmt".to_string(),
+        passed: !registered.is_empty(),
+        critical: false,
+        detail: if registered.is_empty() {
+            "no qemu handler registered, RUN only works on an ARM host".to_string()
+        } else {
+            registered.join(", ")
+        },
+    }
+}
+
+pub fn checks() -> Vec<Check> {
+    vec![
+        check_root(privileges::euid()),
+        check_loop_control(Path::new("/dev/loop-control")),
+        check_binary("systemd-nspawn", true, find_in_path),
+        check_binary("chroot", false, find_in_path),
+        check_binary("systemd-vmspawn", false, find_in_path),
+        check_binary("7z", false, find_in_path),
+        check_binary("mcopy", false, find_in_path),
+        #[cfg(feature = "torrent")]
+        check_binary("aria2c", false, find_in_path),
+        check_binfmt(Path::new("/proc/sys/fs/binfmt_misc")),
+    ]
+}
+
+pub fn doctor(checks: &[Check]) -> Result<(), Error> {
+    if checks
+        .iter()
+        .any(|check| check.critical() && !check.passed())
+    {
+        return Err(Error::Other("Missing critical prerequisites".to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn print_report(checks: &[Check]) {
+    for check in checks {
+        let status = match (check.passed(), check.critical()) {
+            (true, _) => " OK ",
+            (false, true) => "FAIL",
+            (false, false) => "WARN",
+        };
+        println!("[{}] {:<16} {}", status, check.name(), check.detail());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_binary() {
+        let found = check_binary("chroot", true, |_| Some(PathBuf::from("/usr/sbin/chroot")));
+        assert!(found.passed());
+        assert_eq!(found.detail(), "/usr/sbin/chroot");
+
+        let missing = check_binary("chroot", true, |_| None);
+        assert!(!missing.passed());
+        assert!(missing.critical());
+    }
+
+    #[test]
+    fn test_check_root() {
+        assert!(check_root(0).passed());
+        assert!(!check_root(1000).passed());
+    }
+
+    #[test]
+    fn test_check_binfmt() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        assert!(!check_binfmt(dir.path()).passed());
+
+        fs::write(dir.path().join("qemu-arm"), "disabled\n").unwrap();
+        assert!(!check_binfmt(dir.path()).passed());
+
+        fs::write(dir.path().join("qemu-aarch64"), "enabled\ninterpreter\n").unwrap();
+        let check = check_binfmt(dir.path());
+        assert!(check.passed());
+        assert_eq!(check.detail(), "qemu-aarch64");
+    }
+
+    #[test]
+    fn test_check_loop_control_missing() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        assert!(!check_loop_control(&dir.path().join("loop-control")).passed());
+    }
+
+    #[test]
+    fn test_doctor_fails_only_on_critical_checks() {
+        let warning = check_binary("systemd-vmspawn", false, |_| None);
+        assert!(doctor(&[check_root(0), warning]).is_ok());
+        assert!(doctor(&[check_root(1000)]).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Image not found: {0}")]
+    ImageNotFound(String),
+    #[error("Invalid image name: {0}")]
+    InvalidReference(String),
+    #[error("{0}")]
+    Usage(String),
+    #[error("{file}:{line}:{col}: {msg}")]
+    Parse {
+        file: String,
+        line: usize,
+        col: usize,
+        msg: String,
+    },
+    #[error(transparent)]
+    Network(#[from] reqwest::Error),
+    #[error("{0} must be run as root (try sudo)")]
+    RootRequired(String),
+    #[error("Mount failed: {0}")]
+    Mount(String),
+    #[error("Command failed with exit code {}", code.map_or("unknown".to_string(), |code| code.to_string()))]
+    RunFailed { code: Option<i32> },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Archive(#[from] zip::result::ZipError),
+    #[error("{0}")]
+    Other(String),
+}
+
+impl From<url::ParseError> for Error {
+    fn from(error: url::ParseError) -> Self {
+        Error::Usage(format!("Invalid url: {}", error))
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(error: glob::PatternError) -> Self {
+        Error::Usage(format!("Invalid pattern: {}", error))
+    }
+}
+
+impl From<glob::GlobError> for Error {
+    fn from(error: glob::GlobError) -> Self {
+        Error::Io(io::Error::new(error.error().kind(), error.to_string()))
+    }
+}
+
+impl From<chrono::ParseError> for Error {
+    fn from(error: chrono::ParseError) -> Self {
+        Error::Other(error.to_string())
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(error: regex::Error) -> Self {
+        Error::Other(error.to_string())
+    }
+}
+
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(error: openssl::error::ErrorStack) -> Self {
+        Error::Other(error.to_string())
+    }
+}
+
+impl From<ctrlc::Error> for Error {
+    fn from(error: ctrlc::Error) -> Self {
+        Error::Other(error.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    Usage,
+    NotFound,
+    Io,
+    Network,
+    Parse,
+    Run,
+    Mount,
+    Permission,
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::ImageNotFound(_) => ErrorKind::NotFound,
+            Error::InvalidReference(_) | Error::Usage(_) => ErrorKind::Usage,
+            Error::Parse { .. } | Error::Json(_) => ErrorKind::Parse,
+            Error::Network(_) => ErrorKind::Network,
+            Error::Mount(_) => ErrorKind::Mount,
+            Error::RootRequired(_) => ErrorKind::Permission,
+            Error::RunFailed { .. } => ErrorKind::Run,
+            Error::Io(error) if error.kind() == io::ErrorKind::NotFound => ErrorKind::NotFound,
+            Error::Io(_) | Error::Archive(_) => ErrorKind::Io,
+            Error::Other(_) => ErrorKind::Other,
+        }
+    }
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Other => "other",
+            ErrorKind::Usage => "usage",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Io => "io",
+            ErrorKind::Network => "network",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Run => "run",
+            ErrorKind::Mount => "mount",
+            ErrorKind::Permission => "permission",
+        }
+    }
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::NotFound => 3,
+            ErrorKind::Io => 4,
+            ErrorKind::Network => 5,
+            ErrorKind::Parse => 6,
+            ErrorKind::Run => 7,
+            ErrorKind::Mount => 8,
+            ErrorKind::Permission => 9,
+        }
+    }
+}
+
+/// Formats an error for stderr, returning it along with the exit code.
+pub fn report(error: &Error, json: bool) -> (String, i32) {
+    let kind = error.kind();
+
+    let message = if json {
+        serde_json::json!({
+            "error": error.to_string(),
+            "kind": kind.as_str(),
+        })
+        .to_string()
+    } else {
+        format!("Error: {}", error)
+    };
+
+    (message, kind.exit_code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_json() {
+        let error = Error::InvalidReference("a:b:c".to_string());
+        let (message, code) = report(&error, true);
+
+        let value: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(value["error"], "Invalid image name: a:b:c");
+        assert_eq!(value["kind"], "usage");
+        assert_ne!(code, 0);
+    }
+
+    #[test]
+    fn test_report_plain() {
+        let error = Error::Other("Something broke".to_string());
+        let (message, code) = report(&error, false);
+
+        assert_eq!(message, "Error: Something broke");
+        assert_eq!(code, 1);
+    }
+
+    #[test]
+    fn test_kind_of_io_error() {
+        let error: Error = io::Error::new(io::ErrorKind::NotFound, "missing").into();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        let error: Error = io::Error::new(io::ErrorKind::PermissionDenied, "denied").into();
+        assert_eq!(error.kind(), ErrorKind::Io);
+        assert_eq!(error.kind().exit_code(), 4);
+    }
+
+    #[test]
+    fn test_run_failed_message() {
+        let error = Error::RunFailed { code: Some(2) };
+        assert_eq!(error.to_string(), "Command failed with exit code 2");
+        assert_eq!(error.kind(), ErrorKind::Run);
+
+        let error = Error::RunFailed { code: None };
+        assert_eq!(error.to_string(), "Command failed with exit code unknown");
+    }
+}
@@ -0,0 +1,58 @@
+use std::process::Command;
+
+use crate::error::Error;
+
+/// Runs a hook command on the host through `sh -c`, passing it what it is
+/// about as `BAKER_*` environment variables.
+pub fn run(stage: &str, command: &str, envs: &[(&str, String)]) -> Result<(), Error> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .envs(
+            envs.iter()
+                .map(|(key, value)| (format!("BAKER_{}", key), value)),
+        )
+        .status()?;
+
+    if !status.success() {
+        return Err(Error::Other(format!(
+            "The {} hook failed with exit code {}",
+            stage,
+            status
+                .code()
+                .map_or("unknown".to_string(), |code| code.to_string())
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_passes_environment() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let output = tmp_dir.path().join("output");
+
+        run(
+            "post-build",
+            &format!(
+                "echo \"$BAKER_STATUS $BAKER_SHA256\" > {}",
+                output.display()
+            ),
+            &[
+                ("STATUS", "success".to_string()),
+                ("SHA256", "abcd".to_string()),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "success abcd\n");
+        assert!(matches!(
+            run("pre-build", "exit 3", &[]),
+            Err(Error::Other(message)) if message.ends_with("exit code 3")
+        ));
+    }
+}
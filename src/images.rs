@@ -1,21 +1,109 @@
 use crate::{
-    images::{download::download_image, fetch::fetch_baker_images},
+    context::{BuildContext, Source, SymlinkPolicy},
+    error::Error,
+    images::{download::download_image, fetch::fetch_baker_images, logs::BuildLog},
     mount::MountedImage,
-    parsing::parser,
+    parsing::{parser, variables},
+    partitions::{self, Partition},
+    progress::{self, Progress},
+    run::{BindMount, RunEnvironment},
+    secrets::Secret,
+    system::wifi::WifiNetwork,
 };
-use glob::glob;
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, File},
-    io::Read,
-    path::PathBuf,
+    collections::{BTreeMap, VecDeque},
+    fmt, fs,
+    path::{Path, PathBuf},
 };
 
+mod alpine;
+mod archive;
+mod armbian;
+mod cache;
+mod dietpi;
 mod download;
 mod fetch;
+mod history;
+mod imager;
+mod logs;
+mod mirrors;
+mod provenance;
+mod raspios;
+mod registries;
+mod report;
 mod repository;
+mod resume;
+mod retry;
+mod sources;
+#[cfg(test)]
+mod test_server;
+#[cfg(feature = "torrent")]
+mod torrent;
+mod ubuntu;
 
-fn get_images_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+pub use archive::ArchiveFormat;
+pub use history::HistoryEntry;
+pub use mirrors::set_mirrors;
+
+pub const LATEST_TAG: &str = "latest";
+pub const DEFAULT_PLATFORM: &str = "arm64";
+const KNOWN_PLATFORMS: &[&str] = &["arm64", "armhf"];
+
+/// The platforms upstream images are published for.
+pub fn known_platforms() -> &'static [&'static str] {
+    KNOWN_PLATFORMS
+}
+
+pub fn check_platform(platform: &str) -> Result<(), Error> {
+    if !known_platforms().contains(&platform) {
+        return Err(Error::Usage(format!(
+            "Unknown platform {}, expected one of: {}",
+            platform,
+            known_platforms().join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// The platform used when none is given, overridable with the
+/// `BAKER_DEFAULT_PLATFORM` environment variable.
+pub fn default_platform() -> String {
+    platform_or_default(std::env::var("BAKER_DEFAULT_PLATFORM").ok())
+}
+
+fn platform_or_default(platform: Option<String>) -> String {
+    platform
+        .filter(|platform| !platform.is_empty())
+        .unwrap_or(DEFAULT_PLATFORM.to_string())
+}
+
+/// Held while reading and writing back the repository, which stages and
+/// targets built concurrently all update.
+static REPOSITORY_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn lock_repository() -> std::sync::MutexGuard<'static, ()> {
+    REPOSITORY_LOCK
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+}
+
+/// One lock per image file being downloaded, so that stages and targets
+/// needing the same base download it once while other downloads go on.
+static DOWNLOAD_LOCKS: std::sync::Mutex<BTreeMap<String, std::sync::Arc<std::sync::Mutex<()>>>> =
+    std::sync::Mutex::new(BTreeMap::new());
+
+fn download_lock(sha256: &str) -> std::sync::Arc<std::sync::Mutex<()>> {
+    DOWNLOAD_LOCKS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .entry(sha256.to_string())
+        .or_default()
+        .clone()
+}
+
+fn get_images_dir() -> Result<PathBuf, Error> {
     Ok(crate::get_app_dir()?.join("images"))
 }
 
@@ -25,6 +113,10 @@ pub struct BakerImage {
     name: String,
     tag: String,
     sha256: String,
+    #[serde(default)]
+    history: Vec<HistoryEntry>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
 }
 
 impl BakerImage {
@@ -43,60 +135,550 @@ impl BakerImage {
     pub fn full_name(&self) -> String {
         format!("{}:{}", self.name, self.tag)
     }
-    pub fn path(&self) -> Result<PathBuf, Box<dyn std::error::Error>> {
-        Ok(get_images_dir()?.join(format!("{}.img", self.sha256)))
+    pub fn path(&self) -> Result<PathBuf, Error> {
+        Ok(self.path_in(&get_images_dir()?))
+    }
+    fn path_in(&self, images_dir: &Path) -> PathBuf {
+        images_dir.join(format!("{}.img", self.sha256))
+    }
+    /// Where the output of the commands run to build the image is kept.
+    pub fn log_path(&self) -> Result<PathBuf, Error> {
+        Ok(get_images_dir()?.join(format!("{}.log", self.sha256)))
+    }
+    /// Where the signed provenance of a built image is kept.
+    pub fn provenance_path(&self) -> Result<PathBuf, Error> {
+        Ok(get_images_dir()?.join(format!("{}.provenance", self.sha256)))
+    }
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+    /// The instructions that produced the image, oldest first. Pulled images
+    /// only consist of their base.
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        if self.history.is_empty() {
+            return vec![HistoryEntry::new(format!("FROM {}", self.full_name()))];
+        }
+        self.history.clone()
     }
 }
 
-pub fn list() -> Result<Vec<BakerImage>, Box<dyn std::error::Error>> {
-    repository::read_repository().or_else(|_| Ok(Vec::new()))
+#[derive(Debug, Serialize)]
+pub struct ImageDetails {
+    sha256: String,
+    platform: String,
+    tags: Vec<String>,
+    size: u64,
+    created: String,
+    partitions: Vec<Partition>,
+    labels: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<Vec<HistoryEntry>>,
+}
+
+/// Splits a `NAME[:TAG]` reference, defaulting a missing tag to `latest`.
+pub fn parse_reference(reference: &str) -> Result<(String, String), Error> {
+    match reference.split(':').collect::<Vec<&str>>().as_slice() {
+        [name] if !name.is_empty() => Ok((name.to_string(), LATEST_TAG.to_string())),
+        [name, tag] if !name.is_empty() && !tag.is_empty() => {
+            Ok((name.to_string(), tag.to_string()))
+        }
+        _ => Err(Error::InvalidReference(reference.to_string())),
+    }
+}
+
+fn tag_date(tag: &str) -> Option<NaiveDate> {
+    tag.split('-')
+        .filter(|part| part.len() == 8)
+        .find_map(|part| NaiveDate::parse_from_str(part, "%Y%m%d").ok())
+}
+
+/// The tag with its embedded date left out, dated images of the same family
+/// only differing by their date.
+fn tag_family(tag: &str) -> Option<String> {
+    tag_date(tag)?;
+    Some(
+        tag.split('-')
+            .map(|part| match tag_date(part) {
+                Some(_) => "*",
+                None => part,
+            })
+            .collect::<Vec<_>>()
+            .join("-"),
+    )
+}
+
+/// Picks the image with the most recent date embedded in its tag, which is
+/// what `latest` resolves to since upstream images are only tagged by date.
+fn newest<'a, I>(images: I, platform: &str, name: &str) -> Option<&'a BakerImage>
+where
+    I: IntoIterator<Item = &'a BakerImage>,
+{
+    images
+        .into_iter()
+        .filter(|image| image.platform() == platform && image.name() == name)
+        .filter_map(|image| tag_date(image.tag()).map(|date| (date, image)))
+        .max_by_key(|(date, _)| *date)
+        .map(|(_, image)| image)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyStatus {
+    Ok,
+    Corrupt,
+    Missing,
+}
+
+impl fmt::Display for VerifyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyStatus::Ok => write!(f, "OK"),
+            VerifyStatus::Corrupt => write!(f, "CORRUPT"),
+            VerifyStatus::Missing => write!(f, "MISSING"),
+        }
+    }
+}
+
+/// Re-hashes the file backing an image and compares it to the recorded digest.
+fn check_file(image: &BakerImage, path: &Path) -> Result<VerifyStatus, Error> {
+    if !path.is_file() {
+        return Ok(VerifyStatus::Missing);
+    }
+
+    if sha256::try_digest(path)? != image.sha256() {
+        return Ok(VerifyStatus::Corrupt);
+    }
+
+    Ok(VerifyStatus::Ok)
+}
+
+/// Checks that the file backing an image exists and, when `verify` is set,
+/// that its content still matches the recorded digest.
+fn is_intact(image: &BakerImage, path: &Path, verify: bool) -> Result<bool, Error> {
+    if !verify {
+        return Ok(path.is_file());
+    }
+
+    Ok(check_file(image, path)? == VerifyStatus::Ok)
+}
+
+/// Re-hashes every given image, reporting progress as hashing large images
+/// takes a while.
+pub fn verify(images: &[BakerImage]) -> Result<Vec<VerifyStatus>, Error> {
+    let mut progress = Progress::new(images.len());
+
+    images
+        .iter()
+        .map(|image| {
+            progress.advance(&format!("Verifying {}", image.full_name()));
+            check_file(image, &image.path()?)
+        })
+        .collect()
 }
 
-pub fn pull(
+/// Returns the cached image if its file in `images_dir` is usable, otherwise
+/// drops the stale entry from `images` so that it gets downloaded again.
+fn find_intact(
+    images: &mut Vec<BakerImage>,
+    images_dir: &Path,
     platform: &str,
     name: &str,
     tag: &str,
-) -> Result<BakerImage, Box<dyn std::error::Error>> {
-    let mut images = list()?;
+    verify: bool,
+) -> Result<Option<BakerImage>, Error> {
+    let index = match images.iter().position(|image| {
+        image.platform() == platform && image.name() == name && image.tag() == tag
+    }) {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+
+    let image = images[index].clone();
+
+    if is_intact(&image, &image.path_in(images_dir), verify)? {
+        return Ok(Some(image));
+    }
+
+    if !progress::is_quiet() {
+        println!("Image {} is missing or corrupt", image.full_name());
+    }
+
+    images.remove(index);
+
+    Ok(None)
+}
+
+pub fn list() -> Result<Vec<BakerImage>, Error> {
+    repository::read_repository().or_else(|_| Ok(Vec::new()))
+}
+
+/// Matches a `label=KEY` or `label=KEY=VALUE` filter against an image.
+pub fn matches_filter(image: &BakerImage, filter: &str) -> Result<bool, Error> {
+    let label = filter
+        .strip_prefix("label=")
+        .ok_or_else(|| Error::Usage(format!("Unsupported filter {}", filter)))?;
+
+    Ok(match label.split_once('=') {
+        Some((key, value)) => image.labels.get(key).map(|v| v.as_str()) == Some(value),
+        None => image.labels.contains_key(label),
+    })
+}
+
+/// Every term has to appear, case-insensitively, in the name or the tag.
+fn matches_terms(image: &BakerImage, terms: &[String]) -> bool {
+    let haystack = format!("{} {}", image.name(), image.tag()).to_lowercase();
 
-    let image = images
+    terms
         .iter()
-        .find(|image| image.platform() == platform && image.name() == name && image.tag() == tag);
+        .all(|term| haystack.contains(&term.to_lowercase()))
+}
 
-    match image {
-        Some(image) => Ok(image.clone()),
-        None => {
-            let downloadable_image = fetch_baker_images()?
-                .into_iter()
-                .find(|downloadable_image| {
-                    let image = downloadable_image.image();
-                    image.platform() == platform && image.name() == name && image.tag() == tag
-                })
-                .ok_or("Image not found")?;
+/// Searches the upstream images, refreshing the index first.
+pub fn search(terms: &[String], platform: Option<&str>) -> Result<Vec<BakerImage>, Error> {
+    let mut images: Vec<BakerImage> = fetch_baker_images(false)?
+        .iter()
+        .map(|downloadable_image| downloadable_image.image().clone())
+        .filter(|image| match platform {
+            Some(platform) => image.platform() == platform,
+            None => true,
+        })
+        .filter(|image| matches_terms(image, terms))
+        .collect();
+
+    images.sort_by(|a, b| {
+        (a.name(), a.platform(), tag_date(b.tag())).cmp(&(
+            b.name(),
+            b.platform(),
+            tag_date(a.tag()),
+        ))
+    });
+
+    Ok(images)
+}
+
+pub fn inspect(image: &BakerImage) -> Result<ImageDetails, Error> {
+    let path = image.path()?;
+    let metadata = fs::metadata(&path)?;
+    let created: chrono::DateTime<chrono::Utc> = metadata.modified()?.into();
+
+    let tags = list()?
+        .iter()
+        .filter(|other| other.sha256() == image.sha256() && other.platform() == image.platform())
+        .map(|other| other.full_name())
+        .collect();
+
+    Ok(ImageDetails {
+        sha256: image.sha256().to_string(),
+        platform: image.platform().to_string(),
+        tags,
+        size: metadata.len(),
+        created: created.to_rfc3339(),
+        partitions: partitions::read_partition_table(&path)?,
+        labels: image.labels.clone(),
+        history: Some(image.history.clone()).filter(|history| !history.is_empty()),
+    })
+}
+
+pub fn export(image: &BakerImage, output: &Path, format: ArchiveFormat) -> Result<(), Error> {
+    let task = progress::Task::start(format!(
+        "Exporting {} to {}",
+        image.full_name(),
+        output.display()
+    ));
+    archive::compress(&image.path()?, output, format)?;
+    task.finish();
+
+    Ok(())
+}
+
+/// Adds a local image file to the repository, decompressing it if needed.
+pub fn import(
+    source: &Path,
+    format: ArchiveFormat,
+    platform: &str,
+    name: &str,
+    tag: &str,
+) -> Result<BakerImage, Error> {
+    check_platform(platform)?;
+
+    let images_dir = get_images_dir()?;
+    fs::create_dir_all(&images_dir)?;
+
+    let tmp_path = images_dir.join(format!("import-{}.tmp", std::process::id()));
+    let cleanup_path = tmp_path.clone();
+    let _tmp_registration = crate::cleanup::register(move || {
+        let _ = fs::remove_file(cleanup_path);
+    });
+
+    let task = progress::Task::start(format!(
+        "Importing {} as {}:{}",
+        source.display(),
+        name,
+        tag
+    ));
+    let result = archive::extract(source, &tmp_path, format)
+        .and_then(|_| Ok(sha256::try_digest(tmp_path.as_path())?));
+    if result.is_ok() {
+        task.finish();
+    }
+    let digest = match result {
+        Ok(digest) => digest,
+        Err(err) => {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+    };
+
+    let image = BakerImage {
+        platform: platform.to_string(),
+        name: name.to_string(),
+        tag: tag.to_string(),
+        sha256: digest,
+        history: Vec::new(),
+        labels: BTreeMap::new(),
+    };
+    fs::rename(&tmp_path, image.path()?)?;
+
+    let (mut images, _) = partition_matching(list()?, platform, name, tag);
+    images.push(image.clone());
+    repository::write_repository(&images)?;
+
+    Ok(image)
+}
+
+pub fn find(platform: &str, name: &str, tag: &str) -> Result<BakerImage, Error> {
+    list()?
+        .into_iter()
+        .find(|image| image.platform() == platform && image.name() == name && image.tag() == tag)
+        .ok_or_else(|| Error::ImageNotFound(format!("{}:{}", name, tag)))
+}
+
+/// The image already pulled as `name:tag`, if its file is usable.
+fn find_pulled(
+    platform: &str,
+    name: &str,
+    tag: &str,
+    verify: bool,
+) -> Result<Option<BakerImage>, Error> {
+    let mut images = {
+        let _lock = lock_repository();
+        list()?
+    };
 
+    find_intact(&mut images, &get_images_dir()?, platform, name, tag, verify)
+}
+
+pub fn pull(platform: &str, name: &str, tag: &str, verify: bool) -> Result<BakerImage, Error> {
+    if let Some(image) = find_pulled(platform, name, tag, verify)? {
+        return Ok(image);
+    }
+
+    let downloadable_images = fetch_baker_images(false)?;
+
+    let downloadable_image = downloadable_images
+        .iter()
+        .find(|downloadable_image| {
             let image = downloadable_image.image();
+            image.platform() == platform && image.name() == name && image.tag() == tag
+        })
+        .or_else(|| {
+            if tag != LATEST_TAG {
+                return None;
+            }
+            let newest = newest(
+                downloadable_images.iter().map(|d| d.image()),
+                platform,
+                name,
+            )?;
+            downloadable_images
+                .iter()
+                .find(|downloadable_image| std::ptr::eq(downloadable_image.image(), newest))
+        })
+        .ok_or_else(|| Error::ImageNotFound(format!("{}:{}", name, tag)))?;
+
+    let image = downloadable_image.image();
+
+    // Builds needing the same image wait for the first one to download it
+    let download_lock = download_lock(image.sha256());
+    let _download = download_lock.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(image) = find_pulled(platform, name, image.tag(), verify)? {
+        return Ok(image);
+    }
+
+    let task = progress::Task::start(format!("Downloading image: {}", image.full_name()));
+    download_image(image.path()?, downloadable_image)?;
+    task.finish();
+
+    let _lock = lock_repository();
+    let (mut images, _) = partition_matching(list()?, platform, name, image.tag());
+    images.push(image.clone());
+
+    repository::write_repository(&images)?;
+
+    Ok(image.clone())
+}
 
-            println!("Downloading image: {}", image.full_name());
+/// Checks upstream for a newer image of the family of `tag`, any image of
+/// that name for `latest`, and pulls it instead of a stale local one.
+pub fn pull_newest(platform: &str, name: &str, tag: &str) -> Result<BakerImage, Error> {
+    let family = tag_family(tag);
+    let candidates: Vec<BakerImage> = fetch_baker_images(false)?
+        .iter()
+        .map(|downloadable_image| downloadable_image.image().clone())
+        .filter(|image| {
+            tag == LATEST_TAG || (family.is_some() && tag_family(image.tag()) == family)
+        })
+        .collect();
+
+    let tag = match newest(&candidates, platform, name) {
+        Some(image) => {
+            if image.tag() != tag {
+                progress::message(&format!("Using {} for {}:{}", image.full_name(), name, tag));
+            }
+            image.tag()
+        }
+        None => tag,
+    };
+
+    pull(platform, name, tag, false)
+}
+
+/// The image `pull`, or `pull_newest` when `newest_upstream`, would give,
+/// found without downloading anything.
+fn resolve_image(
+    platform: &str,
+    name: &str,
+    tag: &str,
+    newest_upstream: bool,
+) -> Result<BakerImage, Error> {
+    if !newest_upstream {
+        if let Ok(image) = find(platform, name, tag) {
+            return Ok(image);
+        }
+    }
+
+    let available: Vec<BakerImage> = fetch_baker_images(false)?
+        .iter()
+        .map(|downloadable_image| downloadable_image.image().clone())
+        .collect();
+    let family = tag_family(tag);
+    let exact = available
+        .iter()
+        .find(|image| image.platform() == platform && image.name() == name && image.tag() == tag);
+    let newer = newest(
+        available.iter().filter(|image| {
+            tag == LATEST_TAG
+                || (newest_upstream && family.is_some() && tag_family(image.tag()) == family)
+        }),
+        platform,
+        name,
+    );
+
+    match (newest_upstream, exact, newer) {
+        (true, _, Some(image)) | (true, Some(image), None) => Ok(image.clone()),
+        (true, None, None) => find(platform, name, tag),
+        (false, Some(image), _) | (false, None, Some(image)) => Ok(image.clone()),
+        (false, None, None) => Err(Error::ImageNotFound(format!("{}:{}", name, tag))),
+    }
+}
 
-            download_image(image.path()?, &downloadable_image)?;
+/// Pulls an image for every platform, carrying on past failures so that one
+/// missing architecture does not prevent fetching the others.
+pub fn pull_platforms<F>(platforms: &[String], pull: F) -> Result<Vec<BakerImage>, Error>
+where
+    F: FnMut(&str) -> Result<BakerImage, Error>,
+{
+    for_platforms(platforms, ("pull", "pulled"), pull)
+}
+
+/// Builds an image for every platform, the others still being built when
+/// one fails.
+pub fn build_platforms<F>(platforms: &[String], build: F) -> Result<Vec<BakerImage>, Error>
+where
+    F: FnMut(&str) -> Result<BakerImage, Error>,
+{
+    for_platforms(platforms, ("build", "built"), build)
+}
 
-            images.push(image.clone());
+fn for_platforms<F>(
+    platforms: &[String],
+    (verb, done): (&str, &str),
+    mut action: F,
+) -> Result<Vec<BakerImage>, Error>
+where
+    F: FnMut(&str) -> Result<BakerImage, Error>,
+{
+    let mut images = Vec::new();
+    let mut failures = Vec::new();
 
-            repository::write_repository(&images)?;
+    for platform in platforms {
+        match check_platform(platform).and_then(|_| action(platform)) {
+            Ok(image) => {
+                progress::message(&format!("{}: {} {}", platform, done, image.full_name()));
+                images.push(image);
+            }
+            Err(err) => {
+                if !progress::is_quiet() {
+                    eprintln!("{}: {}", platform, err);
+                }
+                failures.push((platform, err));
+            }
+        }
+    }
 
-            Ok(image.clone())
+    if platforms.len() == 1 {
+        if let Some((_, err)) = failures.pop() {
+            return Err(err);
         }
     }
+
+    if !failures.is_empty() {
+        let failed: Vec<&str> = failures
+            .iter()
+            .map(|(platform, _)| platform.as_str())
+            .collect();
+        return Err(Error::Other(format!(
+            "Failed to {} {} of {} platforms: {}",
+            verb,
+            failures.len(),
+            platforms.len(),
+            failed.join(", ")
+        )));
+    }
+
+    Ok(images)
+}
+
+/// Splits `images` into the ones to keep and the ones matching the reference.
+fn partition_matching(
+    images: Vec<BakerImage>,
+    platform: &str,
+    name: &str,
+    tag: &str,
+) -> (Vec<BakerImage>, Vec<BakerImage>) {
+    images.into_iter().partition(|image| {
+        !(image.platform() == platform && image.name() == name && image.tag() == tag)
+    })
 }
 
-pub fn rmi(platform: &str, name: &str, tag: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let mut images: Vec<BakerImage> = Vec::new();
+/// Keeps the removed images whose file no remaining tag points at.
+fn unreferenced(removed: Vec<BakerImage>, kept: &[BakerImage]) -> Vec<BakerImage> {
+    removed
+        .into_iter()
+        .filter(|image| !kept.iter().any(|other| other.sha256() == image.sha256()))
+        .collect()
+}
+
+pub fn rmi(platform: &str, name: &str, tag: &str) -> Result<(), Error> {
+    let (images, removed) = partition_matching(list()?, platform, name, tag);
 
-    for image in list()? {
-        if image.platform() == platform && image.name() == name && image.tag() == tag {
-            fs::remove_file(image.path()?)?;
-        } else {
-            images.push(image.clone());
+    for image in unreferenced(removed, &images) {
+        fs::remove_file(image.path()?)?;
+        for path in [image.log_path()?, image.provenance_path()?] {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
         }
     }
 
@@ -105,83 +687,2474 @@ pub fn rmi(platform: &str, name: &str, tag: &str) -> Result<(), Box<dyn std::err
     Ok(())
 }
 
-pub fn build(
-    file: PathBuf,
-    name: Option<String>,
-    tag: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut f = File::open(&file)?;
-    let mut contents = String::new();
-    f.read_to_string(&mut contents)?;
-    let (_, bakerfile) = parser::parse_baker_file::<()>(&contents)?;
-    let from = bakerfile.from;
-    let platform = from.platform.unwrap_or("arm64".into());
-    let image = pull(
-        &platform.clone(),
-        &from.image,
-        &from.tag.ok_or("Image tag is required")?,
-    )?;
+/// The output of the commands run to build an image.
+pub fn build_log(image: &BakerImage) -> Result<String, Error> {
+    logs::read(&image.log_path()?, &image.full_name())
+}
 
-    // Copy image into a temporary file
-    let image_path = image.path()?;
-    let tmp_dir = tempdir::TempDir::new("baker")?;
-    let tmp_path = tmp_dir.path().join("i_love_bakery.img");
-    fs::copy(image_path, &tmp_path)?;
+/// The signed provenance of an image, checked against the trusted keys,
+/// `trusted_keys` being public keys trusted besides the pinned ones, and
+/// against the image it describes.
+pub fn provenance(image: &BakerImage, trusted_keys: &[PathBuf]) -> Result<impl Serialize, Error> {
+    let envelope = provenance::load(&image.provenance_path()?, &image.full_name())?;
+    let trusted = provenance::trusted_keys(trusted_keys)?;
+    if provenance::verify(&envelope, &trusted)?.sha256() != image.sha256() {
+        return Err(Error::Other(format!(
+            "The provenance of {} describes another image",
+            image.full_name()
+        )));
+    }
+
+    Ok(envelope)
+}
 
-    // Mount image
-    let mounted = MountedImage::new(&tmp_path)?;
+/// PEM of the public key this builder signs provenance with, to pin in the
+/// trusted keys of the machines checking it.
+pub fn provenance_key() -> Result<String, Error> {
+    provenance::builder_public_key()
+}
 
-    // Init environment
-    let mut user = "root".to_string();
-    let mut workdir = "/".to_string();
-    let mut envs: std::collections::HashMap<String, String> = std::collections::HashMap::new();
+/// Adds `new_name:new_tag` as another name for an image, sharing its file.
+pub fn tag(
+    platform: &str,
+    name: &str,
+    tag: &str,
+    new_name: &str,
+    new_tag: &str,
+) -> Result<BakerImage, Error> {
+    let _lock = lock_repository();
+    let image = find(platform, name, tag)?;
+    let (mut images, _) = partition_matching(list()?, platform, new_name, new_tag);
 
-    // Apply instructions
-    for instruction in bakerfile.instructions {
-        match instruction {
-            parser::Instruction::USER(u) => user = u,
-            parser::Instruction::WORKDIR(w) => workdir = w,
-            parser::Instruction::ENV(e) => envs.extend(e),
-            parser::Instruction::RUN(r) => {
-                mounted.run(
-                    mounted.labels().last().ok_or("No label found")?,
-                    crate::run::RunEnvironment::SystemdNspawn,
-                    &envs,
-                    &user,
-                    &workdir,
-                    &r,
-                )?;
-            }
-            parser::Instruction::COPY(sources, dest) => {
-                for source in glob(&sources)?.collect::<Result<Vec<_>, _>>()? {
-                    mounted.copy(
-                        mounted.labels().last().ok_or("No label found")?,
-                        &source,
-                        &dest,
-                    )?;
-                }
+    let tagged = BakerImage {
+        name: new_name.to_string(),
+        tag: new_tag.to_string(),
+        ..image
+    };
+    images.push(tagged.clone());
+
+    repository::write_repository(&images)?;
+
+    Ok(tagged)
+}
+
+/// Lists the files of the images directory no image of `images` points at.
+fn unreferenced_files(dir: &Path, images: &[BakerImage]) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        let referenced = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .map(|stem| images.iter().any(|image| image.sha256() == stem))
+            .unwrap_or(false);
+        if !referenced {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// Deletes the image files left behind by removed or interrupted images and
+/// the build cache, returning them along with the number of bytes reclaimed.
+pub fn prune(dry_run: bool) -> Result<(Vec<PathBuf>, u64), Error> {
+    let mut files = unreferenced_files(&get_images_dir()?, &list()?)?;
+    files.extend(cache::files()?);
+    let mut reclaimed = 0;
+
+    for file in &files {
+        reclaimed += fs::metadata(file)?.len();
+        if !dry_run {
+            fs::remove_file(file)?;
+        }
+    }
+
+    Ok((files, reclaimed))
+}
+
+#[derive(Clone)]
+pub struct BuildOptions {
+    pub run_environment: RunEnvironment,
+    pub keep_on_failure: bool,
+    pub context: PathBuf,
+    pub symlinks: SymlinkPolicy,
+    pub max_context_size: u64,
+    pub max_context_files: usize,
+    pub volumes: Vec<BindMount>,
+    pub secrets: Vec<Secret>,
+    pub build_args: Vec<(String, String)>,
+    /// Whether the file to build is a Dockerfile to convert
+    pub from_dockerfile: bool,
+    /// Whether to ignore the build cache, also refreshing the image index
+    /// when a base image is not already local
+    pub no_cache: bool,
+    /// Whether to look upstream for a newer base image than the local one
+    pub pull: bool,
+    /// Stage to stop at instead of the last one
+    pub target: Option<String>,
+    /// Platform of the stages whose FROM does not give one
+    pub platform: Option<String>,
+    /// How many independent stages may be built at once
+    pub jobs: usize,
+    /// Whether to carry on from where the last build of the file failed
+    pub resume: bool,
+    /// Command run on the host before building
+    pub hook_pre: Option<String>,
+    /// Command run on the host once the build succeeded or failed
+    pub hook_post: Option<String>,
+    /// Contents of the file to build when it was given on stdin
+    pub contents: Option<String>,
+    /// File to write the timings of the steps to, as JSON
+    pub report: Option<PathBuf>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            run_environment: RunEnvironment::SystemdNspawn,
+            keep_on_failure: false,
+            context: PathBuf::from("."),
+            symlinks: SymlinkPolicy::Contained,
+            max_context_size: crate::context::DEFAULT_MAX_SIZE_BYTES,
+            max_context_files: crate::context::DEFAULT_MAX_FILES,
+            volumes: Vec::new(),
+            secrets: Vec::new(),
+            build_args: Vec::new(),
+            from_dockerfile: false,
+            no_cache: false,
+            pull: false,
+            target: None,
+            platform: None,
+            jobs: 1,
+            resume: false,
+            hook_pre: None,
+            hook_post: None,
+            contents: None,
+            report: None,
+        }
+    }
+}
+
+fn get_failed_builds_dir() -> Result<PathBuf, Error> {
+    Ok(crate::get_app_dir()?.join("failed"))
+}
+
+/// Moves the working image of a failed build into `dir` so it survives the
+/// temporary directory, returning where it was kept.
+fn keep_working_image(working_image: &Path, dir: &Path) -> Result<PathBuf, Error> {
+    fs::create_dir_all(dir)?;
+
+    let kept_path = dir.join(format!(
+        "{}.img",
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    ));
+
+    if fs::rename(working_image, &kept_path).is_err() {
+        fs::copy(working_image, &kept_path)?;
+    }
+
+    Ok(kept_path)
+}
+
+/// A stage of the Bakerfile built earlier in the same build.
+struct BuiltStage {
+    index: usize,
+    name: Option<String>,
+    path: PathBuf,
+    platform: String,
+    /// Cache key of the stage, which `COPY --from` keys depend on
+    key: String,
+    history: Vec<HistoryEntry>,
+    labels: BTreeMap<String, String>,
+    /// Images pulled to start the stage from, if not an earlier stage, and to
+    /// copy files from
+    bases: Vec<provenance::BaseImage>,
+    report: Vec<report::StepReport>,
+}
+
+/// Finds a stage by its `AS` name or by its index, like `COPY --from` expects.
+fn find_stage<'a>(stages: &'a [BuiltStage], reference: &str) -> Result<&'a BuiltStage, Error> {
+    stages
+        .iter()
+        .find(|stage| stage.name.as_deref() == Some(reference))
+        .or_else(|| {
+            let index = reference.parse::<usize>().ok()?;
+            stages.iter().find(|stage| stage.index == index)
+        })
+        .ok_or_else(|| Error::Usage(format!("Unknown stage {}", reference)))
+}
+
+/// Stage references of `COPY --from` instructions, conditional ones included.
+fn copy_from_references(instructions: &[parser::Instruction]) -> Vec<&str> {
+    instructions
+        .iter()
+        .flat_map(|instruction| match instruction {
+            parser::Instruction::COPY(_, _, Some(stage)) => vec![stage.as_str()],
+            parser::Instruction::ONPLATFORM(_, instruction) => {
+                copy_from_references(std::slice::from_ref(instruction.as_ref()))
             }
-            _ => {
-                println!("Skipping Instruction {:?}: Not implemented", instruction);
+            parser::Instruction::IF(_, instructions) => copy_from_references(instructions),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Earlier stages the stage at `index` uses, as its base or through
+/// `COPY --from`, which have to be built before it.
+fn stage_dependencies(stages: &[parser::Stage], index: usize) -> Vec<usize> {
+    let stage = &stages[index];
+    let find = |reference: &str| {
+        stages[..index]
+            .iter()
+            .position(|earlier| earlier.from.alias.as_deref() == Some(reference))
+            .or_else(|| {
+                reference
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|other| *other < index)
+            })
+    };
+
+    let base = match stage.from.tag {
+        Some(_) => None,
+        None => stages[..index]
+            .iter()
+            .position(|earlier| earlier.from.alias.as_deref() == Some(stage.from.image.as_str())),
+    };
+    let mut dependencies: Vec<usize> = base
+        .into_iter()
+        .chain(
+            copy_from_references(&stage.instructions)
+                .into_iter()
+                .filter_map(find),
+        )
+        .collect();
+    dependencies.sort_unstable();
+    dependencies.dedup();
+
+    dependencies
+}
+
+/// Resolves what `COPY --from` points to, a stage first and an image otherwise.
+fn copy_source_path(
+    stages: &[BuiltStage],
+    platform: &str,
+    reference: &str,
+) -> Result<PathBuf, Error> {
+    match find_stage(stages, reference) {
+        Ok(stage) => Ok(stage.path.clone()),
+        Err(_) => {
+            let (name, tag) = parse_reference(reference)?;
+            pull(platform, &name, &tag, false)?.path()
+        }
+    }
+}
+
+/// Images `COPY --from` copies from in `steps`, as `resolve` finds them,
+/// earlier stages left aside. Cached steps count too, the image they got
+/// their files from being an input of the stage all the same.
+fn copied_images(
+    steps: &[Step],
+    stages: &[BuiltStage],
+    platform: &str,
+    resolve: impl Fn(&str, &str) -> Result<BakerImage, Error>,
+) -> Result<Vec<provenance::BaseImage>, Error> {
+    let mut images = Vec::new();
+
+    for step in steps {
+        if let Some(parser::Instruction::COPY(_, _, Some(reference))) = &step.instruction {
+            if find_stage(stages, reference).is_ok() {
+                continue;
             }
+            let (name, tag) = parse_reference(reference)?;
+            let image = resolve(&name, &tag)?;
+            images.push(provenance::BaseImage::new(
+                image.full_name(),
+                platform.to_string(),
+                image.sha256().to_string(),
+            ));
         }
     }
-    // Unmount image and save it
-    mounted.unmount()?;
-    let img_dir = get_images_dir()?;
-    let digest = sha256::try_digest(&tmp_path)?;
-    let dest_path = img_dir.join(digest.clone() + ".img");
-    fs::copy(&tmp_path, dest_path)?;
 
-    // Update repository
-    let mut repos = repository::read_repository()?;
-    repos.push(BakerImage {
-        platform,
-        name: name.unwrap_or(digest.clone()),
-        tag: tag.unwrap_or("latest".into()),
-        sha256: digest,
-    });
+    Ok(images)
+}
+
+/// Drops the stages after the one named `target`, which becomes the result.
+fn stop_at_target(bakerfile: &mut parser::BakerFile, target: &str) -> Result<(), Error> {
+    let index = bakerfile
+        .stages
+        .iter()
+        .position(|stage| stage.from.alias.as_deref() == Some(target))
+        .ok_or_else(|| Error::Usage(format!("No stage named {} to target", target)))?;
+    bakerfile.stages.truncate(index + 1);
 
-    repository::write_repository(&repos)?;
     Ok(())
 }
+
+/// Runs a script generated for an instruction as root from `/`.
+fn run_as_root(
+    mounted: &MountedImage,
+    options: &BuildOptions,
+    log: &BuildLog,
+    envs: &[(String, String)],
+    script: String,
+) -> Result<(), Error> {
+    mounted.run(
+        &mounted.root_label()?,
+        &options.run_environment,
+        &options.volumes,
+        envs,
+        "root",
+        "/",
+        &crate::run::default_shell(),
+        &parser::RunCommand::Heredoc {
+            delimiter: "EOF".to_string(),
+            script,
+        },
+        Some(log.file()),
+    )
+}
+
+/// Value of an ARG: the one given on the command line, else its default
+/// expanded with `variables`, else `inherited`.
+fn arg_value(
+    options: &BuildOptions,
+    name: &str,
+    default: Option<&str>,
+    variables: &[(String, String)],
+    inherited: Option<String>,
+) -> String {
+    variables::lookup(&options.build_args, name)
+        .or_else(|| {
+            default.map(|default| {
+                variables::expand(default, |name| variables::lookup(variables, name))
+            })
+        })
+        .or(inherited)
+        .unwrap_or_default()
+}
+
+/// What the instructions of a stage set up besides the image contents.
+#[derive(Debug, Clone)]
+struct StageState {
+    user: String,
+    workdir: String,
+    envs: Vec<(String, String)>,
+    args: Vec<(String, String)>,
+    shell: Vec<String>,
+    command: Option<String>,
+    entrypoint: Option<(parser::EntrypointOptions, String)>,
+}
+
+impl Default for StageState {
+    fn default() -> Self {
+        StageState {
+            user: "root".to_string(),
+            workdir: "/".to_string(),
+            envs: Vec::new(),
+            args: Vec::new(),
+            shell: crate::run::default_shell(),
+            command: None,
+            entrypoint: None,
+        }
+    }
+}
+
+impl StageState {
+    /// Variables commands run with, the ENV ones taking precedence.
+    fn variables(&self) -> Vec<(String, String)> {
+        [self.args.as_slice(), self.envs.as_slice()].concat()
+    }
+    /// What commands run in, which the cache key of an instruction covers.
+    fn environment(&self) -> String {
+        format!(
+            "{:?}",
+            (
+                &self.user,
+                &self.workdir,
+                &self.envs,
+                &self.args,
+                &self.shell
+            )
+        )
+    }
+}
+
+const STEP_SUMMARY_LENGTH: usize = 60;
+
+/// An instruction of a stage along with the state it applies in and the
+/// cache key of the image once it is applied. Instructions only changing the
+/// state have no instruction left to apply and keep the previous key.
+struct Step {
+    description: String,
+    instruction: Option<parser::Instruction>,
+    state: StageState,
+    key: String,
+}
+
+impl Step {
+    /// Instructions running commands are the slow ones, the image is
+    /// snapshotted after them.
+    fn is_checkpoint(&self) -> bool {
+        matches!(
+            self.instruction,
+            Some(
+                parser::Instruction::RUN(..)
+                    | parser::Instruction::INSTALL(..)
+                    | parser::Instruction::USERADD(..)
+                    | parser::Instruction::LOCALE(..)
+            )
+        )
+    }
+    /// First line of the instruction, shortened to fit a progress line.
+    fn summary(&self) -> String {
+        let line = self.description.lines().next().unwrap_or_default();
+        match line.char_indices().nth(STEP_SUMMARY_LENGTH) {
+            Some((end, _)) => format!("{}...", &line[..end]),
+            None => line.to_string(),
+        }
+    }
+    fn history_entry(&self, duration: Option<std::time::Duration>) -> HistoryEntry {
+        let entry = match duration {
+            Some(duration) => HistoryEntry::timed(self.description.clone(), duration),
+            None => HistoryEntry::new(self.description.clone()),
+        };
+        match self.instruction {
+            Some(_) => entry.with_digest(self.key.clone()),
+            None => entry,
+        }
+    }
+}
+
+/// Digests of what an instruction reads from the build context or from an
+/// earlier stage, which its cache key has to cover.
+fn input_digests(
+    instruction: &parser::Instruction,
+    context: &BuildContext,
+    stages: &[BuiltStage],
+) -> Result<Vec<String>, Error> {
+    if let parser::Instruction::COPY(_, _, Some(stage)) = instruction {
+        return Ok(find_stage(stages, stage)
+            .map(|stage| vec![stage.key.clone()])
+            .unwrap_or_default());
+    }
+
+    context_patterns(instruction)
+        .into_iter()
+        .map(|pattern| context.digest(pattern))
+        .collect()
+}
+
+/// Patterns of the build context files an instruction reads.
+fn context_patterns(instruction: &parser::Instruction) -> Vec<&str> {
+    match instruction {
+        parser::Instruction::COPY(source, _, None) => vec![source],
+        parser::Instruction::ADD(source, _, _) if !crate::add::is_url(source) => vec![source],
+        parser::Instruction::DTOVERLAY(_, Some(dtbo)) => vec![dtbo],
+        parser::Instruction::KERNEL(kernel, modules) => [Some(kernel), modules.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect(),
+        parser::Instruction::AUTHORIZED_KEYS(_, sources) => {
+            sources.iter().map(String::as_str).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Walks the instructions of a stage without touching the image, resolving
+/// conditionals and variables and keying every instruction that changes the
+/// image on the ones before it.
+#[allow(clippy::too_many_arguments)]
+fn plan_stage(
+    platform: &str,
+    instructions: Vec<parser::Instruction>,
+    options: &BuildOptions,
+    global_args: &[(String, String)],
+    stages: &[BuiltStage],
+    context: &BuildContext,
+    base_key: &str,
+    labels: &mut BTreeMap<String, String>,
+) -> Result<(Vec<Step>, StageState), Error> {
+    let mut state = StageState::default();
+    let mut key = base_key.to_string();
+    let mut steps = Vec::new();
+
+    // Conditionals queue the instructions they gate
+    let mut pending: VecDeque<parser::Instruction> = instructions.into();
+    while let Some(instruction) = pending.pop_front() {
+        let description = instruction.to_string();
+        let instruction = variables::expand_instruction(instruction, |name| {
+            variables::lookup(&state.envs, name).or_else(|| variables::lookup(&state.args, name))
+        });
+
+        match instruction {
+            parser::Instruction::ARG(name, default) => {
+                let value = arg_value(
+                    options,
+                    &name,
+                    default.as_deref(),
+                    &state.variables(),
+                    variables::lookup(global_args, &name),
+                );
+                state.args.push((name, value));
+            }
+            parser::Instruction::ONPLATFORM(target, instruction) => {
+                check_platform(&target)?;
+                if target == platform {
+                    pending.push_front(*instruction);
+                }
+                continue;
+            }
+            parser::Instruction::IF(condition, instructions) => {
+                if condition.holds() {
+                    for instruction in instructions.into_iter().rev() {
+                        pending.push_front(instruction);
+                    }
+                }
+                continue;
+            }
+            parser::Instruction::USER(u) => state.user = u,
+            parser::Instruction::SHELL(s) => state.shell = s,
+            parser::Instruction::CMD(c) => state.command = Some(c),
+            parser::Instruction::ENTRYPOINT(o, e) => state.entrypoint = Some((o, e)),
+            parser::Instruction::WORKDIR(w) => state.workdir = w,
+            parser::Instruction::ENV(e) => {
+                crate::run::set_environment_variables(&mut state.envs, e)
+            }
+            parser::Instruction::LABEL(l) => labels.extend(l),
+            instruction => {
+                // Debug rather than Display, which hides secrets
+                let mut parts = vec![format!("{:?}", instruction), state.environment()];
+                parts.extend(input_digests(&instruction, context, stages)?);
+                key = cache::key(&key, &parts);
+
+                steps.push(Step {
+                    description,
+                    instruction: Some(instruction),
+                    state: state.clone(),
+                    key: key.clone(),
+                });
+                continue;
+            }
+        }
+
+        steps.push(Step {
+            description,
+            instruction: None,
+            state: state.clone(),
+            key: key.clone(),
+        });
+    }
+
+    Ok((steps, state))
+}
+
+/// Applies an instruction that changes the image.
+#[allow(clippy::too_many_arguments)]
+fn apply_instruction(
+    mounted: &MountedImage,
+    platform: &str,
+    instruction: parser::Instruction,
+    state: &StageState,
+    options: &BuildOptions,
+    stages: &[BuiltStage],
+    context: &mut BuildContext,
+    log: &BuildLog,
+) -> Result<(), Error> {
+    match instruction {
+        // Planning the stage took care of them
+        parser::Instruction::ARG(..)
+        | parser::Instruction::ONPLATFORM(..)
+        | parser::Instruction::IF(..)
+        | parser::Instruction::USER(_)
+        | parser::Instruction::SHELL(_)
+        | parser::Instruction::CMD(_)
+        | parser::Instruction::ENTRYPOINT(..)
+        | parser::Instruction::WORKDIR(_)
+        | parser::Instruction::ENV(_)
+        | parser::Instruction::LABEL(_) => {}
+        parser::Instruction::WIFI(w) => {
+            let network = WifiNetwork::from_pairs(&w, &options.secrets)?;
+            crate::system::wifi::configure(
+                &mounted.root_mount_point()?,
+                mounted.boot_mount_point()?.as_deref(),
+                &network,
+            )?;
+        }
+        parser::Instruction::SSH(enabled) => {
+            crate::system::ssh::configure(
+                &mounted.root_mount_point()?,
+                mounted.boot_mount_point()?.as_deref(),
+                enabled,
+            )?;
+        }
+        parser::Instruction::TIMEZONE(timezone) => {
+            crate::system::locale::set_timezone(&mounted.root_mount_point()?, &timezone)?
+        }
+        parser::Instruction::KEYBOARD(layout) => {
+            crate::system::locale::set_keyboard(&mounted.root_mount_point()?, &layout)?
+        }
+        parser::Instruction::LOCALE(locale) => {
+            crate::system::locale::set_locale(&mounted.root_mount_point()?, &locale)?;
+            run_as_root(mounted, options, log, &[], "locale-gen\n".to_string())?;
+        }
+        parser::Instruction::BOOTCONFIG(key, value) => {
+            let boot = mounted
+                .boot_mount_point()?
+                .ok_or_else(|| Error::Usage("BOOTCONFIG requires a boot partition".to_string()))?;
+            crate::system::boot::configure(&boot, &key, &value)?;
+        }
+        parser::Instruction::CMDLINE(edit) => {
+            let boot = mounted
+                .boot_mount_point()?
+                .ok_or_else(|| Error::Usage("CMDLINE requires a boot partition".to_string()))?;
+            crate::system::boot::configure_cmdline(&boot, &edit)?;
+        }
+        parser::Instruction::DTOVERLAY(overlay, dtbo) => {
+            let boot = mounted
+                .boot_mount_point()?
+                .ok_or_else(|| Error::Usage("DTOVERLAY requires a boot partition".to_string()))?;
+            let dtbo = match dtbo {
+                Some(dtbo) => match context.expand(&dtbo)?.as_slice() {
+                    [Source::File(path)] => Some(path.clone()),
+                    _ => {
+                        return Err(Error::Usage(format!(
+                            "DTOVERLAY file {} must match a single file",
+                            dtbo
+                        )))
+                    }
+                },
+                None => None,
+            };
+            crate::system::boot::add_overlay(&boot, &overlay, dtbo.as_deref())?;
+        }
+        parser::Instruction::SERVICE(enabled, units) => {
+            let root = mounted.root_mount_point()?;
+            for unit in units {
+                let unit = crate::system::services::unit_name(&unit)?;
+                if enabled {
+                    crate::system::services::enable(&root, &unit)?;
+                } else {
+                    crate::system::services::disable(&root, &unit)?;
+                }
+            }
+        }
+        parser::Instruction::EXPANDROOT(parser::RootExpansion::FirstBoot(enabled)) => {
+            let boot = mounted
+                .boot_mount_point()?
+                .ok_or_else(|| Error::Usage("EXPANDROOT requires a boot partition".to_string()))?;
+            crate::system::boot::configure_first_boot_resize(
+                &mounted.root_mount_point()?,
+                &boot,
+                enabled,
+            )?;
+        }
+        parser::Instruction::EXPANDROOT(parser::RootExpansion::To(_)) => {
+            // The image was grown before being mounted
+        }
+        parser::Instruction::PARTITION(partition) => {
+            // The partition was added before the image got mounted
+            if let Some(mount) = &partition.mount {
+                crate::system::fstab::mount_label(
+                    &mounted.root_mount_point()?,
+                    &partition.label,
+                    mount,
+                    &partition.filesystem,
+                )?;
+            }
+        }
+        parser::Instruction::AUTHORIZED_KEYS(key_user, sources) => {
+            let mut keys = Vec::new();
+            for pattern in &sources {
+                for source in context.expand(pattern)? {
+                    match source {
+                        Source::File(path) => keys.push(fs::read_to_string(path)?),
+                        _ => {
+                            return Err(Error::Usage(format!(
+                                "AUTHORIZED_KEYS source {} must only match files",
+                                pattern
+                            )))
+                        }
+                    }
+                }
+            }
+            crate::system::ssh::add_authorized_keys(
+                &mounted.root_mount_point()?,
+                key_user.as_deref().unwrap_or(&state.user),
+                &keys,
+            )?;
+        }
+        parser::Instruction::KERNEL(kernel, modules) => {
+            let boot = mounted
+                .boot_mount_point()?
+                .ok_or_else(|| Error::Usage("KERNEL requires a boot partition".to_string()))?;
+            let kernel = match context.expand(&kernel)?.as_slice() {
+                [Source::File(path)] => path.clone(),
+                _ => {
+                    return Err(Error::Usage(format!(
+                        "KERNEL image {} must match a single file",
+                        kernel
+                    )))
+                }
+            };
+            crate::system::boot::install_kernel(&boot, &kernel)?;
+
+            if let Some(modules) = modules {
+                match context.expand(&modules)?.as_slice() {
+                    [Source::File(path)] => {
+                        mounted.extract(&mounted.root_label()?, path, Path::new("/lib/modules"))?
+                    }
+                    _ => {
+                        return Err(Error::Usage(format!(
+                            "KERNEL modules {} must match a single archive",
+                            modules
+                        )))
+                    }
+                }
+            }
+        }
+        parser::Instruction::FSTAB(entry) => {
+            crate::system::fstab::add_entry(&mounted.root_mount_point()?, &entry)?;
+        }
+        parser::Instruction::INSTALL(packages) => {
+            run_as_root(
+                mounted,
+                options,
+                log,
+                &state.variables(),
+                crate::system::packages::install_script(&packages)?,
+            )?;
+        }
+        parser::Instruction::USERADD(new_user) => {
+            run_as_root(
+                mounted,
+                options,
+                log,
+                &[],
+                crate::system::users::useradd_script(&new_user),
+            )?;
+
+            if new_user.userconf {
+                let boot = mounted.boot_mount_point()?.ok_or_else(|| {
+                    Error::Usage("USERADD --userconf requires a boot partition".to_string())
+                })?;
+                crate::system::users::write_userconf(&boot, &new_user)?;
+            }
+        }
+        parser::Instruction::RUN(run_options, r) => {
+            let mut volumes = options.volumes.clone();
+            for mount in &run_options.mounts {
+                match mount {
+                    parser::RunMount::Secret { id, target } => volumes.push(
+                        crate::secrets::find(&options.secrets, id)?
+                            .bind_mount(target.as_deref())?,
+                    ),
+                    parser::RunMount::Cache { id, target } => {
+                        volumes.push(BindMount::cache(id.as_deref().unwrap_or(target), target)?)
+                    }
+                }
+            }
+
+            mounted.run(
+                &mounted.root_label()?,
+                &options.run_environment,
+                &volumes,
+                &state.variables(),
+                run_options.user.as_deref().unwrap_or(&state.user),
+                &state.workdir,
+                &state.shell,
+                &r,
+                Some(log.file()),
+            )?;
+        }
+        parser::Instruction::COPY(sources, dest, Some(stage)) => {
+            let label = mounted.root_label()?;
+            let source = MountedImage::new_read_only(&copy_source_path(stages, platform, &stage)?)?;
+            let source_label = source.root_label()?;
+
+            let result = mounted.copy_from(&label, &source, &source_label, &sources, &dest);
+            source.unmount()?;
+            result?;
+        }
+        parser::Instruction::COPY(sources, dest, None) => {
+            let label = mounted.root_label()?;
+            for source in context.expand(&sources)? {
+                match source {
+                    Source::File(source) => mounted.copy(&label, &source, &dest)?,
+                    Source::Symlink(source) => mounted.copy_symlink(&label, &source, &dest)?,
+                    Source::Directory(source) => {
+                        mounted
+                            .copy_tree(&label, &source, &dest, &|path| context.is_ignored(path))?
+                    }
+                }
+            }
+        }
+        parser::Instruction::ADD(source, dest, checksum) => {
+            let label = mounted.root_label()?;
+            if crate::add::is_url(&source) {
+                mounted.add_url(&label, &source, checksum.as_deref(), &dest)?;
+            } else if checksum.is_some() {
+                return Err(Error::Usage(
+                    "ADD --checksum is only supported for urls".to_string(),
+                ));
+            } else {
+                for source in context.expand(&source)? {
+                    match source {
+                        Source::File(source) => mounted.add(&label, &source, &dest)?,
+                        Source::Symlink(source) => mounted.copy_symlink(&label, &source, &dest)?,
+                        Source::Directory(source) => {
+                            mounted.copy_tree(&label, &source, &dest, &|path| {
+                                context.is_ignored(path)
+                            })?
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn apply_steps(
+    mounted: &MountedImage,
+    platform: &str,
+    steps: &[Step],
+    options: &BuildOptions,
+    stages: &[BuiltStage],
+    context: &mut BuildContext,
+    history: &mut Vec<HistoryEntry>,
+    progress: &mut Progress,
+    log: &BuildLog,
+    stage_report: &mut Vec<report::StepReport>,
+    stage_name: &str,
+) -> Result<(), Error> {
+    for step in steps {
+        let task = progress.step(&step.summary());
+        log.section(&step.description)?;
+        let started = std::time::Instant::now();
+        let mut bytes_written = None;
+        if let Some(instruction) = &step.instruction {
+            let used = mounted.used_bytes()?;
+            let mut apply = || {
+                apply_instruction(
+                    mounted,
+                    platform,
+                    instruction.clone(),
+                    &step.state,
+                    options,
+                    stages,
+                    context,
+                    log,
+                )
+            };
+            // Commands print to the terminal, the spinner would get in the way
+            match step.is_checkpoint() {
+                true => task.suspend(apply)?,
+                false => apply()?,
+            }
+            bytes_written = Some(mounted.used_bytes()?.saturating_sub(used));
+        }
+        task.finish();
+        history.push(step.history_entry(Some(started.elapsed())));
+        stage_report.push(report::StepReport::built(
+            stage_name.to_string(),
+            step.description.clone(),
+            started.elapsed(),
+            bytes_written,
+        ));
+    }
+
+    Ok(())
+}
+
+/// Installs the service running the CMD and ENTRYPOINT of a stage, if any.
+fn install_command(mounted: &MountedImage, state: &StageState) -> Result<(), Error> {
+    // CMD gives the default arguments of the ENTRYPOINT, like in Dockerfiles
+    let service = match (&state.entrypoint, &state.command) {
+        (Some((options, entrypoint)), Some(command)) => {
+            Some((options.clone(), format!("{} {}", entrypoint, command)))
+        }
+        (Some((options, entrypoint)), None) => Some((options.clone(), entrypoint.clone())),
+        (None, Some(command)) => Some((parser::EntrypointOptions::default(), command.clone())),
+        (None, None) => None,
+    };
+    if let Some((options, command)) = service {
+        crate::system::command::install(
+            &mounted.root_mount_point()?,
+            &state.user,
+            &state.workdir,
+            &crate::system::command::exec_start(&state.shell, &state.envs, &command),
+            &options,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Keeps the working image of a failed stage around when asked to.
+fn keep_failed_stage(options: &BuildOptions, tmp_path: &Path) -> Result<Option<PathBuf>, Error> {
+    if !options.keep_on_failure {
+        return Ok(None);
+    }
+
+    let kept_path = keep_working_image(tmp_path, &get_failed_builds_dir()?)?;
+    eprintln!("Working image kept at {}", kept_path.display());
+    eprintln!(
+        "Inspect it with: sudo losetup --find --show --partscan {}",
+        kept_path.display()
+    );
+
+    Ok(Some(kept_path))
+}
+
+/// Loads the file to build along with the value of its global ARGs, dropping
+/// the stages after the target.
+fn load_build_file(
+    file: &Path,
+    options: &BuildOptions,
+) -> Result<(parser::BakerFile, Vec<(String, String)>), Error> {
+    let mut bakerfile = match (&options.contents, options.from_dockerfile) {
+        (Some(contents), true) => crate::parsing::dockerfile::parse_dockerfile(contents, file)?,
+        (Some(contents), false) => crate::parsing::parse_bakerfile_in(contents, file)?,
+        (None, true) => crate::parsing::dockerfile::load_dockerfile(file)?,
+        (None, false) => crate::parsing::load_bakerfile(file)?,
+    };
+    if let Some(target) = &options.target {
+        stop_at_target(&mut bakerfile, target)?;
+    }
+
+    let mut global_args: Vec<(String, String)> = Vec::new();
+    for (name, default) in &bakerfile.args {
+        let value = arg_value(options, name, default.as_deref(), &global_args, None);
+        global_args.push((name.clone(), value));
+    }
+
+    Ok((bakerfile, global_args))
+}
+
+/// Expands the global ARGs a FROM refers to.
+fn expand_from(from: parser::FromClause, global_args: &[(String, String)]) -> parser::FromClause {
+    let expand_global =
+        |value: &str| variables::expand(value, |name| variables::lookup(global_args, name));
+
+    parser::FromClause {
+        image: expand_global(&from.image),
+        tag: from.tag.as_deref().map(expand_global),
+        platform: from.platform.as_deref().map(expand_global),
+        alias: from.alias,
+    }
+}
+
+/// The earlier stage a stage is based on, if its FROM names one rather than
+/// an image to pull.
+fn base_stage<'a>(from: &parser::FromClause, stages: &'a [BuiltStage]) -> Option<&'a BuiltStage> {
+    match from.tag {
+        Some(_) => None,
+        None => stages
+            .iter()
+            .find(|stage| stage.name.as_deref() == Some(from.image.as_str())),
+    }
+}
+
+/// Whether every FROM of `bakerfile` names an earlier stage or one of the
+/// local `images`, the build then needing no image index.
+fn resolves_locally(
+    bakerfile: &parser::BakerFile,
+    global_args: &[(String, String)],
+    options: &BuildOptions,
+    images: &[BakerImage],
+) -> bool {
+    if options.pull {
+        return false;
+    }
+
+    let mut aliases = Vec::new();
+    for stage in &bakerfile.stages {
+        let from = expand_from(stage.from.clone(), global_args);
+        let local = match &from.tag {
+            None => aliases.contains(&from.image),
+            Some(tag) => {
+                let platform = base_platform(&from, options);
+                images.iter().any(|image| {
+                    image.platform() == platform && image.name() == from.image && image.tag() == tag
+                })
+            }
+        };
+        if !local {
+            return false;
+        }
+        aliases.extend(from.alias);
+    }
+
+    true
+}
+
+/// Platform of a pulled base: the one of its FROM, else the one asked for.
+fn base_platform(from: &parser::FromClause, options: &BuildOptions) -> String {
+    from.platform
+        .clone()
+        .or_else(|| options.platform.clone())
+        .unwrap_or_else(default_platform)
+}
+
+/// Instructions changing the partition layout, which is changed on the base
+/// before any instruction.
+fn layout_instructions(instructions: &[parser::Instruction]) -> Vec<&parser::Instruction> {
+    instructions
+        .iter()
+        .filter(|instruction| {
+            matches!(
+                instruction,
+                parser::Instruction::EXPANDROOT(parser::RootExpansion::To(_))
+                    | parser::Instruction::PARTITION(_)
+            )
+        })
+        .collect()
+}
+
+/// Key of the base once its partition layout is changed.
+fn layout_key(base_key: &str, layout: &[&parser::Instruction]) -> String {
+    cache::key(
+        base_key,
+        &layout
+            .iter()
+            .map(|instruction| format!("{:?}", instruction))
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Checks the instructions a failed build applied before `marker` are still
+/// the ones planned in `steps`, the image it left being stale otherwise.
+fn check_resumable(
+    marker: &resume::ResumeMarker,
+    base_key: &str,
+    steps: &[Step],
+) -> Result<(), Error> {
+    let key = match marker.completed() {
+        0 => Some(base_key),
+        completed => steps.get(completed - 1).map(|step| step.key.as_str()),
+    };
+    if key != Some(marker.key()) {
+        return Err(Error::Usage(
+            "Instructions before the one that failed have changed, the build cannot be resumed"
+                .into(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The last checkpoint whose snapshot is in the cache, with the snapshot.
+fn cached_checkpoint(
+    steps: &[Step],
+    options: &BuildOptions,
+) -> Result<Option<(usize, cache::Snapshot)>, Error> {
+    cached_checkpoint_in(steps, options, cache::find)
+}
+
+/// `cached_checkpoint`, snapshots being looked up with `find`.
+fn cached_checkpoint_in<T>(
+    steps: &[Step],
+    options: &BuildOptions,
+    find: impl Fn(&str) -> Result<Option<T>, Error>,
+) -> Result<Option<(usize, T)>, Error> {
+    if options.no_cache {
+        return Ok(None);
+    }
+
+    for (index, step) in steps.iter().enumerate().rev() {
+        if step.is_checkpoint() {
+            if let Some(snapshot) = find(&step.key)? {
+                return Ok(Some((index, snapshot)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Key of a built stage, which later stages build on or copy from.
+fn stage_key(base_key: &str, steps: &[Step], state: &StageState) -> String {
+    cache::key(
+        steps.last().map_or(base_key, |step| &step.key),
+        &[format!("{:?}", state)],
+    )
+}
+
+/// Builds a stage into `tmp_dir`, the stages it depends on being in `stages`.
+/// A failure keeps the working image so that the build of `resume_id` can be
+/// resumed from the failing instruction.
+fn build_stage(
+    index: usize,
+    stage: parser::Stage,
+    options: &BuildOptions,
+    global_args: &[(String, String)],
+    stages: &[BuiltStage],
+    tmp_dir: &Path,
+    resume_id: &str,
+) -> Result<BuiltStage, Error> {
+    let from = expand_from(stage.from, global_args);
+    let tmp_path = tmp_dir.join(format!("stage-{}.img", index));
+
+    let (platform, base_path, base_key, mut history, mut labels, base) =
+        match base_stage(&from, stages) {
+            Some(base_stage) => (
+                base_stage.platform.clone(),
+                base_stage.path.clone(),
+                base_stage.key.clone(),
+                base_stage.history.clone(),
+                base_stage.labels.clone(),
+                None,
+            ),
+            None => {
+                let platform = base_platform(&from, options);
+                let tag = from
+                    .tag
+                    .clone()
+                    .ok_or_else(|| Error::Usage("Image tag is required".to_string()))?;
+                let image = if options.pull {
+                    pull_newest(&platform, &from.image, &tag)?
+                } else {
+                    pull(&platform, &from.image, &tag, false)?
+                };
+                let key = image.sha256().to_string();
+                let base =
+                    provenance::BaseImage::new(image.full_name(), platform.clone(), key.clone());
+                (
+                    platform,
+                    image.path()?,
+                    key,
+                    image.history(),
+                    image.labels,
+                    Some(base),
+                )
+            }
+        };
+    let base_history = history.len();
+
+    let layout = layout_instructions(&stage.instructions);
+    let base_key = layout_key(&base_key, &layout);
+    let grow_to = layout
+        .iter()
+        .filter_map(|instruction| match instruction {
+            parser::Instruction::EXPANDROOT(parser::RootExpansion::To(size)) => {
+                Some(crate::size::parse_size(size))
+            }
+            _ => None,
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .max();
+    let partitions: Vec<parser::NewPartition> = layout
+        .iter()
+        .filter_map(|instruction| match instruction {
+            parser::Instruction::PARTITION(partition) => Some(partition.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let mut context = BuildContext::new(
+        &options.context,
+        options.symlinks,
+        options.max_context_size,
+        options.max_context_files,
+    )?;
+    let (steps, state) = plan_stage(
+        &platform,
+        stage.instructions,
+        options,
+        global_args,
+        stages,
+        &context,
+        &base_key,
+        &mut labels,
+    )?;
+
+    // Resume from the image the failed build left, as long as the
+    // instructions it applied are unchanged
+    let resumed = match resume::load(resume_id, index)?.filter(|_| options.resume) {
+        Some(marker) => {
+            check_resumable(&marker, &base_key, &steps)?;
+            progress::copy_file(marker.image(), &tmp_path, "Restoring the failed build")?;
+            Some(marker.completed())
+        }
+        None => None,
+    };
+
+    // Start from the snapshot of the last checkpoint found in the cache,
+    // which gets refreshed when it is not used
+    let restored = match resumed {
+        Some(_) => None,
+        None => cached_checkpoint(&steps, options)?,
+    };
+    let (start, mut grown) = match (resumed, restored) {
+        (Some(completed), _) => (completed, false),
+        (None, Some((index, snapshot))) => {
+            progress::copy_file(snapshot.path(), &tmp_path, "Restoring from the cache")?;
+            (index + 1, false)
+        }
+        (None, None) => {
+            // Change the partition layout up front, every instruction of
+            // the stage then has room and the added partitions get mounted
+            progress::copy_file(&base_path, &tmp_path, "Copying the base image")?;
+            let grown = match grow_to {
+                Some(size) => crate::partitions::grow_image(&tmp_path, size)?,
+                None => false,
+            };
+            for partition in &partitions {
+                crate::partitions::add_partition(
+                    &tmp_path,
+                    &partition.label,
+                    crate::size::parse_size(&partition.size)?,
+                    &partition.filesystem,
+                )?;
+            }
+            (0, grown)
+        }
+    };
+    if resumed.is_some() {
+        progress::message(&format!(
+            "Resuming after {} of {} instructions",
+            start,
+            steps.len()
+        ));
+    } else if start > 0 {
+        progress::message(&format!(
+            "Using the cache for {} of {} instructions",
+            start,
+            steps.len()
+        ));
+    }
+    let mut progress = Progress::new(steps.len());
+    progress.skip(start);
+
+    let stage_name = from.alias.clone().unwrap_or(index.to_string());
+    let log = BuildLog::create(&tmp_dir.join(format!("stage-{}.log", index)))?;
+    log.section(&format!("Stage {}", stage_name))?;
+    let mut stage_report: Vec<report::StepReport> = steps[..start]
+        .iter()
+        .map(|step| {
+            report::StepReport::skipped(
+                stage_name.clone(),
+                step.description.clone(),
+                match resumed {
+                    Some(_) => report::CacheStatus::Resumed,
+                    None => report::CacheStatus::Cached,
+                },
+            )
+        })
+        .collect();
+    for step in &steps[..start] {
+        log.section(&format!(
+            "{} ({})",
+            step.description,
+            match resumed {
+                Some(_) => "done before resuming",
+                None => "cached",
+            }
+        ))?;
+    }
+    history.extend(steps[..start].iter().map(|step| step.history_entry(None)));
+
+    // The image is unmounted after every checkpoint to snapshot it, the
+    // last mount also installing the CMD and ENTRYPOINT service
+    let mut segments: Vec<&[Step]> = steps[start..]
+        .split_inclusive(Step::is_checkpoint)
+        .collect();
+    if segments
+        .last()
+        .is_none_or(|segment| segment.last().is_some_and(Step::is_checkpoint))
+    {
+        segments.push(&[]);
+    }
+    for (position, segment) in segments.iter().enumerate() {
+        // Mount image
+        let mounted = MountedImage::new(&tmp_path)?;
+        if std::mem::take(&mut grown) {
+            crate::partitions::resize_filesystem(&mounted.root_device()?)?;
+        }
+
+        let mut result = apply_steps(
+            &mounted,
+            &platform,
+            segment,
+            options,
+            stages,
+            &mut context,
+            &mut history,
+            &mut progress,
+            &log,
+            &mut stage_report,
+            &stage_name,
+        );
+        if result.is_ok() && position + 1 == segments.len() {
+            result = install_command(&mounted, &state);
+        }
+
+        // Unmount image and save it
+        mounted.unmount()?;
+
+        if let Err(err) = result {
+            let completed = history.len() - base_history;
+            let key = match completed {
+                0 => base_key,
+                completed => steps[completed - 1].key.clone(),
+            };
+            let image = match keep_failed_stage(options, &tmp_path)? {
+                Some(kept_path) => kept_path,
+                None => resume::keep_image(resume_id, index, &tmp_path)?,
+            };
+            resume::save(
+                resume_id,
+                &resume::ResumeMarker::new(index, completed, key, image),
+            )?;
+            progress::message("Fix the Bakerfile and carry on with: baker build --resume");
+            return Err(err);
+        }
+        if let Some(step) = segment.last().filter(|step| step.is_checkpoint()) {
+            cache::store(&step.key, &tmp_path)?;
+        }
+    }
+    resume::remove_stage(resume_id, index)?;
+
+    let mut bases: Vec<provenance::BaseImage> = base.into_iter().collect();
+    bases.extend(copied_images(&steps, stages, &platform, |name, tag| {
+        resolve_image(&platform, name, tag, false)
+    })?);
+
+    let key = stage_key(&base_key, &steps, &state);
+    Ok(BuiltStage {
+        index,
+        name: from.alias,
+        path: tmp_path,
+        platform,
+        key,
+        history,
+        labels,
+        bases,
+        report: stage_report,
+    })
+}
+
+/// Lists planned steps with their variables expanded and the context files
+/// they read, the first `cached` ones being in the cache.
+fn describe_steps(
+    steps: &[Step],
+    cached: usize,
+    context: &mut BuildContext,
+) -> Result<Vec<String>, Error> {
+    let mut lines = Vec::new();
+
+    for (number, step) in steps.iter().enumerate() {
+        let text = match &step.instruction {
+            Some(instruction) => instruction.to_string(),
+            None => step.description.clone(),
+        };
+        let mut text_lines = text.lines();
+        lines.push(format!(
+            "  [{}/{}] {}{}",
+            number + 1,
+            steps.len(),
+            text_lines.next().unwrap_or_default(),
+            if number < cached { " (cached)" } else { "" }
+        ));
+        lines.extend(text_lines.map(|line| format!("        {}", line)));
+
+        for pattern in step.instruction.iter().flat_map(context_patterns) {
+            for source in context.expand(pattern)? {
+                let path = source.path();
+                lines.push(format!(
+                    "        < {}",
+                    path.strip_prefix(context.root()).unwrap_or(path).display()
+                ));
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Describes what building `file` would do without downloading, mounting or
+/// running anything: the base of every stage and its steps.
+pub fn plan_build(file: &Path, options: &BuildOptions) -> Result<Vec<String>, Error> {
+    let (bakerfile, global_args) = load_build_file(file, options)?;
+    let mut lines = Vec::new();
+    let mut stages: Vec<BuiltStage> = Vec::new();
+
+    for (index, stage) in bakerfile.stages.into_iter().enumerate() {
+        let from = expand_from(stage.from, &global_args);
+        let (platform, base_key, mut labels, base) = match base_stage(&from, &stages) {
+            Some(base_stage) => (
+                base_stage.platform.clone(),
+                base_stage.key.clone(),
+                base_stage.labels.clone(),
+                from.image.clone(),
+            ),
+            None => {
+                let platform = base_platform(&from, options);
+                let tag = from
+                    .tag
+                    .as_deref()
+                    .ok_or_else(|| Error::Usage("Image tag is required".to_string()))?;
+                let image = resolve_image(&platform, &from.image, tag, options.pull)?;
+                let base = format!(
+                    "{} ({}, sha256 {}{})",
+                    image.full_name(),
+                    platform,
+                    &image.sha256()[..12.min(image.sha256().len())],
+                    match image.path()?.is_file() {
+                        true => "",
+                        false => ", to download",
+                    }
+                );
+                (platform, image.sha256().to_string(), image.labels, base)
+            }
+        };
+        lines.push(match &from.alias {
+            Some(alias) => format!("Stage {} {}: FROM {}", index, alias, base),
+            None => format!("Stage {}: FROM {}", index, base),
+        });
+
+        let base_key = layout_key(&base_key, &layout_instructions(&stage.instructions));
+        let mut context = BuildContext::new(
+            &options.context,
+            options.symlinks,
+            options.max_context_size,
+            options.max_context_files,
+        )?;
+        let (steps, state) = plan_stage(
+            &platform,
+            stage.instructions,
+            options,
+            &global_args,
+            &stages,
+            &context,
+            &base_key,
+            &mut labels,
+        )?;
+        let cached = cached_checkpoint(&steps, options)?.map_or(0, |(index, _)| index + 1);
+        lines.extend(describe_steps(&steps, cached, &mut context)?);
+
+        // Nothing gets built, the stages after only need the key
+        stages.push(BuiltStage {
+            index,
+            name: from.alias,
+            path: PathBuf::new(),
+            platform,
+            key: stage_key(&base_key, &steps, &state),
+            history: Vec::new(),
+            labels,
+            bases: Vec::new(),
+            report: Vec::new(),
+        });
+    }
+
+    Ok(lines)
+}
+
+/// Builds an image, running the hooks of `options` around the build. A failed
+/// build fails with its own error, a failing post-build hook being reported
+/// along with it.
+pub fn build(
+    file: PathBuf,
+    name: Option<String>,
+    tag: Option<String>,
+    options: BuildOptions,
+) -> Result<BakerImage, Error> {
+    let (hook_pre, hook_post) = (options.hook_pre.clone(), options.hook_post.clone());
+    let envs = vec![
+        ("BAKERFILE", file.display().to_string()),
+        ("CONTEXT", options.context.display().to_string()),
+        (
+            "PLATFORM",
+            options.platform.clone().unwrap_or_else(default_platform),
+        ),
+    ];
+
+    // Hooks only run for builds that can start
+    crate::privileges::require_root("build")?;
+    options.run_environment.check_available()?;
+
+    if let Some(hook) = &hook_pre {
+        let mut envs = envs.clone();
+        envs.extend(name.clone().map(|name| ("NAME", name)));
+        envs.extend(tag.clone().map(|tag| ("TAG", tag)));
+        crate::hooks::run("pre-build", hook, &envs)?;
+    }
+
+    let result = build_image(file, name, tag, options);
+
+    if let Some(hook) = &hook_post {
+        let mut envs = envs;
+        let hooked = match &result {
+            Ok(image) => image.path().map(|path| {
+                envs.extend([
+                    ("STATUS", "success".to_string()),
+                    ("NAME", image.name().to_string()),
+                    ("TAG", image.tag().to_string()),
+                    ("SHA256", image.sha256().to_string()),
+                    ("IMAGE", path.display().to_string()),
+                ])
+            }),
+            Err(err) => {
+                envs.extend([
+                    ("STATUS", "failure".to_string()),
+                    ("ERROR", err.to_string()),
+                ]);
+                Ok(())
+            }
+        }
+        .and_then(|_| crate::hooks::run("post-build", hook, &envs));
+
+        match (&result, hooked) {
+            (Ok(_), Err(err)) => return Err(err),
+            (Err(_), Err(err)) if !progress::is_quiet() => eprintln!("{}", err),
+            _ => {}
+        }
+    }
+
+    result
+}
+
+fn build_image(
+    file: PathBuf,
+    name: Option<String>,
+    tag: Option<String>,
+    options: BuildOptions,
+) -> Result<BakerImage, Error> {
+    let started = chrono::Utc::now();
+    for volume in &options.volumes {
+        volume.check()?;
+    }
+    for secret in &options.secrets {
+        secret.check()?;
+    }
+
+    let (bakerfile, global_args) = load_build_file(&file, &options)?;
+    // Bases already there are used as they are, so building works offline
+    if options.no_cache && !resolves_locally(&bakerfile, &global_args, &options, &list()?) {
+        fetch_baker_images(true)?;
+    }
+    let resume_id = resume::build_id(&file, options.platform.as_deref())?;
+    if options.resume && !resume::has_failed(&resume_id)? {
+        return Err(Error::Usage(format!(
+            "No failed build of {} to resume",
+            file.display()
+        )));
+    }
+    // Failures of an earlier build are superseded by the ones of this one
+    if !options.resume {
+        resume::remove(&resume_id)?;
+    }
+
+    let tmp_dir = tempdir::TempDir::new("baker")?;
+    let tmp_dir_path = tmp_dir.path().to_path_buf();
+    let _tmp_dir_registration = crate::cleanup::register(move || {
+        let _ = fs::remove_dir_all(tmp_dir_path);
+    });
+
+    // Stages whose dependencies are built get built together, up to `jobs`
+    // of them at once
+    let dependencies: Vec<Vec<usize>> = (0..bakerfile.stages.len())
+        .map(|index| stage_dependencies(&bakerfile.stages, index))
+        .collect();
+    let mut pending: Vec<(usize, parser::Stage)> =
+        bakerfile.stages.into_iter().enumerate().collect();
+    let mut stages: Vec<BuiltStage> = Vec::new();
+    while !pending.is_empty() {
+        let (mut ready, mut waiting): (Vec<_>, Vec<_>) =
+            pending.into_iter().partition(|(index, _)| {
+                dependencies[*index]
+                    .iter()
+                    .all(|dependency| stages.iter().any(|stage| stage.index == *dependency))
+            });
+        if ready.is_empty() {
+            return Err(Error::Usage(
+                "Stage dependencies cannot be resolved".to_string(),
+            ));
+        }
+        waiting.extend(ready.split_off(options.jobs.clamp(1, ready.len())));
+        waiting.sort_by_key(|(index, _)| *index);
+        pending = waiting;
+
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = ready
+                .into_iter()
+                .map(|(index, stage)| {
+                    let (options, global_args, stages) = (&options, &global_args, &stages);
+                    let (tmp_dir, resume_id) = (tmp_dir.path(), resume_id.as_str());
+                    scope.spawn(move || {
+                        build_stage(
+                            index,
+                            stage,
+                            options,
+                            global_args,
+                            stages,
+                            tmp_dir,
+                            resume_id,
+                        )
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or_else(|_| {
+                        Err(Error::Other("Building a stage panicked".to_string()))
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        for result in results {
+            stages.push(result?);
+        }
+    }
+
+    // Images pulled to build the stages, as their base or to copy files from
+    let mut bases: Vec<provenance::BaseImage> = Vec::new();
+    for base in stages.iter().flat_map(|stage| stage.bases.clone()) {
+        if !bases.contains(&base) {
+            bases.push(base);
+        }
+    }
+    stages.sort_by_key(|stage| stage.index);
+    let steps = stages
+        .iter_mut()
+        .flat_map(|stage| std::mem::take(&mut stage.report))
+        .collect();
+    let BuiltStage {
+        index: final_index,
+        path: tmp_path,
+        platform,
+        history,
+        labels,
+        ..
+    } = stages
+        .into_iter()
+        .max_by_key(|stage| stage.index)
+        .ok_or_else(|| Error::Usage("No stage to build".to_string()))?;
+
+    let img_dir = get_images_dir()?;
+    let digest = sha256::try_digest(&tmp_path)?;
+    let dest_path = img_dir.join(digest.clone() + ".img");
+    progress::copy_file(&tmp_path, &dest_path, "Saving the image")?;
+    logs::save(
+        &(0..=final_index)
+            .map(|index| tmp_dir.path().join(format!("stage-{}.log", index)))
+            .collect::<Vec<_>>(),
+        &img_dir.join(digest.clone() + ".log"),
+    )?;
+    let (bakerfile_path, bakerfile_sha256) = match &options.contents {
+        Some(contents) => (file.clone(), sha256::digest(contents.as_str())),
+        None => (fs::canonicalize(&file)?, sha256::try_digest(&file)?),
+    };
+    let document = provenance::Provenance::new(
+        digest.clone(),
+        platform.clone(),
+        bakerfile_path,
+        bakerfile_sha256,
+        bases,
+        options.build_args.iter().cloned().collect(),
+        options.target.clone(),
+        started,
+    );
+    provenance::save(
+        &provenance::sign(&document)?,
+        &img_dir.join(digest.clone() + ".provenance"),
+    )?;
+
+    // Update repository, a rebuilt name and tag replaces the previous image
+    let _lock = lock_repository();
+    let image = BakerImage {
+        platform,
+        name: name.unwrap_or(digest.clone()),
+        tag: tag.unwrap_or(LATEST_TAG.into()),
+        sha256: digest,
+        history,
+        labels,
+    };
+    let (mut repos, _) = partition_matching(list()?, &image.platform, &image.name, &image.tag);
+    repos.push(image.clone());
+
+    repository::write_repository(&repos)?;
+    resume::remove(&resume_id)?;
+
+    let build_report = report::BuildReport::new(
+        image.full_name(),
+        image.platform.clone(),
+        (chrono::Utc::now() - started).to_std().unwrap_or_default(),
+        steps,
+    );
+    if let Some(path) = &options.report {
+        report::save(&build_report, path)?;
+    }
+    if !progress::is_quiet() {
+        print!("{}", build_report.table());
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(platform: &str, name: &str, tag: &str) -> BakerImage {
+        BakerImage {
+            platform: platform.to_string(),
+            name: name.to_string(),
+            tag: tag.to_string(),
+            sha256: String::new(),
+            history: Vec::new(),
+            labels: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_reference() {
+        assert_eq!(
+            parse_reference("raspios").unwrap(),
+            ("raspios".to_string(), "latest".to_string())
+        );
+        assert_eq!(
+            parse_reference("raspios:bookworm-20240704").unwrap(),
+            ("raspios".to_string(), "bookworm-20240704".to_string())
+        );
+        assert!(matches!(
+            parse_reference(""),
+            Err(Error::InvalidReference(_))
+        ));
+        assert!(matches!(
+            parse_reference("raspios:"),
+            Err(Error::InvalidReference(_))
+        ));
+        assert!(matches!(
+            parse_reference("raspios:a:b"),
+            Err(Error::InvalidReference(reference)) if reference == "raspios:a:b"
+        ));
+    }
+
+    #[test]
+    fn test_newest_resolves_by_embedded_date() {
+        let images = vec![
+            image("arm64", "raspios", "bookworm-20240315"),
+            image("arm64", "raspios", "bookworm-20240704-lite"),
+            image("armhf", "raspios", "bookworm-20241119"),
+            image("arm64", "raspios", "bullseye-20230503"),
+            image("arm64", "other", "bookworm-20250101"),
+        ];
+
+        assert_eq!(
+            newest(&images, "arm64", "raspios").map(|image| image.tag()),
+            Some("bookworm-20240704-lite")
+        );
+        assert!(newest(&images, "arm64", "missing").is_none());
+    }
+
+    #[test]
+    fn test_tag_family() {
+        assert_eq!(
+            tag_family("bookworm-20240704-lite").as_deref(),
+            Some("bookworm-*-lite")
+        );
+        assert_eq!(
+            tag_family("bookworm-20241119-lite"),
+            tag_family("bookworm-20240704-lite")
+        );
+        assert_ne!(
+            tag_family("bookworm-20240704"),
+            tag_family("bookworm-20240704-lite")
+        );
+        assert!(tag_family("bookworm").is_none());
+    }
+
+    #[test]
+    fn test_history_round_trips_through_repository() {
+        let mut built = image("arm64", "myimage", "latest");
+        built.history = vec![
+            HistoryEntry::new("FROM raspios:bookworm-20240704".to_string()),
+            HistoryEntry::timed(
+                "RUN apt-get update".to_string(),
+                std::time::Duration::from_secs(12),
+            ),
+            HistoryEntry::timed(
+                "COPY app.conf /etc/app.conf".to_string(),
+                std::time::Duration::from_millis(3),
+            ),
+        ];
+
+        let json = serde_json::to_string_pretty(&vec![built.clone()]).unwrap();
+        let images: Vec<BakerImage> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(images[0].history(), built.history);
+    }
+
+    #[test]
+    fn test_history_of_pulled_image() {
+        let json = r#"[{"platform":"arm64","name":"raspios","tag":"bookworm","sha256":""}]"#;
+        let images: Vec<BakerImage> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(
+            images[0].history(),
+            vec![HistoryEntry::new("FROM raspios:bookworm".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_pull_platforms_aggregates_errors() {
+        let platforms = vec!["arm64".to_string(), "armhf".to_string(), "x86".to_string()];
+        let mut attempted = Vec::new();
+
+        let result = pull_platforms(&platforms, |platform| {
+            attempted.push(platform.to_string());
+            match platform {
+                "arm64" => Ok(image(platform, "raspios", "bookworm")),
+                _ => Err(Error::ImageNotFound("raspios:bookworm".to_string())),
+            }
+        });
+
+        assert_eq!(attempted, vec!["arm64", "armhf"]);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Failed to pull 2 of 3 platforms: armhf, x86"
+        );
+    }
+
+    #[test]
+    fn test_pull_platforms_single_keeps_error() {
+        let result = pull_platforms(&["armhf".to_string()], |_| {
+            Err(Error::ImageNotFound("raspios:bookworm".to_string()))
+        });
+
+        assert!(matches!(result, Err(Error::ImageNotFound(_))));
+    }
+
+    #[test]
+    fn test_check_file() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let intact_path = tmp_dir.path().join("intact.img");
+        let tampered_path = tmp_dir.path().join("tampered.img");
+        fs::write(&intact_path, b"image").unwrap();
+        fs::write(&tampered_path, b"image").unwrap();
+
+        let mut image = image("arm64", "raspios", "bookworm-20240704");
+        image.sha256 = sha256::try_digest(intact_path.as_path()).unwrap();
+        fs::write(&tampered_path, b"imagf").unwrap();
+
+        assert_eq!(check_file(&image, &intact_path).unwrap(), VerifyStatus::Ok);
+        assert_eq!(
+            check_file(&image, &tampered_path).unwrap(),
+            VerifyStatus::Corrupt
+        );
+        assert_eq!(
+            check_file(&image, &tmp_dir.path().join("missing.img")).unwrap(),
+            VerifyStatus::Missing
+        );
+    }
+
+    #[test]
+    fn test_matches_terms() {
+        let image = image("arm64", "raspios", "bookworm-20240704-lite");
+        let terms = |terms: &[&str]| terms.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+
+        assert!(matches_terms(&image, &terms(&[])));
+        assert!(matches_terms(&image, &terms(&["raspios", "lite"])));
+        assert!(matches_terms(&image, &terms(&["RaspiOS", "2024"])));
+        assert!(!matches_terms(&image, &terms(&["raspios", "full"])));
+    }
+
+    #[test]
+    fn test_platform_or_default() {
+        assert_eq!(platform_or_default(None), "arm64");
+        assert_eq!(platform_or_default(Some(String::new())), "arm64");
+        assert_eq!(platform_or_default(Some("armhf".to_string())), "armhf");
+    }
+
+    #[test]
+    fn test_partition_matching_targets_platform() {
+        let images = vec![
+            image("arm64", "raspios", "bookworm-20240704"),
+            image("armhf", "raspios", "bookworm-20240704"),
+        ];
+
+        let (kept, removed) = partition_matching(images, "armhf", "raspios", "bookworm-20240704");
+
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].platform(), "arm64");
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].platform(), "armhf");
+    }
+
+    #[test]
+    fn test_unreferenced_keeps_shared_files() {
+        let mut base = image("arm64", "mybase", "1.0");
+        base.sha256 = "aaaa".to_string();
+        let stable = BakerImage {
+            tag: "stable".to_string(),
+            ..base.clone()
+        };
+        let mut other = image("arm64", "other", "1.0");
+        other.sha256 = "bbbb".to_string();
+
+        assert!(unreferenced(vec![base.clone()], &[stable.clone(), other.clone()]).is_empty());
+
+        let orphans = unreferenced(vec![base, other], &[]);
+        assert_eq!(orphans.len(), 2);
+    }
+
+    #[test]
+    fn test_unreferenced_files() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        fs::write(tmp_dir.path().join("aaaa.img"), b"kept").unwrap();
+        fs::write(tmp_dir.path().join("bbbb.img"), b"orphan").unwrap();
+        fs::write(tmp_dir.path().join("import-42.tmp"), b"partial").unwrap();
+        fs::create_dir(tmp_dir.path().join("cccc.img")).unwrap();
+
+        let mut kept = image("arm64", "raspios", "bookworm");
+        kept.sha256 = "aaaa".to_string();
+
+        assert_eq!(
+            unreferenced_files(tmp_dir.path(), &[kept]).unwrap(),
+            vec![
+                tmp_dir.path().join("bbbb.img"),
+                tmp_dir.path().join("import-42.tmp")
+            ]
+        );
+        assert!(unreferenced_files(&tmp_dir.path().join("missing"), &[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_matches_filter() {
+        let mut labelled = image("arm64", "kiosk", "1.0");
+        labelled
+            .labels
+            .insert("project".to_string(), "kiosk".to_string());
+
+        assert!(matches_filter(&labelled, "label=project").unwrap());
+        assert!(matches_filter(&labelled, "label=project=kiosk").unwrap());
+        assert!(!matches_filter(&labelled, "label=project=signage").unwrap());
+        assert!(!matches_filter(&labelled, "label=owner").unwrap());
+        assert!(matches!(
+            matches_filter(&labelled, "name=kiosk"),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_find_stage() {
+        let stage = |index: usize, name: Option<&str>| BuiltStage {
+            index,
+            name: name.map(|name| name.to_string()),
+            path: PathBuf::new(),
+            platform: "arm64".to_string(),
+            key: String::new(),
+            history: Vec::new(),
+            labels: BTreeMap::new(),
+            bases: Vec::new(),
+            report: Vec::new(),
+        };
+        // Built stages are listed in the order they completed
+        let stages = vec![stage(1, None), stage(0, Some("builder"))];
+
+        assert_eq!(
+            find_stage(&stages, "builder").unwrap().name.as_deref(),
+            Some("builder")
+        );
+        assert!(find_stage(&stages, "1").unwrap().name.is_none());
+        assert!(matches!(
+            find_stage(&stages, "missing"),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_stage_dependencies() {
+        let bakerfile = crate::parsing::parse_bakerfile(
+            "FROM raspios:bookworm AS tools\n\
+             FROM raspios:bookworm AS assets\n\
+             FROM raspios:bookworm\n\
+             COPY --from=tools /usr/bin/tool /usr/bin/tool\n\
+             IF ${VARIANT} = full\n\
+             COPY --from=1 /assets /assets\n\
+             ENDIF\n\
+             COPY --from=tools /etc/tool /etc/tool\n\
+             FROM tools\n\
+             COPY --from=other:1.0 /opt /opt\n",
+        )
+        .unwrap();
+
+        assert!(stage_dependencies(&bakerfile.stages, 0).is_empty());
+        assert!(stage_dependencies(&bakerfile.stages, 1).is_empty());
+        assert_eq!(stage_dependencies(&bakerfile.stages, 2), vec![0, 1]);
+        assert_eq!(stage_dependencies(&bakerfile.stages, 3), vec![0]);
+    }
+
+    #[test]
+    fn test_no_cache_ignores_snapshots() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let bakerfile =
+            crate::parsing::parse_bakerfile("FROM raspios:bookworm\nRUN whoami\nRUN id\n").unwrap();
+        let context =
+            BuildContext::new(tmp_dir.path(), SymlinkPolicy::Contained, 1024 * 1024, 100).unwrap();
+        let (steps, _) = plan_stage(
+            "arm64",
+            bakerfile.stages[0].instructions.clone(),
+            &BuildOptions::default(),
+            &[],
+            &[],
+            &context,
+            "base",
+            &mut BTreeMap::new(),
+        )
+        .unwrap();
+        let snapshot = tmp_dir.path().join("snapshot.img");
+        let find = |_: &str| Ok(Some(snapshot.clone()));
+
+        assert_eq!(
+            cached_checkpoint_in(&steps, &BuildOptions::default(), find).unwrap(),
+            Some((steps.len() - 1, snapshot.clone()))
+        );
+        let options = BuildOptions {
+            no_cache: true,
+            ..Default::default()
+        };
+        assert_eq!(cached_checkpoint_in(&steps, &options, find).unwrap(), None);
+    }
+
+    #[test]
+    fn test_resume_rejects_changed_instructions() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let context =
+            BuildContext::new(tmp_dir.path(), SymlinkPolicy::Contained, 1024 * 1024, 100).unwrap();
+        let plan = |contents: &str| {
+            let bakerfile = crate::parsing::parse_bakerfile(contents).unwrap();
+            plan_stage(
+                "arm64",
+                bakerfile.stages[0].instructions.clone(),
+                &BuildOptions::default(),
+                &[],
+                &[],
+                &context,
+                "base",
+                &mut BTreeMap::new(),
+            )
+            .unwrap()
+            .0
+        };
+        let steps = plan("FROM raspios:bookworm\nRUN whoami\nRUN id\n");
+        let marker = resume::ResumeMarker::new(0, 1, steps[0].key.clone(), PathBuf::new());
+
+        check_resumable(&marker, "base", &steps).unwrap();
+        check_resumable(
+            &resume::ResumeMarker::new(0, 0, "base".to_string(), PathBuf::new()),
+            "base",
+            &steps,
+        )
+        .unwrap();
+
+        let edited = plan("FROM raspios:bookworm\nRUN hostname\nRUN id\n");
+        assert!(matches!(
+            check_resumable(&marker, "base", &edited),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            check_resumable(&marker, "other", &plan("FROM raspios:bookworm\n")),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolves_locally() {
+        let bakerfile = crate::parsing::parse_bakerfile(
+            "FROM acme/golden-base:2024-10 AS base\nRUN whoami\nFROM base\nRUN id\n",
+        )
+        .unwrap();
+        let images = [image("arm64", "acme/golden-base", "2024-10")];
+        let options = BuildOptions::default();
+
+        assert!(resolves_locally(&bakerfile, &[], &options, &images));
+        assert!(!resolves_locally(&bakerfile, &[], &options, &[]));
+        let armhf = BuildOptions {
+            platform: Some("armhf".to_string()),
+            ..Default::default()
+        };
+        assert!(!resolves_locally(&bakerfile, &[], &armhf, &images));
+        let pull = BuildOptions {
+            pull: true,
+            ..Default::default()
+        };
+        assert!(!resolves_locally(&bakerfile, &[], &pull, &images));
+    }
+
+    fn plan_keys(contents: &str, context: &Path) -> Vec<(bool, String)> {
+        let bakerfile = crate::parsing::parse_bakerfile(contents).unwrap();
+        let context =
+            BuildContext::new(context, SymlinkPolicy::Contained, 1024 * 1024, 100).unwrap();
+        let (steps, _) = plan_stage(
+            "arm64",
+            bakerfile.stages[0].instructions.clone(),
+            &BuildOptions::default(),
+            &[],
+            &[],
+            &context,
+            "base",
+            &mut BTreeMap::new(),
+        )
+        .unwrap();
+
+        steps
+            .iter()
+            .map(|step| (step.is_checkpoint(), step.key.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_describe_steps() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        fs::create_dir(tmp_dir.path().join("conf")).unwrap();
+        fs::write(tmp_dir.path().join("conf/app.conf"), "a").unwrap();
+        fs::write(tmp_dir.path().join("conf/db.conf"), "b").unwrap();
+        let bakerfile = crate::parsing::parse_bakerfile(
+            "FROM raspios:bookworm
+             ARG APP=kiosk
+             RUN apt-get install -y $APP
+             COPY conf/*.conf /etc/$APP/
+",
+        )
+        .unwrap();
+        let mut context =
+            BuildContext::new(tmp_dir.path(), SymlinkPolicy::Contained, 1024 * 1024, 100).unwrap();
+        let (steps, _) = plan_stage(
+            "arm64",
+            bakerfile.stages[0].instructions.clone(),
+            &BuildOptions::default(),
+            &[],
+            &[],
+            &context,
+            "base",
+            &mut BTreeMap::new(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            describe_steps(&steps, 2, &mut context).unwrap(),
+            vec![
+                "  [1/3] ARG APP=kiosk (cached)",
+                "  [2/3] RUN apt-get install -y $APP (cached)",
+                "  [3/3] COPY conf/*.conf /etc/kiosk/",
+                "        < conf/app.conf",
+                "        < conf/db.conf",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_stage_keys() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        fs::write(tmp_dir.path().join("app.conf"), "a").unwrap();
+        let bakerfile = "FROM raspios:bookworm\n\
+                         RUN apt-get update\n\
+                         COPY app.conf /etc/app.conf\n\
+                         USER pi\n\
+                         RUN whoami\n";
+
+        let keys = plan_keys(bakerfile, tmp_dir.path());
+        assert_eq!(
+            keys.iter()
+                .map(|(checkpoint, _)| *checkpoint)
+                .collect::<Vec<_>>(),
+            vec![true, false, false, true]
+        );
+        assert_eq!(keys[1].1, keys[2].1);
+
+        // Editing the last line keeps the keys of the lines before
+        let edited = plan_keys(&bakerfile.replace("whoami", "id"), tmp_dir.path());
+        assert_eq!(edited[..3], keys[..3]);
+        assert_ne!(edited[3], keys[3]);
+
+        // So does changing a copied file, the steps after it being invalidated
+        fs::write(tmp_dir.path().join("app.conf"), "b").unwrap();
+        let changed = plan_keys(bakerfile, tmp_dir.path());
+        assert_eq!(changed[0], keys[0]);
+        assert_ne!(changed[1], keys[1]);
+        assert_ne!(changed[3], keys[3]);
+
+        // The user a command runs as is part of its key
+        let user = plan_keys(&bakerfile.replace("USER pi", "USER root"), tmp_dir.path());
+        assert_ne!(user[3], changed[3]);
+    }
+
+    #[test]
+    fn test_stop_at_target() {
+        let mut bakerfile = crate::parsing::parse_bakerfile(
+            "FROM raspios:bookworm AS build-tools\n\
+             FROM build-tools AS app\n\
+             FROM raspios:bookworm\n",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            stop_at_target(&mut bakerfile, "missing"),
+            Err(Error::Usage(_))
+        ));
+        assert_eq!(bakerfile.stages.len(), 3);
+
+        stop_at_target(&mut bakerfile, "build-tools").unwrap();
+        assert_eq!(bakerfile.stages.len(), 1);
+    }
+
+    #[test]
+    fn test_load_build_file_from_stdin() {
+        let options = BuildOptions {
+            contents: Some("ARG BASE=bookworm\nFROM raspios:$BASE\nRUN echo hello\n".to_string()),
+            ..Default::default()
+        };
+
+        let (bakerfile, global_args) = load_build_file(Path::new("-"), &options).unwrap();
+        assert_eq!(bakerfile.stages.len(), 1);
+        assert_eq!(
+            global_args,
+            vec![("BASE".to_string(), "bookworm".to_string())]
+        );
+
+        let options = BuildOptions {
+            contents: Some("RUN echo hello\n".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            load_build_file(Path::new("-"), &options),
+            Err(Error::Parse { file, .. }) if file == "-"
+        ));
+    }
+
+    #[test]
+    fn test_copy_source_path_prefers_stages() {
+        let stages = vec![BuiltStage {
+            index: 0,
+            name: Some("builder".to_string()),
+            path: PathBuf::from("/tmp/stage-0.img"),
+            platform: "arm64".to_string(),
+            key: String::new(),
+            history: Vec::new(),
+            labels: BTreeMap::new(),
+            bases: Vec::new(),
+            report: Vec::new(),
+        }];
+
+        assert_eq!(
+            copy_source_path(&stages, "arm64", "builder").unwrap(),
+            PathBuf::from("/tmp/stage-0.img")
+        );
+        assert!(matches!(
+            copy_source_path(&stages, "arm64", "tools:1.0:extra"),
+            Err(Error::InvalidReference(_))
+        ));
+    }
+
+    #[test]
+    fn test_copied_images_are_recorded() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let context =
+            BuildContext::new(tmp_dir.path(), SymlinkPolicy::Contained, 1024 * 1024, 100).unwrap();
+        let stages = vec![BuiltStage {
+            index: 0,
+            name: Some("builder".to_string()),
+            path: PathBuf::from("/tmp/stage-0.img"),
+            platform: "arm64".to_string(),
+            key: String::new(),
+            history: Vec::new(),
+            labels: BTreeMap::new(),
+            bases: Vec::new(),
+            report: Vec::new(),
+        }];
+        let bakerfile = crate::parsing::parse_bakerfile(
+            "FROM raspios:bookworm\nCOPY --from=builder /app /app\nCOPY --from=tools:1.0 /bin/tool /usr/bin/tool\n",
+        )
+        .unwrap();
+        let (steps, _) = plan_stage(
+            "arm64",
+            bakerfile.stages[0].instructions.clone(),
+            &BuildOptions::default(),
+            &[],
+            &stages,
+            &context,
+            "base",
+            &mut BTreeMap::new(),
+        )
+        .unwrap();
+
+        let copied = copied_images(&steps, &stages, "arm64", |name, tag| {
+            let mut tools = image("arm64", name, tag);
+            tools.sha256 = "5678".to_string();
+            Ok(tools)
+        })
+        .unwrap();
+
+        assert_eq!(
+            copied,
+            vec![provenance::BaseImage::new(
+                "tools:1.0".to_string(),
+                "arm64".to_string(),
+                "5678".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_arg_value_precedence() {
+        let options = BuildOptions {
+            build_args: vec![("VARIANT".to_string(), "kiosk".to_string())],
+            ..Default::default()
+        };
+        let variables = vec![("BASE".to_string(), "bookworm".to_string())];
+
+        assert_eq!(
+            arg_value(&options, "VARIANT", Some("lite"), &variables, None),
+            "kiosk"
+        );
+        assert_eq!(
+            arg_value(&options, "TAG", Some("${BASE}-lite"), &variables, None),
+            "bookworm-lite"
+        );
+        assert_eq!(
+            arg_value(
+                &options,
+                "TAG",
+                None,
+                &variables,
+                Some("global".to_string())
+            ),
+            "global"
+        );
+        assert_eq!(arg_value(&options, "TAG", None, &variables, None), "");
+    }
+
+    #[test]
+    fn test_keep_working_image() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let working_image = tmp_dir.path().join("i_love_bakery.img");
+        fs::write(&working_image, b"partially built").unwrap();
+
+        let failed_dir = tmp_dir.path().join("failed");
+        let kept_path = keep_working_image(&working_image, &failed_dir).unwrap();
+
+        assert!(kept_path.starts_with(&failed_dir));
+        assert_eq!(fs::read(kept_path).unwrap(), b"partially built");
+    }
+
+    #[test]
+    fn test_is_intact_missing_file_needs_download() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let image = image("arm64", "raspios", "bookworm-20240704");
+
+        assert!(!is_intact(&image, &dir.path().join("missing.img"), false).unwrap());
+        assert!(!is_intact(&image, &dir.path().join("missing.img"), true).unwrap());
+    }
+
+    #[test]
+    fn test_is_intact_reuses_intact_file() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let path = dir.path().join("image.img");
+        fs::write(&path, b"image content").unwrap();
+
+        let mut image = image("arm64", "raspios", "bookworm-20240704");
+        image.sha256 = sha256::try_digest(path.as_path()).unwrap();
+
+        assert!(is_intact(&image, &path, false).unwrap());
+        assert!(is_intact(&image, &path, true).unwrap());
+    }
+
+    #[test]
+    fn test_is_intact_detects_corruption_only_when_verifying() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let path = dir.path().join("image.img");
+        fs::write(&path, b"truncated").unwrap();
+
+        let mut image = image("arm64", "raspios", "bookworm-20240704");
+        image.sha256 = sha256::digest("image content");
+
+        assert!(is_intact(&image, &path, false).unwrap());
+        assert!(!is_intact(&image, &path, true).unwrap());
+    }
+
+    #[test]
+    fn test_find_intact_drops_missing_image() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let mut image = image("arm64", "raspios", "bookworm-20240704");
+        image.sha256 = sha256::digest("image content");
+        let mut images = vec![image];
+
+        let found = find_intact(
+            &mut images,
+            dir.path(),
+            "arm64",
+            "raspios",
+            "bookworm-20240704",
+            false,
+        )
+        .unwrap();
+
+        assert!(found.is_none());
+        assert!(images.is_empty());
+    }
+
+    #[test]
+    fn test_find_intact_drops_corrupt_image_when_verifying() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let mut image = image("arm64", "raspios", "bookworm-20240704");
+        image.sha256 = sha256::digest("image content");
+        fs::write(image.path_in(dir.path()), b"truncated").unwrap();
+        let mut images = vec![image];
+
+        let found = find_intact(
+            &mut images,
+            dir.path(),
+            "arm64",
+            "raspios",
+            "bookworm-20240704",
+            true,
+        )
+        .unwrap();
+
+        assert!(found.is_none());
+        assert!(images.is_empty());
+    }
+
+    #[test]
+    fn test_find_intact_reuses_intact_image() {
+        let dir = tempdir::TempDir::new("baker").unwrap();
+        let mut intact = image("arm64", "raspios", "bookworm-20240704");
+        intact.sha256 = sha256::digest("image content");
+        fs::write(intact.path_in(dir.path()), b"image content").unwrap();
+        let mut images = vec![image("arm64", "raspios", "bullseye-20240101"), intact];
+
+        let found = find_intact(
+            &mut images,
+            dir.path(),
+            "arm64",
+            "raspios",
+            "bookworm-20240704",
+            true,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(found.tag(), "bookworm-20240704");
+        assert_eq!(images.len(), 2);
+    }
+}
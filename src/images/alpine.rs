@@ -0,0 +1,273 @@
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::Path,
+};
+
+use chrono::NaiveDateTime;
+use regex::Regex;
+use url::Url;
+
+use crate::error::Error;
+use crate::images::download::{parse_links, DownloadableBakerImage};
+use crate::images::retry;
+use crate::images::sources::ImageSource;
+use crate::images::BakerImage;
+
+const ALPINE_URL: &str = "https://dl-cdn.alpinelinux.org/alpine";
+
+/// How many of the latest releases get indexed, about the ones still
+/// supported.
+const INDEXED_RELEASES: usize = 4;
+
+const BOOT_SIZE: u64 = 256 * 1024 * 1024;
+const ROOT_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Alpine architectures with Raspberry Pi releases, and their platform.
+const ARCHITECTURES: &[(&str, &str)] = &[("aarch64", "arm64"), ("armv7", "armhf")];
+
+fn parse_version(version: &str) -> Option<Vec<u32>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// The latest releases, named by version like `3.20`.
+fn list_alpine_releases() -> Result<Vec<String>, Error> {
+    let body = retry::get(format!("{}/", ALPINE_URL))?.text()?;
+
+    let mut releases: Vec<(Vec<u32>, String)> = parse_links(&body)
+        .into_iter()
+        .filter_map(|link| {
+            let release = link.strip_prefix('v')?.to_string();
+            Some((parse_version(&release).filter(|v| v.len() == 2)?, release))
+        })
+        .collect();
+    releases.sort();
+
+    Ok(releases
+        .into_iter()
+        .rev()
+        .take(INDEXED_RELEASES)
+        .map(|(_, release)| release)
+        .collect())
+}
+
+/// Newest Raspberry Pi tarball of a listing of releases.
+fn newest_rpi_tarball<'a>(files: &'a [String], architecture: &str) -> Option<&'a String> {
+    let tarball = Regex::new(&format!(
+        r"^alpine-rpi-(\d+(?:\.\d+)*)-{}\.tar\.gz$",
+        architecture
+    ))
+    .ok()?;
+
+    files
+        .iter()
+        .filter_map(|file| Some((parse_version(&tarball.captures(file)?[1])?, file)))
+        .max()
+        .map(|(_, file)| file)
+}
+
+/// Images of a release, as `alpine:3.20`. They point to the Raspberry Pi
+/// tarball, turned into a disk image when downloaded.
+fn get_alpine_images(release: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+    let mut downloadable_images = Vec::new();
+
+    for (architecture, platform) in ARCHITECTURES {
+        let releases_url = format!("{}/v{}/releases/{}", ALPINE_URL, release, architecture);
+        let body = match retry::get(format!("{}/", releases_url))?.error_for_status() {
+            Ok(response) => response.text()?,
+            Err(_) => continue,
+        };
+        let files = parse_links(&body);
+        let Some(tarball) = newest_rpi_tarball(&files, architecture) else {
+            continue;
+        };
+
+        let sha256 = retry::get(format!("{}/{}.sha256", releases_url, tarball))?
+            .error_for_status()?
+            .text()?
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| Error::Other("No sha256 found".to_string()))?
+            .to_lowercase();
+
+        downloadable_images.push(DownloadableBakerImage::new(
+            format!("{}/{}", releases_url, tarball),
+            BakerImage {
+                platform: platform.to_string(),
+                name: "alpine".to_string(),
+                tag: release.to_string(),
+                sha256,
+                history: Vec::new(),
+                labels: Default::default(),
+            },
+        ));
+    }
+
+    if downloadable_images.is_empty() {
+        return Err(Error::Other("No Raspberry Pi release found".to_string()));
+    }
+
+    Ok(downloadable_images)
+}
+
+/// The minimal root filesystem released along with a Raspberry Pi tarball.
+fn minirootfs_url(rpi_url: &Url) -> Result<Url, Error> {
+    let filename = rpi_url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .ok_or_else(|| Error::Other("Invalid url".to_string()))?;
+    let minirootfs = filename
+        .strip_prefix("alpine-rpi-")
+        .ok_or_else(|| Error::Other("Not an Alpine Raspberry Pi tarball".to_string()))?;
+
+    Ok(rpi_url.join(&format!("alpine-minirootfs-{}", minirootfs))?)
+}
+
+fn unpack<R: Read>(reader: R, dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(reader));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.unpack(dir)?;
+
+    Ok(())
+}
+
+/// Boots from the root partition rather than running from memory, which is
+/// what Alpine does from the tarball alone.
+fn configure_boot(boot: &Path, root: &Path) -> Result<(), Error> {
+    let cmdline_path = boot.join("cmdline.txt");
+    let cmdline = match fs::read_to_string(&cmdline_path) {
+        Ok(cmdline) => cmdline,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    fs::write(
+        &cmdline_path,
+        format!(
+            "{} root=LABEL=rootfs rootfstype=ext4 rw\n",
+            cmdline.trim_end()
+        )
+        .trim_start(),
+    )?;
+
+    fs::create_dir_all(root.join("boot"))?;
+    fs::write(
+        root.join("etc/fstab"),
+        "LABEL=rootfs / ext4 defaults,noatime 0 1\n\
+         LABEL=bootfs /boot vfat defaults 0 2\n",
+    )?;
+
+    Ok(())
+}
+
+/// Turns the Raspberry Pi tarball at `url` into a disk image: a `bootfs` FAT
+/// partition holding the tarball and a `rootfs` ext4 one holding the minimal
+/// root filesystem. The kernel modules are not part of it, installing the
+/// `linux-rpi` package adds them.
+fn create_image(url: &Url, image_path: &Path) -> Result<(), Error> {
+    // Keeping the ownership of the root filesystem files needs root
+    crate::privileges::require_root("pull Alpine images")?;
+    let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+
+    let tmp_dir = tempdir::TempDir::new("baker-alpine")?;
+    let boot = tmp_dir.path().join("boot");
+    let root = tmp_dir.path().join("root");
+    unpack(
+        retry::send(client.get(url.clone()))?.error_for_status()?,
+        &boot,
+    )?;
+    unpack(
+        retry::send(client.get(minirootfs_url(url)?))?.error_for_status()?,
+        &root,
+    )?;
+    configure_boot(&boot, &root)?;
+
+    fs::create_dir_all(
+        image_path
+            .parent()
+            .ok_or_else(|| Error::Other("Invalid image path".to_string()))?,
+    )?;
+    crate::partitions::create_image(image_path)?;
+    crate::partitions::add_partition_with(image_path, "bootfs", BOOT_SIZE, "vfat", Some(&boot))?;
+    crate::partitions::add_partition_with(image_path, "rootfs", ROOT_SIZE, "ext4", Some(&root))?;
+    File::open(image_path)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Alpine Linux for the Raspberry Pi, from dl-cdn.alpinelinux.org. Its
+/// repositories are the latest releases.
+pub struct AlpineSource;
+
+impl ImageSource for AlpineSource {
+    fn name(&self) -> &'static str {
+        "alpine"
+    }
+    fn list_repositories(&self, _date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+        list_alpine_releases()
+    }
+    fn list_images(&self, repository: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+        get_alpine_images(repository)
+    }
+    fn download(&self, image: &DownloadableBakerImage, image_path: &Path) -> Result<(), Error> {
+        create_image(&self.resolve_download(image)?, image_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newest_rpi_tarball() {
+        let files = vec![
+            "alpine-minirootfs-3.20.3-aarch64.tar.gz".to_string(),
+            "alpine-rpi-3.20.2-aarch64.tar.gz".to_string(),
+            "alpine-rpi-3.20.10-aarch64.tar.gz".to_string(),
+            "alpine-rpi-3.20.10-aarch64.tar.gz.sha256".to_string(),
+            "alpine-rpi-3.20.3-armv7.tar.gz".to_string(),
+        ];
+
+        assert_eq!(
+            newest_rpi_tarball(&files, "aarch64").map(String::as_str),
+            Some("alpine-rpi-3.20.10-aarch64.tar.gz")
+        );
+        assert_eq!(
+            newest_rpi_tarball(&files, "armv7").map(String::as_str),
+            Some("alpine-rpi-3.20.3-armv7.tar.gz")
+        );
+        assert_eq!(newest_rpi_tarball(&files, "x86_64"), None);
+    }
+
+    #[test]
+    fn test_minirootfs_url() {
+        let url =
+            Url::parse("https://dl-cdn.alpinelinux.org/alpine/v3.20/releases/aarch64/alpine-rpi-3.20.3-aarch64.tar.gz")
+                .unwrap();
+
+        assert_eq!(
+            minirootfs_url(&url).unwrap().as_str(),
+            "https://dl-cdn.alpinelinux.org/alpine/v3.20/releases/aarch64/alpine-minirootfs-3.20.3-aarch64.tar.gz"
+        );
+    }
+
+    #[test]
+    fn test_configure_boot() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let (boot, root) = (tmp_dir.path().join("boot"), tmp_dir.path().join("root"));
+        fs::create_dir_all(&boot).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(boot.join("cmdline.txt"), "modules=loop,squashfs quiet\n").unwrap();
+
+        configure_boot(&boot, &root).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(boot.join("cmdline.txt")).unwrap(),
+            "modules=loop,squashfs quiet root=LABEL=rootfs rootfstype=ext4 rw\n"
+        );
+        assert!(fs::read_to_string(root.join("etc/fstab"))
+            .unwrap()
+            .contains("LABEL=bootfs /boot vfat"));
+    }
+}
@@ -0,0 +1,157 @@
+use std::{fs::File, io, path::Path};
+
+use zip::write::SimpleFileOptions;
+
+use crate::error::Error;
+use crate::images::download::{decompress_xz, image_entry_index};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ArchiveFormat {
+    /// Raw disk image
+    Img,
+    /// xz compressed disk image
+    Xz,
+    /// Zip archive holding the disk image
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guesses the format from the file extension, raw images being the default.
+    pub fn from_path(path: &Path) -> ArchiveFormat {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("xz") => ArchiveFormat::Xz,
+            Some("zip") => ArchiveFormat::Zip,
+            _ => ArchiveFormat::Img,
+        }
+    }
+}
+
+/// Writes the raw image at `image_path` to `output` in the given format.
+pub fn compress(image_path: &Path, output: &Path, format: ArchiveFormat) -> Result<(), Error> {
+    let mut image = File::open(image_path)?;
+    let file = File::create(output)?;
+
+    match format {
+        ArchiveFormat::Img => {
+            let mut file = file;
+            io::copy(&mut image, &mut file)?;
+            file.sync_data()?;
+        }
+        ArchiveFormat::Xz => {
+            let mut encoder = xz2::write::XzEncoder::new(file, 6);
+            io::copy(&mut image, &mut encoder)?;
+            encoder.finish()?.sync_data()?;
+        }
+        ArchiveFormat::Zip => {
+            let name = output
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| Error::Other("Invalid output path".to_string()))?;
+            let name = if name.ends_with(".img") {
+                name.to_string()
+            } else {
+                format!("{}.img", name)
+            };
+
+            let mut writer = zip::ZipWriter::new(file);
+            writer.start_file(
+                name,
+                SimpleFileOptions::default()
+                    .compression_method(zip::CompressionMethod::Deflated)
+                    .large_file(true),
+            )?;
+            io::copy(&mut image, &mut writer)?;
+            writer.finish()?.sync_data()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the raw image held by `source` to `output`, the reverse of `compress`.
+pub fn extract(source: &Path, output: &Path, format: ArchiveFormat) -> Result<(), Error> {
+    let mut file = File::create(output)?;
+
+    match format {
+        ArchiveFormat::Img => {
+            io::copy(&mut File::open(source)?, &mut file)?;
+        }
+        ArchiveFormat::Xz => {
+            decompress_xz(File::open(source)?, &mut file)?;
+        }
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(File::open(source)?)?;
+            let index = image_entry_index(&mut archive)?;
+            io::copy(&mut archive.by_index(index)?, &mut file)?;
+        }
+    }
+
+    file.sync_data()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, io::Read};
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("a.img.xz")),
+            ArchiveFormat::Xz
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("a.zip")),
+            ArchiveFormat::Zip
+        );
+        assert_eq!(
+            ArchiveFormat::from_path(Path::new("a.img")),
+            ArchiveFormat::Img
+        );
+    }
+
+    #[test]
+    fn test_compress() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let image_path = tmp_dir.path().join("image.img");
+        fs::write(&image_path, b"raw image").unwrap();
+
+        let xz_path = tmp_dir.path().join("out.img.xz");
+        compress(&image_path, &xz_path, ArchiveFormat::Xz).unwrap();
+        let mut decompressed = String::new();
+        xz2::read::XzDecoder::new(File::open(&xz_path).unwrap())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, "raw image");
+
+        let zip_path = tmp_dir.path().join("out.zip");
+        compress(&image_path, &zip_path, ArchiveFormat::Zip).unwrap();
+        let mut archive = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        let mut entry = archive.by_name("out.img").unwrap();
+        let mut extracted = String::new();
+        entry.read_to_string(&mut extracted).unwrap();
+        assert_eq!(extracted, "raw image");
+    }
+
+    #[test]
+    fn test_extract_reverses_compress() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let image_path = tmp_dir.path().join("image.img");
+        fs::write(&image_path, b"raw image").unwrap();
+
+        for (name, format) in [
+            ("out.img", ArchiveFormat::Img),
+            ("out.img.xz", ArchiveFormat::Xz),
+            ("out.zip", ArchiveFormat::Zip),
+        ] {
+            let archive_path = tmp_dir.path().join(name);
+            let extracted_path = tmp_dir.path().join("extracted.img");
+            compress(&image_path, &archive_path, format).unwrap();
+            extract(&archive_path, &extracted_path, format).unwrap();
+
+            assert_eq!(fs::read(&extracted_path).unwrap(), b"raw image");
+        }
+    }
+}
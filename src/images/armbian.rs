@@ -0,0 +1,161 @@
+use chrono::NaiveDateTime;
+use regex::Regex;
+use url::Url;
+
+use crate::error::Error;
+use crate::images::download::{parse_links, DownloadableBakerImage};
+use crate::images::retry;
+use crate::images::sources::ImageSource;
+use crate::images::BakerImage;
+
+const ARMBIAN_REDIRECTOR_URL: &str = "https://dl.armbian.com";
+
+/// Boards indexed when `BAKER_ARMBIAN_BOARDS` does not list others.
+const DEFAULT_BOARDS: &str = "rpi4b";
+
+fn parse_boards(boards: &str) -> Vec<(String, String)> {
+    boards
+        .split(',')
+        .map(str::trim)
+        .filter(|board| !board.is_empty())
+        .map(|board| match board.split_once(':') {
+            Some((board, platform)) => (board.to_string(), platform.to_string()),
+            None => (board.to_string(), "arm64".to_string()),
+        })
+        .collect()
+}
+
+/// Redirect of the listing of a board, like `Bookworm_current_minimal`,
+/// parsed as the tag it is pulled as. The kernel branch only shows in the
+/// tag when it is not the usual `current` one.
+fn parse_redirect(redirect: &str) -> Option<String> {
+    let captures = Regex::new(r"^([A-Z][a-z]+)_(current|edge|legacy|vendor)_(\w+)$")
+        .ok()?
+        .captures(redirect)?;
+
+    let release = captures[1].to_lowercase();
+    Some(match &captures[2] {
+        "current" => format!("{}-{}", release, &captures[3]),
+        branch => format!("{}-{}-{}", release, branch, &captures[3]),
+    })
+}
+
+/// Images of a board, as `armbian-rpi4b:bookworm-minimal`. They point to
+/// redirects, the digest of the archive one leads to being given by its
+/// `.sha` twin.
+fn get_armbian_images(board: &str, platform: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+    let client = reqwest::blocking::Client::new();
+    let board_url = format!("{}/{}", ARMBIAN_REDIRECTOR_URL, board);
+    let body = retry::send(client.get(format!("{}/", board_url)))?.text()?;
+
+    let mut downloadable_images = Vec::new();
+    for redirect in parse_links(&body) {
+        let Some(tag) = parse_redirect(&redirect) else {
+            continue;
+        };
+        let redirect_url = format!("{}/{}", board_url, redirect);
+
+        let sha256 = retry::send(client.get(format!("{}.sha", redirect_url)))?
+            .error_for_status()?
+            .text()?
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| Error::Other("No sha256 found".to_string()))?
+            .to_lowercase();
+
+        downloadable_images.push(DownloadableBakerImage::new(
+            redirect_url,
+            BakerImage {
+                platform: platform.to_string(),
+                name: format!("armbian-{}", board),
+                tag,
+                sha256,
+                history: Vec::new(),
+                labels: Default::default(),
+            },
+        ));
+    }
+
+    if downloadable_images.is_empty() {
+        return Err(Error::Other(format!("No image found for board {}", board)));
+    }
+
+    Ok(downloadable_images)
+}
+
+/// Armbian images, from its redirector. Its repositories are the boards.
+pub struct ArmbianSource {
+    boards: Vec<(String, String)>,
+}
+
+impl ArmbianSource {
+    /// Listing every board Armbian supports would take hundreds of requests,
+    /// so they are picked with `BAKER_ARMBIAN_BOARDS`, like
+    /// `rpi4b,orangepizero:armhf`, boards being arm64 unless told otherwise.
+    pub fn from_env() -> ArmbianSource {
+        ArmbianSource {
+            boards: parse_boards(
+                &std::env::var("BAKER_ARMBIAN_BOARDS")
+                    .unwrap_or_else(|_| DEFAULT_BOARDS.to_string()),
+            ),
+        }
+    }
+}
+
+impl ImageSource for ArmbianSource {
+    fn name(&self) -> &'static str {
+        "armbian"
+    }
+    /// Redirects always point to the latest build of a board, so every
+    /// board is listed again.
+    fn list_repositories(&self, _date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+        Ok(self.boards.iter().map(|(board, _)| board.clone()).collect())
+    }
+    fn list_images(&self, repository: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+        let (board, platform) = self
+            .boards
+            .iter()
+            .find(|(board, _)| board == repository)
+            .ok_or_else(|| Error::Other(format!("Unknown Armbian board {}", repository)))?;
+        get_armbian_images(board, platform)
+    }
+    /// Follows the redirect, whose target names the archive format.
+    fn resolve_download(&self, image: &DownloadableBakerImage) -> Result<Url, Error> {
+        Ok(
+            retry::send(reqwest::blocking::Client::new().head(image.url()))?
+                .error_for_status()?
+                .url()
+                .clone(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_boards() {
+        assert_eq!(
+            parse_boards("rpi4b, orangepizero:armhf,"),
+            vec![
+                ("rpi4b".to_string(), "arm64".to_string()),
+                ("orangepizero".to_string(), "armhf".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_redirect() {
+        assert_eq!(
+            parse_redirect("Bookworm_current_minimal"),
+            Some("bookworm-minimal".to_string())
+        );
+        assert_eq!(
+            parse_redirect("Noble_edge_gnome"),
+            Some("noble-edge-gnome".to_string())
+        );
+        assert_eq!(parse_redirect("Bookworm_current_minimal.sha"), None);
+        assert_eq!(parse_redirect("archive"), None);
+    }
+}
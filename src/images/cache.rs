@@ -0,0 +1,262 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use crate::error::Error;
+
+/// Size the snapshots are kept under when `BAKER_CACHE_MAX_SIZE` does not
+/// give another one.
+const DEFAULT_MAX_SIZE: u64 = 20 * 1024 * 1024 * 1024;
+
+fn get_cache_dir() -> Result<PathBuf, Error> {
+    Ok(crate::get_app_dir()?.join("build-cache"))
+}
+
+/// Snapshots the stages building side by side are restoring from, which
+/// eviction leaves alone. A snapshot is listed once per stage using it.
+static IN_USE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+fn lock_in_use() -> MutexGuard<'static, Vec<PathBuf>> {
+    IN_USE.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// A snapshot found in the cache, kept from eviction as long as it is held.
+pub struct Snapshot {
+    path: PathBuf,
+}
+
+impl Snapshot {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut in_use = lock_in_use();
+        if let Some(index) = in_use.iter().position(|path| *path == self.path) {
+            in_use.remove(index);
+        }
+    }
+}
+
+fn max_size() -> Result<u64, Error> {
+    match std::env::var("BAKER_CACHE_MAX_SIZE") {
+        Ok(size) => crate::size::parse_size(&size),
+        Err(_) => Ok(DEFAULT_MAX_SIZE),
+    }
+}
+
+/// Key of the image obtained by applying what `parts` describe on top of the
+/// image keyed `parent`.
+pub fn key(parent: &str, parts: &[String]) -> String {
+    let mut input = parent.to_string();
+    for part in parts {
+        input.push('\0');
+        input.push_str(part);
+    }
+    sha256::digest(input)
+}
+
+/// The snapshot stored under `key`, if any. It is marked as used, so that
+/// it is the last to be evicted, and is not evicted while it is held.
+pub fn find(key: &str) -> Result<Option<Snapshot>, Error> {
+    let path = get_cache_dir()?.join(format!("{}.img", key));
+    let mut in_use = lock_in_use();
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    let _ = fs::File::options()
+        .append(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    in_use.push(path.clone());
+    Ok(Some(Snapshot { path }))
+}
+
+/// Snapshots of `dir`, least recently used first, with their sizes.
+fn snapshots(dir: &Path) -> Result<Vec<(PathBuf, u64)>, Error> {
+    let mut snapshots = Vec::new();
+    for path in files_in(dir)? {
+        if path.extension().is_some_and(|extension| extension == "img") {
+            let metadata = fs::metadata(&path)?;
+            snapshots.push((metadata.modified()?, path, metadata.len()));
+        }
+    }
+    snapshots.sort();
+
+    Ok(snapshots
+        .into_iter()
+        .map(|(_, path, size)| (path, size))
+        .collect())
+}
+
+/// Deletes the least recently used snapshots of `dir` until the others fit
+/// in `max_size`, returning the deleted ones. The `kept` ones are never
+/// deleted, even when the others do not fit.
+fn evict_from(dir: &Path, max_size: u64, kept: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let snapshots = snapshots(dir)?;
+    let mut total: u64 = snapshots.iter().map(|(_, size)| size).sum();
+
+    let mut evicted = Vec::new();
+    for (path, size) in snapshots {
+        if total <= max_size {
+            break;
+        }
+        if kept.contains(&path) {
+            continue;
+        }
+        fs::remove_file(&path)?;
+        total -= size;
+        evicted.push(path);
+    }
+
+    Ok(evicted)
+}
+
+fn files_in(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+
+    Ok(files)
+}
+
+/// Every file of the cache, the snapshots as well as the copies interrupted
+/// before becoming one.
+pub fn files() -> Result<Vec<PathBuf>, Error> {
+    files_in(&get_cache_dir()?)
+}
+
+/// Stores a snapshot of `image` under `key`, copying it to a temporary file
+/// first so that an interrupted copy is never taken for a snapshot. An image
+/// bigger than the whole cache is not stored.
+pub fn store(key: &str, image: &Path) -> Result<(), Error> {
+    store_in(&get_cache_dir()?, max_size()?, key, image)
+}
+
+fn store_in(cache_dir: &Path, max_size: u64, key: &str, image: &Path) -> Result<(), Error> {
+    if fs::metadata(image)?.len() > max_size {
+        return Ok(());
+    }
+
+    fs::create_dir_all(cache_dir)?;
+
+    let tmp_path = cache_dir.join(format!("{}.tmp", key));
+    let cleanup_path = tmp_path.clone();
+    let _tmp_registration = crate::cleanup::register(move || {
+        let _ = fs::remove_file(cleanup_path);
+    });
+
+    if let Err(err) = crate::progress::copy_file(image, &tmp_path, "Caching a snapshot") {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err.into());
+    }
+    let path = cache_dir.join(format!("{}.img", key));
+    fs::rename(&tmp_path, &path)?;
+
+    // Neither the snapshot just stored nor the ones being restored go
+    let in_use = lock_in_use();
+    let mut kept = in_use.clone();
+    kept.push(path);
+    evict_from(cache_dir, max_size, &kept)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_depends_on_parent_and_parts() {
+        let parts = vec!["RUN apt-get update".to_string()];
+
+        assert_eq!(key("base", &parts), key("base", &parts));
+        assert_ne!(key("base", &parts), key("other", &parts));
+        assert_ne!(key("base", &parts), key("base", &[]));
+        assert_ne!(
+            key("base", &["a".to_string(), "b".to_string()]),
+            key("base", &["ab".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let now = SystemTime::now();
+        for (index, name) in ["old.img", "used.img", "new.img"].iter().enumerate() {
+            let path = tmp_dir.path().join(name);
+            fs::write(&path, [0; 10]).unwrap();
+            fs::File::options()
+                .append(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - std::time::Duration::from_secs(60 * (3 - index as u64)))
+                .unwrap();
+        }
+        fs::write(tmp_dir.path().join("copy.tmp"), [0; 100]).unwrap();
+
+        assert!(evict_from(tmp_dir.path(), 30, &[]).unwrap().is_empty());
+        assert_eq!(
+            evict_from(tmp_dir.path(), 25, &[]).unwrap(),
+            vec![tmp_dir.path().join("old.img")]
+        );
+        assert_eq!(
+            files_in(tmp_dir.path()).unwrap(),
+            vec![
+                tmp_dir.path().join("copy.tmp"),
+                tmp_dir.path().join("new.img"),
+                tmp_dir.path().join("used.img"),
+            ]
+        );
+
+        // Kept snapshots stay even when the cache is still too big
+        let kept = vec![tmp_dir.path().join("used.img")];
+        assert_eq!(
+            evict_from(tmp_dir.path(), 5, &kept).unwrap(),
+            vec![tmp_dir.path().join("new.img")]
+        );
+        assert_eq!(
+            files_in(tmp_dir.path()).unwrap(),
+            vec![
+                tmp_dir.path().join("copy.tmp"),
+                tmp_dir.path().join("used.img"),
+            ]
+        );
+        assert!(files_in(&tmp_dir.path().join("missing"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_store_keeps_the_new_snapshot() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let cache_dir = tmp_dir.path().join("cache");
+        let image = tmp_dir.path().join("stage.img");
+        fs::write(&image, [0; 10]).unwrap();
+
+        store_in(&cache_dir, 5, "big", &image).unwrap();
+        assert!(files_in(&cache_dir).unwrap().is_empty());
+
+        store_in(&cache_dir, 15, "first", &image).unwrap();
+        store_in(&cache_dir, 15, "second", &image).unwrap();
+        assert_eq!(
+            files_in(&cache_dir).unwrap(),
+            vec![cache_dir.join("second.img")]
+        );
+    }
+}
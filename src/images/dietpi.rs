@@ -0,0 +1,127 @@
+use chrono::NaiveDateTime;
+use regex::Regex;
+
+use crate::error::Error;
+use crate::images::download::{parse_links, DownloadableBakerImage};
+use crate::images::retry;
+use crate::images::sources::ImageSource;
+use crate::images::BakerImage;
+
+const DIETPI_IMAGES_URL: &str = "https://dietpi.com/downloads/images";
+
+/// Image archive of the listing, parsed as the name and platform it is
+/// pulled as along with its Debian release. The image for the Pi 2, 3 and 4
+/// is plain `dietpi`, the ones for other boards are named after them.
+fn parse_image_filename(filename: &str) -> Option<(String, String, &'static str)> {
+    let captures = Regex::new(r"^DietPi_(RPi\d*)-(ARMv[678])-(\w+)\.(?:img\.xz|7z)$")
+        .ok()?
+        .captures(filename)?;
+
+    let name = match &captures[1] {
+        "RPi" | "RPi234" => "dietpi".to_string(),
+        board => format!("dietpi-{}", board.to_lowercase()),
+    };
+    let platform = match &captures[2] {
+        "ARMv8" => "arm64",
+        _ => "armhf",
+    };
+    Some((name, captures[3].to_lowercase(), platform))
+}
+
+/// Raspberry Pi images of the DietPi listing, as `dietpi:bookworm`. An
+/// `.img.xz` archive is preferred to the `.7z` of the same image, and either
+/// needs its `.sha256` file to be indexed.
+fn get_dietpi_images() -> Result<Vec<DownloadableBakerImage>, Error> {
+    let body = retry::get(format!("{}/", DIETPI_IMAGES_URL))?.text()?;
+    let files = parse_links(&body);
+
+    let mut downloadable_images: Vec<DownloadableBakerImage> = Vec::new();
+    for filename in files
+        .iter()
+        .filter(|file| file.ends_with(".img.xz"))
+        .chain(files.iter().filter(|file| file.ends_with(".7z")))
+    {
+        let Some((name, tag, platform)) = parse_image_filename(filename) else {
+            continue;
+        };
+        let already_found = downloadable_images.iter().any(|downloadable_image| {
+            let image = downloadable_image.image();
+            (image.name(), image.tag(), image.platform()) == (name.as_str(), tag.as_str(), platform)
+        });
+        let sha256_file = format!("{}.sha256", filename);
+        if already_found || !files.contains(&sha256_file) {
+            continue;
+        }
+
+        let sha256 = retry::get(format!("{}/{}", DIETPI_IMAGES_URL, sha256_file))?
+            .text()?
+            .split_whitespace()
+            .next()
+            .ok_or_else(|| Error::Other("No sha256 found".to_string()))?
+            .to_lowercase();
+
+        downloadable_images.push(DownloadableBakerImage::new(
+            format!("{}/{}", DIETPI_IMAGES_URL, filename),
+            BakerImage {
+                platform: platform.to_string(),
+                name,
+                tag,
+                sha256,
+                history: Vec::new(),
+                labels: Default::default(),
+            },
+        ));
+    }
+
+    if downloadable_images.is_empty() {
+        return Err(Error::Other("No Raspberry Pi image found".to_string()));
+    }
+
+    Ok(downloadable_images)
+}
+
+/// DietPi images for the Raspberry Pi, from dietpi.com.
+pub struct DietPiSource;
+
+impl ImageSource for DietPiSource {
+    fn name(&self) -> &'static str {
+        "dietpi"
+    }
+    /// DietPi publishes a few images under fixed names, which are listed
+    /// again every time to pick up rebuilds.
+    fn list_repositories(&self, _date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+        Ok(vec!["images".to_string()])
+    }
+    fn list_images(&self, _repository: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+        get_dietpi_images()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_filename() {
+        assert_eq!(
+            parse_image_filename("DietPi_RPi234-ARMv8-Bookworm.img.xz"),
+            Some(("dietpi".to_string(), "bookworm".to_string(), "arm64"))
+        );
+        assert_eq!(
+            parse_image_filename("DietPi_RPi5-ARMv8-Bookworm.7z"),
+            Some(("dietpi-rpi5".to_string(), "bookworm".to_string(), "arm64"))
+        );
+        assert_eq!(
+            parse_image_filename("DietPi_RPi1-ARMv6-Bookworm.img.xz"),
+            Some(("dietpi-rpi1".to_string(), "bookworm".to_string(), "armhf"))
+        );
+        assert_eq!(
+            parse_image_filename("DietPi_RPi234-ARMv8-Bookworm.img.xz.sha256"),
+            None
+        );
+        assert_eq!(
+            parse_image_filename("DietPi_NativePC-BIOS-x86_64-Bookworm.img.xz"),
+            None
+        );
+    }
+}
@@ -1,17 +1,24 @@
-use std::env;
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::path::PathBuf;
-
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::error::Error;
+use crate::get_app_dir;
+use crate::images::registries::local_path;
+use crate::images::retry;
+use crate::images::sources::find_source;
 use crate::images::BakerImage;
+use crate::progress;
 use chrono::NaiveDateTime;
-use regex::Regex;
 use scraper::{ElementRef, Html};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use url::Url;
 
-struct ApacheFile {
+pub(super) struct ApacheFile {
     name: String,
     last_modified: NaiveDateTime,
     is_directory: bool,
@@ -29,7 +36,7 @@ impl ApacheFile {
     }
 }
 
-fn handle_element(element: ElementRef) -> Result<Option<ApacheFile>, Box<dyn std::error::Error>> {
+fn handle_element(element: ElementRef) -> Result<Option<ApacheFile>, Error> {
     let mut children = element.children().filter_map(ElementRef::wrap);
 
     if element.children().count() != 5 {
@@ -38,13 +45,13 @@ fn handle_element(element: ElementRef) -> Result<Option<ApacheFile>, Box<dyn std
 
     let filetype = children
         .next()
-        .ok_or("Missing file element")?
+        .ok_or_else(|| Error::Other("Missing file element".to_string()))?
         .select(&scraper::Selector::parse("img").unwrap())
         .next()
-        .ok_or("Missing img element")?
+        .ok_or_else(|| Error::Other("Missing img element".to_string()))?
         .value()
         .attr("alt")
-        .ok_or("Missing alt attribute")?;
+        .ok_or_else(|| Error::Other("Missing alt attribute".to_string()))?;
 
     let is_directory = match filetype {
         "[DIR]" => true,
@@ -54,10 +61,10 @@ fn handle_element(element: ElementRef) -> Result<Option<ApacheFile>, Box<dyn std
 
     let name = children
         .next()
-        .ok_or("Missing name element")?
+        .ok_or_else(|| Error::Other("Missing name element".to_string()))?
         .select(&scraper::Selector::parse("a").unwrap())
         .next()
-        .ok_or("Missing href element")?
+        .ok_or_else(|| Error::Other("Missing href element".to_string()))?
         .inner_html()
         .trim_end_matches("/")
         .to_string();
@@ -65,7 +72,7 @@ fn handle_element(element: ElementRef) -> Result<Option<ApacheFile>, Box<dyn std
     let last_modified = NaiveDateTime::parse_from_str(
         children
             .next()
-            .ok_or("Missing date element")?
+            .ok_or_else(|| Error::Other("Missing date element".to_string()))?
             .inner_html()
             .trim(),
         "%Y-%m-%d %H:%M",
@@ -78,48 +85,55 @@ fn handle_element(element: ElementRef) -> Result<Option<ApacheFile>, Box<dyn std
     }))
 }
 
-fn parse_apache_directory_listing(
-    body: &str,
-) -> Result<Vec<ApacheFile>, Box<dyn std::error::Error>> {
+pub(super) fn parse_apache_directory_listing(body: &str) -> Result<Vec<ApacheFile>, Error> {
     Html::parse_document(body)
         .select(&scraper::Selector::parse("tr").unwrap())
         .filter_map(|element| handle_element(element).transpose())
         .collect()
 }
 
-fn list_raspios_image_names(
-    registry: &str,
-) -> Result<Vec<(String, NaiveDateTime)>, Box<dyn std::error::Error>> {
-    let body = reqwest::blocking::get(&format!(
-        "https://downloads.raspberrypi.org/{}/images/",
-        registry
-    ))?
-    .text()?;
-
-    Ok(parse_apache_directory_listing(&body)?
-        .into_iter()
-        .filter(|file| file.is_directory())
-        .map(|file| (file.name().to_string(), file.last_modified()))
-        .collect())
+/// Targets of the links of a directory listing of any server, without the
+/// parent directory and sort links.
+pub(super) fn parse_links(body: &str) -> Vec<String> {
+    Html::parse_document(body)
+        .select(&scraper::Selector::parse("a[href]").unwrap())
+        .filter_map(|element| element.value().attr("href"))
+        .filter(|href| !href.starts_with(['?', '/', '.']) && !href.contains("://"))
+        .map(|href| href.trim_end_matches('/').to_string())
+        .collect()
 }
 
-fn list_raspios_repositories() -> Result<Vec<(String, NaiveDateTime)>, Box<dyn std::error::Error>> {
-    let body = reqwest::blocking::get("https://downloads.raspberrypi.org/")?.text()?;
-
-    Ok(parse_apache_directory_listing(&body)?
-        .into_iter()
-        .filter(|file| file.is_directory() && file.name().starts_with("raspios"))
-        .map(|file| (file.name().to_string(), file.last_modified()))
-        .collect())
+/// Indexes written before there were other sources only held these images.
+fn default_source() -> String {
+    "raspios".to_string()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DownloadableBakerImage {
+    /// Name of the `ImageSource` the image comes from
+    #[serde(default = "default_source")]
+    source: String,
     url: String,
     image: BakerImage,
 }
 
 impl DownloadableBakerImage {
+    pub(super) fn new(url: String, image: BakerImage) -> DownloadableBakerImage {
+        DownloadableBakerImage {
+            source: default_source(),
+            url,
+            image,
+        }
+    }
+    pub(super) fn with_source(self, source: &str) -> DownloadableBakerImage {
+        DownloadableBakerImage {
+            source: source.to_string(),
+            ..self
+        }
+    }
+    pub fn source(&self) -> &str {
+        &self.source
+    }
     pub fn url(&self) -> &str {
         &self.url
     }
@@ -128,135 +142,467 @@ impl DownloadableBakerImage {
     }
 }
 
-fn get_raspios_images(
-    registry: &str,
-    image_name: &str,
-) -> Result<DownloadableBakerImage, Box<dyn std::error::Error>> {
-    let body = reqwest::blocking::get(&format!(
-        "https://downloads.raspberrypi.org/{}/images/{}/",
-        registry, image_name
-    ))?
-    .text()?;
-
-    let files: Vec<String> = parse_apache_directory_listing(&body)?
+pub(super) fn modified_since(
+    files: Vec<(String, NaiveDateTime)>,
+    date: Option<NaiveDateTime>,
+) -> Vec<String> {
+    files
         .into_iter()
-        .filter(|file| !file.is_directory())
-        .map(|file| file.name().to_string())
-        .collect();
+        .filter(|(_, last_modified)| date.map_or(true, |date| date <= *last_modified))
+        .map(|(name, _)| name)
+        .collect()
+}
 
-    let filename = files
-        .iter()
-        .find(|file| file.ends_with(".zip") || file.ends_with(".xz"))
-        .ok_or("No image url found")?;
+/// Reads a `SHA256SUMS` file, mapping each file name to its digest. Names
+/// may be marked as binary with a leading `*`.
+pub(super) fn parse_sha256sums(body: &str) -> BTreeMap<String, String> {
+    body.lines()
+        .filter_map(|line| {
+            let (sha256, filename) = line.trim().split_once(char::is_whitespace)?;
+            let filename = filename.trim_start();
+            let filename = filename.strip_prefix('*').unwrap_or(filename);
+            Some((filename.to_string(), sha256.to_lowercase()))
+        })
+        .collect()
+}
 
-    let sha256_url = files
-        .iter()
-        .find(|file| file.ends_with(".sha256"))
-        .ok_or("No sha256 url found")?;
-
-    let sha256 = reqwest::blocking::get(&format!(
-        "https://downloads.raspberrypi.org/{}/images/{}/{}",
-        registry, image_name, sha256_url
-    ))?
-    .text()?
-    .split_whitespace()
-    .next()
-    .ok_or("No sha256 found")?
-    .to_string();
-
-    let (name, tag, platform) =
-        match Regex::new(r"(\d{4}-\d{2}-\d{2})-(\w+)-(\w+)-(\w+)(?:-(\w+))?")?
-            .captures(filename)
-            .ok_or("Invalid filename")?
-            .iter()
-            .collect::<Vec<_>>()
-            .as_slice()
-        {
-            [Some(_), Some(date), Some(name), Some(version), Some(platform), feature] => {
-                let date_concat = date.as_str().replace("-", "");
-                let tag = match feature {
-                    Some(feature) => {
-                        format!("{}-{}-{}", version.as_str(), date_concat, feature.as_str())
-                    }
-                    None => format!("{}-{}", version.as_str(), date_concat),
-                };
-                (name.as_str(), tag, platform.as_str())
-            }
-            _ => {
-                return Err("Invalid image file".into());
-            }
-        };
+/// Finds the disk image in a zip archive, preferring `.img` entries and
+/// falling back to the largest file.
+pub(super) fn image_entry_index<R: io::Read + io::Seek>(
+    archive: &mut zip::ZipArchive<R>,
+) -> Result<usize, Error> {
+    let mut largest: Option<(usize, u64)> = None;
 
-    let url = format!(
-        "https://downloads.raspberrypi.org/{}/images/{}/{}",
-        registry, image_name, filename
-    );
+    for index in 0..archive.len() {
+        let entry = archive.by_index(index)?;
 
-    Ok(DownloadableBakerImage {
-        url,
-        image: BakerImage {
-            platform: platform.to_string(),
-            name: name.to_string(),
-            tag,
-            sha256,
-        },
-    })
-}
+        if entry.is_dir() {
+            continue;
+        }
 
-fn list_raspios_images_from_repository(
-    repository: String,
-    date: Option<NaiveDateTime>,
-) -> Result<impl Iterator<Item = DownloadableBakerImage>, Box<dyn std::error::Error>> {
-    Ok(list_raspios_image_names(&repository)?
-        .into_iter()
-        .filter(move |(_, last_modified)| date.map_or(true, |date| date <= *last_modified))
-        .flat_map(move |(name, _)| get_raspios_images(&repository, &name)))
-}
+        if entry.name().ends_with(".img") {
+            return Ok(index);
+        }
 
-pub fn list_raspios_images(
-    date: Option<NaiveDateTime>,
-) -> Result<impl Iterator<Item = DownloadableBakerImage>, Box<dyn std::error::Error>> {
-    Ok(list_raspios_repositories()?
-        .into_iter()
-        .filter(move |(_, last_modified)| date.map_or(true, |date| date <= *last_modified))
-        .flat_map(move |(name, _)| list_raspios_images_from_repository(name, date))
-        .flatten())
+        match largest {
+            Some((_, size)) if size >= entry.size() => {}
+            _ => largest = Some((index, entry.size())),
+        }
+    }
+
+    largest
+        .map(|(index, _)| index)
+        .ok_or_else(|| Error::Other("No image found in archive".to_string()))
 }
 
+/// Downloads an indexed image through the source it comes from.
 pub fn download_image(
     image_path: PathBuf,
     downloadable_image: &DownloadableBakerImage,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+) -> Result<(), Error> {
+    find_source(downloadable_image.source())?.download(downloadable_image, &image_path)
+}
 
-    let url = Url::parse(downloadable_image.url())?;
+fn get_partial_downloads_dir() -> Result<PathBuf, Error> {
+    Ok(get_app_dir()?.join("downloads"))
+}
 
+/// Where an archive is downloaded to, kept when the download is interrupted
+/// for the next one to resume it.
+fn partial_download_path(url: &Url) -> Result<PathBuf, Error> {
     let filename = url
         .path_segments()
-        .ok_or("Invalid url")?
-        .last()
-        .ok_or("Invalid filename")?;
+        .and_then(|mut segments| segments.next_back())
+        .ok_or_else(|| Error::Other("Invalid url".to_string()))?;
+
+    Ok(get_partial_downloads_dir()?.join(format!(
+        "{}-{}.part",
+        &sha256::digest(url.as_str())[..16],
+        filename
+    )))
+}
 
-    let mut response = client.get(url.clone()).send()?;
+fn request_from(
+    client: &reqwest::blocking::Client,
+    url: &Url,
+    offset: u64,
+) -> Result<reqwest::blocking::Response, Error> {
+    let mut request = client.get(url.clone());
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    retry::send(request)
+}
 
-    let temp_filepath = env::temp_dir().join(filename);
-    let mut temp_file = File::create(&temp_filepath)?;
-    response.copy_to(&mut temp_file)?;
-    temp_file.sync_data()?;
+/// Body of a download, requested again with a Range request from where it
+/// stopped when the connection drops, as long as the request it came from
+/// got some bytes.
+struct ResumableBody {
+    client: reqwest::blocking::Client,
+    url: Url,
+    response: reqwest::blocking::Response,
+    /// Where the body starts in the file, past what was already downloaded
+    start: u64,
+    received: u64,
+    progressed: bool,
+    /// Whether the body could not be downloaded whole, what was received
+    /// being worth resuming from
+    interrupted: bool,
+    transfer: progress::Transfer,
+}
 
-    fs::create_dir_all(image_path.parent().ok_or("Invalid image path")?)?;
+impl ResumableBody {
+    /// Downloads `url` from `offset` on. Servers ignoring the range send the
+    /// whole file, the body then starting from the beginning.
+    fn open(url: &Url, offset: u64) -> Result<ResumableBody, Error> {
+        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+
+        let mut response = request_from(&client, url, offset)?;
+        let start = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT if offset > 0 => offset,
+            // The partial download is stale, like when it is longer than the
+            // file now is
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+                response = request_from(&client, url, 0)?;
+                0
+            }
+            _ => 0,
+        };
+        let response = response.error_for_status()?;
+
+        let filename = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .ok_or_else(|| Error::Other("Invalid url".to_string()))?;
+        let transfer = progress::Transfer::start(
+            &format!("Downloading {}", filename),
+            start,
+            response.content_length().map(|length| start + length),
+        );
+
+        Ok(ResumableBody {
+            client,
+            url: url.clone(),
+            response,
+            start,
+            received: start,
+            progressed: false,
+            interrupted: false,
+            transfer,
+        })
+    }
+
+    /// Requests the rest of the body, or the whole file again when the server
+    /// ignores the range, skipping what was already received.
+    fn resume(&mut self) -> Result<(), Error> {
+        let mut response = request_from(&self.client, &self.url, self.received)?;
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            response = response.error_for_status()?;
+            let skipped = io::copy(
+                &mut io::Read::take(&mut response, self.received),
+                &mut io::sink(),
+            )?;
+            if skipped < self.received {
+                return Err(Error::Other(format!(
+                    "{} changed while being downloaded",
+                    self.url
+                )));
+            }
+        }
+        self.response = response;
+        self.progressed = false;
+        Ok(())
+    }
+
+    fn finish(self) {
+        self.transfer.finish();
+    }
+}
+
+impl io::Read for ResumableBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match io::Read::read(&mut self.response, buf) {
+                Ok(read) => {
+                    self.received += read as u64;
+                    self.progressed |= read > 0;
+                    self.transfer.advance(read as u64);
+                    return Ok(read);
+                }
+                Err(err) if self.progressed => {
+                    if progress::is_verbose() {
+                        eprintln!("Resuming the download of {}: {}", self.url, err);
+                    }
+                    if let Err(err) = self.resume() {
+                        self.interrupted = true;
+                        return Err(io::Error::other(err));
+                    }
+                }
+                Err(err) => {
+                    self.interrupted = true;
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Downloads `url` to `path`, resuming what a previous download left there.
+fn download_resumable(url: &Url, path: &Path) -> Result<(), Error> {
+    fs::create_dir_all(
+        path.parent()
+            .ok_or_else(|| Error::Other("Invalid download path".to_string()))?,
+    )?;
+    let offset = fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+
+    let mut body = ResumableBody::open(url, offset)?;
+    let mut file = match body.start {
+        0 => File::create(path)?,
+        _ => fs::OpenOptions::new().append(true).open(path)?,
+    };
+    io::copy(&mut body, &mut file)?;
+    file.sync_data()?;
+    body.finish();
 
-    let mut file = File::create(image_path)?;
+    Ok(())
+}
+
+/// Reader writing what is read through it to a file too.
+struct TeeReader<'a, R> {
+    inner: R,
+    file: &'a File,
+}
+
+impl<R: io::Read> io::Read for TeeReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        io::Write::write_all(&mut self.file, &buf[..read])?;
+        Ok(read)
+    }
+}
+
+/// Reader hashing what is read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: io::Read> HashingReader<R> {
+    fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+    /// Hashes what was not read yet, giving back the digest of everything.
+    fn finish(mut self) -> io::Result<String> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(data_encoding::HEXLOWER.encode(&self.hasher.finalize()))
+    }
+}
+
+impl<R: io::Read> io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Writes the disk image of a streamed zip archive to `file`, its first
+/// `.img` entry or else its largest file, as `image_entry_index` finds it.
+fn extract_zip<R: io::Read>(reader: &mut R, file: &File) -> Result<(), Error> {
+    let mut largest: Option<u64> = None;
+
+    while let Some(mut entry) = zip::read::read_zipfile_from_stream(reader)? {
+        if !entry.is_file() {
+            continue;
+        }
+
+        let is_image = entry.name().ends_with(".img");
+        if !is_image && largest.is_some_and(|size| size >= entry.size()) {
+            continue;
+        }
+
+        // The entry replaces the smaller one written before
+        file.set_len(0)?;
+        let mut writer = io::BufWriter::new(file);
+        io::Seek::rewind(&mut writer)?;
+        io::copy(&mut entry, &mut writer)?;
+        io::Write::flush(&mut writer)?;
+
+        if is_image {
+            return Ok(());
+        }
+        largest = Some(entry.size());
+    }
 
-    if filename.ends_with(".zip") {
-        let mut archive = zip::ZipArchive::new(&temp_file)?;
-        let mut image_file = archive.by_index(0)?;
-        io::copy(&mut image_file, &mut file)?;
-    } else if filename.ends_with(".xz") {
-        let mut archive = xz2::read::XzDecoder::new(File::open(&temp_filepath)?);
-        io::copy(&mut archive, &mut file)?;
+    largest
+        .map(|_| ())
+        .ok_or_else(|| Error::Other("No image found in archive".to_string()))
+}
+
+fn extract_7z(archive_path: &Path, file: &File, filename: &str) -> Result<(), Error> {
+    // No 7z decoder is bundled, the 7z tool extracts the archive
+    let status = Command::new("7z")
+        .args(["e", "-so", "-r"])
+        .arg(archive_path)
+        .arg("*.img")
+        .stdout(file.try_clone()?)
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err(Error::Other(format!("7z failed to extract {}", filename))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(Error::Other(
+            "Extracting .7z images needs the 7z command, from p7zip".to_string(),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Decompresses a streamed `.zip` or `.xz` archive to `file`, giving back the
+/// digest of the archive.
+fn extract_stream<R: io::Read>(reader: R, filename: &str, file: &File) -> Result<String, Error> {
+    let mut reader = HashingReader::new(reader);
+
+    if filename.ends_with(".xz") {
+        let mut writer = io::BufWriter::new(file);
+        decompress_xz(&mut reader, &mut writer)?;
+        io::Write::flush(&mut writer)?;
     } else {
-        return Err("Invalid image file".into());
+        extract_zip(&mut reader, file)?;
+    }
+
+    Ok(reader.finish()?)
+}
+
+/// Downloads a `.zip` or `.xz` archive while decompressing it to `file`,
+/// giving back the digest of the archive. What is downloaded is kept in a
+/// partial download too, so that an interrupted download resumes from there
+/// instead of from the start.
+fn stream_resumable(
+    url: &Url,
+    part_path: &Path,
+    filename: &str,
+    file: &File,
+) -> Result<String, Error> {
+    fs::create_dir_all(
+        part_path
+            .parent()
+            .ok_or_else(|| Error::Other("Invalid download path".to_string()))?,
+    )?;
+    let offset = fs::metadata(part_path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+
+    let mut body = ResumableBody::open(url, offset)?;
+    let part = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(part_path)?;
+    if body.start == 0 {
+        part.set_len(0)?;
+    }
+
+    // The archive is decompressed from the start, what the previous
+    // download got being read back from the partial download
+    let downloaded = io::Read::take(File::open(part_path)?, body.start);
+    let result = extract_stream(
+        io::Read::chain(
+            downloaded,
+            TeeReader {
+                inner: &mut body,
+                file: &part,
+            },
+        ),
+        filename,
+        file,
+    );
+
+    match result {
+        Ok(digest) => {
+            body.finish();
+            fs::remove_file(part_path)?;
+            Ok(digest)
+        }
+        // Archives that cannot be decompressed are not worth resuming
+        Err(err) => {
+            if !body.interrupted {
+                drop(part);
+                let _ = fs::remove_file(part_path);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Writes the disk image of an archive to `file`, giving back the digest of
+/// the archive.
+fn write_image(url: &Url, filename: &str, file: &File) -> Result<String, Error> {
+    if filename.ends_with(".7z") {
+        let (archive_path, downloaded) = match local_path(url)? {
+            Some(path) => (path, false),
+            None => {
+                let path = partial_download_path(url)?;
+                download_resumable(url, &path)?;
+                (path, true)
+            }
+        };
+        let digest = sha256::try_digest(archive_path.as_path())?;
+        extract_7z(&archive_path, file, filename)?;
+        if downloaded {
+            fs::remove_file(&archive_path)?;
+        }
+        return Ok(digest);
+    }
+
+    match local_path(url)? {
+        Some(path) => extract_stream(File::open(path)?, filename, file),
+        None => stream_resumable(url, &partial_download_path(url)?, filename, file),
+    }
+}
+
+/// Downloads a `.zip`, `.xz` or `.7z` archive and writes the disk image it
+/// holds to `image_path`, checking the archive against `sha256`. `.zip` and
+/// `.xz` archives are decompressed and hashed as they are downloaded, `.7z`
+/// ones are downloaded first as the 7z tool needs them whole. `file://`
+/// archives, from local registries, are read in place.
+pub(super) fn download_archive(url: &Url, image_path: &Path, sha256: &str) -> Result<(), Error> {
+    let filename = url
+        .path_segments()
+        .ok_or_else(|| Error::Other("Invalid url".to_string()))?
+        .last()
+        .ok_or_else(|| Error::Other("Invalid filename".to_string()))?;
+
+    if ![".zip", ".xz", ".7z"]
+        .iter()
+        .any(|extension| filename.ends_with(extension))
+    {
+        return Err(Error::Other("Invalid image file".to_string()));
+    }
+
+    fs::create_dir_all(
+        image_path
+            .parent()
+            .ok_or_else(|| Error::Other("Invalid image path".to_string()))?,
+    )?;
+    let file = File::create(image_path)?;
+
+    let checked = write_image(url, filename, &file).and_then(|digest| {
+        if digest != sha256.to_lowercase() {
+            return Err(Error::Other(format!(
+                "{} does not match its sha256, expected {} but got {}",
+                filename, sha256, digest
+            )));
+        }
+        Ok(())
+    });
+    if let Err(err) = checked {
+        drop(file);
+        let _ = fs::remove_file(image_path);
+        return Err(err);
     }
 
     file.sync_data()?;
@@ -264,29 +610,275 @@ pub fn download_image(
     Ok(())
 }
 
+pub(super) fn decompress_xz<R: io::Read, W: io::Write>(
+    reader: R,
+    writer: &mut W,
+) -> io::Result<u64> {
+    let mut decoder = xz2::read::XzDecoder::new(reader);
+    io::copy(&mut decoder, writer)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::images::test_server::serve;
+
+    fn zip_archive(entries: &[(&str, &[u8])]) -> zip::ZipArchive<io::Cursor<Vec<u8>>> {
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, content) in entries {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            io::Write::write_all(&mut writer, content).unwrap();
+        }
+        zip::ZipArchive::new(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_image_entry_index_skips_non_image_first_entry() {
+        let mut archive = zip_archive(&[
+            (
+                "README.txt",
+                b"read me first, this is longer than the image",
+            ),
+            ("2024-07-04-raspios-bookworm-arm64.img", b"image"),
+        ]);
+
+        assert_eq!(image_entry_index(&mut archive).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_image_entry_index_falls_back_to_largest_entry() {
+        let mut archive = zip_archive(&[("sha256sum", b"abc"), ("disk", b"much larger image")]);
+
+        assert_eq!(image_entry_index(&mut archive).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_extract_zip_falls_back_to_largest_entry() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let image_path = tmp_dir.path().join("image.img");
+
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, data) in [
+            ("sha256sum", &b"abc"[..]),
+            ("disk", b"much larger image"),
+            ("notes", b"short"),
+        ] {
+            writer
+                .start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            io::Write::write_all(&mut writer, data).unwrap();
+        }
+        let compressed = writer.finish().unwrap().into_inner();
+
+        extract_zip(
+            &mut io::Cursor::new(compressed),
+            &File::create(&image_path).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(fs::read(&image_path).unwrap(), b"much larger image");
+    }
+
+    #[test]
+    fn test_image_entry_index_empty_archive() {
+        let mut archive = zip_archive(&[]);
+
+        assert!(image_entry_index(&mut archive).is_err());
+    }
+
+    #[test]
+    fn test_decompress_xz_from_reader() {
+        let content = b"raspberry pi image content".repeat(1024);
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        io::Write::write_all(&mut encoder, &content).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut output = Vec::new();
+        let written = decompress_xz(io::Cursor::new(compressed), &mut output).unwrap();
+
+        assert_eq!(written, content.len() as u64);
+        assert_eq!(output, content);
+    }
+
+    #[test]
+    fn test_extract_stream_hashes_archive() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let image_path = tmp_dir.path().join("image.img");
+        let content = b"raspberry pi image content".repeat(1024);
+
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        io::Write::write_all(&mut encoder, &content).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let digest = extract_stream(
+            io::Cursor::new(&compressed),
+            "image.img.xz",
+            &File::create(&image_path).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(digest, sha256::digest(compressed.as_slice()));
+        assert_eq!(fs::read(&image_path).unwrap(), content);
+
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, data) in [("README.txt", &b"read me"[..]), ("disk.img", &content)] {
+            writer
+                .start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            io::Write::write_all(&mut writer, data).unwrap();
+        }
+        let compressed = writer.finish().unwrap().into_inner();
+        let digest = extract_stream(
+            io::Cursor::new(&compressed),
+            "image.zip",
+            &File::create(&image_path).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(digest, sha256::digest(compressed.as_slice()));
+        assert_eq!(fs::read(&image_path).unwrap(), content);
+    }
+
+    #[test]
+    fn test_parse_links() {
+        let body = r#"<html><body><h1>Index of /downloads/images/</h1>
+            <a href="../">../</a>
+            <a href="?C=M;O=A">Last modified</a>
+            <a href="/downloads/">Parent Directory</a>
+            <a href="testing/">testing/</a>
+            <a href="DietPi_RPi234-ARMv8-Bookworm.img.xz">DietPi_RPi234-ARMv8-Bookworm.img.xz</a>
+            <a href="https://dietpi.com/">DietPi</a>
+            </body></html>"#;
+
+        assert_eq!(
+            parse_links(body),
+            vec![
+                "testing".to_string(),
+                "DietPi_RPi234-ARMv8-Bookworm.img.xz".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_download_resumable() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("image.img.xz.part");
+        fs::write(&path, "hello ").unwrap();
+        let (url, server) = serve(vec![
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 5\r\nContent-Range: bytes 6-10/11\r\nConnection: close\r\n\r\nworld",
+        ]);
+        download_resumable(&url, &path).unwrap();
+        assert!(server.join().unwrap()[0].contains("range: bytes=6-"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world");
+
+        // A server ignoring the range restarts the download
+        fs::write(&path, "stale ").unwrap();
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+        ]);
+        download_resumable(&url, &path).unwrap();
+        server.join().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_resume_restarts_when_range_is_ignored() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("image.img.xz.part");
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello",
+            "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world",
+        ]);
+        download_resumable(&url, &path).unwrap();
+        assert!(server.join().unwrap()[1].contains("range: bytes=5-"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello world");
+    }
+
+    #[test]
+    fn test_stream_resumable_resumes_partial_download() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let part_path = tmp_dir.path().join("image.img.xz.part");
+        let image_path = tmp_dir.path().join("image.img");
+        let content = b"raspberry pi image content".repeat(16);
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        io::Write::write_all(&mut encoder, &content).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        // The previous download got the first half of the archive
+        let half = compressed.len() / 2;
+        fs::write(&part_path, &compressed[..half]).unwrap();
+        let response = format!(
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+            compressed.len() - half,
+            half,
+            compressed.len() - 1,
+            compressed.len()
+        );
+        let mut response = response.into_bytes();
+        response.extend_from_slice(&compressed[half..]);
+        let (url, server) = serve(vec![response]);
+
+        let digest = stream_resumable(
+            &url,
+            &part_path,
+            "image.img.xz",
+            &File::create(&image_path).unwrap(),
+        )
+        .unwrap();
+        assert!(server.join().unwrap()[0].contains(&format!("range: bytes={}-", half)));
+        assert_eq!(digest, sha256::digest(compressed.as_slice()));
+        assert_eq!(fs::read(&image_path).unwrap(), content);
+        assert!(!part_path.exists());
+
+        // A server ignoring the range sends the whole archive again
+        fs::write(&part_path, b"stale").unwrap();
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            compressed.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&compressed);
+        let (url, server) = serve(vec![response]);
+
+        let digest = stream_resumable(
+            &url,
+            &part_path,
+            "image.img.xz",
+            &File::create(&image_path).unwrap(),
+        )
+        .unwrap();
+        server.join().unwrap();
+        assert_eq!(digest, sha256::digest(compressed.as_slice()));
+        assert_eq!(fs::read(&image_path).unwrap(), content);
+    }
+
+    #[test]
+    fn test_parse_sha256sums() {
+        let sha256sums = parse_sha256sums(
+            "ABCD *ubuntu-24.04.1-preinstalled-server-arm64+raspi.img.xz\n\
+             ef01  ubuntu-24.04.1-live-server-arm64.iso\n\
+             \n",
+        );
+
+        assert_eq!(sha256sums.len(), 2);
+        assert_eq!(
+            sha256sums["ubuntu-24.04.1-preinstalled-server-arm64+raspi.img.xz"],
+            "abcd"
+        );
+        assert_eq!(sha256sums["ubuntu-24.04.1-live-server-arm64.iso"], "ef01");
+    }
 
     #[test]
-    fn test_list_raspios_registries() {
-        let registries = list_raspios_repositories()
-            .unwrap()
-            .into_iter()
-            .map(|(name, _)| name)
-            .collect::<Vec<String>>();
-
-        assert!(registries.contains(&"raspios_arm64".to_string()));
-        assert!(registries.contains(&"raspios_armhf".to_string()));
-        assert!(registries.contains(&"raspios_full_arm64".to_string()));
-        assert!(registries.contains(&"raspios_full_armhf".to_string()));
-        assert!(registries.contains(&"raspios_lite_arm64".to_string()));
-        assert!(registries.contains(&"raspios_lite_armhf".to_string()));
-        assert!(registries.contains(&"raspios_oldstable_arm64".to_string()));
-        assert!(registries.contains(&"raspios_oldstable_armhf".to_string()));
-        assert!(registries.contains(&"raspios_oldstable_lite_arm64".to_string()));
-        assert!(registries.contains(&"raspios_oldstable_lite_armhf".to_string()));
-        assert!(registries.contains(&"raspios_oldstable_full_arm64".to_string()));
-        assert!(registries.contains(&"raspios_oldstable_full_armhf".to_string()));
+    fn test_modified_since() {
+        let date = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        let files = vec![
+            ("old".to_string(), date("2023-01-01 00:00")),
+            ("same".to_string(), date("2024-01-01 00:00")),
+            ("new".to_string(), date("2024-06-01 12:00")),
+        ];
+
+        assert_eq!(modified_since(files.clone(), None).len(), 3);
+        assert_eq!(
+            modified_since(files, Some(date("2024-01-01 00:00"))),
+            vec!["same".to_string(), "new".to_string()]
+        );
     }
 }
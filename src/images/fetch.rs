@@ -1,5 +1,8 @@
+use crate::error::Error;
 use crate::get_app_dir;
-use crate::images::download::{list_raspios_images, DownloadableBakerImage};
+use crate::images::download::DownloadableBakerImage;
+use crate::images::sources::sources;
+use crate::progress::{self, Progress};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use serde_json;
 use std::fs::{self, File};
@@ -7,37 +10,102 @@ use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::Duration;
 
-fn get_downloadable_images_path() -> Result<PathBuf, Box<dyn std::error::Error>> {
+fn get_downloadable_images_path() -> Result<PathBuf, Error> {
     Ok(get_app_dir()?.join("downloadable-images.json"))
 }
 
-pub fn fetch_baker_images() -> Result<Vec<DownloadableBakerImage>, Box<dyn std::error::Error>> {
+/// Held while updating the index, which stages and targets pulling their
+/// bases concurrently all read and write back.
+static INDEX_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Adds fetched images to the index, replacing the ones they republish
+/// under the same name and tag.
+fn add_images(
+    downloadable_images: &mut Vec<DownloadableBakerImage>,
+    fetched: Vec<DownloadableBakerImage>,
+) {
+    for downloadable_image in fetched {
+        if progress::is_verbose() {
+            let image = downloadable_image.image();
+            eprintln!(
+                "Fetched {:?} for {:?} from {}",
+                image.full_name(),
+                image.platform(),
+                downloadable_image.url()
+            );
+        }
+        downloadable_images.retain(|other| {
+            let (image, other) = (downloadable_image.image(), other.image());
+            (other.platform(), other.name(), other.tag())
+                != (image.platform(), image.name(), image.tag())
+        });
+        downloadable_images.push(downloadable_image);
+    }
+}
+
+/// Updates the index of the downloadable images with what upstream published
+/// since it was last written, or rebuilds it from scratch when `refresh`.
+pub fn fetch_baker_images(refresh: bool) -> Result<Vec<DownloadableBakerImage>, Error> {
+    let _lock = INDEX_LOCK.lock().unwrap_or_else(|err| err.into_inner());
     let downloadable_images_dir = get_downloadable_images_path()?;
 
     let (mut downloadable_images, date): (Vec<DownloadableBakerImage>, Option<NaiveDateTime>) =
         match File::open(downloadable_images_dir.as_path()) {
-            Ok(file) => {
+            Ok(file) if !refresh => {
                 let date: DateTime<Utc> = file.metadata()?.modified()?.into();
                 (serde_json::from_reader(file)?, Some(date.naive_utc()))
             }
-            Err(_) => (Vec::new(), None),
+            _ => (Vec::new(), None),
         };
 
-    for downloadable_image in list_raspios_images(date)? {
-        let image = downloadable_image.image();
-        println!(
-            "Fetching {:?} for {:?}",
-            image.full_name(),
-            image.platform()
-        );
-        downloadable_images.push(downloadable_image);
+    // A source being unreachable does not keep the others from being fetched
+    let mut repositories = Vec::new();
+    let mut listing_errors = Vec::new();
+    let sources = sources();
+    for source in &sources {
+        match source.list_repositories(date) {
+            Ok(listed) => repositories.extend(listed.into_iter().map(|repo| (source, repo))),
+            Err(err) => {
+                if progress::is_verbose() {
+                    eprintln!("Skipping {}: {}", source.name(), err);
+                }
+                listing_errors.push(format!("{}: {}", source.name(), err));
+            }
+        }
+    }
+    if listing_errors.len() == sources.len() {
+        return Err(Error::Other(format!(
+            "No image source could be listed ({})",
+            listing_errors.join(", ")
+        )));
+    }
+
+    let mut progress = Progress::new(repositories.len());
+    for (source, repository) in repositories {
+        progress.advance(&format!("{}/{}", source.name(), repository));
+
+        match source.list_images(&repository) {
+            Ok(fetched) => add_images(
+                &mut downloadable_images,
+                fetched
+                    .into_iter()
+                    .map(|image| image.with_source(source.name()))
+                    .collect(),
+            ),
+            Err(err) => {
+                if progress::is_verbose() {
+                    eprintln!("Skipping {}/{}: {}", source.name(), repository, err);
+                }
+            }
+        }
+
         sleep(Duration::from_millis(500));
     }
 
     fs::create_dir_all(
         downloadable_images_dir
             .parent()
-            .ok_or("Invalid downloadable images path")?,
+            .ok_or_else(|| Error::Other("Invalid downloadable images path".to_string()))?,
     )?;
 
     serde_json::to_writer_pretty(
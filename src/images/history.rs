@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// One step of the provenance of an image.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "HistoryRecord")]
+pub struct HistoryEntry {
+    instruction: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+}
+
+/// Histories used to be stored as bare instructions.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HistoryRecord {
+    Instruction(String),
+    Entry {
+        instruction: String,
+        #[serde(default)]
+        duration_ms: Option<u64>,
+        #[serde(default)]
+        digest: Option<String>,
+    },
+}
+
+impl From<HistoryRecord> for HistoryEntry {
+    fn from(record: HistoryRecord) -> Self {
+        match record {
+            HistoryRecord::Instruction(instruction) => HistoryEntry::new(instruction),
+            HistoryRecord::Entry {
+                instruction,
+                duration_ms,
+                digest,
+            } => HistoryEntry {
+                instruction,
+                duration_ms,
+                digest,
+            },
+        }
+    }
+}
+
+impl HistoryEntry {
+    pub fn new(instruction: String) -> HistoryEntry {
+        HistoryEntry {
+            instruction,
+            duration_ms: None,
+            digest: None,
+        }
+    }
+    pub fn timed(instruction: String, duration: Duration) -> HistoryEntry {
+        HistoryEntry {
+            duration_ms: Some(duration.as_millis() as u64),
+            ..HistoryEntry::new(instruction)
+        }
+    }
+    /// Records the cache key of the image the instruction left behind.
+    pub fn with_digest(self, digest: String) -> HistoryEntry {
+        HistoryEntry {
+            digest: Some(digest),
+            ..self
+        }
+    }
+    pub fn instruction(&self) -> &str {
+        &self.instruction
+    }
+    pub fn duration(&self) -> Option<Duration> {
+        self.duration_ms.map(Duration::from_millis)
+    }
+    pub fn digest(&self) -> Option<&str> {
+        self.digest.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_entry_reads_bare_instructions() {
+        let json = r#"["FROM raspios:bookworm", {"instruction": "RUN true", "duration_ms": 1500}]"#;
+        let history: Vec<HistoryEntry> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(
+            history,
+            vec![
+                HistoryEntry::new("FROM raspios:bookworm".to_string()),
+                HistoryEntry::timed("RUN true".to_string(), Duration::from_millis(1500)),
+            ]
+        );
+    }
+}
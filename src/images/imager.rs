@@ -0,0 +1,222 @@
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+
+use crate::error::Error;
+use crate::images::download::DownloadableBakerImage;
+use crate::images::mirrors;
+use crate::images::retry;
+use crate::images::sources::ImageSource;
+use crate::images::BakerImage;
+
+const OS_LIST_URL: &str = "https://downloads.raspberrypi.org/os_list_imagingutility_v4.json";
+
+/// How deep lists pointing to other lists are followed, which third-party
+/// lists could otherwise do forever.
+const MAX_LIST_DEPTH: usize = 4;
+
+#[derive(Debug, Deserialize)]
+struct OsList {
+    os_list: Vec<OsEntry>,
+}
+
+/// An entry of the Raspberry Pi Imager list, either an image or a category
+/// of entries, listed inline or in another list.
+#[derive(Debug, Deserialize)]
+struct OsEntry {
+    name: String,
+    url: Option<String>,
+    release_date: Option<String>,
+    image_download_sha256: Option<String>,
+    #[serde(default)]
+    devices: Vec<String>,
+    #[serde(default)]
+    subitems: Vec<OsEntry>,
+    subitems_url: Option<String>,
+}
+
+/// Name of an entry, like `raspberry-pi-os-lite` for `Raspberry Pi OS Lite
+/// (64-bit)`. The bitness is left out as it is the platform.
+fn parse_name(name: &str) -> String {
+    let name = match name.split_once('(') {
+        Some((head, tail)) if tail.contains("bit)") => head,
+        _ => name,
+    };
+
+    name.to_lowercase()
+        .split(|ch: char| !ch.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Platform of an image, from the devices it targets or else its URL.
+fn parse_platform(entry: &OsEntry, url: &str) -> &'static str {
+    if entry
+        .devices
+        .iter()
+        .any(|device| device.ends_with("-64bit"))
+        || url.contains("arm64")
+        || url.contains("aarch64")
+    {
+        "arm64"
+    } else {
+        "armhf"
+    }
+}
+
+fn fetch_os_list(url: &str) -> Result<Vec<OsEntry>, Error> {
+    let body = retry::get(url)?.error_for_status()?.text()?;
+    Ok(serde_json::from_str::<OsList>(&body)?.os_list)
+}
+
+/// Images of an entry and its subitems, as `raspberry-pi-os-lite:2024-07-04`.
+/// Entries without a download, like the ones erasing cards, and archives
+/// that cannot be extracted are left out.
+fn collect_images(
+    mut entry: OsEntry,
+    depth: usize,
+    images: &mut Vec<DownloadableBakerImage>,
+) -> Result<(), Error> {
+    if let Some(subitems_url) = &entry.subitems_url {
+        if depth < MAX_LIST_DEPTH {
+            for subitem in fetch_os_list(subitems_url)? {
+                collect_images(subitem, depth + 1, images)?;
+            }
+        }
+    }
+    for subitem in std::mem::take(&mut entry.subitems) {
+        collect_images(subitem, depth, images)?;
+    }
+
+    let (Some(url), Some(tag), Some(sha256)) = (
+        &entry.url,
+        &entry.release_date,
+        &entry.image_download_sha256,
+    ) else {
+        return Ok(());
+    };
+    if ![".zip", ".xz", ".7z"]
+        .iter()
+        .any(|extension| url.ends_with(extension))
+    {
+        return Ok(());
+    }
+
+    images.push(DownloadableBakerImage::new(
+        url.clone(),
+        BakerImage {
+            platform: parse_platform(&entry, url).to_string(),
+            name: parse_name(&entry.name),
+            tag: tag.clone(),
+            sha256: sha256.to_lowercase(),
+            history: Vec::new(),
+            labels: Default::default(),
+        },
+    ));
+
+    Ok(())
+}
+
+/// Images listed by the Raspberry Pi Imager, from its JSON list, which gives
+/// the digests directly and includes third-party systems. Its repositories
+/// are the categories of the list.
+pub struct ImagerSource;
+
+impl ImageSource for ImagerSource {
+    fn name(&self) -> &'static str {
+        "imager"
+    }
+    /// The list is a single file, so every category is listed again.
+    fn list_repositories(&self, _date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+        Ok(fetch_os_list(OS_LIST_URL)?
+            .into_iter()
+            .map(|entry| parse_name(&entry.name))
+            .collect())
+    }
+    fn list_images(&self, repository: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+        let entry = fetch_os_list(OS_LIST_URL)?
+            .into_iter()
+            .find(|entry| parse_name(&entry.name) == repository)
+            .ok_or_else(|| Error::Other(format!("Unknown category {}", repository)))?;
+
+        let mut images = Vec::new();
+        collect_images(entry, 0, &mut images)?;
+        Ok(images)
+    }
+    /// Raspberry Pi OS images of the list come from the same host as the
+    /// ones of `RaspiosSource`, and are mirrored the same way.
+    fn download(&self, image: &DownloadableBakerImage, image_path: &Path) -> Result<(), Error> {
+        mirrors::download_archive(
+            &self.resolve_download(image)?,
+            image_path,
+            image.image().sha256(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_name() {
+        assert_eq!(
+            parse_name("Raspberry Pi OS Lite (64-bit)"),
+            "raspberry-pi-os-lite"
+        );
+        assert_eq!(
+            parse_name("Ubuntu Server 24.04.1 LTS (64-bit)"),
+            "ubuntu-server-24-04-1-lts"
+        );
+        assert_eq!(
+            parse_name("Other general-purpose OS"),
+            "other-general-purpose-os"
+        );
+        assert_eq!(parse_name("LibreELEC (RPi4)"), "libreelec-rpi4");
+    }
+
+    #[test]
+    fn test_collect_images() {
+        let os_list: OsList = serde_json::from_str(
+            r#"{"os_list": [
+                {
+                    "name": "Raspberry Pi OS (other)",
+                    "subitems": [
+                        {
+                            "name": "Raspberry Pi OS Lite (64-bit)",
+                            "url": "https://downloads.raspberrypi.org/raspios_lite_arm64/images/raspios_lite_arm64-2024-07-04/2024-07-04-raspios-bookworm-arm64-lite.img.xz",
+                            "release_date": "2024-07-04",
+                            "image_download_sha256": "43D150E7",
+                            "devices": ["pi5-64bit", "pi4-64bit"]
+                        },
+                        {
+                            "name": "Raspberry Pi OS Lite (32-bit)",
+                            "url": "https://downloads.raspberrypi.org/raspios_lite_armhf/images/raspios_lite_armhf-2024-07-04/2024-07-04-raspios-bookworm-armhf-lite.img.xz",
+                            "release_date": "2024-07-04",
+                            "image_download_sha256": "df9c1920",
+                            "devices": ["pi4-32bit"]
+                        },
+                        {"name": "Use custom", "url": ""}
+                    ]
+                },
+                {"name": "Erase", "url": "internal://format"}
+            ]}"#,
+        )
+        .unwrap();
+
+        let mut images = Vec::new();
+        for entry in os_list.os_list {
+            collect_images(entry, 0, &mut images).unwrap();
+        }
+
+        assert_eq!(images.len(), 2);
+        let image = images[0].image();
+        assert_eq!(
+            (image.name(), image.tag(), image.platform(), image.sha256()),
+            ("raspberry-pi-os-lite", "2024-07-04", "arm64", "43d150e7")
+        );
+        assert_eq!(images[1].image().platform(), "armhf");
+    }
+}
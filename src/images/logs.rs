@@ -0,0 +1,90 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::error::Error;
+
+/// Output of the commands a stage runs, each step starting with its
+/// instruction.
+pub struct BuildLog {
+    file: File,
+}
+
+impl BuildLog {
+    pub fn create(path: &Path) -> Result<BuildLog, Error> {
+        Ok(BuildLog {
+            file: File::create(path)?,
+        })
+    }
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+    pub fn section(&self, title: &str) -> Result<(), Error> {
+        writeln!(&self.file, "==> {}", title)?;
+        Ok(())
+    }
+}
+
+/// Joins the logs of the stages of a build, in order, into the log of the
+/// image it produced.
+pub fn save(stage_logs: &[impl AsRef<Path>], path: &Path) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+
+    for stage_log in stage_logs {
+        match File::open(stage_log) {
+            Ok(mut stage_log) => {
+                io::copy(&mut stage_log, &mut file)?;
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn read(path: &Path, image: &str) -> Result<String, Error> {
+    match fs::read_to_string(path) {
+        Ok(log) => Ok(log),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Err(Error::Other(format!(
+            "{} has no build log, only built images have one",
+            image
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_joins_stage_logs() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let first = tmp_dir.path().join("stage-0.log");
+        let second = tmp_dir.path().join("stage-1.log");
+
+        let log = BuildLog::create(&first).unwrap();
+        log.section("RUN make").unwrap();
+        writeln!(log.file(), "built").unwrap();
+        BuildLog::create(&second)
+            .unwrap()
+            .section("COPY --from=0 /app /app")
+            .unwrap();
+
+        let path = tmp_dir.path().join("image.log");
+        save(
+            &[&first, &tmp_dir.path().join("missing.log"), &second],
+            &path,
+        )
+        .unwrap();
+
+        assert_eq!(
+            read(&path, "kiosk:latest").unwrap(),
+            "==> RUN make\nbuilt\n==> COPY --from=0 /app /app\n"
+        );
+        assert!(read(&tmp_dir.path().join("missing.log"), "kiosk:latest").is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use url::Url;
+
+use crate::error::Error;
+#[cfg(not(feature = "torrent"))]
+use crate::images::download::download_archive as download_upstream;
+use crate::images::download::download_archive as download_mirrored;
+use crate::images::registries::load_mirrors;
+#[cfg(feature = "torrent")]
+use crate::images::torrent::download_archive as download_upstream;
+use crate::progress;
+
+/// Host the Raspberry Pi OS images are downloaded from unless mirrored.
+const UPSTREAM_URL: &str = "https://downloads.raspberrypi.org/";
+
+static MIRRORS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Mirrors given with `--mirror`, used instead of the ones of
+/// `registries.toml`.
+pub fn set_mirrors(mirrors: Vec<String>) {
+    let _ = MIRRORS.set(mirrors);
+}
+
+fn mirrors() -> Result<Vec<String>, Error> {
+    match MIRRORS.get() {
+        Some(mirrors) => Ok(mirrors.clone()),
+        None => load_mirrors(),
+    }
+}
+
+/// The same archive on every mirror, in order, then upstream. Archives not
+/// coming from upstream are not mirrored.
+fn mirrored_urls(url: &Url, mirrors: &[String]) -> Result<Vec<Url>, Error> {
+    let Some(path) = url.as_str().strip_prefix(UPSTREAM_URL) else {
+        return Ok(vec![url.clone()]);
+    };
+
+    let mut urls = mirrors
+        .iter()
+        .map(|mirror| {
+            Ok(Url::parse(&format!(
+                "{}/{}",
+                mirror.trim_end_matches('/'),
+                path
+            ))?)
+        })
+        .collect::<Result<Vec<Url>, Error>>()?;
+    urls.push(url.clone());
+    Ok(urls)
+}
+
+/// Downloads a Raspberry Pi OS archive from the first mirror having it,
+/// falling back to upstream.
+pub(super) fn download_archive(url: &Url, image_path: &Path, sha256: &str) -> Result<(), Error> {
+    let urls = mirrored_urls(url, &mirrors()?)?;
+
+    for mirrored in &urls[..urls.len() - 1] {
+        match download_mirrored(mirrored, image_path, sha256) {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                if progress::is_verbose() {
+                    eprintln!("Skipping mirror {}: {}", mirrored, err);
+                }
+            }
+        }
+    }
+
+    download_upstream(url, image_path, sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirrored_urls() {
+        let url = Url::parse(
+            "https://downloads.raspberrypi.org/raspios_lite_arm64/images/raspios_lite_arm64-2024-07-04/2024-07-04-raspios-bookworm-arm64-lite.img.xz",
+        )
+        .unwrap();
+        let urls = mirrored_urls(
+            &url,
+            &[
+                "https://mirror.example/raspberrypi/".to_string(),
+                "http://10.0.0.2/rpi".to_string(),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            urls.iter().map(Url::as_str).collect::<Vec<_>>(),
+            vec![
+                "https://mirror.example/raspberrypi/raspios_lite_arm64/images/raspios_lite_arm64-2024-07-04/2024-07-04-raspios-bookworm-arm64-lite.img.xz",
+                "http://10.0.0.2/rpi/raspios_lite_arm64/images/raspios_lite_arm64-2024-07-04/2024-07-04-raspios-bookworm-arm64-lite.img.xz",
+                url.as_str(),
+            ]
+        );
+
+        let url = Url::parse("https://cdn.example/other.img.xz").unwrap();
+        assert_eq!(
+            mirrored_urls(&url, &["https://mirror.example".to_string()]).unwrap(),
+            vec![url]
+        );
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use data_encoding::BASE64;
+use openssl::{
+    pkey::{PKey, Private, Public},
+    sign::{Signer, Verifier},
+};
+use serde::{Deserialize, Serialize};
+use tempdir::TempDir;
+
+use crate::error::Error;
+
+/// DSSE payload type of the documents, which is part of what gets signed.
+const PAYLOAD_TYPE: &str = "application/vnd.baker.provenance+json";
+
+fn get_key_path() -> Result<PathBuf, Error> {
+    Ok(crate::get_app_dir()?.join("provenance-key.pem"))
+}
+
+/// Public keys of other builders whose provenance is trusted, as `.pem` files.
+fn get_trusted_keys_dir() -> Result<PathBuf, Error> {
+    Ok(crate::get_app_dir()?.join("trusted-keys"))
+}
+
+/// A pulled image a build started from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BaseImage {
+    reference: String,
+    platform: String,
+    sha256: String,
+}
+
+impl BaseImage {
+    pub fn new(reference: String, platform: String, sha256: String) -> BaseImage {
+        BaseImage {
+            reference,
+            platform,
+            sha256,
+        }
+    }
+}
+
+/// How an image was produced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Digest of the image file
+    sha256: String,
+    platform: String,
+    builder: String,
+    builder_version: String,
+    bakerfile: PathBuf,
+    bakerfile_sha256: String,
+    bases: Vec<BaseImage>,
+    build_args: BTreeMap<String, String>,
+    target: Option<String>,
+    started: String,
+    finished: String,
+}
+
+impl Provenance {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        sha256: String,
+        platform: String,
+        bakerfile: PathBuf,
+        bakerfile_sha256: String,
+        bases: Vec<BaseImage>,
+        build_args: BTreeMap<String, String>,
+        target: Option<String>,
+        started: chrono::DateTime<chrono::Utc>,
+    ) -> Provenance {
+        Provenance {
+            sha256,
+            platform,
+            builder: env!("CARGO_PKG_NAME").to_string(),
+            builder_version: env!("CARGO_PKG_VERSION").to_string(),
+            bakerfile,
+            bakerfile_sha256,
+            bases,
+            build_args,
+            target,
+            started: started.to_rfc3339(),
+            finished: chrono::Utc::now().to_rfc3339(),
+        }
+    }
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Signature {
+    keyid: String,
+    sig: String,
+    /// PEM of the key, to help consumers pin it. It is never trusted for
+    /// being there, only keys pinned beforehand are.
+    public_key: String,
+}
+
+/// A signed provenance document, in the DSSE envelope format.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Envelope {
+    payload_type: String,
+    payload: String,
+    signatures: Vec<Signature>,
+}
+
+/// What gets signed, the DSSE pre-authentication encoding of the payload.
+fn pae(payload_type: &str, payload: &[u8]) -> Vec<u8> {
+    let mut encoded = format!(
+        "DSSEv1 {} {} {} ",
+        payload_type.len(),
+        payload_type,
+        payload.len()
+    )
+    .into_bytes();
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+fn key_id(key: &PKey<Public>) -> Result<String, Error> {
+    Ok(sha256::digest(key.public_key_to_der()?))
+}
+
+/// The Ed25519 key of this builder, created the first time it is needed.
+fn signing_key(path: &Path) -> Result<PKey<Private>, Error> {
+    match fs::read(path) {
+        Ok(pem) => Ok(PKey::private_key_from_pem(&pem)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => create_signing_key(path),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Generates the key of this builder at `path`. The key is written aside and
+/// linked in place, so a build never reads a partly written key, and when
+/// builds run side by side the first key linked is the one they all use.
+fn create_signing_key(path: &Path) -> Result<PKey<Private>, Error> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    let key = PKey::generate_ed25519()?;
+    let tmp_dir = TempDir::new_in(parent, "provenance-key")?;
+    let tmp_path = tmp_dir.path().join("key.pem");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp_path)?
+        .write_all(&key.private_key_to_pem_pkcs8()?)?;
+
+    match fs::hard_link(&tmp_path, path) {
+        Ok(()) => Ok(key),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            Ok(PKey::private_key_from_pem(&fs::read(path)?)?)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn public_key(key: &PKey<Private>) -> Result<PKey<Public>, Error> {
+    Ok(PKey::public_key_from_der(&key.public_key_to_der()?)?)
+}
+
+fn sign_with(provenance: &Provenance, key: &PKey<Private>) -> Result<Envelope, Error> {
+    let payload = serde_json::to_vec(provenance)?;
+    let sig = Signer::new_without_digest(key)?.sign_oneshot_to_vec(&pae(PAYLOAD_TYPE, &payload))?;
+    let public_key = public_key(key)?;
+
+    Ok(Envelope {
+        payload_type: PAYLOAD_TYPE.to_string(),
+        payload: BASE64.encode(&payload),
+        signatures: vec![Signature {
+            keyid: key_id(&public_key)?,
+            sig: BASE64.encode(&sig),
+            public_key: String::from_utf8_lossy(&public_key.public_key_to_pem()?).into_owned(),
+        }],
+    })
+}
+
+/// Signs a provenance document with the key of this builder.
+pub fn sign(provenance: &Provenance) -> Result<Envelope, Error> {
+    sign_with(provenance, &signing_key(&get_key_path()?)?)
+}
+
+/// PEM of the public key of this builder, for consumers to pin.
+pub fn builder_public_key() -> Result<String, Error> {
+    let key = public_key(&signing_key(&get_key_path()?)?)?;
+    Ok(String::from_utf8_lossy(&key.public_key_to_pem()?).into_owned())
+}
+
+fn load_public_key(path: &Path) -> Result<PKey<Public>, Error> {
+    PKey::public_key_from_pem(&fs::read(path)?)
+        .map_err(|err| Error::Usage(format!("Invalid public key {}: {}", path.display(), err)))
+}
+
+/// Keys provenance is checked against: the one of this builder, the ones
+/// pinned in the trusted keys directory and the `extra` ones given.
+pub fn trusted_keys(extra: &[PathBuf]) -> Result<Vec<PKey<Public>>, Error> {
+    let mut keys = Vec::new();
+
+    match fs::read(get_key_path()?) {
+        Ok(pem) => keys.push(public_key(&PKey::private_key_from_pem(&pem)?)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut pinned = match fs::read_dir(get_trusted_keys_dir()?) {
+        Ok(entries) => entries
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<PathBuf>, std::io::Error>>()?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    pinned.retain(|path| path.extension().is_some_and(|extension| extension == "pem"));
+    pinned.sort();
+
+    for path in pinned.iter().chain(extra) {
+        keys.push(load_public_key(path)?);
+    }
+
+    Ok(keys)
+}
+
+/// Checks the signatures of an envelope against the `trusted` keys,
+/// returning the document they sign. Signatures by any other key, even one
+/// the envelope carries, are rejected.
+pub fn verify(envelope: &Envelope, trusted: &[PKey<Public>]) -> Result<Provenance, Error> {
+    let invalid = || Error::Other("Invalid provenance signature".to_string());
+    if envelope.payload_type != PAYLOAD_TYPE || envelope.signatures.is_empty() {
+        return Err(invalid());
+    }
+
+    let payload = BASE64
+        .decode(envelope.payload.as_bytes())
+        .map_err(|_| invalid())?;
+    for signature in &envelope.signatures {
+        let mut key = None;
+        for trusted_key in trusted {
+            if key_id(trusted_key)? == signature.keyid {
+                key = Some(trusted_key);
+            }
+        }
+        let key = key.ok_or_else(|| {
+            Error::Other(format!(
+                "Provenance signed by the untrusted key {}",
+                signature.keyid
+            ))
+        })?;
+        let sig = BASE64
+            .decode(signature.sig.as_bytes())
+            .map_err(|_| invalid())?;
+        if !Verifier::new_without_digest(key)?
+            .verify_oneshot(&sig, &pae(&envelope.payload_type, &payload))?
+        {
+            return Err(invalid());
+        }
+    }
+
+    Ok(serde_json::from_slice(&payload)?)
+}
+
+pub fn save(envelope: &Envelope, path: &Path) -> Result<(), Error> {
+    serde_json::to_writer_pretty(fs::File::create(path)?, envelope)?;
+    Ok(())
+}
+
+pub fn load(path: &Path, image: &str) -> Result<Envelope, Error> {
+    match fs::File::open(path) {
+        Ok(file) => Ok(serde_json::from_reader(file)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Err(Error::Other(format!(
+            "{} has no provenance, only built images have one",
+            image
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provenance() -> Provenance {
+        Provenance::new(
+            "abcd".to_string(),
+            "arm64".to_string(),
+            PathBuf::from("/src/kiosk/Bakerfile"),
+            "1234".to_string(),
+            vec![BaseImage::new(
+                "raspios_lite:bookworm-20240704".to_string(),
+                "arm64".to_string(),
+                "5678".to_string(),
+            )],
+            BTreeMap::from([("VERSION".to_string(), "1.2".to_string())]),
+            None,
+            chrono::Utc::now(),
+        )
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let key_path = tmp_dir.path().join("key.pem");
+        let provenance = provenance();
+
+        let key = signing_key(&key_path).unwrap();
+        let envelope = sign_with(&provenance, &key).unwrap();
+        assert_eq!(
+            verify(&envelope, &[public_key(&key).unwrap()]).unwrap(),
+            provenance
+        );
+
+        // The key is kept for the next builds
+        let again = sign_with(&provenance, &signing_key(&key_path).unwrap()).unwrap();
+        assert_eq!(again.signatures[0].keyid, envelope.signatures[0].keyid);
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_payload() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let key = signing_key(&tmp_dir.path().join("key.pem")).unwrap();
+        let mut envelope = sign_with(&provenance(), &key).unwrap();
+
+        let mut tampered = provenance();
+        tampered.sha256 = "ffff".to_string();
+        envelope.payload = BASE64.encode(&serde_json::to_vec(&tampered).unwrap());
+
+        assert!(verify(&envelope, &[public_key(&key).unwrap()]).is_err());
+    }
+
+    #[test]
+    fn test_signing_key_created_concurrently() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let key_path = tmp_dir.path().join("keys/key.pem");
+
+        // Another build linked its key first
+        let first = create_signing_key(&key_path).unwrap();
+        let second = create_signing_key(&key_path).unwrap();
+        assert_eq!(
+            public_key(&first).unwrap().public_key_to_der().unwrap(),
+            public_key(&second).unwrap().public_key_to_der().unwrap()
+        );
+        assert_eq!(
+            fs::read_dir(tmp_dir.path().join("keys")).unwrap().count(),
+            1
+        );
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let key_path = tmp_dir.path().join("key.pem");
+                std::thread::spawn(move || signing_key(&key_path).unwrap())
+            })
+            .collect();
+        let keys: Vec<_> = threads
+            .into_iter()
+            .map(|thread| public_key(&thread.join().unwrap()).unwrap())
+            .collect();
+        assert!(keys.iter().all(|key| key.public_eq(&keys[0])));
+    }
+
+    #[test]
+    fn test_verify_rejects_untrusted_key() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let key = signing_key(&tmp_dir.path().join("key.pem")).unwrap();
+        let forger = signing_key(&tmp_dir.path().join("forger.pem")).unwrap();
+
+        // Re-signed with another key, carrying that key along
+        let mut tampered = provenance();
+        tampered.sha256 = "ffff".to_string();
+        let envelope = sign_with(&tampered, &forger).unwrap();
+
+        assert!(verify(&envelope, &[public_key(&key).unwrap()]).is_err());
+        assert!(verify(&envelope, &[]).is_err());
+    }
+
+    #[test]
+    fn test_load_public_key() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let key = signing_key(&tmp_dir.path().join("key.pem")).unwrap();
+        let pem_path = tmp_dir.path().join("builder.pem");
+        fs::write(
+            &pem_path,
+            public_key(&key).unwrap().public_key_to_pem().unwrap(),
+        )
+        .unwrap();
+
+        let loaded = load_public_key(&pem_path).unwrap();
+        assert_eq!(
+            key_id(&loaded).unwrap(),
+            key_id(&public_key(&key).unwrap()).unwrap()
+        );
+
+        // A private key is not mistaken for a public one
+        assert!(matches!(
+            load_public_key(&tmp_dir.path().join("key.pem")),
+            Err(Error::Usage(_))
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use regex::Regex;
+
+use crate::error::Error;
+use crate::images::download::{
+    modified_since, parse_apache_directory_listing, DownloadableBakerImage,
+};
+use crate::images::mirrors;
+use crate::images::retry;
+use crate::images::sources::ImageSource;
+use crate::images::BakerImage;
+use crate::progress;
+
+fn list_raspios_image_names(registry: &str) -> Result<Vec<(String, NaiveDateTime)>, Error> {
+    let body = retry::get(&format!(
+        "https://downloads.raspberrypi.org/{}/images/",
+        registry
+    ))?
+    .text()?;
+
+    Ok(parse_apache_directory_listing(&body)?
+        .into_iter()
+        .filter(|file| file.is_directory())
+        .map(|file| (file.name().to_string(), file.last_modified()))
+        .collect())
+}
+
+fn list_raspios_repositories() -> Result<Vec<(String, NaiveDateTime)>, Error> {
+    let body = retry::get("https://downloads.raspberrypi.org/")?.text()?;
+
+    Ok(parse_apache_directory_listing(&body)?
+        .into_iter()
+        .filter(|file| file.is_directory() && file.name().starts_with("raspios"))
+        .map(|file| (file.name().to_string(), file.last_modified()))
+        .collect())
+}
+
+fn get_raspios_images(registry: &str, image_name: &str) -> Result<DownloadableBakerImage, Error> {
+    let body = retry::get(&format!(
+        "https://downloads.raspberrypi.org/{}/images/{}/",
+        registry, image_name
+    ))?
+    .text()?;
+
+    let files: Vec<String> = parse_apache_directory_listing(&body)?
+        .into_iter()
+        .filter(|file| !file.is_directory())
+        .map(|file| file.name().to_string())
+        .collect();
+
+    let filename = files
+        .iter()
+        .find(|file| file.ends_with(".zip") || file.ends_with(".xz"))
+        .ok_or_else(|| Error::Other("No image url found".to_string()))?;
+
+    let sha256_url = files
+        .iter()
+        .find(|file| file.ends_with(".sha256"))
+        .ok_or_else(|| Error::Other("No sha256 url found".to_string()))?;
+
+    let sha256 = retry::get(&format!(
+        "https://downloads.raspberrypi.org/{}/images/{}/{}",
+        registry, image_name, sha256_url
+    ))?
+    .text()?
+    .split_whitespace()
+    .next()
+    .ok_or_else(|| Error::Other("No sha256 found".to_string()))?
+    .to_string();
+
+    let (name, tag, platform) =
+        match Regex::new(r"(\d{4}-\d{2}-\d{2})-(\w+)-(\w+)-(\w+)(?:-(\w+))?")?
+            .captures(filename)
+            .ok_or_else(|| Error::Other("Invalid filename".to_string()))?
+            .iter()
+            .collect::<Vec<_>>()
+            .as_slice()
+        {
+            [Some(_), Some(date), Some(name), Some(version), Some(platform), feature] => {
+                let date_concat = date.as_str().replace("-", "");
+                let tag = match feature {
+                    Some(feature) => {
+                        format!("{}-{}-{}", version.as_str(), date_concat, feature.as_str())
+                    }
+                    None => format!("{}-{}", version.as_str(), date_concat),
+                };
+                (name.as_str(), tag, platform.as_str())
+            }
+            _ => {
+                return Err(Error::Other("Invalid image file".to_string()));
+            }
+        };
+
+    let url = format!(
+        "https://downloads.raspberrypi.org/{}/images/{}/{}",
+        registry, image_name, filename
+    );
+
+    Ok(DownloadableBakerImage::new(
+        url,
+        BakerImage {
+            platform: platform.to_string(),
+            name: name.to_string(),
+            tag,
+            sha256,
+            history: Vec::new(),
+            labels: Default::default(),
+        },
+    ))
+}
+
+/// Images published since `date`, as `repository/image` entries.
+fn list_raspios_image_entries(date: Option<NaiveDateTime>) -> Result<Vec<(String, String)>, Error> {
+    let mut entries = Vec::new();
+
+    for repository in modified_since(list_raspios_repositories()?, date) {
+        let image_names = match list_raspios_image_names(&repository) {
+            Ok(image_names) => image_names,
+            Err(err) => {
+                if progress::is_verbose() {
+                    eprintln!("Skipping repository {}: {}", repository, err);
+                }
+                continue;
+            }
+        };
+
+        for image_name in modified_since(image_names, date) {
+            entries.push((repository.clone(), image_name));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Raspberry Pi OS images, from downloads.raspberrypi.org. Its repositories
+/// are the images of a repository there, like
+/// `raspios_lite_arm64/raspios_lite_arm64-2024-07-04`.
+pub struct RaspiosSource;
+
+impl ImageSource for RaspiosSource {
+    fn name(&self) -> &'static str {
+        "raspios"
+    }
+    fn list_repositories(&self, date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+        Ok(list_raspios_image_entries(date)?
+            .into_iter()
+            .map(|(repository, image_name)| format!("{}/{}", repository, image_name))
+            .collect())
+    }
+    fn list_images(&self, repository: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+        let (repository, image_name) = repository
+            .split_once('/')
+            .ok_or_else(|| Error::Other("Invalid Raspberry Pi OS repository".to_string()))?;
+        Ok(vec![get_raspios_images(repository, image_name)?])
+    }
+    fn download(&self, image: &DownloadableBakerImage, image_path: &Path) -> Result<(), Error> {
+        mirrors::download_archive(
+            &self.resolve_download(image)?,
+            image_path,
+            image.image().sha256(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_raspios_registries() {
+        let registries = list_raspios_repositories()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<String>>();
+
+        assert!(registries.contains(&"raspios_arm64".to_string()));
+        assert!(registries.contains(&"raspios_armhf".to_string()));
+        assert!(registries.contains(&"raspios_full_arm64".to_string()));
+        assert!(registries.contains(&"raspios_full_armhf".to_string()));
+        assert!(registries.contains(&"raspios_lite_arm64".to_string()));
+        assert!(registries.contains(&"raspios_lite_armhf".to_string()));
+        assert!(registries.contains(&"raspios_oldstable_arm64".to_string()));
+        assert!(registries.contains(&"raspios_oldstable_armhf".to_string()));
+        assert!(registries.contains(&"raspios_oldstable_lite_arm64".to_string()));
+        assert!(registries.contains(&"raspios_oldstable_lite_armhf".to_string()));
+        assert!(registries.contains(&"raspios_oldstable_full_arm64".to_string()));
+        assert!(registries.contains(&"raspios_oldstable_full_armhf".to_string()));
+    }
+}
@@ -0,0 +1,404 @@
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+use serde::Deserialize;
+use url::Url;
+
+use crate::error::Error;
+use crate::get_app_dir;
+use crate::images::download::{parse_apache_directory_listing, DownloadableBakerImage};
+use crate::images::retry;
+use crate::images::sources::ImageSource;
+use crate::images::BakerImage;
+
+fn get_registries_path() -> Result<PathBuf, Error> {
+    Ok(get_app_dir()?.join("registries.toml"))
+}
+
+/// A `registries.toml`, declaring registries hosting images besides the
+/// distributions.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistriesFile {
+    #[serde(default)]
+    registries: Vec<Registry>,
+    /// Bases mirroring downloads.raspberrypi.org, tried in order
+    #[serde(default)]
+    mirrors: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Registry {
+    /// Prefix of the names of its images, as in `acme/golden-base`
+    name: String,
+    url: Option<String>,
+    /// Local or NFS directory holding a `manifest.json`, instead of `url`
+    path: Option<PathBuf>,
+    #[serde(default)]
+    format: RegistryFormat,
+    /// Platform of the images of a listing, which does not tell it
+    #[serde(default = "default_platform")]
+    platform: String,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RegistryFormat {
+    /// `url` is a JSON manifest of the images, or `path` the directory
+    /// holding one
+    #[default]
+    Manifest,
+    /// `url` is an Apache-style listing of `NAME/TAG/` directories, each
+    /// holding an archive and its `.sha256`
+    Listing,
+}
+
+fn default_platform() -> String {
+    "arm64".to_string()
+}
+
+/// Manifest of a local registry, in its directory.
+const LOCAL_MANIFEST: &str = "manifest.json";
+
+impl Registry {
+    fn url(&self) -> Result<Url, Error> {
+        match (&self.url, &self.path, &self.format) {
+            (Some(url), None, RegistryFormat::Manifest) => Ok(Url::parse(url)?),
+            // Entries of the listing are joined to it as to a directory
+            (Some(url), None, RegistryFormat::Listing) => {
+                Ok(Url::parse(&format!("{}/", url.trim_end_matches('/')))?)
+            }
+            (None, Some(path), RegistryFormat::Manifest) => Url::from_directory_path(path)
+                .map_err(|_| {
+                    Error::Usage(format!(
+                        "Registry {} path must be absolute: {}",
+                        self.name,
+                        path.display()
+                    ))
+                })?
+                .join(LOCAL_MANIFEST)
+                .map_err(Error::from),
+            (None, Some(_), RegistryFormat::Listing) => Err(Error::Usage(format!(
+                "Registry {} is a local directory, which needs a manifest",
+                self.name
+            ))),
+            _ => Err(Error::Usage(format!(
+                "Registry {} needs either a url or a path",
+                self.name
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    images: Vec<ManifestImage>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestImage {
+    name: String,
+    tag: String,
+    #[serde(default = "default_platform")]
+    platform: String,
+    /// Archive of the image, relative to the manifest
+    url: String,
+    sha256: String,
+}
+
+fn parse_registries_file(contents: &str) -> Result<RegistriesFile, Error> {
+    toml::from_str(contents)
+        .map_err(|err| Error::Usage(format!("Invalid registries file: {}", err)))
+}
+
+fn load_registries_file() -> Result<RegistriesFile, Error> {
+    match fs::read_to_string(get_registries_path()?) {
+        Ok(contents) => parse_registries_file(&contents),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(RegistriesFile::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn load_registries() -> Result<Vec<Registry>, Error> {
+    Ok(load_registries_file()?.registries)
+}
+
+pub(super) fn load_mirrors() -> Result<Vec<String>, Error> {
+    Ok(load_registries_file()?.mirrors)
+}
+
+fn registry_image(
+    registry: &str,
+    name: &str,
+    tag: &str,
+    platform: &str,
+    sha256: &str,
+) -> BakerImage {
+    BakerImage {
+        platform: platform.to_string(),
+        name: format!("{}/{}", registry, name),
+        tag: tag.to_string(),
+        sha256: sha256.to_lowercase(),
+        history: Vec::new(),
+        labels: Default::default(),
+    }
+}
+
+/// Images of a manifest, named after the registry as `acme/golden-base`.
+fn parse_manifest(
+    registry: &str,
+    manifest_url: &Url,
+    body: &str,
+) -> Result<Vec<DownloadableBakerImage>, Error> {
+    let manifest: Manifest = serde_json::from_str(body)?;
+
+    manifest
+        .images
+        .into_iter()
+        .map(|image| {
+            Ok(DownloadableBakerImage::new(
+                manifest_url.join(&image.url)?.to_string(),
+                registry_image(
+                    registry,
+                    &image.name,
+                    &image.tag,
+                    &image.platform,
+                    &image.sha256,
+                ),
+            ))
+        })
+        .collect()
+}
+
+fn list_directories(client: &reqwest::blocking::Client, url: &Url) -> Result<Vec<String>, Error> {
+    let body = retry::send(client.get(url.clone()))?
+        .error_for_status()?
+        .text()?;
+    Ok(parse_apache_directory_listing(&body)?
+        .into_iter()
+        .filter(|file| file.is_directory())
+        .map(|file| file.name().to_string())
+        .collect())
+}
+
+fn get_listing_images(registry: &Registry) -> Result<Vec<DownloadableBakerImage>, Error> {
+    let client = reqwest::blocking::Client::new();
+    let mut downloadable_images = Vec::new();
+
+    let url = registry.url()?;
+
+    for name in list_directories(&client, &url)? {
+        let name_url = url.join(&format!("{}/", name))?;
+        for tag in list_directories(&client, &name_url)? {
+            let tag_url = name_url.join(&format!("{}/", tag))?;
+            let body = retry::send(client.get(tag_url.clone()))?.text()?;
+            let files: Vec<String> = parse_apache_directory_listing(&body)?
+                .into_iter()
+                .filter(|file| !file.is_directory())
+                .map(|file| file.name().to_string())
+                .collect();
+
+            let Some(archive) = files.iter().find(|file| {
+                [".zip", ".xz", ".7z"]
+                    .iter()
+                    .any(|extension| file.ends_with(extension))
+            }) else {
+                continue;
+            };
+            let sha256_file = format!("{}.sha256", archive);
+            if !files.contains(&sha256_file) {
+                continue;
+            }
+            let sha256 = retry::send(client.get(tag_url.join(&sha256_file)?))?
+                .error_for_status()?
+                .text()?
+                .split_whitespace()
+                .next()
+                .ok_or_else(|| Error::Other("No sha256 found".to_string()))?
+                .to_string();
+
+            downloadable_images.push(DownloadableBakerImage::new(
+                tag_url.join(archive)?.to_string(),
+                registry_image(&registry.name, &name, &tag, &registry.platform, &sha256),
+            ));
+        }
+    }
+
+    Ok(downloadable_images)
+}
+
+/// Path of a `file://` URL, which local registries point their images to.
+pub(super) fn local_path(url: &Url) -> Result<Option<PathBuf>, Error> {
+    if url.scheme() != "file" {
+        return Ok(None);
+    }
+    url.to_file_path()
+        .map(Some)
+        .map_err(|_| Error::Other(format!("Invalid local path {}", url)))
+}
+
+/// Registries declared in `registries.toml`, like the ones companies host
+/// their golden images on. Its repositories are the registries.
+pub struct RegistrySource;
+
+impl ImageSource for RegistrySource {
+    fn name(&self) -> &'static str {
+        "registry"
+    }
+    /// Registries are listed again every time, listings not telling which
+    /// images changed.
+    fn list_repositories(&self, _date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+        Ok(load_registries()?
+            .into_iter()
+            .map(|registry| registry.name)
+            .collect())
+    }
+    fn list_images(&self, repository: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+        let registry = load_registries()?
+            .into_iter()
+            .find(|registry| registry.name == repository)
+            .ok_or_else(|| Error::Other(format!("Unknown registry {}", repository)))?;
+
+        match registry.format {
+            RegistryFormat::Manifest => {
+                let url = registry.url()?;
+                let body = match local_path(&url)? {
+                    Some(path) => fs::read_to_string(path)?,
+                    None => retry::get(url.clone())?.error_for_status()?.text()?,
+                };
+                parse_manifest(&registry.name, &url, &body)
+            }
+            RegistryFormat::Listing => get_listing_images(&registry),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_registries_file() {
+        let registries = parse_registries_file(
+            r#"
+            [[registries]]
+            name = "acme"
+            url = "https://images.acme.example/baker/index.json"
+
+            [[registries]]
+            name = "lab"
+            url = "https://lab.example/images/"
+            format = "listing"
+            platform = "armhf"
+            "#,
+        )
+        .unwrap()
+        .registries;
+
+        assert_eq!(registries.len(), 2);
+        assert_eq!(registries[0].format, RegistryFormat::Manifest);
+        assert_eq!(registries[0].platform, "arm64");
+        assert_eq!(registries[1].format, RegistryFormat::Listing);
+        assert_eq!(registries[1].platform, "armhf");
+        assert_eq!(
+            registries[1].url().unwrap().join("base/").unwrap().as_str(),
+            "https://lab.example/images/base/"
+        );
+
+        assert!(matches!(
+            parse_registries_file("[[registries]]\nurl = \"https://acme.example\"\n"),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            parse_registries_file("[[registries]]\nname = \"acme\"\n")
+                .unwrap()
+                .registries[0]
+                .url(),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_mirrors() {
+        let registries_file = parse_registries_file(
+            "mirrors = [\"https://mirror.example/raspberrypi\", \"http://10.0.0.2/rpi\"]\n",
+        )
+        .unwrap();
+
+        assert!(registries_file.registries.is_empty());
+        assert_eq!(
+            registries_file.mirrors,
+            vec!["https://mirror.example/raspberrypi", "http://10.0.0.2/rpi"]
+        );
+    }
+
+    #[test]
+    fn test_local_registry() {
+        let registries = parse_registries_file(
+            r#"
+            [[registries]]
+            name = "local"
+            path = "/mnt/golden"
+
+            [[registries]]
+            name = "relative"
+            path = "golden"
+            "#,
+        )
+        .unwrap()
+        .registries;
+
+        let url = registries[0].url().unwrap();
+        assert_eq!(url.as_str(), "file:///mnt/golden/manifest.json");
+        assert_eq!(
+            local_path(&url.join("golden-base-2024-10.img.xz").unwrap()).unwrap(),
+            Some(PathBuf::from("/mnt/golden/golden-base-2024-10.img.xz"))
+        );
+        assert!(matches!(registries[1].url(), Err(Error::Usage(_))));
+        assert_eq!(
+            local_path(&Url::parse("https://acme.example/a.img.xz").unwrap()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest_url = Url::parse("https://images.acme.example/baker/index.json").unwrap();
+        let images = parse_manifest(
+            "acme",
+            &manifest_url,
+            r#"{"images": [
+                {
+                    "name": "golden-base",
+                    "tag": "2024-10",
+                    "url": "golden-base/2024-10.img.xz",
+                    "sha256": "ABCD"
+                },
+                {
+                    "name": "kiosk",
+                    "tag": "1.2",
+                    "platform": "armhf",
+                    "url": "https://cdn.acme.example/kiosk-1.2.zip",
+                    "sha256": "ef01"
+                }
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            images[0].url(),
+            "https://images.acme.example/baker/golden-base/2024-10.img.xz"
+        );
+        let image = images[0].image();
+        assert_eq!(
+            (image.name(), image.tag(), image.platform(), image.sha256()),
+            ("acme/golden-base", "2024-10", "arm64", "abcd")
+        );
+        assert_eq!(images[1].url(), "https://cdn.acme.example/kiosk-1.2.zip");
+        assert_eq!(images[1].image().platform(), "armhf");
+    }
+}
@@ -0,0 +1,160 @@
+use std::{fmt::Write as _, fs::File, path::Path, time::Duration};
+
+use serde::Serialize;
+
+use crate::{error::Error, size::format_size};
+
+/// Where the result of a step came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    Built,
+    Cached,
+    Resumed,
+}
+
+impl CacheStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Built => "built",
+            CacheStatus::Cached => "cached",
+            CacheStatus::Resumed => "resumed",
+        }
+    }
+}
+
+/// What applying an instruction of a stage took.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepReport {
+    stage: String,
+    instruction: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// Growth of the space used on the filesystems of the image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_written: Option<u64>,
+    cache: CacheStatus,
+}
+
+impl StepReport {
+    pub fn built(
+        stage: String,
+        instruction: String,
+        duration: Duration,
+        bytes_written: Option<u64>,
+    ) -> StepReport {
+        StepReport {
+            stage,
+            instruction,
+            duration_ms: Some(duration.as_millis() as u64),
+            bytes_written,
+            cache: CacheStatus::Built,
+        }
+    }
+    pub fn skipped(stage: String, instruction: String, cache: CacheStatus) -> StepReport {
+        StepReport {
+            stage,
+            instruction,
+            duration_ms: None,
+            bytes_written: None,
+            cache,
+        }
+    }
+}
+
+/// Timings of a whole build, step by step.
+#[derive(Debug, Serialize)]
+pub struct BuildReport {
+    image: String,
+    platform: String,
+    duration_ms: u64,
+    steps: Vec<StepReport>,
+}
+
+impl BuildReport {
+    pub fn new(
+        image: String,
+        platform: String,
+        duration: Duration,
+        steps: Vec<StepReport>,
+    ) -> BuildReport {
+        BuildReport {
+            image,
+            platform,
+            duration_ms: duration.as_millis() as u64,
+            steps,
+        }
+    }
+
+    /// The report as a table printed at the end of a build.
+    pub fn table(&self) -> String {
+        let mut table = format!(
+            "{:<12} {:<10} {:<10} {:<8} Instruction\n",
+            "Stage", "Duration", "Written", "Cache"
+        );
+        let seconds = |ms: u64| format!("{:.1}s", ms as f64 / 1000.0);
+        for step in &self.steps {
+            let _ = writeln!(
+                table,
+                "{:<12} {:<10} {:<10} {:<8} {}",
+                step.stage,
+                step.duration_ms.map_or("-".to_string(), seconds),
+                step.bytes_written.map_or("-".to_string(), format_size),
+                step.cache.as_str(),
+                step.instruction.lines().next().unwrap_or_default()
+            );
+        }
+        let _ = writeln!(table, "{:<12} {}", "Total", seconds(self.duration_ms));
+        table
+    }
+}
+
+pub fn save(report: &BuildReport, path: &Path) -> Result<(), Error> {
+    serde_json::to_writer_pretty(File::create(path)?, report)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_table_and_json() {
+        let report = BuildReport::new(
+            "kiosk:latest".to_string(),
+            "arm64".to_string(),
+            Duration::from_millis(75_300),
+            vec![
+                StepReport::skipped(
+                    "0".to_string(),
+                    "RUN apt-get update".to_string(),
+                    CacheStatus::Cached,
+                ),
+                StepReport::built(
+                    "0".to_string(),
+                    "COPY app /opt/app".to_string(),
+                    Duration::from_millis(1300),
+                    Some(2048),
+                ),
+            ],
+        );
+
+        assert_eq!(
+            report.table(),
+            "Stage        Duration   Written    Cache    Instruction\n\
+             0            -          -          cached   RUN apt-get update\n\
+             0            1.3s       2.0 KiB    built    COPY app /opt/app\n\
+             Total        75.3s\n"
+        );
+        assert_eq!(
+            serde_json::to_value(&report).unwrap()["steps"][1],
+            serde_json::json!({
+                "stage": "0",
+                "instruction": "COPY app /opt/app",
+                "duration_ms": 1300,
+                "bytes_written": 2048,
+                "cache": "built",
+            })
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use path_absolutize::*;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+fn get_resume_dir() -> Result<PathBuf, Error> {
+    Ok(crate::get_app_dir()?.join("resume"))
+}
+
+/// Directory of the failed stages of a build, each one having its own files
+/// as stages built side by side can fail at the same time.
+fn get_build_dir(id: &str) -> Result<PathBuf, Error> {
+    Ok(get_resume_dir()?.join(id))
+}
+
+fn stage_path(build_dir: &Path, stage: usize, extension: &str) -> PathBuf {
+    build_dir.join(format!("stage-{}.{}", stage, extension))
+}
+
+/// Where a failed build stopped, so that it can carry on from there.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeMarker {
+    stage: usize,
+    /// Instructions of the stage applied before the failing one
+    completed: usize,
+    /// Cache key of the image once they are applied, which changes if any of
+    /// them is edited
+    key: String,
+    image: PathBuf,
+}
+
+impl ResumeMarker {
+    pub fn new(stage: usize, completed: usize, key: String, image: PathBuf) -> ResumeMarker {
+        ResumeMarker {
+            stage,
+            completed,
+            key,
+            image,
+        }
+    }
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    pub fn image(&self) -> &Path {
+        &self.image
+    }
+}
+
+/// Identifies the builds of a Bakerfile for a platform.
+pub fn build_id(file: &Path, platform: Option<&str>) -> Result<String, Error> {
+    Ok(sha256::digest(format!(
+        "{}\0{}",
+        file.absolutize()?.display(),
+        platform.unwrap_or_default()
+    )))
+}
+
+/// Where the `stage` of a build failed, if it did.
+pub fn load(id: &str, stage: usize) -> Result<Option<ResumeMarker>, Error> {
+    load_from(&get_build_dir(id)?, stage)
+}
+
+fn load_from(build_dir: &Path, stage: usize) -> Result<Option<ResumeMarker>, Error> {
+    match File::open(stage_path(build_dir, stage, "json")) {
+        Ok(file) => Ok(Some(serde_json::from_reader(file)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Whether any stage of a build failed and can be resumed.
+pub fn has_failed(id: &str) -> Result<bool, Error> {
+    has_failed_in(&get_build_dir(id)?)
+}
+
+fn has_failed_in(build_dir: &Path) -> Result<bool, Error> {
+    let entries = match fs::read_dir(build_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        if entry?
+            .path()
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Moves the working image of a failed stage next to its marker.
+pub fn keep_image(id: &str, stage: usize, working_image: &Path) -> Result<PathBuf, Error> {
+    keep_image_in(&get_build_dir(id)?, stage, working_image)
+}
+
+fn keep_image_in(build_dir: &Path, stage: usize, working_image: &Path) -> Result<PathBuf, Error> {
+    fs::create_dir_all(build_dir)?;
+
+    let kept_path = stage_path(build_dir, stage, "img");
+    if fs::rename(working_image, &kept_path).is_err() {
+        fs::copy(working_image, &kept_path)?;
+    }
+
+    Ok(kept_path)
+}
+
+pub fn save(id: &str, marker: &ResumeMarker) -> Result<(), Error> {
+    save_in(&get_build_dir(id)?, marker)
+}
+
+fn save_in(build_dir: &Path, marker: &ResumeMarker) -> Result<(), Error> {
+    fs::create_dir_all(build_dir)?;
+
+    serde_json::to_writer_pretty(
+        File::create(stage_path(build_dir, marker.stage, "json"))?,
+        marker,
+    )?;
+
+    Ok(())
+}
+
+/// Forgets a failed stage once it got through, along with its image.
+pub fn remove_stage(id: &str, stage: usize) -> Result<(), Error> {
+    remove_stage_in(&get_build_dir(id)?, stage)
+}
+
+fn remove_stage_in(build_dir: &Path, stage: usize) -> Result<(), Error> {
+    for path in [
+        stage_path(build_dir, stage, "json"),
+        stage_path(build_dir, stage, "img"),
+    ] {
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Forgets a failed build once it got through, along with its images.
+pub fn remove(id: &str) -> Result<(), Error> {
+    remove_in(&get_build_dir(id)?)
+}
+
+fn remove_in(build_dir: &Path) -> Result<(), Error> {
+    match fs::remove_dir_all(build_dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_id() {
+        let file = Path::new("Bakerfile");
+        let absolute = std::env::current_dir().unwrap().join("Bakerfile");
+
+        assert_eq!(
+            build_id(file, None).unwrap(),
+            build_id(&absolute, None).unwrap()
+        );
+        assert_ne!(
+            build_id(file, None).unwrap(),
+            build_id(file, Some("arm64")).unwrap()
+        );
+        assert_ne!(
+            build_id(file, None).unwrap(),
+            build_id(Path::new("other/Bakerfile"), None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_markers_round_trip() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let build_dir = tmp_dir.path().join("build");
+        let working_image = tmp_dir.path().join("working.img");
+        fs::write(&working_image, "partially built").unwrap();
+
+        assert!(!has_failed_in(&build_dir).unwrap());
+        assert!(load_from(&build_dir, 1).unwrap().is_none());
+
+        let kept = keep_image_in(&build_dir, 1, &working_image).unwrap();
+        save_in(
+            &build_dir,
+            &ResumeMarker::new(1, 3, "key".to_string(), kept.clone()),
+        )
+        .unwrap();
+        save_in(
+            &build_dir,
+            &ResumeMarker::new(2, 0, "base".to_string(), PathBuf::new()),
+        )
+        .unwrap();
+
+        assert!(has_failed_in(&build_dir).unwrap());
+        let marker = load_from(&build_dir, 1).unwrap().unwrap();
+        assert_eq!(marker.completed(), 3);
+        assert_eq!(marker.key(), "key");
+        assert_eq!(
+            fs::read_to_string(marker.image()).unwrap(),
+            "partially built"
+        );
+
+        remove_stage_in(&build_dir, 1).unwrap();
+        assert!(load_from(&build_dir, 1).unwrap().is_none());
+        assert!(!kept.exists());
+        assert!(has_failed_in(&build_dir).unwrap());
+
+        remove_in(&build_dir).unwrap();
+        assert!(!has_failed_in(&build_dir).unwrap());
+        assert!(!build_dir.exists());
+        remove_in(&build_dir).unwrap();
+    }
+}
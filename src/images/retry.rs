@@ -0,0 +1,150 @@
+use std::thread::sleep;
+use std::time::Duration;
+
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::{IntoUrl, StatusCode};
+
+use crate::error::Error;
+use crate::progress;
+
+const DEFAULT_ATTEMPTS: u32 = 4;
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How often a request failing for a transient reason is sent again, waiting
+/// twice as long before each attempt.
+#[derive(Debug, PartialEq)]
+struct RetryPolicy {
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// The default policy, overridable with the `BAKER_RETRY_ATTEMPTS` and
+    /// `BAKER_RETRY_BACKOFF_MS` environment variables.
+    fn from_env() -> RetryPolicy {
+        RetryPolicy::parse(
+            std::env::var("BAKER_RETRY_ATTEMPTS").ok(),
+            std::env::var("BAKER_RETRY_BACKOFF_MS").ok(),
+        )
+    }
+    fn parse(attempts: Option<String>, backoff_ms: Option<String>) -> RetryPolicy {
+        RetryPolicy {
+            attempts: attempts
+                .and_then(|attempts| attempts.parse().ok())
+                .filter(|attempts| *attempts > 0)
+                .unwrap_or(DEFAULT_ATTEMPTS),
+            backoff: backoff_ms
+                .and_then(|backoff| backoff.parse().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_BACKOFF),
+        }
+    }
+    /// Wait before the attempt following the `failed`th one.
+    fn delay(&self, failed: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(failed.saturating_sub(1)))
+            .min(MAX_BACKOFF)
+    }
+}
+
+/// Statuses a mirror answers with when busy or restarting.
+fn is_transient_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+fn is_transient_error(err: &reqwest::Error) -> bool {
+    err.is_timeout() || err.is_connect() || err.is_request()
+}
+
+fn send_with(request: RequestBuilder, policy: &RetryPolicy) -> Result<Response, Error> {
+    let mut failed = 0;
+
+    loop {
+        let result = request
+            .try_clone()
+            .ok_or_else(|| Error::Other("Request cannot be retried".to_string()))?
+            .send();
+        let reason = match &result {
+            Ok(response) if is_transient_status(response.status()) => response.status().to_string(),
+            Err(err) if is_transient_error(err) => err.to_string(),
+            _ => return Ok(result?),
+        };
+
+        failed += 1;
+        if failed >= policy.attempts {
+            return Ok(result?);
+        }
+        let delay = policy.delay(failed);
+        if progress::is_verbose() {
+            eprintln!("Retrying in {}ms: {}", delay.as_millis(), reason);
+        }
+        sleep(delay);
+    }
+}
+
+/// Sends a request, sending it again when it fails for a transient reason
+/// such as a 502 from a mirror or a dropped connection.
+pub(super) fn send(request: RequestBuilder) -> Result<Response, Error> {
+    send_with(request, &RetryPolicy::from_env())
+}
+
+/// `reqwest::blocking::get` with the retries of `send`.
+pub(super) fn get<U: IntoUrl>(url: U) -> Result<Response, Error> {
+    send(reqwest::blocking::Client::new().get(url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::test_server::serve;
+
+    #[test]
+    fn test_retry_policy_from_env_values() {
+        assert_eq!(
+            RetryPolicy::parse(None, None),
+            RetryPolicy {
+                attempts: DEFAULT_ATTEMPTS,
+                backoff: DEFAULT_BACKOFF
+            }
+        );
+        assert_eq!(
+            RetryPolicy::parse(Some("0".to_string()), Some("250".to_string())),
+            RetryPolicy {
+                attempts: DEFAULT_ATTEMPTS,
+                backoff: Duration::from_millis(250)
+            }
+        );
+        assert_eq!(
+            RetryPolicy::parse(Some("2".to_string()), Some("soon".to_string())).attempts,
+            2
+        );
+    }
+
+    #[test]
+    fn test_retry_delay_doubles() {
+        let policy = RetryPolicy::parse(None, Some("500".to_string()));
+
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(1));
+        assert_eq!(policy.delay(3), Duration::from_secs(2));
+        assert_eq!(policy.delay(40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_send_retries_server_errors() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        ]);
+
+        let policy = RetryPolicy::parse(Some("3".to_string()), Some("1".to_string()));
+        let response = send_with(reqwest::blocking::Client::new().get(url), &policy).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().unwrap(), "ok");
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::Path;
+
+use chrono::NaiveDateTime;
+use url::Url;
+
+use crate::error::Error;
+use crate::images::alpine::AlpineSource;
+use crate::images::armbian::ArmbianSource;
+use crate::images::dietpi::DietPiSource;
+use crate::images::download::{download_archive, DownloadableBakerImage};
+use crate::images::imager::ImagerSource;
+use crate::images::raspios::RaspiosSource;
+use crate::images::registries::RegistrySource;
+use crate::images::ubuntu::UbuntuSource;
+
+/// A distribution publishing images to pull.
+pub trait ImageSource {
+    /// Name the images of the source are indexed under.
+    fn name(&self) -> &'static str;
+
+    /// Whatever the source groups its images by, only the ones changed since
+    /// `date` when given.
+    fn list_repositories(&self, date: Option<NaiveDateTime>) -> Result<Vec<String>, Error>;
+
+    /// Images of a repository listed by `list_repositories`.
+    fn list_images(&self, repository: &str) -> Result<Vec<DownloadableBakerImage>, Error>;
+
+    /// URL the archive of an indexed image is downloaded from.
+    fn resolve_download(&self, image: &DownloadableBakerImage) -> Result<Url, Error> {
+        Ok(Url::parse(image.url())?)
+    }
+
+    /// Writes the disk image of an indexed image to `image_path`.
+    fn download(&self, image: &DownloadableBakerImage, image_path: &Path) -> Result<(), Error> {
+        download_archive(
+            &self.resolve_download(image)?,
+            image_path,
+            image.image().sha256(),
+        )
+    }
+}
+
+/// Every source, in the order they get fetched.
+pub fn sources() -> Vec<Box<dyn ImageSource>> {
+    vec![
+        Box::new(RaspiosSource),
+        Box::new(UbuntuSource),
+        Box::new(DietPiSource),
+        Box::new(ArmbianSource::from_env()),
+        Box::new(AlpineSource),
+        Box::new(ImagerSource),
+        Box::new(RegistrySource),
+    ]
+}
+
+pub fn find_source(name: &str) -> Result<Box<dyn ImageSource>, Error> {
+    sources()
+        .into_iter()
+        .find(|source| source.name() == name)
+        .ok_or_else(|| Error::Other(format!("Unknown image source {}", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_names_are_unique() {
+        let mut names: Vec<&str> = sources().iter().map(|source| source.name()).collect();
+        names.sort();
+        names.dedup();
+
+        assert_eq!(names.len(), sources().len());
+        assert!(find_source("raspios").is_ok());
+        assert!(find_source("gentoo").is_err());
+    }
+}
@@ -0,0 +1,31 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::thread::{self, JoinHandle};
+
+use url::Url;
+
+/// Serves each response to a connection on a loopback port, giving back the
+/// URL of an image on it and the requests it received.
+pub(crate) fn serve<R: Into<Vec<u8>>>(responses: Vec<R>) -> (Url, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!(
+        "http://{}/image.img.xz",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+    let responses: Vec<Vec<u8>> = responses.into_iter().map(Into::into).collect();
+
+    let handle = thread::spawn(move || {
+        responses
+            .into_iter()
+            .map(|response| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 1024];
+                let read = stream.read(&mut request).unwrap();
+                stream.write_all(&response).unwrap();
+                String::from_utf8_lossy(&request[..read]).to_string()
+            })
+            .collect()
+    });
+    (url, handle)
+}
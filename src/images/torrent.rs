@@ -0,0 +1,198 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tempdir::TempDir;
+use url::Url;
+
+use crate::error::Error;
+use crate::images::download;
+use crate::images::retry;
+use crate::progress;
+
+/// Archives from this size on are worth the overhead of joining a swarm.
+const TORRENT_MIN_SIZE: u64 = 512 * 1024 * 1024;
+
+const ARIA2C: &str = "aria2c";
+
+/// Downloads the torrent published next to an archive with `aria2c`, giving
+/// back the directory in `dir` it was downloaded to and the archive, or
+/// nothing when the archive is small or has no torrent.
+fn download_torrent(
+    url: &Url,
+    dir: &Path,
+    aria2c: &str,
+) -> Result<Option<(TempDir, PathBuf)>, Error> {
+    let client = reqwest::blocking::Client::new();
+
+    // The header is read as is, `content_length` being the one of the empty
+    // body of the answer
+    let size = retry::send(client.head(url.clone()))?
+        .error_for_status()?
+        .headers()
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if size.is_none_or(|size| size < TORRENT_MIN_SIZE) {
+        return Ok(None);
+    }
+
+    let response = retry::send(client.get(format!("{}.torrent", url)))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let torrent = response.error_for_status()?.bytes()?;
+
+    // Next to the images, /tmp often being too small for them
+    fs::create_dir_all(dir)?;
+    let tmp_dir = TempDir::new_in(dir, "baker-torrent")?;
+    let torrent_path = tmp_dir.path().join("image.torrent");
+    fs::write(&torrent_path, torrent)?;
+
+    let status = Command::new(aria2c)
+        .args([
+            "--seed-time=0",
+            "--summary-interval=0",
+            "--console-log-level=warn",
+        ])
+        .arg("--dir")
+        .arg(tmp_dir.path())
+        .arg(&torrent_path)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(_) => {
+            return Err(Error::Other(
+                "aria2c failed to download the torrent".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::Other(
+                "Torrent downloads need the aria2c command".to_string(),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    let filename = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .ok_or_else(|| Error::Other("Invalid url".to_string()))?;
+    let archive = tmp_dir.path().join(filename);
+    if !archive.is_file() {
+        return Err(Error::Other(format!("Torrent did not hold {}", filename)));
+    }
+
+    Ok(Some((tmp_dir, archive)))
+}
+
+fn download_archive_with(
+    url: &Url,
+    image_path: &Path,
+    sha256: &str,
+    aria2c: &str,
+) -> Result<(), Error> {
+    let dir = image_path
+        .parent()
+        .ok_or_else(|| Error::Other("Invalid image path".to_string()))?;
+    match download_torrent(url, dir, aria2c) {
+        Ok(Some((_tmp_dir, archive))) => download::download_archive(
+            &Url::from_file_path(&archive)
+                .map_err(|_| Error::Other("Invalid archive path".to_string()))?,
+            image_path,
+            sha256,
+        ),
+        Ok(None) => download::download_archive(url, image_path, sha256),
+        Err(err) => {
+            if progress::is_verbose() {
+                eprintln!("Falling back to HTTP for {}: {}", url, err);
+            }
+            download::download_archive(url, image_path, sha256)
+        }
+    }
+}
+
+/// Downloads a large archive through the torrent the Raspberry Pi
+/// Foundation publishes next to it, which is faster and spares its mirrors.
+/// Anything going wrong with the torrent falls back to HTTP.
+pub(super) fn download_archive(url: &Url, image_path: &Path, sha256: &str) -> Result<(), Error> {
+    download_archive_with(url, image_path, sha256, ARIA2C)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::images::test_server::serve;
+
+    /// aria2c as if it was not installed.
+    const MISSING_ARIA2C: &str = "baker-missing-aria2c";
+
+    fn head(size: u64) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            size
+        )
+    }
+
+    #[test]
+    fn test_small_archives_are_not_torrented() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let (url, server) = serve(vec![head(TORRENT_MIN_SIZE - 1)]);
+
+        assert!(download_torrent(&url, tmp_dir.path(), MISSING_ARIA2C)
+            .unwrap()
+            .is_none());
+        assert!(server.join().unwrap()[0].starts_with("HEAD /image.img.xz "));
+    }
+
+    #[test]
+    fn test_missing_torrent_is_skipped() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let (url, server) = serve(vec![
+            head(TORRENT_MIN_SIZE),
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+        ]);
+
+        assert!(download_torrent(&url, tmp_dir.path(), MISSING_ARIA2C)
+            .unwrap()
+            .is_none());
+        assert!(server.join().unwrap()[1].starts_with("GET /image.img.xz.torrent "));
+    }
+
+    #[test]
+    fn test_missing_aria2c_fails() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let (url, server) = serve(vec![
+            head(TORRENT_MIN_SIZE),
+            "HTTP/1.1 200 OK\r\nContent-Length: 7\r\nConnection: close\r\n\r\ntorrent".to_string(),
+        ]);
+
+        let err = download_torrent(&url, tmp_dir.path(), MISSING_ARIA2C).unwrap_err();
+        server.join().unwrap();
+        assert!(err.to_string().contains("aria2c"));
+        // The torrent was downloaded next to the images, and cleaned up
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_download_archive_falls_back_to_http() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let content = b"raspberry pi image content".repeat(16);
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+        io::Write::write_all(&mut encoder, &content).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let archive_path = tmp_dir.path().join("image.img.xz");
+        fs::write(&archive_path, &compressed).unwrap();
+
+        // A local archive has no torrent, which fails and falls back
+        let image_path = tmp_dir.path().join("images/image.img");
+        download_archive_with(
+            &Url::from_file_path(&archive_path).unwrap(),
+            &image_path,
+            &sha256::digest(compressed.as_slice()),
+            MISSING_ARIA2C,
+        )
+        .unwrap();
+        assert_eq!(fs::read(&image_path).unwrap(), content);
+    }
+}
@@ -0,0 +1,143 @@
+use chrono::NaiveDateTime;
+use regex::Regex;
+
+use crate::error::Error;
+use crate::images::download::{
+    modified_since, parse_apache_directory_listing, parse_sha256sums, DownloadableBakerImage,
+};
+use crate::images::retry;
+use crate::images::sources::ImageSource;
+use crate::images::BakerImage;
+
+const UBUNTU_RELEASES_URL: &str = "https://cdimage.ubuntu.com/releases";
+
+/// Releases published since `date`, named by version like `24.04`. The
+/// directories named after the release codenames are the same releases.
+fn list_ubuntu_releases(date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+    let body = retry::get(format!("{}/", UBUNTU_RELEASES_URL))?.text()?;
+    let version = Regex::new(r"^\d+\.\d+(?:\.\d+)?$")?;
+
+    Ok(modified_since(
+        parse_apache_directory_listing(&body)?
+            .into_iter()
+            .filter(|file| file.is_directory() && version.is_match(file.name()))
+            .map(|file| (file.name().to_string(), file.last_modified()))
+            .collect(),
+        date,
+    ))
+}
+
+/// Preinstalled Raspberry Pi image file of a release, parsed as its
+/// version, flavour and platform.
+fn parse_image_filename(filename: &str) -> Option<(Vec<u32>, &str, &str)> {
+    let captures = Regex::new(
+        r"^ubuntu-(\d+\.\d+(?:\.\d+)?)-preinstalled-(server|desktop)-(arm64|armhf)\+raspi\.img\.xz$",
+    )
+    .ok()?
+    .captures(filename)?;
+
+    let version = captures[1]
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<Vec<u32>, _>>()
+        .ok()?;
+    Some((
+        version,
+        captures.get(2)?.as_str(),
+        captures.get(3)?.as_str(),
+    ))
+}
+
+/// Images of a release, as `ubuntu-server:24.04` and `ubuntu-desktop:24.04`.
+/// The directory of a release holds its latest point release.
+fn get_ubuntu_images(release: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+    let release_url = format!("{}/{}/release", UBUNTU_RELEASES_URL, release);
+    let body = retry::get(format!("{}/", release_url))?.text()?;
+    let files: Vec<String> = parse_apache_directory_listing(&body)?
+        .into_iter()
+        .filter(|file| !file.is_directory())
+        .map(|file| file.name().to_string())
+        .collect();
+
+    let mut newest: Vec<(Vec<u32>, &str, &str, &str)> = Vec::new();
+    for filename in &files {
+        let Some((version, flavour, platform)) = parse_image_filename(filename) else {
+            continue;
+        };
+        match newest
+            .iter_mut()
+            .find(|(_, other_flavour, other_platform, _)| {
+                (*other_flavour, *other_platform) == (flavour, platform)
+            }) {
+            Some(entry) if entry.0 < version => *entry = (version, flavour, platform, filename),
+            Some(_) => {}
+            None => newest.push((version, flavour, platform, filename)),
+        }
+    }
+    if newest.is_empty() {
+        return Err(Error::Other("No Raspberry Pi image found".to_string()));
+    }
+
+    let sha256sums = parse_sha256sums(&retry::get(format!("{}/SHA256SUMS", release_url))?.text()?);
+
+    newest
+        .into_iter()
+        .map(|(_, flavour, platform, filename)| {
+            let sha256 = sha256sums
+                .get(filename)
+                .ok_or_else(|| Error::Other(format!("No sha256 found for {}", filename)))?;
+            Ok(DownloadableBakerImage::new(
+                format!("{}/{}", release_url, filename),
+                BakerImage {
+                    platform: platform.to_string(),
+                    name: format!("ubuntu-{}", flavour),
+                    tag: release.to_string(),
+                    sha256: sha256.clone(),
+                    history: Vec::new(),
+                    labels: Default::default(),
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Ubuntu images for the Raspberry Pi, from cdimage.ubuntu.com. Its
+/// repositories are the releases.
+pub struct UbuntuSource;
+
+impl ImageSource for UbuntuSource {
+    fn name(&self) -> &'static str {
+        "ubuntu"
+    }
+    fn list_repositories(&self, date: Option<NaiveDateTime>) -> Result<Vec<String>, Error> {
+        list_ubuntu_releases(date)
+    }
+    fn list_images(&self, repository: &str) -> Result<Vec<DownloadableBakerImage>, Error> {
+        get_ubuntu_images(repository)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_image_filename() {
+        assert_eq!(
+            parse_image_filename("ubuntu-24.04.1-preinstalled-server-arm64+raspi.img.xz"),
+            Some((vec![24, 4, 1], "server", "arm64"))
+        );
+        assert_eq!(
+            parse_image_filename("ubuntu-22.04-preinstalled-desktop-armhf+raspi.img.xz"),
+            Some((vec![22, 4], "desktop", "armhf"))
+        );
+        assert_eq!(
+            parse_image_filename("ubuntu-24.04.1-live-server-arm64.iso"),
+            None
+        );
+        assert_eq!(
+            parse_image_filename("ubuntu-24.04.1-preinstalled-server-arm64+raspi.img.xz.zsync"),
+            None
+        );
+    }
+}
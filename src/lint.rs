@@ -0,0 +1,374 @@
+use std::{fs, path::Path};
+
+use glob::glob;
+use serde::Serialize;
+
+use crate::{
+    context::{BuildContext, SymlinkPolicy},
+    error::Error,
+    parsing::{
+        self,
+        parser::{BakerFile, Instruction, RunCommand},
+    },
+};
+
+/// Users found in stock images, which a Bakerfile may switch to without
+/// creating them.
+const SYSTEM_USERS: &[&str] = &["root", "daemon", "bin", "sys", "nobody", "www-data"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    severity: Severity,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instruction: Option<String>,
+}
+
+impl Problem {
+    fn new(severity: Severity, message: String, instruction: &Instruction) -> Problem {
+        Problem {
+            severity,
+            message,
+            line: None,
+            column: None,
+            instruction: Some(instruction.to_string()),
+        }
+    }
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Whether `pattern` matches nothing in the build context once what
+/// `.bakerignore` excludes is left out, as when building. Patterns using
+/// variables are assumed to match since their value is only known when
+/// building.
+fn matches_nothing(context: &BuildContext, pattern: &str) -> bool {
+    if pattern.contains('$') {
+        return false;
+    }
+    let pattern = context.root().join(pattern);
+    pattern.to_str().is_none_or(|pattern| {
+        glob(pattern).map_or(true, |paths| {
+            paths.flatten().all(|path| context.is_ignored(&path))
+        })
+    })
+}
+
+/// Users created by USERADD or by a `useradd`/`adduser` in a RUN.
+fn created_users(instructions: &[Instruction], users: &mut Vec<String>) {
+    for instruction in instructions {
+        match instruction {
+            Instruction::USERADD(user) => users.push(user.name.clone()),
+            Instruction::RUN(_, RunCommand::Shell(script))
+            | Instruction::RUN(_, RunCommand::Heredoc { script, .. }) => {
+                let words: Vec<&str> = script.split_whitespace().collect();
+                for (index, word) in words.iter().enumerate() {
+                    if *word == "useradd" || *word == "adduser" {
+                        users.extend(
+                            words[index + 1..]
+                                .iter()
+                                .find(|word| !word.starts_with('-'))
+                                .map(|name| name.trim_matches(|ch| ch == '\'' || ch == '"'))
+                                .map(str::to_string),
+                        );
+                    }
+                }
+            }
+            Instruction::ONPLATFORM(_, instruction) => {
+                created_users(std::slice::from_ref(instruction.as_ref()), users)
+            }
+            Instruction::IF(_, instructions) => created_users(instructions, users),
+            _ => {}
+        }
+    }
+}
+
+fn lint_instructions(
+    context: &BuildContext,
+    instructions: &[Instruction],
+    aliases: &[String],
+    users: &[String],
+    problems: &mut Vec<Problem>,
+) {
+    for instruction in instructions {
+        let missing_sources = |sources: &[&str]| {
+            sources
+                .iter()
+                .filter(|source| matches_nothing(context, source))
+                .map(|source| {
+                    Problem::new(
+                        Severity::Error,
+                        format!("{} matches no file", source),
+                        instruction,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        match instruction {
+            Instruction::COPY(source, _, None) => problems.extend(missing_sources(&[source])),
+            Instruction::COPY(_, _, Some(stage))
+                if !aliases.contains(stage) && stage.parse::<usize>().is_err() =>
+            {
+                // Build falls back to pulling an image of that name
+                problems.push(Problem::new(
+                    Severity::Warning,
+                    format!(
+                        "COPY --from={} names no earlier stage, it is pulled as an image",
+                        stage
+                    ),
+                    instruction,
+                ));
+            }
+            Instruction::ADD(source, _, _) if !crate::add::is_url(source) => {
+                problems.extend(missing_sources(&[source]))
+            }
+            Instruction::DTOVERLAY(_, Some(dtbo)) => problems.extend(missing_sources(&[dtbo])),
+            Instruction::KERNEL(kernel, modules) => problems.extend(missing_sources(
+                &[Some(kernel), modules.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>(),
+            )),
+            Instruction::AUTHORIZED_KEYS(user, sources) => {
+                problems.extend(missing_sources(
+                    &sources.iter().map(String::as_str).collect::<Vec<_>>(),
+                ));
+                if let Some(user) = user.as_ref().filter(|user| !users.contains(user)) {
+                    problems.push(Problem::new(
+                        Severity::Warning,
+                        format!("user {} is never created", user),
+                        instruction,
+                    ));
+                }
+            }
+            Instruction::ENV(envs) => {
+                for (key, _) in envs.iter().filter(|(_, value)| value.is_empty()) {
+                    problems.push(Problem::new(
+                        Severity::Warning,
+                        format!("ENV {} has no value", key),
+                        instruction,
+                    ));
+                }
+            }
+            Instruction::USER(user) if !user.contains('$') && !users.contains(user) => {
+                problems.push(Problem::new(
+                    Severity::Warning,
+                    format!("user {} is never created", user),
+                    instruction,
+                ));
+            }
+            Instruction::ONPLATFORM(platform, gated) => {
+                if crate::images::check_platform(platform).is_err() {
+                    problems.push(Problem::new(
+                        Severity::Error,
+                        format!("unknown platform {}", platform),
+                        instruction,
+                    ));
+                }
+                lint_instructions(
+                    context,
+                    std::slice::from_ref(gated.as_ref()),
+                    aliases,
+                    users,
+                    problems,
+                );
+            }
+            Instruction::IF(_, instructions) => {
+                lint_instructions(context, instructions, aliases, users, problems)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Checks a parsed Bakerfile for mistakes the parser lets through, sources
+/// being looked up in the build `context`.
+pub fn lint_bakerfile(bakerfile: &BakerFile, context: &BuildContext) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut aliases = Vec::new();
+
+    for stage in &bakerfile.stages {
+        if stage.from.tag.is_none() {
+            problems.push(Problem {
+                severity: Severity::Warning,
+                message: format!(
+                    "FROM {} has no tag, the newest image is used",
+                    stage.from.image
+                ),
+                line: None,
+                column: None,
+                instruction: None,
+            });
+        }
+
+        let mut users: Vec<String> = SYSTEM_USERS.iter().map(|user| user.to_string()).collect();
+        created_users(&stage.instructions, &mut users);
+        lint_instructions(
+            context,
+            &stage.instructions,
+            &aliases,
+            &users,
+            &mut problems,
+        );
+
+        aliases.extend(stage.from.alias.clone());
+    }
+
+    problems
+}
+
+/// Parses the Bakerfile at `path` and lints it, a syntax error being the
+/// only problem reported when there is one.
+pub fn lint(path: &Path, context: &Path) -> Result<Vec<Problem>, Error> {
+    let contents = fs::read_to_string(path)?;
+    let context = BuildContext::new(context, SymlinkPolicy::Copy, u64::MAX, usize::MAX)?;
+
+    match parsing::parse_bakerfile(&contents) {
+        Ok(bakerfile) => Ok(lint_bakerfile(&bakerfile, &context)),
+        Err(Error::Parse { line, col, msg, .. }) => Ok(vec![Problem {
+            severity: Severity::Error,
+            message: msg,
+            line: Some(line),
+            column: Some(col),
+            instruction: None,
+        }]),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn print_report(path: &Path, problems: &[Problem]) {
+    for problem in problems {
+        let location = match (problem.line, problem.column) {
+            (Some(line), Some(column)) => format!("{}:{}:{}", path.display(), line, column),
+            _ => path.display().to_string(),
+        };
+        let severity = match problem.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match &problem.instruction {
+            Some(instruction) => println!(
+                "{}: {}: {} (in `{}`)",
+                location,
+                severity,
+                problem.message(),
+                instruction.lines().next().unwrap_or_default()
+            ),
+            None => println!("{}: {}: {}", location, severity, problem.message()),
+        }
+    }
+}
+
+/// Fails when any problem is an error, warnings alone passing.
+pub fn check(problems: &[Problem]) -> Result<(), Error> {
+    let errors = problems
+        .iter()
+        .filter(|problem| problem.severity() == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(Error::Usage(format!(
+            "Bakerfile has {} error{}",
+            errors,
+            if errors == 1 { "" } else { "s" }
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint_contents(contents: &str) -> Vec<Problem> {
+        lint_bakerfile(
+            &parsing::parse_bakerfile(contents).unwrap(),
+            &BuildContext::new(Path::new("."), SymlinkPolicy::Copy, u64::MAX, usize::MAX).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_lint_bakerfile() {
+        let problems = lint_contents(
+            "FROM raspios\n\
+             ENV EMPTY=\"\"\n\
+             COPY baker-surely-missing/* /opt/\n\
+             COPY --from=builder /app /app\n\
+             USER kiosk\n",
+        );
+        let messages: Vec<&str> = problems.iter().map(|problem| problem.message()).collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                "FROM raspios has no tag, the newest image is used",
+                "ENV EMPTY has no value",
+                "baker-surely-missing/* matches no file",
+                "COPY --from=builder names no earlier stage, it is pulled as an image",
+                "user kiosk is never created",
+            ]
+        );
+        assert!(check(&problems).is_err());
+    }
+
+    #[test]
+    fn test_lint_bakerfile_created_users() {
+        let problems = lint_contents(
+            "FROM raspios:bookworm AS builder\n\
+             FROM raspios:bookworm\n\
+             USERADD kiosk\n\
+             RUN useradd --system app\n\
+             COPY --from=builder /app /app\n\
+             USER kiosk\n\
+             USER app\n\
+             USER www-data\n",
+        );
+
+        assert!(problems.is_empty());
+        assert!(check(&problems).is_ok());
+    }
+
+    #[test]
+    fn test_lint_reports_syntax_errors() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("Bakerfile");
+        fs::write(&path, "FROM raspios:bookworm\nCOPPY a /b\n").unwrap();
+
+        let problems = lint(&path, tmp_dir.path()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].line, Some(2));
+        assert_eq!(problems[0].severity(), Severity::Error);
+    }
+
+    #[test]
+    fn test_lint_respects_bakerignore() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("Bakerfile");
+        fs::write(&path, "FROM raspios:bookworm\nCOPY *.conf /etc/\n").unwrap();
+        fs::write(tmp_dir.path().join("app.conf"), "a").unwrap();
+        assert!(lint(&path, tmp_dir.path()).unwrap().is_empty());
+
+        // What building would leave out is missing to lint too
+        fs::write(tmp_dir.path().join(".bakerignore"), "*.conf\n").unwrap();
+        let problems = lint(&path, tmp_dir.path()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].message, "*.conf matches no file");
+    }
+}
@@ -1,105 +1,1163 @@
 use clap::{Parser, Subcommand};
+use error::Error;
 use std::path::PathBuf;
 
+mod add;
+mod bake;
+mod burn;
+mod cleanup;
+mod context;
 mod copy;
+mod devices;
+mod doctor;
+mod error;
+mod hooks;
 mod images;
+mod lint;
 mod mount;
 mod parsing;
+mod partitions;
+mod privileges;
+mod progress;
 mod run;
+mod secrets;
+mod size;
+mod system;
+mod watch;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Print progress as plain lines instead of bars, for CI logs"
+    )]
+    plain: bool,
+
+    #[arg(long, global = true, help = "Print results and errors as JSON")]
+    json: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "URL",
+        help = "Download Raspberry Pi OS images from this mirror of downloads.raspberrypi.org, can be repeated to try several in order"
+    )]
+    mirror: Vec<String>,
 }
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 enum Commands {
     #[command(about = "Build an image from a Bakerfile")]
     Build {
+        #[arg(help = "Build context, a directory or a git repository as URL#REF:DIR")]
         path: String,
 
-        #[arg(short, long)]
+        #[arg(
+            short,
+            long,
+            help = "Bakerfile to use, relative to the context path, or - to read it from stdin"
+        )]
         file: Option<String>,
 
-        #[arg(short, long)]
-        output: Option<String>,
+        #[arg(short, long, help = "Also write the built image to this file")]
+        output: Option<PathBuf>,
 
-        #[arg(short, long)]
+        #[arg(short, long, value_name = "NAME[:TAG]")]
         tag: Option<String>,
+
+        #[arg(long, value_enum, default_value = "nspawn")]
+        run_env: run::Backend,
+
+        #[arg(long, required_if_eq("run_env", "vmspawn"))]
+        kernel: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Keep the working image for inspection when the build fails"
+        )]
+        keep_on_failure: bool,
+
+        #[arg(
+            long,
+            value_enum,
+            default_value = "contained",
+            help = "How COPY treats symlinks in the build context"
+        )]
+        symlinks: context::SymlinkPolicy,
+
+        #[arg(long, default_value = context::DEFAULT_MAX_SIZE, value_parser = size::parse_size, help = "Maximum total size COPY may ingest")]
+        max_context_size: u64,
+
+        #[arg(long, default_value_t = context::DEFAULT_MAX_FILES, help = "Maximum number of files COPY may ingest")]
+        max_context_files: usize,
+
+        #[arg(
+            long = "volume",
+            value_name = "HOST:CONTAINER[:ro]",
+            value_parser = run::BindMount::parse,
+            help = "Bind mount a host path while RUN instructions execute, can be repeated"
+        )]
+        volumes: Vec<run::BindMount>,
+
+        #[arg(
+            long = "secret",
+            value_name = "id=NAME,src=PATH",
+            value_parser = secrets::Secret::parse,
+            help = "Make a host file available to instructions reading secrets, can be repeated"
+        )]
+        secrets: Vec<secrets::Secret>,
+
+        #[arg(
+            long = "build-arg",
+            value_name = "KEY[=VALUE]",
+            value_parser = parsing::variables::parse_build_arg,
+            help = "Set the value of an ARG, can be repeated"
+        )]
+        build_args: Vec<(String, String)>,
+
+        #[arg(
+            long = "build-arg-file",
+            value_name = "FILE",
+            help = "Read ARG values from a .env style file, can be repeated, --build-arg taking precedence"
+        )]
+        build_arg_files: Vec<PathBuf>,
+
+        #[arg(
+            long,
+            value_name = "DOCKERFILE",
+            conflicts_with = "file",
+            help = "Build from a Dockerfile instead, relative to the context path or - for stdin, converting the instructions baker supports"
+        )]
+        from_dockerfile: Option<String>,
+
+        #[arg(
+            long,
+            help = "Run every instruction again instead of reusing cached snapshots, and refresh the image index unless every base image is already local"
+        )]
+        no_cache: bool,
+
+        #[arg(
+            long,
+            help = "Check upstream for a newer base image, such as a newer dated tag, and pull it first"
+        )]
+        pull: bool,
+
+        #[arg(
+            long,
+            value_name = "STAGE",
+            help = "Stop at the stage with this name, which gets tagged instead of the last one"
+        )]
+        target: Option<String>,
+
+        #[arg(
+            long,
+            value_delimiter = ',',
+            help = "Platforms to build for, comma separated, each getting the same name and tag"
+        )]
+        platform: Vec<String>,
+
+        #[arg(
+            short,
+            long,
+            default_value_t = 1,
+            help = "How many stages without dependencies between them to build at once"
+        )]
+        jobs: usize,
+
+        #[arg(
+            long,
+            conflicts_with = "no_cache",
+            help = "Carry on from the instruction where the last build of the file failed"
+        )]
+        resume: bool,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["resume", "output"],
+            help = "Print the steps of the build and which are cached, without running anything"
+        )]
+        dry_run: bool,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["resume", "dry_run"],
+            help = "Build again whenever the Bakerfile or a file of the context changes"
+        )]
+        watch: bool,
+
+        #[arg(
+            long,
+            value_name = "COMMAND",
+            help = "Run a command on the host before building, given the build as BAKER_* variables"
+        )]
+        hook_pre: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "COMMAND",
+            help = "Run a command on the host after building, given the image and BAKER_STATUS"
+        )]
+        hook_post: Option<String>,
+
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Write the duration, bytes written and cache status of every step to this file as JSON"
+        )]
+        report: Option<PathBuf>,
+    },
+    #[command(about = "Build the targets of a bake file")]
+    Bake {
+        #[arg(default_value = "bake.toml")]
+        file: PathBuf,
+
+        #[arg(help = "Targets to build, every one by default")]
+        targets: Vec<String>,
+
+        #[arg(long, value_enum, default_value = "nspawn")]
+        run_env: run::Backend,
+
+        #[arg(long, required_if_eq("run_env", "vmspawn"))]
+        kernel: Option<PathBuf>,
+
+        #[arg(
+            long,
+            help = "Run every instruction again instead of reusing cached snapshots, and refresh the image index unless every base image is already local"
+        )]
+        no_cache: bool,
+
+        #[arg(
+            long,
+            help = "Check upstream for a newer base image, such as a newer dated tag, and pull it first"
+        )]
+        pull: bool,
+
+        #[arg(
+            short,
+            long,
+            default_value_t = 1,
+            help = "How many targets, and stages within them, to build at once"
+        )]
+        jobs: usize,
+    },
+    #[command(about = "Check a Bakerfile for problems without building it")]
+    Lint {
+        #[arg(default_value = ".")]
+        path: String,
+
+        #[arg(short, long, help = "Bakerfile to use, relative to the context path")]
+        file: Option<String>,
     },
     #[command(about = "Pull an image")]
     Pull {
-        #[arg(value_name = "NAME:TAG")]
+        #[arg(
+            value_name = "NAME[:TAG]",
+            help = "Image to pull, a missing or `latest` tag resolves to the newest upstream release"
+        )]
         image: String,
 
+        #[arg(
+            short,
+            long,
+            help = "Platform to pull, can be repeated to pull several at once"
+        )]
+        platform: Vec<String>,
+
+        #[arg(
+            long,
+            help = "Re-hash a cached image instead of only checking it exists"
+        )]
+        verify: bool,
+    },
+    #[command(about = "Search the images available upstream")]
+    Search {
+        #[arg(help = "Terms that must all appear in the name or tag")]
+        terms: Vec<String>,
+
         #[arg(short, long)]
         platform: Option<String>,
     },
     #[command(about = "List images")]
-    Images {},
+    Images {
+        #[arg(
+            long,
+            value_name = "label=KEY[=VALUE]",
+            help = "Only list images with a matching label, can be repeated"
+        )]
+        filter: Vec<String>,
+    },
     #[command(about = "Remove an image")]
-    Rmi { image: String },
+    Rmi {
+        #[arg(
+            value_name = "NAME[:TAG]",
+            help = "Image to remove, the tag defaults to `latest`"
+        )]
+        image: String,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+    },
+    #[command(about = "Check stored images against their recorded checksums")]
+    Verify {
+        #[arg(
+            value_name = "NAME[:TAG]",
+            required_unless_present = "all",
+            conflicts_with = "all"
+        )]
+        image: Option<String>,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+
+        #[arg(long, help = "Verify every stored image")]
+        all: bool,
+    },
+    #[command(about = "Print detailed information about an image as JSON")]
+    Inspect {
+        #[arg(value_name = "NAME[:TAG]")]
+        image: String,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+
+        #[arg(
+            long,
+            help = "Print the signed document recording how the image was built instead"
+        )]
+        provenance: bool,
+
+        #[arg(
+            long,
+            value_name = "PEM",
+            requires = "provenance",
+            help = "Also trust provenance signed with this public key, can be repeated"
+        )]
+        trusted_key: Vec<PathBuf>,
+    },
+    #[command(
+        about = "Print the public key provenance is signed with, to pin in the trusted-keys directory of other machines"
+    )]
+    ProvenanceKey,
+    #[command(about = "Show the instructions that produced an image")]
+    History {
+        #[arg(value_name = "NAME[:TAG]")]
+        image: String,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+    },
+    #[command(about = "Show what the commands run to build an image printed")]
+    Logs {
+        #[arg(value_name = "NAME[:TAG]")]
+        image: String,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+    },
+    #[command(about = "Write an image out as a raw, xz or zip file")]
+    Export {
+        #[arg(value_name = "NAME[:TAG]")]
+        image: String,
+
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[arg(
+            long,
+            value_enum,
+            help = "Output format, guessed from the output extension by default"
+        )]
+        format: Option<images::ArchiveFormat>,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+    },
+    #[command(about = "Add a local .img, .img.xz or .zip file to the images")]
+    Import {
+        file: PathBuf,
+
+        #[arg(value_name = "NAME[:TAG]")]
+        image: String,
+
+        #[arg(
+            long,
+            value_enum,
+            help = "Input format, guessed from the file extension by default"
+        )]
+        format: Option<images::ArchiveFormat>,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+    },
+    #[command(about = "Give an image another name without copying it")]
+    Tag {
+        #[arg(value_name = "SOURCE[:TAG]")]
+        source: String,
+
+        #[arg(value_name = "TARGET[:TAG]")]
+        target: String,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+    },
+    #[command(about = "Delete image files no image refers to anymore and the build cache")]
+    Prune {
+        #[arg(long, help = "Only list the files that would be deleted")]
+        dry_run: bool,
+    },
+    #[command(about = "List the removable devices an image can be burnt to")]
+    Devices {
+        #[arg(long, help = "Also list fixed disks")]
+        all: bool,
+    },
     #[command(about = "Burn an image to a device")]
-    Burn { device_file: String, image: String },
+    Burn {
+        device_file: PathBuf,
+
+        #[arg(value_name = "NAME[:TAG]")]
+        image: String,
+
+        #[arg(short, long)]
+        platform: Option<String>,
+
+        #[arg(short, long, help = "Do not ask for confirmation")]
+        yes: bool,
+    },
+    #[command(about = "Check that the tools required to build images are available")]
+    Doctor {},
 }
 
-fn get_app_dir() -> Result<PathBuf, Box<dyn std::error::Error>> {
+fn get_app_dir() -> Result<PathBuf, Error> {
     Ok(dirs::config_local_dir()
-        .ok_or("Invalid config local directory")?
+        .ok_or_else(|| Error::Other("Invalid config local directory".to_string()))?
         .join("raspberrypi-baker"))
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Cli::parse();
+/// The Bakerfile is looked up inside the context unless an absolute path is given.
+fn bakerfile_path(context: &str, file: Option<&str>) -> PathBuf {
+    PathBuf::from(context).join(file.unwrap_or("Bakerfile"))
+}
+
+fn main() {
+    let args = match Cli::try_parse() {
+        Ok(args) => args,
+        // Scripts asking for JSON get the invalid arguments as JSON too
+        Err(err) if err.use_stderr() && std::env::args_os().any(|arg| arg == "--json") => {
+            let (message, code) =
+                error::report(&Error::Usage(err.to_string().trim_end().to_string()), true);
+            eprintln!("{}", message);
+            std::process::exit(code);
+        }
+        Err(err) => err.exit(),
+    };
+    let json = args.json;
+
+    progress::set_verbosity(match (args.quiet || json, args.verbose) {
+        (true, _) => progress::Verbosity::Quiet,
+        (_, true) => progress::Verbosity::Verbose,
+        _ => progress::Verbosity::Normal,
+    });
+    progress::set_plain(args.plain);
+    if !args.mirror.is_empty() {
+        images::set_mirrors(args.mirror.clone());
+    }
+
+    if let Err(err) = cleanup::install_signal_handler().and_then(|_| run(args)) {
+        let (message, code) = error::report(&err, json);
+        eprintln!("{}", message);
+        std::process::exit(code);
+    }
+}
 
+fn run(args: Cli) -> Result<(), Error> {
     match args.command {
-        Commands::Pull { image, platform } => {
-            let platform = platform.unwrap_or("arm64".to_string());
-            match image.split(":").collect::<Vec<&str>>().as_slice() {
-                [name, tag] => images::pull(&platform, name, tag),
-                _ => Err("Invalid image name".into()),
-            }?;
+        Commands::Pull {
+            image,
+            platform,
+            verify,
+        } => {
+            let platforms = if platform.is_empty() {
+                vec![images::default_platform()]
+            } else {
+                platform
+            };
+            let (name, tag) = images::parse_reference(&image)?;
+            let pulled = images::pull_platforms(&platforms, |platform| {
+                images::pull(platform, &name, &tag, verify)
+            })?;
+            if args.json {
+                match pulled.as_slice() {
+                    [image] => println!("{}", serde_json::to_string_pretty(image)?),
+                    _ => println!("{}", serde_json::to_string_pretty(&pulled)?),
+                }
+            }
             Ok(())
         }
-        Commands::Images {} => {
-            println!("{:<15} {:<30} {:<64}", "Repository", "Tag", "SHA256");
+        Commands::Search { terms, platform } => {
+            let found = images::search(&terms, platform.as_deref())?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&found)?);
+                return Ok(());
+            }
+
+            println!("{:<15} {:<30} {:<10}", "Repository", "Tag", "Platform");
+            for image in found {
+                println!(
+                    "{:<15} {:<30} {:<10}",
+                    image.name(),
+                    image.tag(),
+                    image.platform()
+                );
+            }
+            Ok(())
+        }
+        Commands::Images { filter } => {
+            let mut listed = Vec::new();
             for image in images::list()? {
+                let mut matched = true;
+                for filter in &filter {
+                    matched &= images::matches_filter(&image, filter)?;
+                }
+                if matched {
+                    listed.push(image);
+                }
+            }
+
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&listed)?);
+                return Ok(());
+            }
+
+            println!("{:<15} {:<30} {:<64} Labels", "Repository", "Tag", "SHA256");
+            for image in listed {
+                let labels: Vec<String> = image
+                    .labels()
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
                 println!(
-                    "{:<15} {:<30} {:<64}",
+                    "{:<15} {:<30} {:<64} {}",
                     image.name(),
                     image.tag(),
-                    image.sha256()
+                    image.sha256(),
+                    labels.join(",")
                 );
             }
             Ok(())
         }
-        Commands::Rmi { image } => {
-            let platform = "arm64";
-            match image.split(":").collect::<Vec<&str>>().as_slice() {
-                [name, tag] => images::rmi(platform, name, tag),
-                _ => Err("Invalid image name".into()),
+        Commands::Rmi { image, platform } => {
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&image)?;
+            images::rmi(&platform, &name, &tag)
+        }
+        Commands::Verify {
+            image, platform, ..
+        } => {
+            let images = match image {
+                Some(image) => {
+                    let platform = platform.unwrap_or_else(images::default_platform);
+                    let (name, tag) = images::parse_reference(&image)?;
+                    vec![images::find(&platform, &name, &tag)?]
+                }
+                None => images::list()?,
+            };
+            let statuses = images::verify(&images)?;
+
+            if args.json {
+                let results: Vec<_> = images
+                    .iter()
+                    .zip(&statuses)
+                    .map(|(image, status)| {
+                        serde_json::json!({
+                            "platform": image.platform(),
+                            "image": image.full_name(),
+                            "status": status,
+                        })
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                for (image, status) in images.iter().zip(&statuses) {
+                    println!("{:<8} {} ({})", status, image.full_name(), image.platform());
+                }
+            }
+
+            let failed = statuses
+                .iter()
+                .filter(|status| **status != images::VerifyStatus::Ok)
+                .count();
+            if failed > 0 {
+                return Err(Error::Other(format!(
+                    "{} of {} images are corrupt or missing",
+                    failed,
+                    statuses.len()
+                )));
+            }
+            Ok(())
+        }
+        Commands::Inspect {
+            image,
+            platform,
+            provenance,
+            trusted_key,
+        } => {
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&image)?;
+            let image = images::find(&platform, &name, &tag)?;
+            if provenance {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&images::provenance(&image, &trusted_key)?)?
+                );
+                return Ok(());
+            }
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&images::inspect(&image)?)?
+            );
+            Ok(())
+        }
+        Commands::ProvenanceKey => {
+            print!("{}", images::provenance_key()?);
+            Ok(())
+        }
+        Commands::History { image, platform } => {
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&image)?;
+            let history = images::find(&platform, &name, &tag)?.history();
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&history)?);
+                return Ok(());
             }
+
+            println!("{:<10} {:<14} Instruction", "Duration", "Digest");
+            for entry in history {
+                let duration = match entry.duration() {
+                    Some(duration) => format!("{:.1}s", duration.as_secs_f64()),
+                    None => "-".to_string(),
+                };
+                let digest = entry
+                    .digest()
+                    .map_or("-", |digest| &digest[..12.min(digest.len())]);
+                println!("{:<10} {:<14} {}", duration, digest, entry.instruction());
+            }
+            Ok(())
+        }
+        Commands::Logs { image, platform } => {
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&image)?;
+            print!(
+                "{}",
+                images::build_log(&images::find(&platform, &name, &tag)?)?
+            );
+            Ok(())
         }
         Commands::Build {
             path,
             file,
             output,
             tag,
+            run_env,
+            kernel,
+            keep_on_failure,
+            symlinks,
+            max_context_size,
+            max_context_files,
+            volumes,
+            secrets,
+            build_args,
+            build_arg_files,
+            from_dockerfile,
+            no_cache,
+            pull,
+            target,
+            platform,
+            jobs,
+            resume,
+            dry_run,
+            watch,
+            hook_pre,
+            hook_post,
+            report,
         } => {
-            let file = file.unwrap_or("Bakerfile".to_string());
-            let filepath = PathBuf::from(&path).join(&file);
+            // The clone is deleted once the build is done
+            let git_context = match context::GitUrl::parse(&path) {
+                Some(url) => Some(context::GitContext::clone(&url)?),
+                None => None,
+            };
+            let path = match &git_context {
+                Some(git_context) => git_context.root().display().to_string(),
+                None => path,
+            };
+            // The last value given wins, so the files go first
+            let mut file_build_args = Vec::new();
+            for build_arg_file in &build_arg_files {
+                file_build_args.extend(parsing::variables::load_build_arg_file(build_arg_file)?);
+            }
+            let build_args = file_build_args.into_iter().chain(build_args).collect();
+            let file = from_dockerfile.as_deref().or(file.as_deref());
+            // Read once, as every platform built parses it
+            let (filepath, contents) = match file {
+                Some("-") => (
+                    PathBuf::from("-"),
+                    Some(std::io::read_to_string(std::io::stdin())?),
+                ),
+                file => (bakerfile_path(&path, file), None),
+            };
+            let options = images::BuildOptions {
+                run_environment: run::RunEnvironment::new(run_env, kernel)?,
+                keep_on_failure,
+                context: PathBuf::from(&path),
+                symlinks,
+                max_context_size,
+                max_context_files,
+                volumes,
+                secrets,
+                build_args,
+                from_dockerfile: from_dockerfile.is_some(),
+                no_cache,
+                pull,
+                target,
+                platform: None,
+                jobs,
+                resume,
+                hook_pre,
+                hook_post,
+                contents,
+                report,
+            };
+            let (name, tag) = match tag {
+                Some(nametag) => {
+                    let (name, tag) = images::parse_reference(&nametag)?;
+                    (Some(name), Some(tag))
+                }
+                None => (None, None),
+            };
+
+            if dry_run {
+                let platforms = match platform.as_slice() {
+                    [] => vec![None],
+                    platforms => platforms.iter().cloned().map(Some).collect(),
+                };
+                for platform in platforms {
+                    if let Some(platform) = &platform {
+                        images::check_platform(platform)?;
+                    }
+                    let options = images::BuildOptions {
+                        platform,
+                        ..options.clone()
+                    };
+                    for line in images::plan_build(&filepath, &options)? {
+                        println!("{}", line);
+                    }
+                }
+                return Ok(());
+            }
 
-            match tag {
-                Some(nametag) => match nametag.split(":").collect::<Vec<&str>>().as_slice() {
-                    [name, tag] => {
-                        images::build(filepath, Some(name.to_string()), Some(tag.to_string()))
+            if platform.len() > 1 && (output.is_some() || options.report.is_some()) {
+                return Err(Error::Usage(
+                    "--output and --report can only be used when building a single platform"
+                        .to_string(),
+                ));
+            }
+            for platform in &platform {
+                images::check_platform(platform)?;
+            }
+            let build = || -> Result<(), Error> {
+                if platform.len() > 1 {
+                    let built = images::build_platforms(&platform, |platform| {
+                        images::build(
+                            filepath.clone(),
+                            name.clone(),
+                            tag.clone(),
+                            images::BuildOptions {
+                                platform: Some(platform.to_string()),
+                                ..options.clone()
+                            },
+                        )
+                    })?;
+                    if args.json {
+                        println!("{}", serde_json::to_string_pretty(&built)?);
                     }
-                    _ => Err("Invalid image name".into()),
-                },
-                None => images::build(filepath, None, None),
+                    return Ok(());
+                }
+
+                let options = images::BuildOptions {
+                    platform: platform.first().cloned(),
+                    ..options.clone()
+                };
+                let image = images::build(filepath.clone(), name.clone(), tag.clone(), options)?;
+
+                if let Some(output) = &output {
+                    std::fs::copy(image.path()?, output)?;
+                }
+
+                if args.json {
+                    println!("{}", serde_json::to_string_pretty(&image)?);
+                } else if !progress::is_quiet() {
+                    println!("Built {}", image.full_name());
+                }
+                Ok(())
+            };
+
+            if !watch {
+                return build();
+            }
+            if git_context.is_some() || options.contents.is_some() {
+                return Err(Error::Usage(
+                    "--watch needs a local build context and Bakerfile".to_string(),
+                ));
+            }
+            // What the build writes would otherwise trigger the next one
+            let excluded = [output.clone(), options.report.clone(), Some(get_app_dir()?)]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+            // A failed build is reported and waits for the next change like
+            // a successful one, the cache making the rebuilds quick
+            loop {
+                let snapshot = watch::snapshot(&filepath, &options.context, &excluded)?;
+                if let Err(err) = build() {
+                    eprintln!("{}", error::report(&err, args.json).0);
+                }
+                progress::message("Watching for changes, press Ctrl-C to stop");
+                watch::wait_for_change(&filepath, &options.context, &excluded, &snapshot)?;
+            }
+        }
+        Commands::Export {
+            image,
+            output,
+            format,
+            platform,
+        } => {
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&image)?;
+            let image = images::find(&platform, &name, &tag)?;
+            let format = format.unwrap_or_else(|| images::ArchiveFormat::from_path(&output));
+            images::export(&image, &output, format)
+        }
+        Commands::Import {
+            file,
+            image,
+            format,
+            platform,
+        } => {
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&image)?;
+            let format = format.unwrap_or_else(|| images::ArchiveFormat::from_path(&file));
+            let image = images::import(&file, format, &platform, &name, &tag)?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&image)?);
+            }
+            Ok(())
+        }
+        Commands::Tag {
+            source,
+            target,
+            platform,
+        } => {
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&source)?;
+            let (new_name, new_tag) = images::parse_reference(&target)?;
+            images::tag(&platform, &name, &tag, &new_name, &new_tag)?;
+            Ok(())
+        }
+        Commands::Prune { dry_run } => {
+            let (files, reclaimed) = images::prune(dry_run)?;
+            if args.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&serde_json::json!({
+                        "files": files,
+                        "reclaimed": reclaimed,
+                    }))?
+                );
+                return Ok(());
+            }
+
+            for file in &files {
+                println!("{}", file.display());
+            }
+            if dry_run {
+                println!("Would reclaim {}", size::format_size(reclaimed));
+            } else {
+                println!("Reclaimed {}", size::format_size(reclaimed));
+            }
+            Ok(())
+        }
+        Commands::Devices { all } => {
+            let devices = devices::list_devices(all)?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&devices)?);
+                return Ok(());
+            }
+
+            println!(
+                "{:<15} {:<30} {:<10} {:<8} Mounted",
+                "Device", "Model", "Size", "Bus"
+            );
+            for device in devices {
+                println!(
+                    "{:<15} {:<30} {:<10} {:<8} {}",
+                    device.path().display(),
+                    device.model(),
+                    size::format_size(device.size()),
+                    device.bus(),
+                    device.mount_points().join(", ")
+                );
+            }
+            Ok(())
+        }
+        Commands::Burn {
+            device_file,
+            image,
+            platform,
+            yes,
+        } => {
+            privileges::require_root("burn")?;
+            let platform = platform.unwrap_or_else(images::default_platform);
+            let (name, tag) = images::parse_reference(&image)?;
+            let image = images::find(&platform, &name, &tag)?;
+            burn::burn(&image, &device_file, yes)
+        }
+        Commands::Bake {
+            file,
+            targets,
+            run_env,
+            kernel,
+            no_cache,
+            pull,
+            jobs,
+        } => {
+            let options = images::BuildOptions {
+                run_environment: run::RunEnvironment::new(run_env, kernel)?,
+                no_cache,
+                pull,
+                jobs,
+                ..Default::default()
+            };
+            let built = bake::bake(&file, &targets, &options)?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&built)?);
+            }
+            Ok(())
+        }
+        Commands::Lint { path, file } => {
+            let filepath = bakerfile_path(&path, file.as_deref());
+            let problems = lint::lint(&filepath, &PathBuf::from(&path))?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&problems)?);
+            } else {
+                lint::print_report(&filepath, &problems);
+            }
+            lint::check(&problems)
+        }
+        Commands::Doctor {} => {
+            let checks = doctor::checks();
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&checks)?);
+            } else {
+                doctor::print_report(&checks);
+            }
+            doctor::doctor(&checks)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rmi_platform() {
+        let cli = Cli::try_parse_from(["baker", "rmi", "raspios:bookworm", "--platform", "armhf"])
+            .unwrap();
+
+        match cli.command {
+            Commands::Rmi { image, platform } => {
+                assert_eq!(image, "raspios:bookworm");
+                assert_eq!(platform.as_deref(), Some("armhf"));
+            }
+            _ => panic!("Expected rmi command"),
+        }
+    }
+
+    #[test]
+    fn test_build_watch_conflicts_with_dry_run() {
+        assert!(Cli::try_parse_from(["baker", "build", "--watch", "."]).is_ok());
+        assert!(Cli::try_parse_from(["baker", "build", "--watch", "--dry-run", "."]).is_err());
+    }
+
+    #[test]
+    fn test_pull_multiple_platforms() {
+        let cli = Cli::try_parse_from([
+            "baker",
+            "pull",
+            "raspios:bookworm",
+            "--platform",
+            "arm64",
+            "-p",
+            "armhf",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Commands::Pull { platform, .. } => assert_eq!(platform, vec!["arm64", "armhf"]),
+            _ => panic!("Expected pull command"),
+        }
+
+        let cli = Cli::try_parse_from(["baker", "pull", "raspios"]).unwrap();
+        match cli.command {
+            Commands::Pull { platform, .. } => assert!(platform.is_empty()),
+            _ => panic!("Expected pull command"),
+        }
+    }
+
+    #[test]
+    fn test_build_platforms() {
+        let cli =
+            Cli::try_parse_from(["baker", "build", "--platform", "arm64,armhf", "."]).unwrap();
+
+        match cli.command {
+            Commands::Build { platform, .. } => assert_eq!(platform, vec!["arm64", "armhf"]),
+            _ => panic!("Expected build command"),
+        }
+    }
+
+    #[test]
+    fn test_bake_targets() {
+        let cli = Cli::try_parse_from(["baker", "bake", "fleet.toml", "kiosk", "sensor"]).unwrap();
+
+        match cli.command {
+            Commands::Bake { file, targets, .. } => {
+                assert_eq!(file, PathBuf::from("fleet.toml"));
+                assert_eq!(targets, vec!["kiosk", "sensor"]);
+            }
+            _ => panic!("Expected bake command"),
+        }
+    }
+
+    #[test]
+    fn test_verify_requires_image_or_all() {
+        assert!(Cli::try_parse_from(["baker", "verify"]).is_err());
+        assert!(Cli::try_parse_from(["baker", "verify", "raspios", "--all"]).is_err());
+        assert!(Cli::try_parse_from(["baker", "verify", "--all"]).is_ok());
+    }
+
+    #[test]
+    fn test_bakerfile_path() {
+        assert_eq!(
+            bakerfile_path("project", None),
+            PathBuf::from("project/Bakerfile")
+        );
+        assert_eq!(
+            bakerfile_path("project", Some("images/Bakerfile.lite")),
+            PathBuf::from("project/images/Bakerfile.lite")
+        );
+        assert_eq!(
+            bakerfile_path("project", Some("/etc/Bakerfile")),
+            PathBuf::from("/etc/Bakerfile")
+        );
+    }
+
+    #[test]
+    fn test_build_tag_and_output() {
+        let cli =
+            Cli::try_parse_from(["baker", "build", ".", "-t", "myimage:1.0", "-o", "out.img"])
+                .unwrap();
+
+        match cli.command {
+            Commands::Build { tag, output, .. } => {
+                assert_eq!(tag.as_deref(), Some("myimage:1.0"));
+                assert_eq!(output, Some(PathBuf::from("out.img")));
+            }
+            _ => panic!("Expected build command"),
+        }
+    }
+
+    #[test]
+    fn test_build_run_env_defaults_to_nspawn() {
+        let cli = Cli::try_parse_from(["baker", "build", "."]).unwrap();
+
+        match cli.command {
+            Commands::Build {
+                run_env, kernel, ..
+            } => {
+                assert_eq!(run_env, run::Backend::Nspawn);
+                assert_eq!(kernel, None);
+            }
+            _ => panic!("Expected build command"),
+        }
+    }
+
+    #[test]
+    fn test_build_run_env_chroot() {
+        let cli = Cli::try_parse_from(["baker", "build", ".", "--run-env", "chroot"]).unwrap();
+
+        match cli.command {
+            Commands::Build { run_env, .. } => assert_eq!(run_env, run::Backend::Chroot),
+            _ => panic!("Expected build command"),
+        }
+    }
+
+    #[test]
+    fn test_build_volumes() {
+        let cli = Cli::try_parse_from([
+            "baker",
+            "build",
+            ".",
+            "--volume",
+            "/var/cache/apt:/var/cache/apt",
+            "--volume",
+            "/srv:/srv:ro",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Commands::Build { volumes, .. } => assert_eq!(volumes.len(), 2),
+            _ => panic!("Expected build command"),
+        }
+
+        assert!(Cli::try_parse_from(["baker", "build", ".", "--volume", "/srv"]).is_err());
+    }
+
+    #[test]
+    fn test_build_vmspawn_requires_kernel() {
+        assert!(Cli::try_parse_from(["baker", "build", ".", "--run-env", "vmspawn"]).is_err());
+
+        let cli = Cli::try_parse_from([
+            "baker",
+            "build",
+            ".",
+            "--run-env",
+            "vmspawn",
+            "--kernel",
+            "/boot/vmlinuz",
+        ])
+        .unwrap();
+
+        match cli.command {
+            Commands::Build {
+                run_env, kernel, ..
+            } => {
+                assert_eq!(
+                    run::RunEnvironment::new(run_env, kernel).unwrap(),
+                    run::RunEnvironment::SystemdVmspawn("/boot/vmlinuz".into())
+                );
             }
+            _ => panic!("Expected build command"),
         }
-        Commands::Burn { device_file, image } => unimplemented!(),
     }
 }
@@ -1,29 +1,79 @@
 use glob::glob;
 use loopdev::{LoopControl, LoopDevice};
-use std::{collections::BTreeMap, fs, path::PathBuf, thread::sleep, time::Duration};
-use sys_mount::{Mount, Unmount, UnmountFlags};
+use std::{
+    collections::BTreeMap,
+    ffi::CString,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread::sleep,
+    time::Duration,
+};
+use sys_mount::{Mount, MountFlags, Unmount, UnmountFlags};
 use tempdir::TempDir;
 use udev::Device;
 
+use crate::cleanup::{self, Registration};
+use crate::error::Error;
+
+/// Held while picking and attaching a loop device, so that stages mounted
+/// concurrently do not pick the same free one.
+static LOOP_DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
 pub struct MountedImage {
     loop_device: LoopDevice,
-    mount_dir: TempDir,
+    attached: bool,
+    mount_dir: Option<TempDir>,
     mount_points: BTreeMap<String, Mount>,
+    devices: BTreeMap<String, PathBuf>,
+    registration: Option<Registration>,
+}
+
+/// Best-effort teardown used when the process is interrupted, it only relies
+/// on paths so that it can run from the signal handler thread.
+fn teardown(mount_targets: &[PathBuf], loop_device_path: &Path, mount_dir: &Path) {
+    for target in mount_targets.iter().rev() {
+        let _ = sys_mount::unmount(target, UnmountFlags::DETACH);
+    }
+
+    if let Ok(loop_device) = LoopDevice::open(loop_device_path) {
+        let _ = loop_device.detach();
+    }
+
+    let _ = fs::remove_dir_all(mount_dir);
 }
 
 impl MountedImage {
-    pub fn new(image_path: &PathBuf) -> Result<MountedImage, Box<dyn std::error::Error>> {
+    pub fn new(image_path: &PathBuf) -> Result<MountedImage, Error> {
+        MountedImage::mount(image_path, false)
+    }
+    /// Mounts every partition read-only, for images that are only read from.
+    pub fn new_read_only(image_path: &PathBuf) -> Result<MountedImage, Error> {
+        MountedImage::mount(image_path, true)
+    }
+    fn mount(image_path: &PathBuf, read_only: bool) -> Result<MountedImage, Error> {
+        let lock = LOOP_DEVICE_LOCK
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
         let loop_control = LoopControl::open()?;
 
         let loop_device = loop_control.next_free()?;
 
-        loop_device.with().part_scan(true).attach(image_path)?;
+        loop_device
+            .with()
+            .part_scan(true)
+            .read_only(read_only)
+            .attach(image_path)?;
+        drop(lock);
 
-        let loop_device_path = loop_device.path().ok_or("Invalid loop device path")?;
+        let loop_device_path = loop_device
+            .path()
+            .ok_or_else(|| Error::Mount("Invalid loop device path".to_string()))?;
 
         let partition_devices_pattern = loop_device_path
             .to_str()
-            .ok_or("Failed to convert path to string")?
+            .ok_or_else(|| Error::Mount("Failed to convert path to string".to_string()))?
             .to_string()
             + "*";
 
@@ -34,15 +84,16 @@ impl MountedImage {
             .collect::<Vec<_>>();
 
         let mount_dir = TempDir::new("baker")?;
+        let mut devices = BTreeMap::new();
 
         let mount_points = partition_devices
             .into_iter()
             .map(|partition_device| {
                 let sysname = partition_device
                     .file_name()
-                    .ok_or("Invalid device path")?
+                    .ok_or_else(|| Error::Mount("Invalid device path".to_string()))?
                     .to_str()
-                    .ok_or("Failed to convert path to string")?
+                    .ok_or_else(|| Error::Mount("Failed to convert path to string".to_string()))?
                     .to_string();
 
                 let device = Device::from_subsystem_sysname("block".into(), sysname)?;
@@ -53,45 +104,151 @@ impl MountedImage {
 
                 let label = device
                     .property_value("ID_FS_LABEL_ENC")
-                    .ok_or("Failed to get device label")?
+                    .ok_or_else(|| Error::Mount("Failed to get device label".to_string()))?
                     .to_str()
-                    .ok_or("Failed to convert label to string")?
+                    .ok_or_else(|| Error::Mount("Failed to convert label to string".to_string()))?
                     .to_string();
 
                 let mount_point = mount_dir.path().join(&label);
 
                 fs::create_dir_all(mount_point.as_path())?;
+                devices.insert(label.clone(), partition_device.clone());
 
-                Ok((label, Mount::new(partition_device, mount_point)?))
+                let mount = if read_only {
+                    Mount::builder()
+                        .flags(MountFlags::RDONLY)
+                        .mount(partition_device, mount_point)?
+                } else {
+                    Mount::new(partition_device, mount_point)?
+                };
+
+                Ok((label, mount))
             })
-            .collect::<Result<BTreeMap<String, Mount>, Box<dyn std::error::Error>>>()?;
+            .collect::<Result<BTreeMap<String, Mount>, Error>>()?;
+
+        let mount_targets = mount_points
+            .values()
+            .map(|mount| mount.target_path().to_path_buf())
+            .collect::<Vec<_>>();
+        let mount_dir_path = mount_dir.path().to_path_buf();
+
+        let registration =
+            cleanup::register(move || teardown(&mount_targets, &loop_device_path, &mount_dir_path));
 
         Ok(MountedImage {
             loop_device,
-            mount_dir,
+            attached: true,
+            mount_dir: Some(mount_dir),
             mount_points,
+            devices,
+            registration: Some(registration),
         })
     }
     pub fn labels(&self) -> Vec<String> {
         self.mount_points.keys().cloned().collect()
     }
-    pub fn get_mount_point(&self, label: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    /// Label of the root filesystem, `rootfs` on Raspberry Pi OS images,
+    /// `writable` on Ubuntu ones, `armbi_root` on Armbian ones and otherwise
+    /// the last label.
+    pub fn root_label(&self) -> Result<String, Error> {
+        let labels = self.labels();
+        labels
+            .iter()
+            .find(|label| {
+                ["rootfs", "root", "writable", "armbi_root"]
+                    .contains(&label.to_lowercase().as_str())
+            })
+            .or_else(|| labels.last())
+            .cloned()
+            .ok_or_else(|| Error::Mount("No label found".to_string()))
+    }
+    /// Space used on the mounted filesystems, in bytes.
+    pub fn used_bytes(&self) -> Result<u64, Error> {
+        let mut used = 0;
+        for mount in self.mount_points.values() {
+            let path = CString::new(mount.target_path().as_os_str().as_bytes())
+                .map_err(|_| Error::Mount("Invalid mount point".to_string()))?;
+            let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+            if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            used += (stat.f_blocks - stat.f_bfree) as u64 * stat.f_frsize as u64;
+        }
+        Ok(used)
+    }
+    pub fn root_mount_point(&self) -> Result<PathBuf, Error> {
+        self.get_mount_point(&self.root_label()?)
+    }
+    /// Partition device holding the root filesystem.
+    pub fn root_device(&self) -> Result<PathBuf, Error> {
+        Ok(self
+            .devices
+            .get(&self.root_label()?)
+            .ok_or_else(|| Error::Mount("No label found".to_string()))?
+            .clone())
+    }
+    /// Mount point of the boot partition, labelled `bootfs` on recent images,
+    /// `boot` on older ones, `system-boot` on Ubuntu and `armbi_boot` on
+    /// Armbian.
+    pub fn boot_mount_point(&self) -> Result<Option<PathBuf>, Error> {
+        match self.mount_points.keys().find(|label| {
+            ["bootfs", "boot", "system-boot", "armbi_boot"].contains(&label.to_lowercase().as_str())
+        }) {
+            Some(label) => Ok(Some(self.get_mount_point(label)?)),
+            None => Ok(None),
+        }
+    }
+    pub fn get_mount_point(&self, label: &str) -> Result<PathBuf, Error> {
         Ok(self
             .mount_points
             .get(label)
-            .ok_or("Invalid label")?
+            .ok_or_else(|| Error::Mount("Invalid label".to_string()))?
             .target_path()
             .to_path_buf())
     }
-    pub fn unmount(self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Takes cleanup back from the signal handler, returns `false` if the
+    /// handler already tore everything down.
+    fn claim(&mut self) -> bool {
+        match self.registration.take() {
+            Some(registration) => registration.release(),
+            None => true,
+        }
+    }
+    pub fn unmount(mut self) -> Result<(), Error> {
+        if !self.claim() {
+            return Ok(());
+        }
+
         for mount in self.mount_points.values() {
             mount.unmount(UnmountFlags::DETACH)?;
         }
+        self.mount_points.clear();
 
         self.loop_device.detach()?;
+        self.attached = false;
 
-        self.mount_dir.close()?;
+        if let Some(mount_dir) = self.mount_dir.take() {
+            mount_dir.close()?;
+        }
 
         Ok(())
     }
 }
+
+impl Drop for MountedImage {
+    fn drop(&mut self) {
+        if !self.claim() {
+            return;
+        }
+
+        for mount in self.mount_points.values() {
+            let _ = mount.unmount(UnmountFlags::DETACH);
+        }
+        self.mount_points.clear();
+
+        if self.attached {
+            let _ = self.loop_device.detach();
+            self.attached = false;
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use std::{fs, path::Path};
+
+use super::{in_file, parse_bakerfile, parser::BakerFile};
+use crate::{error::Error, progress, system::shell_quote};
+
+/// Dockerfile instructions with a Bakerfile counterpart.
+const SUPPORTED: &[&str] = &[
+    "FROM",
+    "ENV",
+    "RUN",
+    "COPY",
+    "ADD",
+    "WORKDIR",
+    "USER",
+    "CMD",
+    "ENTRYPOINT",
+    "ARG",
+    "LABEL",
+    "SHELL",
+];
+
+/// Flags of COPY and ADD that baker understands, the others being dropped.
+const SUPPORTED_COPY_FLAGS: &[&str] = &["--from=", "--checksum="];
+
+/// Parses the JSON array of an exec form instruction.
+fn exec_form(arguments: &str) -> Option<Vec<String>> {
+    if !arguments.starts_with('[') {
+        return None;
+    }
+    serde_json::from_str(arguments).ok()
+}
+
+/// Maps a Docker platform such as `linux/arm/v7` to the baker one.
+fn platform(platform: &str) -> Option<&'static str> {
+    match platform.trim_start_matches("linux/") {
+        "arm64" | "arm64/v8" => Some("arm64"),
+        "arm" | "arm/v6" | "arm/v7" => Some("armhf"),
+        _ => None,
+    }
+}
+
+fn convert_from(arguments: &str, warnings: &mut Vec<String>) -> String {
+    let mut words = Vec::new();
+    for word in arguments.split_whitespace() {
+        match word.strip_prefix("--platform=") {
+            Some(value) => match platform(value) {
+                Some(value) => words.push(format!("--platform {}", value)),
+                None => warnings.push(format!("Dropping unsupported platform {}", value)),
+            },
+            None => words.push(word.to_string()),
+        }
+    }
+    format!("FROM {}", words.join(" "))
+}
+
+/// Legacy `ENV KEY value` lines become `ENV KEY="value"`.
+fn convert_env(arguments: &str) -> String {
+    match arguments.split_once(char::is_whitespace) {
+        Some((key, value)) if !key.contains('=') => {
+            format!("ENV {}=\"{}\"", key, value.trim().replace('"', "\\\""))
+        }
+        _ => format!("ENV {}", arguments),
+    }
+}
+
+fn convert_copy(
+    keyword: &str,
+    arguments: &str,
+    warnings: &mut Vec<String>,
+) -> Result<String, String> {
+    let mut flags = Vec::new();
+    let mut rest = arguments;
+    while let Some(flag) = rest.strip_prefix("--") {
+        let (flag, tail) = flag.split_once(char::is_whitespace).unwrap_or((flag, ""));
+        let flag = format!("--{}", flag);
+        if SUPPORTED_COPY_FLAGS
+            .iter()
+            .any(|supported| flag.starts_with(supported))
+        {
+            flags.push(flag);
+        } else {
+            warnings.push(format!("Dropping unsupported {} flag {}", keyword, flag));
+        }
+        rest = tail.trim_start();
+    }
+
+    let paths = match exec_form(rest) {
+        Some(paths) if paths.iter().any(|path| path.contains(char::is_whitespace)) => {
+            return Err(format!("{} of paths with spaces", keyword))
+        }
+        Some(paths) => paths.join(" "),
+        None => rest.to_string(),
+    };
+
+    Ok(flags
+        .into_iter()
+        .chain([paths])
+        .fold(keyword.to_string(), |line, word| line + " " + &word))
+}
+
+/// Exec form commands become shell ones, their words quoted.
+fn convert_command(keyword: &str, arguments: &str) -> String {
+    match exec_form(arguments) {
+        Some(words) => format!(
+            "{} {}",
+            keyword,
+            words
+                .iter()
+                .map(|word| shell_quote(word))
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        None => format!("{} {}", keyword, arguments),
+    }
+}
+
+/// Delimiter of the heredoc a RUN line opens, if any.
+fn heredoc_delimiter(line: &str) -> Option<String> {
+    let (_, after) = line.split_once("<<")?;
+    let delimiter = after.trim_start_matches('-').split_whitespace().next()?;
+    let delimiter = delimiter.trim_matches(|ch| ch == '"' || ch == '\'');
+    (!delimiter.is_empty()).then(|| delimiter.to_string())
+}
+
+/// Rewrites the supported subset of a Dockerfile as a Bakerfile, keeping
+/// every instruction on its original line so that errors point into the
+/// Dockerfile. Unsupported instructions are commented out and reported in
+/// the returned warnings.
+pub fn convert(contents: &str) -> (String, Vec<String>) {
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut output = Vec::with_capacity(lines.len());
+    let mut warnings = Vec::new();
+    let mut in_preamble = true;
+    let mut index = 0;
+
+    while index < lines.len() {
+        let start = index;
+        let trimmed = lines[index].trim();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            // Docker parser directives would be read as baker ones
+            let directive = trimmed
+                .trim_start_matches('#')
+                .split_once('=')
+                .map(|(key, _)| key.trim().to_ascii_lowercase());
+            match directive.as_deref() {
+                Some("syntax") | Some("escape") | Some("check") if in_preamble => {
+                    warnings.push(format!("Ignoring parser directive {}", trimmed));
+                    output.push(String::new());
+                }
+                _ => output.push(lines[index].to_string()),
+            }
+            index += 1;
+            continue;
+        }
+        in_preamble = false;
+
+        // Gather continued lines into one instruction
+        let mut instruction = String::new();
+        loop {
+            let line = lines[index].trim_end();
+            index += 1;
+            match line.strip_suffix('\\') {
+                Some(continued) if index < lines.len() => instruction.push_str(continued),
+                _ => {
+                    instruction.push_str(line);
+                    break;
+                }
+            }
+        }
+
+        let instruction = instruction.trim();
+        let (keyword, arguments) = instruction
+            .split_once(char::is_whitespace)
+            .unwrap_or((instruction, ""));
+        let keyword = keyword.to_ascii_uppercase();
+        let arguments = arguments.trim();
+
+        let converted = if !SUPPORTED.contains(&keyword.as_str()) {
+            Err(keyword.clone())
+        } else {
+            match keyword.as_str() {
+                "FROM" => Ok(convert_from(arguments, &mut warnings)),
+                "ENV" => Ok(convert_env(arguments)),
+                "COPY" | "ADD" => convert_copy(&keyword, arguments, &mut warnings),
+                "RUN" | "CMD" | "ENTRYPOINT" => Ok(convert_command(&keyword, arguments)),
+                _ => Ok(format!("{} {}", keyword, arguments)),
+            }
+        };
+
+        match converted {
+            Ok(converted) => output.push(converted),
+            Err(unsupported) => {
+                warnings.push(format!(
+                    "Line {}: skipping unsupported {}",
+                    start + 1,
+                    unsupported
+                ));
+                output.push(format!("# {}", instruction));
+            }
+        }
+        output.extend((start + 1..index).map(|_| String::new()));
+
+        // Heredoc bodies are kept as they are
+        if keyword == "RUN" {
+            if let Some(delimiter) = heredoc_delimiter(instruction) {
+                while index < lines.len() {
+                    output.push(lines[index].to_string());
+                    index += 1;
+                    if lines[index - 1].trim() == delimiter {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    (output.join("\n") + "\n", warnings)
+}
+
+/// Loads a Dockerfile as a Bakerfile, printing what could not be converted.
+pub fn load_dockerfile(path: &Path) -> Result<BakerFile, Error> {
+    parse_dockerfile(&fs::read_to_string(path)?, path)
+}
+
+/// Converts and parses a Dockerfile read from `path`, which can name stdin
+/// as `-`.
+pub fn parse_dockerfile(contents: &str, path: &Path) -> Result<BakerFile, Error> {
+    let (bakerfile, warnings) = convert(contents);
+
+    if !progress::is_quiet() {
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+
+    parse_bakerfile(&bakerfile).map_err(|err| in_file(err, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let dockerfile = "# syntax=docker/dockerfile:1\n\
+                          FROM --platform=linux/arm/v7 raspios:bookworm\n\
+                          ENV APP_HOME /opt/my app\n\
+                          copy --chown=pi:pi [\"app\", \"/opt/app\"]\n\
+                          EXPOSE 8080\n\
+                          RUN apt-get update && \\\n    apt-get install -y nginx\n\
+                          CMD [\"nginx\", \"-g\", \"daemon off;\"]\n";
+
+        let (bakerfile, warnings) = convert(dockerfile);
+
+        assert_eq!(
+            bakerfile,
+            "\n\
+             FROM --platform armhf raspios:bookworm\n\
+             ENV APP_HOME=\"/opt/my app\"\n\
+             COPY app /opt/app\n\
+             # EXPOSE 8080\n\
+             RUN apt-get update &&     apt-get install -y nginx\n\
+             \n\
+             CMD 'nginx' '-g' 'daemon off;'\n"
+        );
+        assert_eq!(
+            warnings,
+            vec![
+                "Ignoring parser directive # syntax=docker/dockerfile:1",
+                "Dropping unsupported COPY flag --chown=pi:pi",
+                "Line 5: skipping unsupported EXPOSE",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_keeps_heredocs() {
+        let dockerfile = "FROM raspios:bookworm\nRUN <<EOF\nEXPOSE 80\nEOF\nUSER pi\n";
+
+        let (bakerfile, warnings) = convert(dockerfile);
+
+        assert_eq!(bakerfile, dockerfile);
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_converted_dockerfile_parses() {
+        let (bakerfile, _) = convert(
+            "FROM raspios:bookworm AS base\nWORKDIR /app\nVOLUME /data\nENTRYPOINT [\"/app/run\"]\n",
+        );
+
+        let bakerfile = parse_bakerfile(&bakerfile).unwrap();
+        assert_eq!(bakerfile.stages[0].instructions.len(), 2);
+    }
+}
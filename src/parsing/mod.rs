@@ -1,15 +1,369 @@
-use std::{fs::File, io::Read};
+use std::{fs, path::Path};
 
 use parser::BakerFile;
 
+use crate::error::Error;
+
+pub mod dockerfile;
 pub mod parser;
+pub mod variables;
+
+/// Returns the 1-based line and column at which `remaining` starts in `contents`.
+fn location(contents: &str, remaining: &str) -> (usize, usize) {
+    let consumed = &contents[..contents.len() - remaining.len()];
+    let line_start = consumed.rfind('\n').map_or(0, |index| index + 1);
+
+    (
+        consumed.matches('\n').count() + 1,
+        consumed[line_start..].chars().count() + 1,
+    )
+}
+
+/// Newest Bakerfile syntax this version of baker understands.
+pub const SYNTAX_VERSION: u32 = 1;
+
+/// Error located at the start of `remaining`, a suffix of `contents`.
+fn parse_error(contents: &str, remaining: &str, msg: String) -> Error {
+    let (line, col) = location(contents, remaining);
+    Error::Parse {
+        file: "Bakerfile".to_string(),
+        line,
+        col,
+        msg,
+    }
+}
+
+/// Names `path` as the file parse errors are located in.
+fn in_file(error: Error, path: &Path) -> Error {
+    match error {
+        Error::Parse { line, col, msg, .. } => Error::Parse {
+            file: path.display().to_string(),
+            line,
+            col,
+            msg,
+        },
+        error => error,
+    }
+}
+
+/// Number of single character edits turning `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            current.push(
+                (previous[j] + usize::from(a != *b))
+                    .min(previous[j + 1] + 1)
+                    .min(current[j] + 1),
+            );
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Explains why the line starting at `remaining` could not be parsed.
+fn describe(remaining: &str) -> String {
+    let keyword = remaining.split_whitespace().next().unwrap_or_default();
+
+    if parser::INSTRUCTIONS.contains(&keyword) {
+        return format!("invalid {} instruction", keyword);
+    }
+    if keyword.is_empty() {
+        return "unexpected end of file".to_string();
+    }
+
+    // Only close typos are worth a suggestion, short keywords being close
+    // to many others
+    let tolerance = (keyword.chars().count() / 3).max(1);
+    let suggestion = parser::INSTRUCTIONS
+        .iter()
+        .map(|instruction| {
+            (
+                edit_distance(&keyword.to_uppercase(), instruction),
+                instruction,
+            )
+        })
+        .filter(|(distance, _)| *distance <= tolerance)
+        .min();
+    match suggestion {
+        Some((_, instruction)) => format!(
+            "unknown instruction '{}', did you mean '{}'?",
+            keyword, instruction
+        ),
+        None => format!("unknown instruction '{}'", keyword),
+    }
+}
+
+/// Reads the `# syntax=baker/<version>` directive, which has to be among the
+/// comments heading the file, failing on syntaxes newer than this baker.
+/// Files without one use the current syntax.
+fn check_syntax(contents: &str) -> Result<u32, Error> {
+    let mut offset = 0;
+    for line in contents.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        let comment = match trimmed.strip_prefix('#') {
+            Some(comment) => comment,
+            None => break,
+        };
+        let value = match comment.split_once('=') {
+            Some((key, value)) if key.trim().eq_ignore_ascii_case("syntax") => value.trim(),
+            _ => continue,
+        };
+
+        let remaining = &contents[line_start + line.find(value).unwrap_or(0)..];
+        let version = value
+            .strip_prefix("baker/")
+            .and_then(|version| version.parse::<u32>().ok())
+            .filter(|version| *version > 0)
+            .ok_or_else(|| {
+                parse_error(
+                    contents,
+                    remaining,
+                    format!("unsupported syntax {}, expected baker/<version>", value),
+                )
+            })?;
+        if version > SYNTAX_VERSION {
+            return Err(parse_error(
+                contents,
+                remaining,
+                format!(
+                    "this Bakerfile requires syntax baker/{} but this baker only supports up to baker/{}, a newer baker is required",
+                    version, SYNTAX_VERSION
+                ),
+            ));
+        }
+
+        return Ok(version);
+    }
+
+    Ok(SYNTAX_VERSION)
+}
+
+/// Only blank lines and comments may come before FROM, anything else gets an
+/// error naming what was found instead of a generic syntax error.
+fn check_first_instruction(contents: &str) -> Result<(), Error> {
+    let mut remaining = contents;
+
+    while !remaining.is_empty() {
+        let (line, rest) = remaining.split_once('\n').unwrap_or((remaining, ""));
+        let trimmed = line.trim_start();
+        let offending = &remaining[line.len() - trimmed.len()..];
+        remaining = rest;
+
+        // Arguments may be declared for FROM to use
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("ARG ") {
+            continue;
+        }
+
+        if trimmed.starts_with("FROM") {
+            return Ok(());
+        }
+
+        let keyword = trimmed.split_whitespace().next().unwrap_or(trimmed);
+        let msg = if parser::INSTRUCTIONS.contains(&keyword) {
+            format!("the first instruction must be FROM, found {}", keyword)
+        } else {
+            describe(trimmed)
+        };
+        return Err(parse_error(contents, offending, msg));
+    }
+
+    Err(parse_error(
+        contents,
+        "",
+        "missing FROM instruction".to_string(),
+    ))
+}
+
+/// Fails on whatever the parser stopped at, unless only blank lines and
+/// comments are left, so that no instruction gets silently dropped.
+fn check_remaining(contents: &str, remaining: &str) -> Result<(), Error> {
+    let is_ignored = |line: &str| {
+        let line = line.trim();
+        line.is_empty() || line.starts_with('#')
+    };
+
+    if remaining.lines().all(is_ignored) {
+        return Ok(());
+    }
+
+    let offending = remaining.trim_start();
+    Err(parse_error(contents, offending, describe(offending)))
+}
+
+pub fn parse_bakerfile(contents: &str) -> Result<BakerFile, Error> {
+    check_syntax(contents)?;
+    check_first_instruction(contents)?;
+
+    let (remaining, bakerfile) = parser::parse_baker_file::<nom::error::Error<&str>>(contents)
+        .map_err(|err| match err {
+            nom::Err::Error(err) | nom::Err::Failure(err) => {
+                parse_error(contents, err.input, describe(err.input))
+            }
+            nom::Err::Incomplete(_) => {
+                parse_error(contents, "", "unexpected end of file".to_string())
+            }
+        })?;
+
+    check_remaining(contents, remaining)?;
+
+    Ok(bakerfile)
+}
+
+pub(crate) fn load_bakerfile(path: &Path) -> Result<BakerFile, Error> {
+    parse_bakerfile_in(&fs::read_to_string(path)?, path)
+}
+
+/// Parses a Bakerfile read from `path`, which can name stdin as `-`.
+pub(crate) fn parse_bakerfile_in(contents: &str, path: &Path) -> Result<BakerFile, Error> {
+    parse_bakerfile(contents).map_err(|err| in_file(err, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        let contents = "FROM a:b\nRUN x\nCOPY";
+        assert_eq!(location(contents, contents), (1, 1));
+        assert_eq!(location(contents, &contents[9..]), (2, 1));
+        assert_eq!(location(contents, &contents[17..]), (3, 3));
+    }
+
+    #[test]
+    fn test_parse_bakerfile_leading_comment() {
+        let bakerfile = parse_bakerfile("# My image\nFROM raspios:bookworm\nRUN echo hello\n");
+        assert_eq!(bakerfile.unwrap().stages[0].from.image, "raspios");
+    }
+
+    #[test]
+    fn test_parse_bakerfile_leading_blank_line() {
+        let bakerfile = parse_bakerfile("\n  \nFROM raspios:bookworm\nRUN echo hello\n");
+        assert_eq!(bakerfile.unwrap().stages[0].from.image, "raspios");
+    }
+
+    #[test]
+    fn test_parse_bakerfile_leading_run() {
+        match parse_bakerfile("\n  RUN echo hello\nFROM raspios:bookworm\n") {
+            Err(Error::Parse { line, col, msg, .. }) => {
+                assert_eq!((line, col), (2, 3));
+                assert_eq!(msg, "the first instruction must be FROM, found RUN");
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_bakerfile_trailing_content() {
+        match parse_bakerfile("FROM raspios:bookworm\nRUN echo hello\n\nFOO bar\nRUN echo world\n")
+        {
+            Err(Error::Parse { line, col, msg, .. }) => {
+                assert_eq!((line, col), (4, 1));
+                assert_eq!(msg, "unknown instruction 'FOO'");
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_bakerfile_trailing_blank_lines() {
+        let bakerfile = parse_bakerfile("FROM raspios:bookworm\nRUN echo hello\n\n  \n# done\n");
+        assert_eq!(bakerfile.unwrap().stages[0].instructions.len(), 1);
+    }
+
+    #[test]
+    fn test_check_syntax() {
+        assert_eq!(
+            check_syntax("FROM raspios:bookworm\n").unwrap(),
+            SYNTAX_VERSION
+        );
+        assert_eq!(
+            check_syntax("# My image\n#syntax=baker/1\nFROM raspios:bookworm\n").unwrap(),
+            1
+        );
+        // Directives are only read before the first instruction
+        assert_eq!(
+            check_syntax("FROM raspios:bookworm\n# syntax=baker/9\n").unwrap(),
+            SYNTAX_VERSION
+        );
+        assert!(matches!(
+            check_syntax("# syntax=docker/dockerfile:1\n"),
+            Err(Error::Parse {
+                line: 1,
+                col: 10,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_bakerfile_newer_syntax() {
+        match parse_bakerfile("# syntax = baker/99\nFROM raspios:bookworm\nRUN echo hello\n") {
+            Err(Error::Parse { line, msg, .. }) => {
+                assert_eq!(line, 1);
+                assert!(msg.contains("requires syntax baker/99"));
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_bakerfile_unknown_instruction() {
+        let err =
+            parse_bakerfile("FROM raspios:bookworm\nRUN echo hello\n    COPPY a /b\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Bakerfile:3:5: unknown instruction 'COPPY', did you mean 'COPY'?"
+        );
+    }
+
+    #[test]
+    fn test_parse_bakerfile_invalid_instruction() {
+        match parse_bakerfile("FROM raspios:bookworm\nSSH maybe\n") {
+            Err(Error::Parse { line, col, msg, .. }) => {
+                assert_eq!((line, col), (2, 1));
+                assert_eq!(msg, "invalid SSH instruction");
+            }
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_bakerfile_names_the_file() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("Bakerfile.kiosk");
+        fs::write(&path, "FROM raspios:bookworm\nTIMEZONE\n").unwrap();
+
+        assert_eq!(
+            load_bakerfile(&path).unwrap_err().to_string(),
+            format!("{}:2:1: invalid TIMEZONE instruction", path.display())
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("COPPY", "COPY"), 1);
+        assert_eq!(edit_distance("", "RUN"), 3);
+        assert_eq!(edit_distance("USER", "USER"), 0);
+    }
 
-pub(crate) fn load_bakerfile(path: &str) -> Result<BakerFile, std::io::Error> {
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    parser::parse_baker_file::<()>(&contents)
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
-        .map(|(_, bakerfile)| bakerfile)
-        .into()
+    #[test]
+    fn test_parse_bakerfile_missing_from() {
+        match parse_bakerfile("# Nothing here\n") {
+            Err(Error::Parse { msg, .. }) => assert_eq!(msg, "missing FROM instruction"),
+            other => panic!("Expected a parse error, got {:?}", other),
+        }
+    }
 }
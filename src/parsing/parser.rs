@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf};
 
 use glob::glob;
 use nom::{
@@ -7,18 +7,259 @@ use nom::{
     character::complete::{space0, space1},
     combinator::opt,
     error::ParseError,
-    multi::many0,
+    multi::{many0, many1},
     sequence::{preceded, separated_pair, tuple},
     Err, IResult,
 };
 #[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Instruction {
     ENV(Vec<(String, String)>),
-    RUN(String),
-    COPY(String, PathBuf),
+    RUN(RunOptions, RunCommand),
+    /// Sources, target and the stage or image to copy from
+    COPY(String, PathBuf, Option<String>),
+    /// Source path or url, target and the expected checksum
+    ADD(String, PathBuf, Option<String>),
     WORKDIR(String),
     USER(String),
     CMD(String),
+    LABEL(Vec<(String, String)>),
+    WIFI(Vec<(String, String)>),
+    /// Whether SSH gets enabled or disabled
+    SSH(bool),
+    USERADD(NewUser),
+    LOCALE(String),
+    TIMEZONE(String),
+    KEYBOARD(String),
+    /// Key and value of a config.txt setting
+    BOOTCONFIG(String, String),
+    CMDLINE(CmdlineEdit),
+    /// Overlay with its parameters and the `.dtbo` file to install
+    DTOVERLAY(String, Option<String>),
+    INSTALL(Vec<String>),
+    /// Whether the units get enabled or disabled
+    SERVICE(bool, Vec<String>),
+    EXPANDROOT(RootExpansion),
+    PARTITION(NewPartition),
+    /// Build argument with its default value
+    ARG(String, Option<String>),
+    /// Interpreter and its arguments, the command being appended last
+    SHELL(Vec<String>),
+    /// Main process of the device, CMD giving its default arguments
+    ENTRYPOINT(EntrypointOptions, String),
+    /// Line appended to /etc/fstab
+    FSTAB(FstabEntry),
+    /// Public key files installed for a user, the USER one by default
+    #[allow(non_camel_case_types)]
+    AUTHORIZED_KEYS(Option<String>, Vec<String>),
+    /// Kernel image installed on the boot partition and its modules archive
+    KERNEL(String, Option<String>),
+    /// Instruction only applied when building for the platform
+    ONPLATFORM(String, Box<Instruction>),
+    /// Instructions only applied when the condition holds, up to ENDIF
+    IF(Condition, Vec<Instruction>),
+}
+
+/// Keywords starting an instruction, FROM included.
+pub const INSTRUCTIONS: &[&str] = &[
+    "FROM",
+    "ENV",
+    "RUN",
+    "COPY",
+    "ADD",
+    "WORKDIR",
+    "USER",
+    "CMD",
+    "ENTRYPOINT",
+    "LABEL",
+    "ARG",
+    "SHELL",
+    "WIFI",
+    "SSH",
+    "USERADD",
+    "LOCALE",
+    "TIMEZONE",
+    "KEYBOARD",
+    "BOOTCONFIG",
+    "CMDLINE",
+    "DTOVERLAY",
+    "INSTALL",
+    "SERVICE",
+    "EXPANDROOT",
+    "PARTITION",
+    "FSTAB",
+    "AUTHORIZED_KEYS",
+    "KERNEL",
+    "ONPLATFORM",
+    "IF",
+    "ENDIF",
+];
+
+/// Comparison of an IF, both sides going through variable substitution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub left: String,
+    pub right: String,
+    pub negated: bool,
+}
+
+impl Condition {
+    pub fn holds(&self) -> bool {
+        (self.left == self.right) != self.negated
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = if self.negated { "!=" } else { "=" };
+        write!(f, "{}{}{}", self.left, operator, self.right)
+    }
+}
+
+/// Systemd policies of the service running the ENTRYPOINT.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct EntrypointOptions {
+    /// `Restart=` policy, systemd not restarting the process by default
+    pub restart: Option<String>,
+    /// Target starting the service, `multi-user.target` by default
+    pub wanted_by: Option<String>,
+}
+
+const RESTART_POLICIES: &[&str] = &[
+    "no",
+    "always",
+    "on-success",
+    "on-failure",
+    "on-abnormal",
+    "on-abort",
+    "on-watchdog",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootExpansion {
+    /// Whether the root partition grows on first boot
+    FirstBoot(bool),
+    /// Size the image is grown to at build time
+    To(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CmdlineEdit {
+    Append(Vec<String>),
+    Remove(Vec<String>),
+}
+
+/// Flags given to RUN before its command.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RunOptions {
+    pub mounts: Vec<RunMount>,
+    /// User running this step only, instead of the one set by USER
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunMount {
+    /// Secret exposed as a file at `target`, `/run/secrets/<id>` by default
+    Secret { id: String, target: Option<String> },
+    /// Host directory kept between builds, shared by the mounts of the same
+    /// id which defaults to the target
+    Cache { id: Option<String>, target: String },
+}
+
+impl RunMount {
+    /// Parses the value of a `--mount=type=secret,id=NAME[,target=PATH]` flag.
+    fn parse(spec: &str) -> Option<RunMount> {
+        let mut kind = None;
+        let mut id = None;
+        let mut target = None;
+
+        for field in spec.split(',') {
+            match field.split_once('=')? {
+                ("type", value) => kind = Some(value),
+                ("id", value) if !value.is_empty() => id = Some(value.to_string()),
+                ("target" | "dst" | "destination", value) if value.starts_with('/') => {
+                    target = Some(value.to_string())
+                }
+                _ => return None,
+            }
+        }
+
+        match kind? {
+            "secret" => Some(RunMount::Secret { id: id?, target }),
+            "cache" => Some(RunMount::Cache {
+                id,
+                target: target?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RunMount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunMount::Secret { id, target: None } => write!(f, "type=secret,id={}", id),
+            RunMount::Secret {
+                id,
+                target: Some(target),
+            } => write!(f, "type=secret,id={},target={}", id, target),
+            RunMount::Cache { id: None, target } => write!(f, "type=cache,target={}", target),
+            RunMount::Cache {
+                id: Some(id),
+                target,
+            } => write!(f, "type=cache,id={},target={}", id, target),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunCommand {
+    Shell(String),
+    /// Script given inline between `<<DELIMITER` and `DELIMITER`
+    Heredoc {
+        delimiter: String,
+        script: String,
+    },
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct NewUser {
+    pub name: String,
+    pub password_hash: Option<String>,
+    pub groups: Vec<String>,
+    pub shell: Option<String>,
+    /// Also write the `userconf.txt` read on first boot
+    pub userconf: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NewPartition {
+    pub label: String,
+    pub size: String,
+    pub filesystem: String,
+    /// Where the partition gets mounted through fstab
+    pub mount: Option<String>,
+}
+
+/// Fields of an fstab line, dump and pass defaulting to 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FstabEntry {
+    pub device: String,
+    pub mount_point: String,
+    pub filesystem: String,
+    pub options: String,
+    pub dump: u8,
+    pub pass: u8,
+}
+
+impl fmt::Display for FstabEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}  {}  {}  {}  {}  {}",
+            self.device, self.mount_point, self.filesystem, self.options, self.dump, self.pass
+        )
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq)]
@@ -26,15 +267,209 @@ pub struct FromClause {
     pub image: String,
     pub tag: Option<String>,
     pub platform: Option<String>,
+    pub alias: Option<String>,
 }
 #[derive(Debug, Clone, PartialEq)]
-pub struct BakerFile {
+pub struct Stage {
     pub from: FromClause,
     pub instructions: Vec<Instruction>,
 }
+#[derive(Debug, Clone, PartialEq)]
+pub struct BakerFile {
+    /// Arguments declared before the first FROM, the only ones FROM sees
+    pub args: Vec<(String, Option<String>)>,
+    pub stages: Vec<Stage>,
+}
+
+/// Formats `key=value`, quoting the value when it would not be read back as
+/// a single word.
+fn format_pair(key: &str, value: &str) -> String {
+    if value.contains(|ch: char| ch.is_whitespace() || ch == '"' || ch == '\'') {
+        format!(
+            "{}=\"{}\"",
+            key,
+            value.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    } else {
+        format!("{}={}", key, value)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::ENV(envs) => {
+                let envs: Vec<String> = envs
+                    .iter()
+                    .map(|(key, value)| format_pair(key, value))
+                    .collect();
+                write!(f, "ENV {}", envs.join(" "))
+            }
+            Instruction::RUN(options, command) => {
+                write!(f, "RUN ")?;
+                if let Some(user) = &options.user {
+                    write!(f, "--user={} ", user)?;
+                }
+                for mount in &options.mounts {
+                    write!(f, "--mount={} ", mount)?;
+                }
+                match command {
+                    RunCommand::Shell(command) => write!(f, "{}", command),
+                    RunCommand::Heredoc { delimiter, script } => {
+                        write!(f, "<<{}\n{}{}", delimiter, script, delimiter)
+                    }
+                }
+            }
+            Instruction::COPY(source, target, None) => {
+                write!(f, "COPY {} {}", source, target.display())
+            }
+            Instruction::COPY(source, target, Some(stage)) => {
+                write!(f, "COPY --from={} {} {}", stage, source, target.display())
+            }
+            Instruction::ADD(source, target, None) => {
+                write!(f, "ADD {} {}", source, target.display())
+            }
+            Instruction::ADD(source, target, Some(checksum)) => {
+                write!(
+                    f,
+                    "ADD --checksum={} {} {}",
+                    checksum,
+                    source,
+                    target.display()
+                )
+            }
+            Instruction::WORKDIR(workdir) => write!(f, "WORKDIR {}", workdir),
+            Instruction::USER(user) => write!(f, "USER {}", user),
+            Instruction::CMD(command) => write!(f, "CMD {}", command),
+            Instruction::ONPLATFORM(platform, instruction) => {
+                write!(f, "ONPLATFORM {} {}", platform, instruction)
+            }
+            Instruction::IF(condition, instructions) => {
+                writeln!(f, "IF {}", condition)?;
+                for instruction in instructions {
+                    writeln!(f, "{}", instruction)?;
+                }
+                write!(f, "ENDIF")
+            }
+            Instruction::KERNEL(kernel, None) => write!(f, "KERNEL {}", kernel),
+            Instruction::KERNEL(kernel, Some(modules)) => {
+                write!(f, "KERNEL {} {}", kernel, modules)
+            }
+            Instruction::AUTHORIZED_KEYS(user, sources) => {
+                write!(f, "AUTHORIZED_KEYS ")?;
+                if let Some(user) = user {
+                    write!(f, "user={} ", user)?;
+                }
+                write!(f, "{}", sources.join(" "))
+            }
+            Instruction::FSTAB(entry) => write!(
+                f,
+                "FSTAB {} {} {} {} {} {}",
+                entry.device,
+                entry.mount_point,
+                entry.filesystem,
+                entry.options,
+                entry.dump,
+                entry.pass
+            ),
+            Instruction::ENTRYPOINT(options, command) => {
+                write!(f, "ENTRYPOINT ")?;
+                if let Some(restart) = &options.restart {
+                    write!(f, "--restart={} ", restart)?;
+                }
+                if let Some(wanted_by) = &options.wanted_by {
+                    write!(f, "--wanted-by={} ", wanted_by)?;
+                }
+                write!(f, "{}", command)
+            }
+            Instruction::SHELL(shell) => {
+                let shell: Vec<String> = shell
+                    .iter()
+                    .map(|word| serde_json::to_string(word).map_err(|_| fmt::Error))
+                    .collect::<Result<_, _>>()?;
+                write!(f, "SHELL [{}]", shell.join(", "))
+            }
+            Instruction::ARG(name, None) => write!(f, "ARG {}", name),
+            Instruction::ARG(name, Some(default)) => write!(f, "ARG {}={}", name, default),
+            Instruction::LABEL(labels) => {
+                let labels: Vec<String> = labels
+                    .iter()
+                    .map(|(key, value)| format_pair(key, value))
+                    .collect();
+                write!(f, "LABEL {}", labels.join(" "))
+            }
+            Instruction::WIFI(options) => {
+                // The PSK must not end up in the image history
+                let options: Vec<String> = options
+                    .iter()
+                    .map(|(key, value)| match key.as_str() {
+                        "psk" => format!("{}=****", key),
+                        _ => format_pair(key, value),
+                    })
+                    .collect();
+                write!(f, "WIFI {}", options.join(" "))
+            }
+            Instruction::SSH(true) => write!(f, "SSH enable"),
+            Instruction::SSH(false) => write!(f, "SSH disable"),
+            Instruction::LOCALE(locale) => write!(f, "LOCALE {}", locale),
+            Instruction::TIMEZONE(timezone) => write!(f, "TIMEZONE {}", timezone),
+            Instruction::KEYBOARD(layout) => write!(f, "KEYBOARD {}", layout),
+            Instruction::BOOTCONFIG(key, value) => write!(f, "BOOTCONFIG {}={}", key, value),
+            Instruction::SERVICE(true, units) => write!(f, "SERVICE enable {}", units.join(" ")),
+            Instruction::SERVICE(false, units) => {
+                write!(f, "SERVICE disable {}", units.join(" "))
+            }
+            Instruction::EXPANDROOT(RootExpansion::FirstBoot(true)) => write!(f, "EXPANDROOT on"),
+            Instruction::EXPANDROOT(RootExpansion::FirstBoot(false)) => {
+                write!(f, "EXPANDROOT off")
+            }
+            Instruction::EXPANDROOT(RootExpansion::To(size)) => write!(f, "EXPANDROOT to={}", size),
+            Instruction::INSTALL(packages) => write!(f, "INSTALL {}", packages.join(" ")),
+            Instruction::DTOVERLAY(overlay, None) => write!(f, "DTOVERLAY {}", overlay),
+            Instruction::DTOVERLAY(overlay, Some(dtbo)) => {
+                write!(f, "DTOVERLAY {} {}", overlay, dtbo)
+            }
+            Instruction::CMDLINE(CmdlineEdit::Append(arguments)) => {
+                write!(f, "CMDLINE append {}", arguments.join(" "))
+            }
+            Instruction::CMDLINE(CmdlineEdit::Remove(arguments)) => {
+                write!(f, "CMDLINE remove {}", arguments.join(" "))
+            }
+            Instruction::PARTITION(partition) => {
+                write!(
+                    f,
+                    "PARTITION add label={} size={} fs={}",
+                    partition.label, partition.size, partition.filesystem
+                )?;
+                if let Some(mount) = &partition.mount {
+                    write!(f, " mount={}", mount)?;
+                }
+                Ok(())
+            }
+            Instruction::USERADD(user) => {
+                write!(f, "USERADD {}", user.name)?;
+                // The password hash must not end up in the image history
+                if user.password_hash.is_some() {
+                    write!(f, " --password-hash ****")?;
+                }
+                if !user.groups.is_empty() {
+                    write!(f, " --groups {}", user.groups.join(","))?;
+                }
+                if let Some(shell) = &user.shell {
+                    write!(f, " --shell {}", shell)?;
+                }
+                if user.userconf {
+                    write!(f, " --userconf")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
 
 impl Eq for Instruction {}
 impl Eq for FromClause {}
+impl Eq for Stage {}
 impl Eq for BakerFile {}
 
 ///
@@ -44,11 +479,62 @@ fn comsume_ws<'a, E: ParseError<&'a str>>(input: &'a str) -> IResult<&'a str, &'
     space0(input)
 }
 
-fn kw_with_ws<'a, E: ParseError<&'a str>>(i: &'a str, kw: &'a str) -> IResult<&'a str, &'a str, E> {
-    let (tail, (_, _, line)) = tuple((tag(kw), comsume_ws, till_eol))(i)?;
+fn keyword<'a, E: ParseError<&'a str>>(i: &'a str, kw: &'a str) -> IResult<&'a str, &'a str, E> {
+    let (rest, kw) = tag(kw)(i)?;
+    // USER must not match the start of USERADD
+    if rest.starts_with(|ch: char| !ch.is_whitespace()) {
+        return Err(Err::Error(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Tag,
+        )));
+    }
+    Ok((rest, kw))
+}
+
+fn kw_with_ws<'a, E: ParseError<&'a str>>(i: &'a str, kw: &'a str) -> IResult<&'a str, String, E> {
+    let (rest, _) = keyword(i, kw)?;
+    let (tail, (_, line)) = tuple((comsume_ws, till_continued_eol))(rest)?;
     Ok((tail, line))
 }
 
+/// Splits a line on whitespace, except inside single or double quotes which
+/// are removed. A backslash escapes the next character outside single quotes,
+/// but is kept before a `$` for variable substitution to see. Returns `None`
+/// on an unterminated quote or a trailing backslash.
+fn split_words(line: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            (Some(open), ch) if ch == open => quote = None,
+            (None, '"' | '\'') => {
+                quote = Some(ch);
+                word.get_or_insert_with(String::new);
+            }
+            (None | Some('"'), '\\') => {
+                let escaped = chars.next()?;
+                let word = word.get_or_insert_with(String::new);
+                if escaped == '$' {
+                    word.push('\\');
+                }
+                word.push(escaped);
+            }
+            (None, ch) if ch.is_whitespace() => words.extend(word.take()),
+            (_, ch) => word.get_or_insert_with(String::new).push(ch),
+        }
+    }
+
+    if quote.is_some() {
+        return None;
+    }
+    words.extend(word);
+
+    Some(words)
+}
+
 fn is_glob_pattern(path: &str) -> bool {
     glob(path).is_ok()
 }
@@ -66,12 +552,40 @@ fn till_eol<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str,
     Ok((tail, line))
 }
 
+/// Joins lines ending with a `\\` with the next one, skipping the comment
+/// lines found in between.
+fn till_continued_eol<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, String, E> {
+    let (mut tail, line) = till_eol(i)?;
+    let mut line = line.to_string();
+
+    while let Some(continued) = line.trim_end().strip_suffix('\\') {
+        line.truncate(continued.len());
+        let (next, _) = many0(consume_comment_line)(tail)?;
+        let (next, next_line) = till_eol(next)?;
+        line.push_str(next_line);
+        tail = next;
+    }
+
+    Ok((tail, line))
+}
+
 fn consume_eol<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
     let (tail, _) = alt((tag("\r\n"), tag("\n")))(i)?;
     Ok((tail, ""))
 }
-fn consume_blank_line<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
-    let (tail, _) = tuple((comsume_ws, opt(consume_eol)))(i)?;
+fn consume_comment_line<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
+    let (tail, _) = tuple((comsume_ws, tag("#"), till_eol))(i)?;
+    Ok((tail, ""))
+}
+
+fn consume_empty_line<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
+    let (tail, _) = tuple((comsume_ws, consume_eol))(i)?;
+    Ok((tail, ""))
+}
+
+fn consume_preamble<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
+    let (tail, _) = many0(alt((consume_comment_line, consume_empty_line)))(i)?;
+    let (tail, _) = comsume_ws(tail)?;
     Ok((tail, ""))
 }
 
@@ -81,21 +595,50 @@ fn consume_blank_line<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str
 
 fn parse_cmd<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
     let (tail, cmd) = kw_with_ws(i, "CMD")?;
-    Ok((tail, Instruction::CMD(cmd.to_string())))
+    Ok((tail, Instruction::CMD(cmd)))
+}
+
+fn parse_entrypoint<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "ENTRYPOINT")?;
+    let fail = || Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail));
+
+    let mut options = EntrypointOptions::default();
+    let mut command = line.as_str();
+    while let Some(flag) = command.strip_prefix("--") {
+        let (flag, rest) = flag.split_once(' ').ok_or_else(fail)?;
+        match flag.split_once('=') {
+            Some(("restart", policy))
+                if options.restart.is_none() && RESTART_POLICIES.contains(&policy) =>
+            {
+                options.restart = Some(policy.to_string())
+            }
+            Some(("wanted-by", target)) if options.wanted_by.is_none() && target.contains('.') => {
+                options.wanted_by = Some(target.to_string())
+            }
+            _ => return Err(fail()),
+        }
+        command = rest.trim_start();
+    }
+    if command.is_empty() {
+        return Err(fail());
+    }
+
+    Ok((tail, Instruction::ENTRYPOINT(options, command.to_string())))
 }
 
 fn parse_user<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
     let (tail, user) = kw_with_ws(i, "USER")?;
-    Ok((tail, Instruction::USER(user.to_string())))
+    Ok((tail, Instruction::USER(user)))
 }
 
 fn parse_workdir<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
     let (tail, workdir) = kw_with_ws(i, "WORKDIR")?;
-    Ok((tail, Instruction::WORKDIR(workdir.to_string())))
+    Ok((tail, Instruction::WORKDIR(workdir)))
 }
 
 fn parse_copy<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
     let (paths, _) = tuple((nom::bytes::complete::tag("COPY"), comsume_ws))(i)?;
+    let (paths, stage) = opt(tuple((tag("--from="), non_space, space1)))(paths)?;
     let (tail, (src, dest)) = separated_pair(non_space, tag(" "), till_eol)(paths)?;
     if !is_glob_pattern(src) || !is_glob_pattern(&dest) {
         return Err(Err::Failure(E::from_error_kind(
@@ -104,16 +647,124 @@ fn parse_copy<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instru
         )));
     }
 
-    Ok((tail, Instruction::COPY(src.to_string(), dest.into())))
+    Ok((
+        tail,
+        Instruction::COPY(
+            src.to_string(),
+            dest.into(),
+            stage.map(|(_, stage, _)| stage.to_string()),
+        ),
+    ))
+}
+
+fn parse_add<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (paths, _) = tuple((tag("ADD"), comsume_ws))(i)?;
+    let (paths, checksum) = opt(tuple((tag("--checksum="), non_space, space1)))(paths)?;
+    let (tail, (src, dest)) = separated_pair(non_space, tag(" "), till_eol)(paths)?;
+    let is_url = src.starts_with("http://") || src.starts_with("https://");
+    if (!is_url && !is_glob_pattern(src)) || !is_glob_pattern(dest) {
+        return Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        )));
+    }
+
+    Ok((
+        tail,
+        Instruction::ADD(
+            src.to_string(),
+            dest.into(),
+            checksum.map(|(_, checksum, _)| checksum.to_string()),
+        ),
+    ))
+}
+
+/// Takes the lines following `RUN <<DELIMITER` verbatim, up to the line
+/// holding only the delimiter.
+fn parse_heredoc<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, RunCommand, E> {
+    let (mut tail, (_, _, delimiter, _)) = tuple((
+        comsume_ws,
+        tag("<<"),
+        take_till(|ch| ch == ' ' || eol(ch)),
+        till_eol,
+    ))(i)?;
+    let delimiter = delimiter.trim_matches(|ch| ch == '\'' || ch == '"');
+    if delimiter.is_empty() {
+        return Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        )));
+    }
+
+    let mut script = String::new();
+    while !tail.is_empty() {
+        let (next, line) = till_eol(tail)?;
+        if line == delimiter {
+            return Ok((
+                next,
+                RunCommand::Heredoc {
+                    delimiter: delimiter.to_string(),
+                    script,
+                },
+            ));
+        }
+        script.push_str(line);
+        script.push('\n');
+        tail = next;
+    }
+
+    Err(Err::Failure(E::from_error_kind(
+        i,
+        nom::error::ErrorKind::Eof,
+    )))
+}
+
+fn parse_shell<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, RunCommand, E> {
+    let (tail, (_, run)) = tuple((comsume_ws, till_continued_eol))(i)?;
+    Ok((tail, RunCommand::Shell(run)))
+}
+
+fn parse_run_flag<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, (&'a str, &'a str), E> {
+    let (tail, (_, _, flag, _, value)) = tuple((
+        space1,
+        tag("--"),
+        alt((tag("mount"), tag("user"))),
+        tag("="),
+        take_till(|ch| ch == ' ' || eol(ch)),
+    ))(i)?;
+    Ok((tail, (flag, value)))
 }
 
 fn parse_run<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
-    let (tail, run) = kw_with_ws(i, "RUN")?;
-    Ok((tail, Instruction::RUN(run.to_string())))
+    let (mut rest, _) = keyword(i, "RUN")?;
+    let mut options = RunOptions::default();
+    loop {
+        let flag_start = rest;
+        match parse_run_flag::<E>(rest) {
+            Ok((next, (flag, value))) => {
+                let fail =
+                    || Err::Failure(E::from_error_kind(flag_start, nom::error::ErrorKind::Fail));
+                match flag {
+                    "mount" => options
+                        .mounts
+                        .push(RunMount::parse(value).ok_or_else(fail)?),
+                    _ if value.is_empty() || options.user.is_some() => return Err(fail()),
+                    _ => options.user = Some(value.to_string()),
+                }
+                rest = next;
+            }
+            Err(Err::Error(_)) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    let (tail, run) = alt((parse_heredoc, parse_shell))(rest)?;
+    Ok((tail, Instruction::RUN(options, run)))
 }
 
 fn parse_tag<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, &'a str, E> {
-    let (tail, (_, tag)) = tuple((tag(":"), till_eol))(i)?;
+    let (tail, (_, tag)) = tuple((tag(":"), non_space))(i)?;
     Ok((tail, tag))
 }
 
@@ -126,158 +777,1253 @@ fn parse_from<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, FromCl
         comsume_ws,
     )))(line)?;
     let platform = pt.map(|(_, _, p, _)| p.to_string());
-    let (last, image) = take_till(|ch| eol(ch) || ch == ':')(img)?;
-    let tag = if !last.is_empty() {
-        let (_, tag) = parse_tag(last)?;
-        Some(tag.to_string())
-    } else {
-        None
-    };
+    let (last, image) = take_till(|ch| eol(ch) || ch == ':' || ch == ' ')(img)?;
+    let (last, image_tag) = opt(parse_tag)(last)?;
+    let (_, alias) = opt(preceded(
+        tuple((space1, alt((tag("AS"), tag("as"))), space1)),
+        non_space,
+    ))(last)?;
 
     Ok((
         tail,
         FromClause {
             image: image.to_string(),
-            tag,
+            tag: image_tag.map(|tag| tag.to_string()),
             platform,
+            alias: alias.map(|alias| alias.to_string()),
         },
     ))
 }
 
 fn parse_env<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
-    let (tail, (_, _, envs)) = tuple((tag("ENV"), comsume_ws, till_eol))(i)?;
-    let envs = envs
-        .split_whitespace()
-        .map(|env| {
-            let mut env = env.split('=');
-            let key = env.next().unwrap().to_string();
-            let value = env.next().unwrap().to_string();
-            (key, value)
-        })
-        .collect();
+    let (tail, envs) = parse_pairs(i, "ENV")?;
+    if envs.iter().any(|(key, _)| {
+        !key.chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+    }) {
+        return Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        )));
+    }
     Ok((tail, Instruction::ENV(envs)))
 }
 
-fn parse_instruction<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
-    let (tail, (_, instruction)) = tuple((
-        consume_blank_line,
-        nom::branch::alt((
-            parse_cmd,
-            parse_user,
-            parse_workdir,
-            parse_copy,
-            parse_run,
-            parse_env,
-        )),
-    ))(i)?;
-    Ok((tail, instruction))
+/// Parses an instruction made of one or more `key=value` pairs.
+fn parse_pairs<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+    kw: &'a str,
+) -> IResult<&'a str, Vec<(String, String)>, E> {
+    let (tail, pairs) = kw_with_ws(i, kw)?;
+    let pairs = split_words(&pairs)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            Some((key.to_string(), value.to_string())).filter(|_| !key.is_empty())
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|pairs| !pairs.is_empty())
+        .ok_or_else(|| Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail)))?;
+    Ok((tail, pairs))
 }
 
-fn parse_instructions<'a, E: ParseError<&'a str>>(
+fn parse_arg_declaration<'a, E: ParseError<&'a str>>(
     i: &'a str,
-) -> IResult<&'a str, Vec<Instruction>, E> {
-    many0(parse_instruction)(i)
+) -> IResult<&'a str, (String, Option<String>), E> {
+    let (tail, line) = kw_with_ws(i, "ARG")?;
+    let line = line.trim();
+    let (name, default) = match line.split_once('=') {
+        Some((name, default)) => (name, Some(default.to_string())),
+        None => (line, None),
+    };
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+    {
+        return Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        )));
+    }
+    Ok((tail, (name.to_string(), default)))
 }
 
-pub fn parse_baker_file<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BakerFile, E> {
-    let (insts, from) = parse_from(i)?;
-    let (tail, instructions) = parse_instructions(insts)?;
-    Ok((tail, BakerFile { from, instructions }))
+fn parse_arg<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, (name, default)) = parse_arg_declaration(i)?;
+    Ok((tail, Instruction::ARG(name, default)))
 }
 
-#[test]
-fn test_parse_copy() {
-    let input = "COPY /src/* /dest\n";
-    let (_, res) = parse_copy::<()>(input).unwrap();
-    assert_eq!(res, Instruction::COPY("/src/*".to_string(), "/dest".into()));
+fn parse_global_arg<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, (String, Option<String>), E> {
+    let (tail, (_, arg)) = tuple((consume_preamble, parse_arg_declaration))(i)?;
+    Ok((tail, arg))
 }
 
-#[test]
-fn test_parse_workdir() {
-    let input = "WORKDIR /src\n";
-    let (_, res) = parse_workdir::<()>(input).unwrap();
-    assert_eq!(res, Instruction::WORKDIR("/src".to_string()));
+fn parse_shell_instruction<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "SHELL")?;
+    match serde_json::from_str::<Vec<String>>(&line) {
+        Ok(shell) if !shell.is_empty() => Ok((tail, Instruction::SHELL(shell))),
+        _ => Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
 }
 
-#[test]
-fn test_parse_user() {
-    let input = "USER root\n";
-    let (_, res) = parse_user::<()>(input).unwrap();
-    assert_eq!(res, Instruction::USER("root".to_string()));
+fn parse_label<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, labels) = parse_pairs(i, "LABEL")?;
+    Ok((tail, Instruction::LABEL(labels)))
 }
 
-#[test]
-fn test_parse_cmd() {
-    let input = "CMD echo hello\n";
-    let (_, res) = parse_cmd::<()>(input).unwrap();
-    assert_eq!(res, Instruction::CMD("echo hello".to_string()));
+fn parse_wifi<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, options) = parse_pairs(i, "WIFI")?;
+    Ok((tail, Instruction::WIFI(options)))
 }
 
-#[test]
-fn test_parse_run() {
-    let input = "RUN echo hello\n";
-    let (_, res) = parse_run::<()>(input).unwrap();
-    assert_eq!(res, Instruction::RUN("echo hello".to_string()));
+fn parse_ssh<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, action) = kw_with_ws(i, "SSH")?;
+    match action.trim() {
+        "enable" => Ok((tail, Instruction::SSH(true))),
+        "disable" => Ok((tail, Instruction::SSH(false))),
+        _ => Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
 }
 
-#[test]
-fn test_parse_env() {
-    let input = "ENV KEY1=VALUE1 KEY2=VALUE2";
-    let (_, res) = parse_env::<()>(input).unwrap();
-    assert_eq!(
-        res,
-        Instruction::ENV(vec![
-            ("KEY1".to_string(), "VALUE1".to_string()),
-            ("KEY2".to_string(), "VALUE2".to_string())
-        ])
-    );
+/// Parses an instruction taking a single word.
+fn parse_word<'a, E: ParseError<&'a str>>(i: &'a str, kw: &'a str) -> IResult<&'a str, String, E> {
+    let (tail, word) = kw_with_ws(i, kw)?;
+    let word = word.trim();
+    if word.is_empty() || word.contains(char::is_whitespace) {
+        return Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        )));
+    }
+    Ok((tail, word.to_string()))
 }
 
-#[test]
-fn test_parse_from_full_options() {
-    let input = "FROM --platform x86 ubuntu:latest\n";
-    let (_, res) = parse_from::<()>(input).unwrap();
-    assert_eq!(
-        res,
-        FromClause {
-            image: "ubuntu".to_string(),
-            tag: Some("latest".to_string()),
-            platform: Some("x86".to_string())
-        }
-    );
+fn parse_locale<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, locale) = parse_word(i, "LOCALE")?;
+    Ok((tail, Instruction::LOCALE(locale)))
 }
 
-#[test]
-fn test_parse_from_no_options() {
-    let input = "FROM ubuntu\n";
-    let (_, res) = parse_from::<()>(input).unwrap();
-    assert_eq!(
-        res,
-        FromClause {
-            image: "ubuntu".to_string(),
-            tag: None,
-            platform: None
-        }
-    );
+fn parse_timezone<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, timezone) = parse_word(i, "TIMEZONE")?;
+    Ok((tail, Instruction::TIMEZONE(timezone)))
 }
 
-#[test]
-fn test_parse_baker_file() {
-    let input = "FROM ubuntu\nUSER root\n   \nCMD echo hello";
-    let (_, res) = parse_baker_file::<()>(input).unwrap();
-    assert_eq!(
-        res,
-        BakerFile {
-            from: FromClause {
-                image: "ubuntu".to_string(),
-                tag: None,
-                platform: None
-            },
-            instructions: vec![
-                Instruction::USER("root".to_string()),
-                Instruction::CMD("echo hello".to_string()),
-            ]
+fn parse_keyboard<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, layout) = parse_word(i, "KEYBOARD")?;
+    Ok((tail, Instruction::KEYBOARD(layout)))
+}
+
+fn parse_bootconfig<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, setting) = parse_word(i, "BOOTCONFIG")?;
+    match setting.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((
+            tail,
+            Instruction::BOOTCONFIG(key.to_string(), value.to_string()),
+        )),
+        _ => Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
+
+fn parse_cmdline<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "CMDLINE")?;
+    let mut words = line.split_whitespace();
+    let action = words.next();
+    let arguments: Vec<String> = words.map(|word| word.to_string()).collect();
+
+    let edit = match action {
+        _ if arguments.is_empty() => None,
+        Some("append") => Some(CmdlineEdit::Append(arguments)),
+        Some("remove") => Some(CmdlineEdit::Remove(arguments)),
+        _ => None,
+    }
+    .ok_or_else(|| Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail)))?;
+
+    Ok((tail, Instruction::CMDLINE(edit)))
+}
+
+fn parse_dtoverlay<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "DTOVERLAY")?;
+    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [overlay] => Ok((tail, Instruction::DTOVERLAY(overlay.to_string(), None))),
+        [overlay, dtbo] if is_glob_pattern(dtbo) => Ok((
+            tail,
+            Instruction::DTOVERLAY(overlay.to_string(), Some(dtbo.to_string())),
+        )),
+        _ => Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
+
+fn parse_kernel<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "KERNEL")?;
+    match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [kernel] if is_glob_pattern(kernel) => {
+            Ok((tail, Instruction::KERNEL(kernel.to_string(), None)))
         }
+        [kernel, modules] if is_glob_pattern(kernel) && is_glob_pattern(modules) => Ok((
+            tail,
+            Instruction::KERNEL(kernel.to_string(), Some(modules.to_string())),
+        )),
+        _ => Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
+
+fn parse_install<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "INSTALL")?;
+    let packages: Vec<String> = line
+        .split_whitespace()
+        .map(|word| word.to_string())
+        .collect();
+    if packages.is_empty() {
+        return Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        )));
+    }
+    Ok((tail, Instruction::INSTALL(packages)))
+}
+
+fn parse_service<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "SERVICE")?;
+    let mut words = line.split_whitespace();
+    let action = words.next();
+    let units: Vec<String> = words.map(|word| word.to_string()).collect();
+
+    match action {
+        Some("enable") if !units.is_empty() => Ok((tail, Instruction::SERVICE(true, units))),
+        Some("disable") if !units.is_empty() => Ok((tail, Instruction::SERVICE(false, units))),
+        _ => Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        ))),
+    }
+}
+
+fn parse_useradd<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "USERADD")?;
+    let fail = || Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail));
+
+    let mut words = line.split_whitespace();
+    let mut user = NewUser {
+        name: words
+            .next()
+            .filter(|name| !name.starts_with("--"))
+            .ok_or_else(fail)?
+            .to_string(),
+        ..Default::default()
+    };
+
+    while let Some(word) = words.next() {
+        match word {
+            "--password-hash" => {
+                user.password_hash = Some(words.next().ok_or_else(fail)?.to_string())
+            }
+            "--groups" => {
+                user.groups = words
+                    .next()
+                    .ok_or_else(fail)?
+                    .split(',')
+                    .map(|group| group.to_string())
+                    .collect()
+            }
+            "--shell" => user.shell = Some(words.next().ok_or_else(fail)?.to_string()),
+            "--userconf" => user.userconf = true,
+            _ => return Err(fail()),
+        }
+    }
+
+    Ok((tail, Instruction::USERADD(user)))
+}
+
+fn parse_authorized_keys<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "AUTHORIZED_KEYS")?;
+    let fail = || Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail));
+
+    let mut words = line.split_whitespace().peekable();
+    let user = match words.peek().and_then(|word| word.strip_prefix("user=")) {
+        Some(user) if !user.is_empty() => Some(user.to_string()),
+        Some(_) => return Err(fail()),
+        None => None,
+    };
+    if user.is_some() {
+        words.next();
+    }
+
+    let sources: Vec<String> = words.map(|word| word.to_string()).collect();
+    if sources.is_empty() {
+        return Err(fail());
+    }
+
+    Ok((tail, Instruction::AUTHORIZED_KEYS(user, sources)))
+}
+
+fn parse_expandroot<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, value) = parse_word(i, "EXPANDROOT")?;
+    let expansion = match value.as_str() {
+        "on" => Some(RootExpansion::FirstBoot(true)),
+        "off" => Some(RootExpansion::FirstBoot(false)),
+        value => value
+            .strip_prefix("to=")
+            .filter(|size| crate::size::parse_size(size).is_ok())
+            .map(|size| RootExpansion::To(size.to_string())),
+    }
+    .ok_or_else(|| Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail)))?;
+
+    Ok((tail, Instruction::EXPANDROOT(expansion)))
+}
+
+fn parse_partition<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "PARTITION")?;
+    let fail = || Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail));
+
+    let mut words = line.split_whitespace();
+    if words.next() != Some("add") {
+        return Err(fail());
+    }
+
+    let (mut label, mut size, mut filesystem, mut mount) = (None, None, None, None);
+    for word in words {
+        match word.split_once('=').ok_or_else(fail)? {
+            ("label", value) => label = Some(value.to_string()),
+            ("size", value) if crate::size::parse_size(value).is_ok() => {
+                size = Some(value.to_string())
+            }
+            ("fs", value) => filesystem = Some(value.to_string()),
+            ("mount", value) => mount = Some(value.to_string()),
+            _ => return Err(fail()),
+        }
+    }
+
+    Ok((
+        tail,
+        Instruction::PARTITION(NewPartition {
+            label: label.ok_or_else(fail)?,
+            size: size.ok_or_else(fail)?,
+            filesystem: filesystem.unwrap_or_else(|| "ext4".to_string()),
+            mount,
+        }),
+    ))
+}
+
+fn parse_fstab<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, line) = kw_with_ws(i, "FSTAB")?;
+    let fail = || Err::Failure(E::from_error_kind(i, nom::error::ErrorKind::Fail));
+
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let (device, mount_point, filesystem, options) = match fields[..] {
+        [device, mount_point, filesystem, options, ..] if fields.len() <= 6 => {
+            (device, mount_point, filesystem, options)
+        }
+        _ => return Err(fail()),
+    };
+    if !mount_point.starts_with('/') && mount_point != "none" && mount_point != "swap" {
+        return Err(fail());
+    }
+    let dump = fields.get(4).map_or(Ok(0), |dump| dump.parse::<u8>());
+    let pass = fields.get(5).map_or(Ok(0), |pass| pass.parse::<u8>());
+
+    match (dump, pass) {
+        (Ok(dump), Ok(pass)) if dump <= 1 && pass <= 2 => Ok((
+            tail,
+            Instruction::FSTAB(FstabEntry {
+                device: device.to_string(),
+                mount_point: mount_point.to_string(),
+                filesystem: filesystem.to_string(),
+                options: options.to_string(),
+                dump,
+                pass,
+            }),
+        )),
+        _ => Err(fail()),
+    }
+}
+
+fn parse_onplatform<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (rest, _) = keyword(i, "ONPLATFORM")?;
+    let (rest, (_, platform, _)) = tuple((space1, non_space, space1))(rest)?;
+    // The instruction has to follow on the same line
+    if rest.starts_with(char::is_whitespace) || platform.is_empty() {
+        return Err(Err::Failure(E::from_error_kind(
+            i,
+            nom::error::ErrorKind::Fail,
+        )));
+    }
+    let (tail, instruction) = parse_instruction(rest)?;
+    Ok((
+        tail,
+        Instruction::ONPLATFORM(platform.to_string(), Box::new(instruction)),
+    ))
+}
+
+fn parse_if<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (rest, line) = kw_with_ws(i, "IF")?;
+    let fail = |input| Err::Failure(E::from_error_kind(input, nom::error::ErrorKind::Fail));
+
+    let line = line.trim();
+    let condition = match line.split_once("!=") {
+        Some((left, right)) => (left, right, true),
+        None => match line.split_once('=') {
+            Some((left, right)) => (left, right, false),
+            None => return Err(fail(i)),
+        },
+    };
+    let (left, right, negated) = condition;
+    if left.trim().is_empty() {
+        return Err(fail(i));
+    }
+
+    let (rest, instructions) = parse_instructions(rest)?;
+    let (rest, _) = consume_preamble(rest)?;
+    let (tail, end) = kw_with_ws::<E>(rest, "ENDIF").map_err(|_| fail(rest))?;
+    if !end.trim().is_empty() {
+        return Err(fail(rest));
+    }
+
+    Ok((
+        tail,
+        Instruction::IF(
+            Condition {
+                left: left.trim().to_string(),
+                right: right.trim().to_string(),
+                negated,
+            },
+            instructions,
+        ),
+    ))
+}
+
+fn parse_instruction<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Instruction, E> {
+    let (tail, (_, instruction)) = tuple((
+        consume_preamble,
+        alt((
+            // Instructions shared with Dockerfiles
+            alt((
+                parse_cmd,
+                parse_entrypoint,
+                parse_user,
+                parse_workdir,
+                parse_copy,
+                parse_add,
+                parse_run,
+                parse_env,
+                parse_arg,
+                parse_shell_instruction,
+                parse_label,
+            )),
+            // Instructions configuring the Raspberry Pi system
+            alt((
+                parse_wifi,
+                parse_ssh,
+                parse_useradd,
+                parse_locale,
+                parse_timezone,
+                parse_keyboard,
+                parse_bootconfig,
+                parse_cmdline,
+                parse_dtoverlay,
+                parse_install,
+                parse_service,
+                parse_expandroot,
+                parse_partition,
+                parse_fstab,
+                parse_authorized_keys,
+                parse_kernel,
+            )),
+            // Instructions gating others
+            alt((parse_onplatform, parse_if)),
+        )),
+    ))(i)?;
+    Ok((tail, instruction))
+}
+
+fn parse_instructions<'a, E: ParseError<&'a str>>(
+    i: &'a str,
+) -> IResult<&'a str, Vec<Instruction>, E> {
+    many0(parse_instruction)(i)
+}
+
+fn parse_stage<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, Stage, E> {
+    let (from_line, _) = consume_preamble(i)?;
+    let (insts, from) = parse_from(from_line)?;
+    let (tail, instructions) = parse_instructions(insts)?;
+    Ok((tail, Stage { from, instructions }))
+}
+
+pub fn parse_baker_file<'a, E: ParseError<&'a str>>(i: &'a str) -> IResult<&'a str, BakerFile, E> {
+    let (i, args) = many0(parse_global_arg)(i)?;
+    let (tail, stages) = many1(parse_stage)(i)?;
+    Ok((tail, BakerFile { args, stages }))
+}
+
+#[test]
+fn test_parse_copy() {
+    let input = "COPY /src/* /dest\n";
+    let (_, res) = parse_copy::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::COPY("/src/*".to_string(), "/dest".into(), None)
+    );
+}
+
+#[test]
+fn test_parse_copy_from_stage() {
+    let input = "COPY --from=builder /usr/local/bin/app /usr/local/bin/\n";
+    let (_, res) = parse_copy::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::COPY(
+            "/usr/local/bin/app".to_string(),
+            "/usr/local/bin/".into(),
+            Some("builder".to_string())
+        )
+    );
+}
+
+#[test]
+fn test_parse_copy_from_image() {
+    let input = "COPY --from=tools:1.0 /usr/bin/tool /usr/bin/\n";
+    let (_, res) = parse_copy::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::COPY(
+            "/usr/bin/tool".to_string(),
+            "/usr/bin/".into(),
+            Some("tools:1.0".to_string())
+        )
+    );
+}
+
+#[test]
+fn test_parse_add() {
+    let input = "ADD --checksum=sha256:abc https://example.com/app.tar.gz /opt/app/\n";
+    let (_, res) = parse_add::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::ADD(
+            "https://example.com/app.tar.gz".to_string(),
+            "/opt/app/".into(),
+            Some("sha256:abc".to_string())
+        )
+    );
+
+    let (_, res) = parse_add::<()>("ADD files/app.tar.xz /opt\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::ADD("files/app.tar.xz".to_string(), "/opt".into(), None)
+    );
+}
+
+#[test]
+fn test_parse_workdir() {
+    let input = "WORKDIR /src\n";
+    let (_, res) = parse_workdir::<()>(input).unwrap();
+    assert_eq!(res, Instruction::WORKDIR("/src".to_string()));
+}
+
+#[test]
+fn test_parse_user() {
+    let input = "USER root\n";
+    let (_, res) = parse_user::<()>(input).unwrap();
+    assert_eq!(res, Instruction::USER("root".to_string()));
+}
+
+#[test]
+fn test_parse_cmd() {
+    let input = "CMD echo hello\n";
+    let (_, res) = parse_cmd::<()>(input).unwrap();
+    assert_eq!(res, Instruction::CMD("echo hello".to_string()));
+}
+
+#[test]
+fn test_parse_run() {
+    let input = "RUN echo hello\n";
+    let (_, res) = parse_run::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::RUN(
+            RunOptions::default(),
+            RunCommand::Shell("echo hello".to_string())
+        )
+    );
+}
+
+#[test]
+fn test_parse_run_heredoc() {
+    let input = "RUN <<'EOF'\napt-get update\n  echo \"$HOME\" \\\nEOF\nUSER pi\n";
+    let (tail, res) = parse_run::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::RUN(
+            RunOptions::default(),
+            RunCommand::Heredoc {
+                delimiter: "EOF".to_string(),
+                script: "apt-get update\n  echo \"$HOME\" \\\n".to_string(),
+            }
+        )
+    );
+    assert_eq!(tail, "USER pi\n");
+
+    assert!(matches!(
+        parse_run::<()>("RUN <<EOF\necho unterminated\n"),
+        Err(Err::Failure(_))
+    ));
+}
+
+#[test]
+fn test_parse_run_continued() {
+    let input = "RUN apt-get install -y \\\n    # editors\n    vim \\  \n    git\nUSER root\n";
+    let (tail, res) = parse_run::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::RUN(
+            RunOptions::default(),
+            RunCommand::Shell("apt-get install -y     vim     git".to_string())
+        )
+    );
+    assert_eq!(tail, "USER root\n");
+}
+
+#[test]
+fn test_parse_run_secret_mount() {
+    let input = "RUN --mount=type=secret,id=apikey cat /run/secrets/apikey\n";
+    let (_, res) = parse_run::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::RUN(
+            RunOptions {
+                mounts: vec![RunMount::Secret {
+                    id: "apikey".to_string(),
+                    target: None,
+                }],
+                ..Default::default()
+            },
+            RunCommand::Shell("cat /run/secrets/apikey".to_string())
+        )
+    );
+
+    assert!(matches!(
+        parse_run::<()>("RUN --mount=type=secret echo\n"),
+        Err(Err::Failure(_))
+    ));
+    assert!(matches!(
+        parse_run::<()>("RUN --mount=type=secret,id=key,target=relative echo\n"),
+        Err(Err::Failure(_))
+    ));
+}
+
+#[test]
+fn test_parse_run_user() {
+    let input = "RUN --user=pi pip install --user requests\n";
+    let (_, res) = parse_run::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::RUN(
+            RunOptions {
+                user: Some("pi".to_string()),
+                ..Default::default()
+            },
+            RunCommand::Shell("pip install --user requests".to_string())
+        )
+    );
+
+    assert!(matches!(
+        parse_run::<()>("RUN --user= make\n"),
+        Err(Err::Failure(_))
+    ));
+    assert!(matches!(
+        parse_run::<()>("RUN --user=pi --user=root make\n"),
+        Err(Err::Failure(_))
+    ));
+}
+
+#[test]
+fn test_parse_run_cache_mount() {
+    let input = "RUN --mount=type=cache,target=/var/cache/apt --mount=type=cache,id=pip,target=/root/.cache/pip make\n";
+    let (_, res) = parse_run::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::RUN(
+            RunOptions {
+                mounts: vec![
+                    RunMount::Cache {
+                        id: None,
+                        target: "/var/cache/apt".to_string(),
+                    },
+                    RunMount::Cache {
+                        id: Some("pip".to_string()),
+                        target: "/root/.cache/pip".to_string(),
+                    },
+                ],
+                ..Default::default()
+            },
+            RunCommand::Shell("make".to_string())
+        )
+    );
+
+    assert!(matches!(
+        parse_run::<()>("RUN --mount=type=cache,id=apt make\n"),
+        Err(Err::Failure(_))
+    ));
+}
+
+#[test]
+fn test_parse_env() {
+    let input = "ENV KEY1=VALUE1 KEY2=VALUE2";
+    let (_, res) = parse_env::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::ENV(vec![
+            ("KEY1".to_string(), "VALUE1".to_string()),
+            ("KEY2".to_string(), "VALUE2".to_string())
+        ])
+    );
+}
+
+#[test]
+fn test_parse_env_quoted() {
+    let input =
+        "ENV GREETING=\"hello world\" NAME='it''s' PATH=$PATH:/opt/bin QUOTE=\"say \\\"hi\\\"\"\n";
+    let (_, res) = parse_env::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::ENV(vec![
+            ("GREETING".to_string(), "hello world".to_string()),
+            ("NAME".to_string(), "its".to_string()),
+            ("PATH".to_string(), "$PATH:/opt/bin".to_string()),
+            ("QUOTE".to_string(), "say \"hi\"".to_string()),
+        ])
+    );
+
+    for input in [
+        "ENV KEY\n",
+        "ENV =value\n",
+        "ENV KEY=\"unterminated\n",
+        "ENV BAD-KEY=1\n",
+    ] {
+        assert!(matches!(parse_env::<()>(input), Err(Err::Failure(_))));
+    }
+}
+
+#[test]
+fn test_split_words() {
+    assert_eq!(
+        split_words("a=\"b c\" d=e\\ f g=\\$HOME h=''").unwrap(),
+        vec!["a=b c", "d=e f", "g=\\$HOME", "h="]
+    );
+    assert_eq!(split_words("  ").unwrap(), Vec::<String>::new());
+    assert!(split_words("a='b").is_none());
+    assert!(split_words("a=b\\").is_none());
+}
+
+#[test]
+fn test_parse_shell_instruction() {
+    let (_, res) = parse_shell_instruction::<()>("SHELL [\"/bin/bash\",\"-c\"]\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::SHELL(vec!["/bin/bash".to_string(), "-c".to_string()])
+    );
+    assert!(parse_shell_instruction::<()>("SHELL /bin/bash -c\n").is_err());
+    assert!(parse_shell_instruction::<()>("SHELL []\n").is_err());
+}
+
+#[test]
+fn test_parse_entrypoint() {
+    let (_, res) =
+        parse_entrypoint::<()>("ENTRYPOINT --restart=on-failure /opt/app/run\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::ENTRYPOINT(
+            EntrypointOptions {
+                restart: Some("on-failure".to_string()),
+                wanted_by: None,
+            },
+            "/opt/app/run".to_string()
+        )
+    );
+    assert!(parse_entrypoint::<()>("ENTRYPOINT --restart=sometimes app\n").is_err());
+    assert!(parse_entrypoint::<()>("ENTRYPOINT --detach app\n").is_err());
+    assert!(parse_entrypoint::<()>("ENTRYPOINT --restart=always\n").is_err());
+}
+
+#[test]
+fn test_parse_fstab() {
+    let (_, res) =
+        parse_fstab::<()>("FSTAB /dev/sda1 /mnt/storage ext4 defaults,nofail\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::FSTAB(FstabEntry {
+            device: "/dev/sda1".to_string(),
+            mount_point: "/mnt/storage".to_string(),
+            filesystem: "ext4".to_string(),
+            options: "defaults,nofail".to_string(),
+            dump: 0,
+            pass: 0,
+        })
+    );
+    assert!(parse_fstab::<()>("FSTAB /dev/sda1 /mnt/storage ext4\n").is_err());
+    assert!(parse_fstab::<()>("FSTAB /dev/sda1 mnt ext4 defaults 0 2\n").is_err());
+    assert!(parse_fstab::<()>("FSTAB /dev/sda1 /mnt ext4 defaults 0 3\n").is_err());
+    assert!(parse_fstab::<()>("FSTAB /dev/sda1 /mnt ext4 defaults 0 2 1\n").is_err());
+}
+
+#[test]
+fn test_parse_authorized_keys() {
+    let (_, res) = parse_authorized_keys::<()>("AUTHORIZED_KEYS user=pi ./keys/*.pub\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::AUTHORIZED_KEYS(Some("pi".to_string()), vec!["./keys/*.pub".to_string()])
+    );
+    assert!(parse_authorized_keys::<()>("AUTHORIZED_KEYS user=pi\n").is_err());
+    assert!(parse_authorized_keys::<()>("AUTHORIZED_KEYS user= key.pub\n").is_err());
+}
+
+#[test]
+fn test_parse_kernel() {
+    let (_, res) = parse_kernel::<()>("KERNEL ./build/kernel8.img\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::KERNEL("./build/kernel8.img".to_string(), None)
+    );
+    assert!(parse_kernel::<()>("KERNEL\n").is_err());
+    assert!(parse_kernel::<()>("KERNEL a.img b.tar c.tar\n").is_err());
+}
+
+#[test]
+fn test_parse_onplatform() {
+    let (_, res) = parse_onplatform::<()>("ONPLATFORM armhf RUN echo 32-bit\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::ONPLATFORM(
+            "armhf".to_string(),
+            Box::new(Instruction::RUN(
+                RunOptions::default(),
+                RunCommand::Shell("echo 32-bit".to_string())
+            ))
+        )
+    );
+    assert!(parse_onplatform::<()>("ONPLATFORM armhf\nRUN echo 32-bit\n").is_err());
+}
+
+#[test]
+fn test_parse_if() {
+    let input = "IF ${VARIANT} = kiosk\n  # Browser\n  INSTALL chromium\n\n  IF $DEBUG=1\n  SSH enable\n  ENDIF\nENDIF\nUSER pi\n";
+    let (tail, res) = parse_if::<()>(input).unwrap();
+    assert_eq!(tail, "USER pi\n");
+    assert_eq!(
+        res,
+        Instruction::IF(
+            Condition {
+                left: "${VARIANT}".to_string(),
+                right: "kiosk".to_string(),
+                negated: false,
+            },
+            vec![
+                Instruction::INSTALL(vec!["chromium".to_string()]),
+                Instruction::IF(
+                    Condition {
+                        left: "$DEBUG".to_string(),
+                        right: "1".to_string(),
+                        negated: false,
+                    },
+                    vec![Instruction::SSH(true)]
+                ),
+            ]
+        )
+    );
+    assert!(parse_if::<()>("IF ${VARIANT}=kiosk\nINSTALL chromium\n").is_err());
+    assert!(parse_if::<()>("IF ${VARIANT}\nENDIF\n").is_err());
+}
+
+#[test]
+fn test_parse_label() {
+    let input = "LABEL project=kiosk owner=ops\n";
+    let (_, res) = parse_label::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::LABEL(vec![
+            ("project".to_string(), "kiosk".to_string()),
+            ("owner".to_string(), "ops".to_string())
+        ])
+    );
+    assert!(parse_label::<()>("LABEL project\n").is_err());
+    assert!(parse_label::<()>("LABEL\n").is_err());
+}
+
+#[test]
+fn test_parse_wifi() {
+    let input = "WIFI ssid=Home psk_secret=wifi country=BE\n";
+    let (_, res) = parse_wifi::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::WIFI(vec![
+            ("ssid".to_string(), "Home".to_string()),
+            ("psk_secret".to_string(), "wifi".to_string()),
+            ("country".to_string(), "BE".to_string())
+        ])
+    );
+
+    let (_, res) = parse_wifi::<()>("WIFI ssid=Home psk=hunter22 country=BE").unwrap();
+    assert_eq!(res.to_string(), "WIFI ssid=Home psk=**** country=BE");
+}
+
+#[test]
+fn test_parse_ssh() {
+    let (_, res) = parse_ssh::<()>("SSH enable\n").unwrap();
+    assert_eq!(res, Instruction::SSH(true));
+    let (_, res) = parse_ssh::<()>("SSH disable").unwrap();
+    assert_eq!(res, Instruction::SSH(false));
+    assert!(parse_ssh::<()>("SSH on\n").is_err());
+}
+
+#[test]
+fn test_parse_useradd() {
+    let input =
+        "USERADD pi --password-hash $6$salt$hash --groups sudo,gpio --shell /bin/bash --userconf\n";
+    let (_, res) = parse_useradd::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        Instruction::USERADD(NewUser {
+            name: "pi".to_string(),
+            password_hash: Some("$6$salt$hash".to_string()),
+            groups: vec!["sudo".to_string(), "gpio".to_string()],
+            shell: Some("/bin/bash".to_string()),
+            userconf: true,
+        })
+    );
+    assert_eq!(
+        res.to_string(),
+        "USERADD pi --password-hash **** --groups sudo,gpio --shell /bin/bash --userconf"
+    );
+
+    assert!(parse_useradd::<()>("USERADD\n").is_err());
+    assert!(parse_useradd::<()>("USERADD pi --shell\n").is_err());
+    assert!(parse_useradd::<()>("USERADD pi --uid 1000\n").is_err());
+}
+
+#[test]
+fn test_parse_locale_timezone_keyboard() {
+    let (_, res) = parse_timezone::<()>("TIMEZONE Europe/Brussels\n").unwrap();
+    assert_eq!(res, Instruction::TIMEZONE("Europe/Brussels".to_string()));
+    let (_, res) = parse_locale::<()>("LOCALE en_GB.UTF-8\n").unwrap();
+    assert_eq!(res, Instruction::LOCALE("en_GB.UTF-8".to_string()));
+    let (_, res) = parse_keyboard::<()>("KEYBOARD be").unwrap();
+    assert_eq!(res, Instruction::KEYBOARD("be".to_string()));
+
+    assert!(parse_keyboard::<()>("KEYBOARD be fr\n").is_err());
+    assert!(parse_locale::<()>("LOCALE\n").is_err());
+}
+
+#[test]
+fn test_parse_bootconfig() {
+    let (_, res) = parse_bootconfig::<()>("BOOTCONFIG dtparam=i2c_arm=on\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::BOOTCONFIG("dtparam".to_string(), "i2c_arm=on".to_string())
+    );
+    assert!(parse_bootconfig::<()>("BOOTCONFIG gpu_mem\n").is_err());
+    assert!(parse_bootconfig::<()>("BOOTCONFIG =16\n").is_err());
+}
+
+#[test]
+fn test_parse_cmdline() {
+    let (_, res) = parse_cmdline::<()>("CMDLINE append console=serial0,115200\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::CMDLINE(CmdlineEdit::Append(vec![
+            "console=serial0,115200".to_string()
+        ]))
+    );
+    let (_, res) = parse_cmdline::<()>("CMDLINE remove quiet splash").unwrap();
+    assert_eq!(
+        res,
+        Instruction::CMDLINE(CmdlineEdit::Remove(vec![
+            "quiet".to_string(),
+            "splash".to_string()
+        ]))
+    );
+    assert!(parse_cmdline::<()>("CMDLINE remove\n").is_err());
+    assert!(parse_cmdline::<()>("CMDLINE replace quiet\n").is_err());
+}
+
+#[test]
+fn test_parse_dtoverlay() {
+    let (_, res) = parse_dtoverlay::<()>("DTOVERLAY gpio-fan,gpiopin=14\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::DTOVERLAY("gpio-fan,gpiopin=14".to_string(), None)
+    );
+    let (_, res) = parse_dtoverlay::<()>("DTOVERLAY my-hat overlays/my-hat.dtbo\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::DTOVERLAY(
+            "my-hat".to_string(),
+            Some("overlays/my-hat.dtbo".to_string())
+        )
+    );
+    assert!(parse_dtoverlay::<()>("DTOVERLAY\n").is_err());
+}
+
+#[test]
+fn test_parse_install() {
+    let (_, res) = parse_install::<()>("INSTALL nginx \\\n    python3-pip\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::INSTALL(vec!["nginx".to_string(), "python3-pip".to_string()])
+    );
+    assert!(parse_install::<()>("INSTALL\n").is_err());
+}
+
+#[test]
+fn test_parse_service() {
+    let (_, res) = parse_service::<()>("SERVICE enable myapp.service\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::SERVICE(true, vec!["myapp.service".to_string()])
+    );
+    let (_, res) = parse_service::<()>("SERVICE disable bluetooth hciuart").unwrap();
+    assert_eq!(
+        res,
+        Instruction::SERVICE(false, vec!["bluetooth".to_string(), "hciuart".to_string()])
+    );
+    assert!(parse_service::<()>("SERVICE enable\n").is_err());
+    assert!(parse_service::<()>("SERVICE start myapp\n").is_err());
+}
+
+#[test]
+fn test_parse_expandroot() {
+    let (_, res) = parse_expandroot::<()>("EXPANDROOT on\n").unwrap();
+    assert_eq!(res, Instruction::EXPANDROOT(RootExpansion::FirstBoot(true)));
+    let (_, res) = parse_expandroot::<()>("EXPANDROOT to=8G\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::EXPANDROOT(RootExpansion::To("8G".to_string()))
+    );
+    assert!(parse_expandroot::<()>("EXPANDROOT to=big\n").is_err());
+    assert!(parse_expandroot::<()>("EXPANDROOT yes\n").is_err());
+}
+
+#[test]
+fn test_parse_partition() {
+    let (_, res) = parse_partition::<()>("PARTITION add label=logs size=512M\n").unwrap();
+    assert_eq!(
+        res,
+        Instruction::PARTITION(NewPartition {
+            label: "logs".to_string(),
+            size: "512M".to_string(),
+            filesystem: "ext4".to_string(),
+            mount: None,
+        })
+    );
+    assert!(parse_partition::<()>("PARTITION add size=2G\n").is_err());
+    assert!(parse_partition::<()>("PARTITION add label=data size=huge\n").is_err());
+    assert!(parse_partition::<()>("PARTITION remove label=data\n").is_err());
+}
+
+#[test]
+fn test_display_round_trips() {
+    for input in [
+        "ENV KEY1=VALUE1 KEY2=VALUE2",
+        "ENV GREETING=\"hello \\\"world\\\"\"",
+        "RUN echo hello",
+        "RUN <<EOF\necho hello\necho world\nEOF",
+        "RUN --mount=type=cache,target=/var/cache/apt apt-get install -y vim",
+        "RUN --user=pi --mount=type=cache,target=/home/pi/.cache/pip pip install --user flask",
+        "RUN --mount=type=secret,id=token,target=/root/.netrc <<EOF\ncurl -n https://example.com\nEOF",
+        "COPY /src/* /dest",
+        "COPY --from=builder /src/* /dest",
+        "ADD app.tar.gz /opt",
+        "ADD --checksum=sha256:abc https://example.com/app /usr/bin/app",
+        "WORKDIR /src",
+        "USER root",
+        "CMD echo hello",
+        "LABEL project=kiosk owner=ops",
+        "SSH enable",
+        "TIMEZONE Europe/Brussels",
+        "LOCALE en_GB.UTF-8",
+        "KEYBOARD be",
+        "BOOTCONFIG gpu_mem=16",
+        "CMDLINE append console=serial0,115200",
+        "CMDLINE remove quiet splash",
+        "DTOVERLAY my-hat overlays/my-hat.dtbo",
+        "INSTALL nginx python3-pip",
+        "SERVICE disable bluetooth.service",
+        "EXPANDROOT off",
+        "ARG VARIANT",
+        "SHELL [\"/bin/bash\", \"-euo\", \"pipefail\", \"-c\"]",
+        "ARG BASE_TAG=bookworm",
+        "EXPANDROOT to=8G",
+        "PARTITION add label=data size=2G fs=ext4 mount=/data",
+        "USERADD kiosk --groups video --shell /bin/bash",
+        "ENTRYPOINT --restart=always --wanted-by=graphical.target chromium --kiosk",
+        "ENTRYPOINT /opt/app/run",
+        "FSTAB tmpfs /var/log tmpfs defaults,noatime,size=50m 0 0",
+        "AUTHORIZED_KEYS user=pi ./keys/*.pub",
+        "AUTHORIZED_KEYS ops.pub deploy.pub",
+        "KERNEL ./build/kernel8.img ./build/modules.tar.gz",
+        "ONPLATFORM arm64 RUN apt-get install -y linux-image-rpi-v8",
+        "IF ${VARIANT}=kiosk\nINSTALL chromium\nSERVICE enable kiosk.service\nENDIF",
+        "IF ${VARIANT}!=kiosk\nENDIF",
+    ] {
+        let (_, res) = parse_instruction::<()>(input).unwrap();
+        assert_eq!(res.to_string(), input);
+        assert!(INSTRUCTIONS.contains(&input.split_whitespace().next().unwrap()));
+    }
+}
+
+#[test]
+fn test_parse_from_full_options() {
+    let input = "FROM --platform x86 ubuntu:latest\n";
+    let (_, res) = parse_from::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        FromClause {
+            image: "ubuntu".to_string(),
+            tag: Some("latest".to_string()),
+            platform: Some("x86".to_string()),
+            alias: None,
+        }
+    );
+}
+
+#[test]
+fn test_parse_from_alias() {
+    let input = "FROM --platform arm64 raspios:bookworm AS builder\n";
+    let (_, res) = parse_from::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        FromClause {
+            image: "raspios".to_string(),
+            tag: Some("bookworm".to_string()),
+            platform: Some("arm64".to_string()),
+            alias: Some("builder".to_string()),
+        }
+    );
+
+    let (_, res) = parse_from::<()>("FROM builder as final").unwrap();
+    assert_eq!(res.image, "builder");
+    assert_eq!(res.tag, None);
+    assert_eq!(res.alias.as_deref(), Some("final"));
+}
+
+#[test]
+fn test_parse_from_no_options() {
+    let input = "FROM ubuntu\n";
+    let (_, res) = parse_from::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        FromClause {
+            image: "ubuntu".to_string(),
+            tag: None,
+            platform: None,
+            alias: None,
+        }
+    );
+}
+
+#[test]
+fn test_parse_baker_file() {
+    let input = "FROM ubuntu\nUSER root\n   \nCMD echo hello";
+    let (_, res) = parse_baker_file::<()>(input).unwrap();
+    assert_eq!(
+        res,
+        BakerFile {
+            args: Vec::new(),
+            stages: vec![Stage {
+                from: FromClause {
+                    image: "ubuntu".to_string(),
+                    ..Default::default()
+                },
+                instructions: vec![
+                    Instruction::USER("root".to_string()),
+                    Instruction::CMD("echo hello".to_string()),
+                ]
+            }]
+        }
+    );
+}
+
+#[test]
+fn test_parse_baker_file_global_args() {
+    let input =
+        "ARG BASE_TAG=bookworm\n# Variant\nARG VARIANT\nFROM raspios:${BASE_TAG}\nARG VARIANT\n";
+    let (_, res) = parse_baker_file::<()>(input).unwrap();
+    assert_eq!(
+        res.args,
+        vec![
+            ("BASE_TAG".to_string(), Some("bookworm".to_string())),
+            ("VARIANT".to_string(), None),
+        ]
+    );
+    assert_eq!(res.stages[0].from.tag.as_deref(), Some("${BASE_TAG}"));
+    assert_eq!(
+        res.stages[0].instructions,
+        vec![Instruction::ARG("VARIANT".to_string(), None)]
+    );
+    assert!(parse_arg::<()>("ARG BAD-NAME=1\n").is_err());
+}
+
+#[test]
+fn test_parse_baker_file_stages() {
+    let input = "FROM raspios:bookworm AS builder\nRUN make\n\nFROM raspios:bookworm\nCOPY --from=builder /app /usr/bin/app\n";
+    let (tail, res) = parse_baker_file::<()>(input).unwrap();
+    assert!(tail.trim().is_empty());
+    assert_eq!(res.stages.len(), 2);
+    assert_eq!(res.stages[0].from.alias.as_deref(), Some("builder"));
+    assert_eq!(
+        res.stages[0].instructions,
+        vec![Instruction::RUN(
+            RunOptions::default(),
+            RunCommand::Shell("make".to_string())
+        )]
+    );
+    assert_eq!(res.stages[1].from.alias, None);
+    assert_eq!(res.stages[1].instructions.len(), 1);
+}
+
+#[test]
+fn test_parse_baker_file_preamble() {
+    let input = "# A comment\n\n   \nFROM ubuntu\nUSER root";
+    let (_, res) = parse_baker_file::<()>(input).unwrap();
+    assert_eq!(res.stages[0].from.image, "ubuntu");
+    assert_eq!(
+        res.stages[0].instructions,
+        vec![Instruction::USER("root".to_string())]
+    );
+}
+
+#[test]
+fn test_parse_baker_file_comments() {
+    let input = "FROM ubuntu\n# Users\n\nUSER root\n  # Startup\nCMD echo \\\n  hello\n# done\n";
+    let (tail, res) = parse_baker_file::<()>(input).unwrap();
+    assert_eq!(tail, "# done\n");
+    assert_eq!(
+        res.stages[0].instructions,
+        vec![
+            Instruction::USER("root".to_string()),
+            Instruction::CMD("echo   hello".to_string()),
+        ]
     );
 }
 
@@ -0,0 +1,266 @@
+use std::{env, fs, path::Path};
+
+use super::parser::{Condition, Instruction};
+use crate::error::Error;
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || ch == '_'
+}
+
+/// Expands `$VAR`, `${VAR}` and `${VAR:-default}` references. Variables
+/// `lookup` does not know and that have no default are left as they are, so
+/// that shell variables still expand when the command runs. `\$` escapes a
+/// dollar sign.
+pub fn expand<F>(input: &str, lookup: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+
+    while let Some(index) = rest.find(['$', '\\']) {
+        output.push_str(&rest[..index]);
+        let reference = &rest[index..];
+
+        if let Some(escaped) = reference.strip_prefix("\\$") {
+            output.push('$');
+            rest = escaped;
+            continue;
+        }
+        if let Some(after) = reference.strip_prefix('\\') {
+            output.push('\\');
+            rest = after;
+            continue;
+        }
+
+        let after = &reference[1..];
+        let (expression, name, default, tail) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => {
+                    let inner = &braced[..end];
+                    let (name, default) = match inner.split_once(":-") {
+                        Some((name, default)) => (name, Some(default)),
+                        None => (inner, None),
+                    };
+                    (&reference[..end + 3], name, default, &braced[end + 1..])
+                }
+                None => ("$", "", None, after),
+            },
+            None => {
+                let end = after.find(|ch| !is_name_char(ch)).unwrap_or(after.len());
+                (&reference[..end + 1], &after[..end], None, &after[end..])
+            }
+        };
+
+        let value = if name.is_empty() || !name.chars().all(is_name_char) {
+            None
+        } else {
+            match (lookup(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => Some(default.to_string()),
+                (None, Some(default)) => Some(default.to_string()),
+                (value, _) => value,
+            }
+        };
+
+        output.push_str(value.as_deref().unwrap_or(expression));
+        rest = tail;
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Parses a `--build-arg` value, `KEY=VALUE` or `KEY` to take the value from
+/// the environment.
+pub fn parse_build_arg(spec: &str) -> Result<(String, String), Error> {
+    let (name, value) = match spec.split_once('=') {
+        Some((name, value)) => (name, value.to_string()),
+        None => (
+            spec,
+            env::var(spec).map_err(|_| {
+                Error::Usage(format!(
+                    "Build argument {} is not set in the environment",
+                    spec
+                ))
+            })?,
+        ),
+    };
+
+    if name.is_empty() || !name.chars().all(is_name_char) {
+        return Err(Error::Usage(format!(
+            "Invalid build argument {}, expected KEY=VALUE",
+            spec
+        )));
+    }
+
+    Ok((name.to_string(), value))
+}
+
+/// Reads a `.env` style file of `--build-arg` values, one per line. Blank
+/// lines and `#` comments are skipped, and a value can be quoted.
+pub fn load_build_arg_file(path: &Path) -> Result<Vec<(String, String)>, Error> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| Error::Usage(format!("Cannot read {}: {}", path.display(), err)))?;
+
+    let mut build_args = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+
+        let (name, value) = parse_build_arg(line).map_err(|err| match err {
+            Error::Usage(msg) => Error::Usage(format!("{}:{}: {}", path.display(), index + 1, msg)),
+            err => err,
+        })?;
+        let value = match value.as_bytes() {
+            [quote @ (b'"' | b'\''), .., last] if quote == last => {
+                value[1..value.len() - 1].to_string()
+            }
+            _ => value,
+        };
+        build_args.push((name, value));
+    }
+
+    Ok(build_args)
+}
+
+/// Value of the variable last defined in `variables`.
+pub fn lookup(variables: &[(String, String)], name: &str) -> Option<String> {
+    variables
+        .iter()
+        .rev()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.clone())
+}
+
+/// Expands the variables of the instructions supporting substitution.
+pub fn expand_instruction<F>(instruction: Instruction, lookup: F) -> Instruction
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match instruction {
+        Instruction::COPY(source, target, stage) => Instruction::COPY(
+            expand(&source, &lookup),
+            expand(&target.to_string_lossy(), &lookup).into(),
+            stage,
+        ),
+        Instruction::ADD(source, target, checksum) => Instruction::ADD(
+            expand(&source, &lookup),
+            expand(&target.to_string_lossy(), &lookup).into(),
+            checksum,
+        ),
+        Instruction::WORKDIR(workdir) => Instruction::WORKDIR(expand(&workdir, &lookup)),
+        Instruction::CMD(command) => Instruction::CMD(expand(&command, &lookup)),
+        Instruction::IF(condition, instructions) => Instruction::IF(
+            Condition {
+                left: expand(&condition.left, &lookup),
+                right: expand(&condition.right, &lookup),
+                ..condition
+            },
+            instructions,
+        ),
+        Instruction::ENTRYPOINT(options, command) => {
+            Instruction::ENTRYPOINT(options, expand(&command, &lookup))
+        }
+        Instruction::ENV(envs) => Instruction::ENV(
+            envs.into_iter()
+                .map(|(key, value)| (key, expand(&value, &lookup)))
+                .collect(),
+        ),
+        instruction => instruction,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "BASE_TAG" => Some("bookworm".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(expand("raspios:${BASE_TAG}", lookup), "raspios:bookworm");
+        assert_eq!(expand("$BASE_TAG/app", lookup), "bookworm/app");
+        assert_eq!(expand("${MISSING:-lite}", lookup), "lite");
+        assert_eq!(expand("${EMPTY:-lite}", lookup), "lite");
+        assert_eq!(expand("${BASE_TAG:-lite}", lookup), "bookworm");
+        assert_eq!(expand("a${EMPTY}b", lookup), "ab");
+    }
+
+    #[test]
+    fn test_expand_keeps_unknown_references() {
+        assert_eq!(expand("echo $HOME ${USER}", lookup), "echo $HOME ${USER}");
+        assert_eq!(
+            expand("cost: 5$ ${unterminated", lookup),
+            "cost: 5$ ${unterminated"
+        );
+        assert_eq!(expand("\\$BASE_TAG C:\\dir", lookup), "$BASE_TAG C:\\dir");
+    }
+
+    #[test]
+    fn test_parse_build_arg() {
+        assert_eq!(
+            parse_build_arg("BASE_TAG=bookworm").unwrap(),
+            ("BASE_TAG".to_string(), "bookworm".to_string())
+        );
+        assert_eq!(
+            parse_build_arg("EMPTY=").unwrap(),
+            ("EMPTY".to_string(), String::new())
+        );
+        assert!(matches!(parse_build_arg("=x"), Err(Error::Usage(_))));
+        assert!(matches!(
+            parse_build_arg("BAKER_SURELY_UNSET_VARIABLE"),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_build_arg_file() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let path = tmp_dir.path().join("prod.env");
+        fs::write(
+            &path,
+            "# Production\n\nBASE_TAG=bookworm\nexport HOSTNAME=\"kiosk 1\"\nEMPTY=\nQUOTE='\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            load_build_arg_file(&path).unwrap(),
+            vec![
+                ("BASE_TAG".to_string(), "bookworm".to_string()),
+                ("HOSTNAME".to_string(), "kiosk 1".to_string()),
+                ("EMPTY".to_string(), String::new()),
+                ("QUOTE".to_string(), "'".to_string()),
+            ]
+        );
+
+        fs::write(&path, "BASE_TAG=bookworm\nnot valid\n").unwrap();
+        assert!(matches!(
+            load_build_arg_file(&path),
+            Err(Error::Usage(msg)) if msg.contains("prod.env:2:")
+        ));
+    }
+
+    #[test]
+    fn test_expand_instruction() {
+        assert_eq!(
+            expand_instruction(
+                Instruction::COPY("app-$BASE_TAG".to_string(), "/opt/${BASE_TAG}".into(), None),
+                lookup
+            ),
+            Instruction::COPY("app-bookworm".to_string(), "/opt/bookworm".into(), None)
+        );
+        assert_eq!(
+            expand_instruction(Instruction::USER("$BASE_TAG".to_string()), lookup),
+            Instruction::USER("$BASE_TAG".to_string())
+        );
+    }
+}
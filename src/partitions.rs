@@ -0,0 +1,424 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    process::Command,
+};
+
+use serde::Serialize;
+
+use crate::error::Error;
+
+const SECTOR_SIZE: u64 = 512;
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+/// Added partitions start and end on 1 MiB boundaries, like the ones of
+/// Raspberry Pi OS images.
+const ALIGNMENT: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Partition {
+    number: usize,
+    bootable: bool,
+    kind: String,
+    /// Offset of the partition in bytes
+    start: u64,
+    /// Size of the partition in bytes
+    size: u64,
+}
+
+fn kind_name(kind: u8) -> String {
+    match kind {
+        0x0b | 0x0c => "fat32".to_string(),
+        0x0e => "fat16".to_string(),
+        0x82 => "swap".to_string(),
+        0x83 => "linux".to_string(),
+        0xee => "gpt".to_string(),
+        kind => format!("0x{:02x}", kind),
+    }
+}
+
+/// Decodes the primary partitions of an MBR, skipping unused slots.
+pub fn parse_mbr(sector: &[u8; SECTOR_SIZE as usize]) -> Result<Vec<Partition>, Error> {
+    if sector[510..512] != [0x55, 0xaa] {
+        return Err(Error::Other("Missing MBR boot signature".to_string()));
+    }
+
+    Ok(sector[TABLE_OFFSET..TABLE_OFFSET + 4 * ENTRY_SIZE]
+        .chunks(ENTRY_SIZE)
+        .enumerate()
+        .filter(|(_, entry)| entry[4] != 0)
+        .map(|(index, entry)| {
+            let start = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]);
+            let sectors = u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]);
+            Partition {
+                number: index + 1,
+                bootable: entry[0] == 0x80,
+                kind: kind_name(entry[4]),
+                start: start as u64 * SECTOR_SIZE,
+                size: sectors as u64 * SECTOR_SIZE,
+            }
+        })
+        .collect())
+}
+
+pub fn read_partition_table(image: &Path) -> Result<Vec<Partition>, Error> {
+    let mut sector = [0; SECTOR_SIZE as usize];
+    File::open(image)?.read_exact(&mut sector)?;
+
+    parse_mbr(&sector)
+}
+
+/// Extends the last partition of the MBR up to `total_sectors`, returning
+/// `false` when it already reaches the end of the disk.
+fn grow_last_partition(
+    sector: &mut [u8; SECTOR_SIZE as usize],
+    total_sectors: u64,
+) -> Result<bool, Error> {
+    let last = parse_mbr(sector)?
+        .into_iter()
+        .max_by_key(|partition| partition.start)
+        .ok_or_else(|| Error::Other("The image has no partition".to_string()))?;
+    if last.kind != "linux" {
+        return Err(Error::Usage(format!(
+            "Cannot grow a {} partition",
+            last.kind
+        )));
+    }
+
+    let sectors = total_sectors.saturating_sub(last.start / SECTOR_SIZE);
+    if sectors * SECTOR_SIZE <= last.size {
+        return Ok(false);
+    }
+    let sectors = u32::try_from(sectors)
+        .map_err(|_| Error::Usage("The partition would exceed the MBR limits".to_string()))?;
+
+    let offset = TABLE_OFFSET + (last.number - 1) * ENTRY_SIZE;
+    sector[offset + 12..offset + 16].copy_from_slice(&sectors.to_le_bytes());
+
+    Ok(true)
+}
+
+/// Grows the image file to `size` and its root partition, the last one, to
+/// fill it. The filesystem itself is grown by `resize_filesystem` once the
+/// image is attached again.
+pub fn grow_image(image: &Path, size: u64) -> Result<bool, Error> {
+    let mut file = OpenOptions::new().read(true).write(true).open(image)?;
+    let size = size - size % SECTOR_SIZE;
+    if size < file.metadata()?.len() {
+        return Err(Error::Usage(format!(
+            "The image is already larger than {}",
+            crate::size::format_size(size)
+        )));
+    }
+
+    let mut sector = [0; SECTOR_SIZE as usize];
+    file.read_exact(&mut sector)?;
+    if !grow_last_partition(&mut sector, size / SECTOR_SIZE)? {
+        return Ok(false);
+    }
+
+    file.set_len(size)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&sector)?;
+    file.sync_all()?;
+
+    Ok(true)
+}
+
+/// MBR partition type and longest label of the filesystems partitions can
+/// be created with.
+fn filesystem_kind(filesystem: &str) -> Result<(u8, usize), Error> {
+    match filesystem {
+        "ext4" => Ok((0x83, 16)),
+        "vfat" => Ok((0x0c, 11)),
+        _ => Err(Error::Usage(format!(
+            "Unsupported filesystem {}",
+            filesystem
+        ))),
+    }
+}
+
+/// Fills the first unused slot of the MBR with a partition, returning its
+/// number.
+fn add_entry(
+    sector: &mut [u8; SECTOR_SIZE as usize],
+    kind: u8,
+    start: u64,
+    size: u64,
+) -> Result<usize, Error> {
+    let index = (0..4)
+        .find(|index| sector[TABLE_OFFSET + index * ENTRY_SIZE + 4] == 0)
+        .ok_or_else(|| Error::Usage("The image has no free partition slot".to_string()))?;
+    let start = u32::try_from(start / SECTOR_SIZE);
+    let sectors = u32::try_from(size / SECTOR_SIZE);
+    let (start, sectors) = match (start, sectors) {
+        (Ok(start), Ok(sectors)) if start.checked_add(sectors).is_some() => (start, sectors),
+        _ => {
+            return Err(Error::Usage(
+                "The partition would exceed the MBR limits".to_string(),
+            ))
+        }
+    };
+
+    let entry = &mut sector[TABLE_OFFSET + index * ENTRY_SIZE..][..ENTRY_SIZE];
+    entry.fill(0);
+    // CHS addresses are left at their maximum, only LBA addresses are used
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = kind;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+
+    Ok(index + 1)
+}
+
+fn align(offset: u64) -> u64 {
+    offset.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Creates an image file holding an empty partition table, for partitions to
+/// be added to.
+pub fn create_image(image: &Path) -> Result<(), Error> {
+    let mut sector = [0; SECTOR_SIZE as usize];
+    sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+    File::create(image)?.write_all(&sector)?;
+
+    Ok(())
+}
+
+/// Appends a partition of `size` bytes to the image and creates a
+/// `filesystem` labelled `label` on it.
+pub fn add_partition(image: &Path, label: &str, size: u64, filesystem: &str) -> Result<(), Error> {
+    add_partition_with(image, label, size, filesystem, None)
+}
+
+/// Like `add_partition`, the filesystem being filled with the contents of a
+/// directory. Ownership and permissions are kept on ext4 only.
+pub fn add_partition_with(
+    image: &Path,
+    label: &str,
+    size: u64,
+    filesystem: &str,
+    contents: Option<&Path>,
+) -> Result<(), Error> {
+    let (kind, max_label) = filesystem_kind(filesystem)?;
+    if label.is_empty()
+        || label.len() > max_label
+        || !label
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
+    {
+        return Err(Error::Usage(format!(
+            "Invalid {} label {}",
+            filesystem, label
+        )));
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(image)?;
+    let mut sector = [0; SECTOR_SIZE as usize];
+    file.read_exact(&mut sector)?;
+
+    let end = parse_mbr(&sector)?
+        .iter()
+        .map(|partition| partition.start + partition.size)
+        .chain([file.metadata()?.len()])
+        .max()
+        .unwrap_or(0);
+    let start = align(end);
+    let size = align(size);
+    add_entry(&mut sector, kind, start, size)?;
+
+    // The filesystem is created in a file of its own and copied into place
+    let tmp_dir = tempdir::TempDir::new_in(
+        image
+            .parent()
+            .ok_or_else(|| Error::Other("Invalid image path".to_string()))?,
+        "baker",
+    )?;
+    let filesystem_path = tmp_dir.path().join("partition.img");
+    File::create(&filesystem_path)?.set_len(size)?;
+    let mut command = match filesystem {
+        "vfat" => {
+            let mut command = Command::new("mkfs.vfat");
+            command.arg("-n").arg(label);
+            command
+        }
+        _ => {
+            let mut command = Command::new(format!("mkfs.{}", filesystem));
+            command.arg("-q").arg("-F").arg("-L").arg(label);
+            if let Some(contents) = contents {
+                command.arg("-d").arg(contents);
+            }
+            command
+        }
+    };
+    let status = command.arg(&filesystem_path).status()?;
+    if !status.success() {
+        return Err(Error::RunFailed {
+            code: status.code(),
+        });
+    }
+    // mkfs.vfat cannot fill the filesystem, mtools copies into it unmounted
+    if let (Some(contents), "vfat") = (contents, filesystem) {
+        let entries = std::fs::read_dir(contents)?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        if !entries.is_empty() {
+            let status = Command::new("mcopy")
+                .arg("-s")
+                .arg("-i")
+                .arg(&filesystem_path)
+                .args(entries)
+                .arg("::/")
+                .status()?;
+            if !status.success() {
+                return Err(Error::RunFailed {
+                    code: status.code(),
+                });
+            }
+        }
+    }
+
+    file.set_len(start + size)?;
+    file.seek(SeekFrom::Start(start))?;
+    io::copy(&mut File::open(&filesystem_path)?, &mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&sector)?;
+    file.sync_all()?;
+
+    Ok(())
+}
+
+/// Grows the ext4 filesystem on `device` to the size of its partition, which
+/// works while it is mounted.
+pub fn resize_filesystem(device: &Path) -> Result<(), Error> {
+    let status = Command::new("resize2fs").arg(device).status()?;
+    if !status.success() {
+        return Err(Error::RunFailed {
+            code: status.code(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(sector: &mut [u8; 512], index: usize, kind: u8, start: u32, sectors: u32) {
+        let offset = TABLE_OFFSET + index * ENTRY_SIZE;
+        sector[offset + 4] = kind;
+        sector[offset + 8..offset + 12].copy_from_slice(&start.to_le_bytes());
+        sector[offset + 12..offset + 16].copy_from_slice(&sectors.to_le_bytes());
+    }
+
+    #[test]
+    fn test_parse_mbr() {
+        let mut sector = [0; 512];
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+        entry(&mut sector, 0, 0x0c, 8192, 1048576);
+        entry(&mut sector, 1, 0x83, 1056768, 4194304);
+
+        let partitions = parse_mbr(&sector).unwrap();
+
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].kind, "fat32");
+        assert_eq!(partitions[0].start, 8192 * 512);
+        assert_eq!(partitions[0].size, 512 * 1024 * 1024);
+        assert_eq!(partitions[1].number, 2);
+        assert_eq!(partitions[1].kind, "linux");
+        assert!(!partitions[1].bootable);
+    }
+
+    #[test]
+    fn test_grow_last_partition() {
+        let mut sector = [0; 512];
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+        entry(&mut sector, 0, 0x0c, 8192, 1048576);
+        entry(&mut sector, 1, 0x83, 1056768, 4194304);
+
+        assert!(grow_last_partition(&mut sector, 16777216).unwrap());
+        let partitions = parse_mbr(&sector).unwrap();
+        assert_eq!(partitions[0].size, 512 * 1024 * 1024);
+        assert_eq!(partitions[1].size, (16777216 - 1056768) * 512);
+
+        assert!(!grow_last_partition(&mut sector, 16777216).unwrap());
+    }
+
+    #[test]
+    fn test_grow_last_partition_rejects_non_linux_partition() {
+        let mut sector = [0; 512];
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+        entry(&mut sector, 0, 0x0c, 8192, 1048576);
+
+        assert!(matches!(
+            grow_last_partition(&mut sector, 16777216),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_add_entry() {
+        let mut sector = [0; 512];
+        sector[510] = 0x55;
+        sector[511] = 0xaa;
+        entry(&mut sector, 0, 0x0c, 8192, 1048576);
+        entry(&mut sector, 1, 0x83, 1056768, 4194304);
+
+        let start = align(5251072 * 512 + 1);
+        assert_eq!(start, 5253120 * 512);
+        assert_eq!(add_entry(&mut sector, 0x83, start, 1 << 30).unwrap(), 3);
+        assert_eq!(
+            add_entry(&mut sector, 0x0c, start + (1 << 30), 1 << 20).unwrap(),
+            4
+        );
+
+        let partitions = parse_mbr(&sector).unwrap();
+        assert_eq!(partitions[2].start, start);
+        assert_eq!(partitions[2].size, 1 << 30);
+        assert_eq!(partitions[3].kind, "fat32");
+        assert!(matches!(
+            add_entry(&mut sector, 0x83, 0, 1 << 20),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_add_partition_rejects_invalid_label() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let image = tmp_dir.path().join("image.img");
+        std::fs::write(&image, [0; 512]).unwrap();
+
+        for (label, filesystem) in [
+            ("", "ext4"),
+            ("data partition", "ext4"),
+            ("averylonglabelname", "ext4"),
+            ("BACKUPVOLUME", "vfat"),
+            ("data", "btrfs"),
+        ] {
+            assert!(matches!(
+                add_partition(&image, label, 1 << 20, filesystem),
+                Err(Error::Usage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_create_image() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let image = tmp_dir.path().join("image.img");
+
+        create_image(&image).unwrap();
+        assert_eq!(read_partition_table(&image).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_parse_mbr_without_signature() {
+        assert!(parse_mbr(&[0; 512]).is_err());
+    }
+}
@@ -0,0 +1,36 @@
+use crate::error::Error;
+
+pub fn euid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+fn check_root(euid: u32, command: &str) -> Result<(), Error> {
+    if euid != 0 {
+        return Err(Error::RootRequired(command.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Fails early with a clear message when a command needing loop devices,
+/// mounts or containers is not run as root.
+pub fn require_root(command: &str) -> Result<(), Error> {
+    check_root(euid(), command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_root() {
+        assert!(check_root(0, "build").is_ok());
+
+        match check_root(1000, "build") {
+            Err(err @ Error::RootRequired(_)) => {
+                assert_eq!(err.to_string(), "build must be run as root (try sudo)")
+            }
+            other => panic!("Expected a root required error, got {:?}", other),
+        }
+    }
+}
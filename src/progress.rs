@@ -0,0 +1,367 @@
+use std::fs::{self, File};
+use std::io::{self, IsTerminal};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+pub fn is_quiet() -> bool {
+    verbosity() == Verbosity::Quiet
+}
+
+pub fn is_verbose() -> bool {
+    verbosity() == Verbosity::Verbose
+}
+
+/// Prints progress as lines instead of drawing bars, for CI logs.
+pub fn set_plain(plain: bool) {
+    PLAIN.store(plain, Ordering::Relaxed);
+}
+
+/// Whether progress gets drawn as bars and spinners, which needs a terminal.
+fn is_interactive() -> bool {
+    !is_quiet() && !PLAIN.load(Ordering::Relaxed) && io::stderr().is_terminal()
+}
+
+/// Bars drawn at once, the stages of a build running side by side.
+fn bars() -> &'static MultiProgress {
+    static BARS: OnceLock<MultiProgress> = OnceLock::new();
+    BARS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
+}
+
+/// Prints a line of progress above the bars being drawn.
+pub fn message(line: &str) {
+    if is_interactive() {
+        let _ = bars().println(line);
+    } else if !is_quiet() {
+        println!("{}", line);
+    }
+}
+
+fn format_elapsed(elapsed: Duration) -> String {
+    format!("{:.1}s", elapsed.as_secs_f64())
+}
+
+/// Something slow being done, drawn as a spinner with its elapsed time.
+pub struct Task {
+    label: String,
+    bar: Option<ProgressBar>,
+    started: Instant,
+}
+
+impl Task {
+    pub fn start(label: String) -> Task {
+        let bar = if is_interactive() {
+            let bar = bars().add(ProgressBar::new_spinner().with_message(label.clone()));
+            bar.set_style(ProgressStyle::with_template("{spinner} {msg} {elapsed}").unwrap());
+            bar.enable_steady_tick(Duration::from_millis(100));
+            Some(bar)
+        } else {
+            if !is_quiet() {
+                println!("{}", label);
+            }
+            None
+        };
+
+        Task {
+            label,
+            bar,
+            started: Instant::now(),
+        }
+    }
+    /// Runs `f` with the spinner hidden, for commands writing to the
+    /// terminal. The label is printed first so that their output follows it.
+    pub fn suspend<T>(&self, f: impl FnOnce() -> T) -> T {
+        match &self.bar {
+            Some(bar) => {
+                bar.println(&self.label);
+                bar.suspend(f)
+            }
+            None => f(),
+        }
+    }
+    pub fn finish(mut self) {
+        let elapsed = format_elapsed(self.started.elapsed());
+        match self.bar.take() {
+            Some(bar) => {
+                bar.set_style(ProgressStyle::with_template("{msg}").unwrap());
+                bar.finish_with_message(format!("{} ({})", self.label, elapsed));
+            }
+            None if !is_quiet() => println!("{} done in {}", self.label, elapsed),
+            None => {}
+        }
+    }
+}
+
+impl Drop for Task {
+    /// A task dropped unfinished failed, its spinner is left stopped.
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.abandon_with_message(format!("{} (failed)", self.label));
+        }
+    }
+}
+
+/// Copies a file like `fs::copy`, with a bar showing how much is copied.
+pub fn copy_file(from: &Path, to: &Path, label: &str) -> io::Result<u64> {
+    if !is_interactive() {
+        return fs::copy(from, to);
+    }
+
+    let source = File::open(from)?;
+    let bar =
+        bars().add(ProgressBar::new(source.metadata()?.len()).with_message(label.to_string()));
+    bar.set_style(
+        ProgressStyle::with_template("{msg} {wide_bar} {bytes}/{total_bytes} {eta}").unwrap(),
+    );
+    let copied = io::copy(&mut bar.wrap_read(source), &mut File::create(to)?);
+    bar.finish_and_clear();
+    bars().remove(&bar);
+
+    copied
+}
+
+/// Average speed of a transfer, like `12.50 MiB/s`.
+fn format_speed(bytes: u64, elapsed: Duration) -> String {
+    match elapsed.as_secs_f64() {
+        secs if secs > 0.0 => format!("{}/s", HumanBytes((bytes as f64 / secs) as u64)),
+        _ => "-".to_string(),
+    }
+}
+
+/// A download, drawn as a bar with how much of it is done, the speed and the
+/// time left.
+pub struct Transfer {
+    label: String,
+    bar: Option<ProgressBar>,
+    started: Instant,
+    /// Bytes downloaded by this transfer, not counting resumed ones
+    transferred: u64,
+}
+
+impl Transfer {
+    /// Starts a download of `total` bytes, `done` of them being downloaded
+    /// already when resuming.
+    pub fn start(label: &str, done: u64, total: Option<u64>) -> Transfer {
+        let bar = if is_interactive() {
+            let bar = match total {
+                Some(total) => ProgressBar::new(total).with_style(
+                    ProgressStyle::with_template(
+                        "{msg} {wide_bar} {bytes}/{total_bytes} {percent}% {binary_bytes_per_sec} {eta}",
+                    )
+                    .unwrap(),
+                ),
+                None => ProgressBar::new_spinner().with_style(
+                    ProgressStyle::with_template("{spinner} {msg} {bytes} {binary_bytes_per_sec}")
+                        .unwrap(),
+                ),
+            };
+            let bar = bars().add(bar.with_message(label.to_string()).with_position(done));
+            bar.reset_eta();
+            Some(bar)
+        } else {
+            if !is_quiet() {
+                println!("{}", label);
+            }
+            None
+        };
+
+        Transfer {
+            label: label.to_string(),
+            bar,
+            started: Instant::now(),
+            transferred: 0,
+        }
+    }
+    pub fn advance(&mut self, bytes: u64) {
+        self.transferred += bytes;
+        if let Some(bar) = &self.bar {
+            bar.inc(bytes);
+        }
+    }
+    pub fn finish(mut self) {
+        let elapsed = self.started.elapsed();
+        match self.bar.take() {
+            Some(bar) => {
+                bar.finish_and_clear();
+                bars().remove(&bar);
+            }
+            None if !is_quiet() => println!(
+                "{} downloaded in {} ({})",
+                HumanBytes(self.transferred),
+                format_elapsed(elapsed),
+                format_speed(self.transferred, elapsed)
+            ),
+            None => {}
+        }
+    }
+}
+
+impl Drop for Transfer {
+    /// A transfer dropped unfinished failed, its bar is left stopped.
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.abandon_with_message(format!("{} (failed)", self.label));
+        }
+    }
+}
+
+pub struct Progress {
+    total: usize,
+    current: usize,
+    started: Instant,
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Progress {
+        Progress {
+            total,
+            current: 0,
+            started: Instant::now(),
+            bar: None,
+        }
+    }
+    pub fn advance(&mut self, label: &str) {
+        self.current = (self.current + 1).min(self.total);
+
+        if is_interactive() {
+            let bar = self.bar.get_or_insert_with(|| {
+                let bar = bars().add(ProgressBar::new(self.total as u64));
+                bar.set_style(
+                    ProgressStyle::with_template("[{pos}/{len}] {msg} {wide_bar} {eta}").unwrap(),
+                );
+                bar
+            });
+            bar.set_position(self.current as u64);
+            bar.set_message(label.to_string());
+        } else if !is_quiet() {
+            eprintln!("{}", self.line(label, self.eta()));
+        }
+    }
+    /// Counts steps done without going through them, such as cached ones.
+    pub fn skip(&mut self, steps: usize) {
+        self.current = (self.current + steps).min(self.total);
+    }
+    /// Starts the next step, numbered like `[3/12] label`.
+    pub fn step(&mut self, label: &str) -> Task {
+        self.current = (self.current + 1).min(self.total);
+
+        Task::start(self.line(label, None))
+    }
+    pub fn eta(&self) -> Option<Duration> {
+        if self.current <= 1 {
+            return None;
+        }
+
+        let done = (self.current - 1) as u32;
+        let remaining = (self.total - self.current + 1) as u32;
+
+        Some(self.started.elapsed() / done * remaining)
+    }
+    fn line(&self, label: &str, eta: Option<Duration>) -> String {
+        match eta {
+            Some(eta) => format!(
+                "[{}/{}] {} (about {}s left)",
+                self.current,
+                self.total,
+                label,
+                eta.as_secs()
+            ),
+            None => format!("[{}/{}] {}", self.current, self.total, label),
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_and_clear();
+            bars().remove(&bar);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_speed() {
+        assert_eq!(
+            format_speed(25 * 1024 * 1024, Duration::from_secs(2)),
+            "12.50 MiB/s"
+        );
+        assert_eq!(format_speed(1024, Duration::ZERO), "-");
+    }
+
+    #[test]
+    fn test_progress_counts_up_to_total() {
+        let mut progress = Progress::new(2);
+
+        assert_eq!(progress.current, 0);
+        progress.advance("first");
+        progress.advance("second");
+        progress.advance("overflow");
+
+        assert_eq!(progress.current, 2);
+    }
+
+    #[test]
+    fn test_progress_line() {
+        let mut progress = Progress::new(3);
+        progress.current = 2;
+
+        assert_eq!(progress.line("raspios_arm64", None), "[2/3] raspios_arm64");
+        assert_eq!(
+            progress.line("raspios_arm64", Some(Duration::from_secs(12))),
+            "[2/3] raspios_arm64 (about 12s left)"
+        );
+    }
+
+    #[test]
+    fn test_progress_skips_cached_steps() {
+        let mut progress = Progress::new(4);
+
+        progress.skip(2);
+        progress.step("RUN make").finish();
+
+        assert_eq!(progress.current, 3);
+        assert_eq!(progress.line("RUN make", None), "[3/4] RUN make");
+    }
+
+    #[test]
+    fn test_progress_has_no_eta_before_first_step_completes() {
+        let mut progress = Progress::new(3);
+
+        assert_eq!(progress.eta(), None);
+        progress.current = 1;
+        assert_eq!(progress.eta(), None);
+        progress.current = 2;
+        assert!(progress.eta().is_some());
+    }
+}
@@ -1,120 +1,874 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+};
 
-use crate::mount::MountedImage;
+use sys_mount::{Mount, MountFlags, Unmount, UnmountFlags};
 
+use crate::{
+    cleanup::{self, Registration},
+    copy, doctor,
+    error::Error,
+    mount::MountedImage,
+    parsing::parser::RunCommand,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    Chroot,
+    Nspawn,
+    Vmspawn,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RunEnvironment {
     Chroot,
     SystemdNspawn,
     SystemdVmspawn(PathBuf),
 }
 
-impl RunEnvironment {
-    pub fn run(
-        &self,
-        mount_point: &PathBuf,
-        environment_variables: &HashMap<String, String>,
-        user: &str,
-        working_dir: &str,
-        command: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let mount_point_str = mount_point
-            .to_str()
-            .ok_or("Failed to convert path to string")?;
+/// A host path made available inside the image while RUN instructions execute.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BindMount {
+    host: PathBuf,
+    container: PathBuf,
+    read_only: bool,
+}
+
+impl BindMount {
+    pub fn new(host: PathBuf, container: PathBuf, read_only: bool) -> BindMount {
+        BindMount {
+            host,
+            container,
+            read_only,
+        }
+    }
+    /// Read-write bind mount of the host directory kept for the cache `id`
+    /// across builds.
+    pub fn cache(id: &str, container: &str) -> Result<BindMount, Error> {
+        let host = crate::get_app_dir()?.join("cache").join(cache_dir_name(id));
+        fs::create_dir_all(&host)?;
+
+        Ok(BindMount::new(host, PathBuf::from(container), false))
+    }
+    /// Parses a `HOST:CONTAINER[:ro]` volume specification.
+    pub fn parse(spec: &str) -> Result<BindMount, Error> {
+        let invalid = || {
+            Error::Usage(format!(
+                "Invalid volume {}, expected HOST:CONTAINER[:ro]",
+                spec
+            ))
+        };
+
+        let (host, container, read_only) = match spec.split(':').collect::<Vec<_>>().as_slice() {
+            [host, container] => (*host, *container, false),
+            [host, container, "ro"] => (*host, *container, true),
+            [host, container, "rw"] => (*host, *container, false),
+            _ => return Err(invalid()),
+        };
+
+        if host.is_empty() || !container.starts_with('/') {
+            return Err(invalid());
+        }
+
+        Ok(BindMount {
+            host: PathBuf::from(host),
+            container: PathBuf::from(container),
+            read_only,
+        })
+    }
+    pub fn check(&self) -> Result<(), Error> {
+        if !self.host.exists() {
+            return Err(Error::Usage(format!(
+                "Volume host path {} does not exist",
+                self.host.display()
+            )));
+        }
+        Ok(())
+    }
+    fn systemd_arg(&self) -> String {
+        format!(
+            "--bind{}={}:{}",
+            if self.read_only { "-ro" } else { "" },
+            self.host.display(),
+            self.container.display()
+        )
+    }
+    /// Mounts the volume in `root`, adding the mount point and the
+    /// directories leading to it to `created` when they had to be created.
+    fn mount(&self, root: &Path, created: &mut Vec<PathBuf>) -> Result<Mount, Error> {
+        let target = crate::copy::resolve_in_root(root, &self.container)?;
+        create_target(&target, self.host.is_dir(), created)?;
+
+        let failed = |err: std::io::Error| {
+            Error::Mount(format!("Failed to bind {}: {}", self.host.display(), err))
+        };
+
+        let mount = Mount::builder()
+            .flags(MountFlags::BIND)
+            .mount(&self.host, &target)
+            .map_err(failed)?;
+
+        if self.read_only {
+            if let Err(err) = Mount::builder()
+                .flags(MountFlags::BIND | MountFlags::REMOUNT | MountFlags::RDONLY)
+                .mount(&self.host, &target)
+            {
+                let _ = mount.unmount(UnmountFlags::DETACH);
+                return Err(failed(err));
+            }
+        }
+
+        Ok(mount)
+    }
+}
+
+/// Creates the mount point of a volume, recording what did not exist yet,
+/// parents first.
+fn create_target(target: &Path, is_dir: bool, created: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut missing = target
+        .ancestors()
+        .skip(1)
+        .take_while(|ancestor| fs::symlink_metadata(ancestor).is_err())
+        .collect::<Vec<_>>();
+    missing.reverse();
+    for dir in missing {
+        fs::create_dir(dir)?;
+        created.push(dir.to_path_buf());
+    }
 
-        let mut environment_variables_str = environment_variables
+    if fs::symlink_metadata(target).is_err() {
+        if is_dir {
+            fs::create_dir(target)?;
+        } else {
+            File::create(target)?;
+        }
+        created.push(target.to_path_buf());
+    }
+
+    Ok(())
+}
+
+/// Removes what `create_target` created, leaving the directories something
+/// else was put in.
+fn remove_created(created: &[PathBuf]) {
+    for path in created.iter().rev() {
+        let _ = if path.is_dir() {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        };
+    }
+}
+
+/// Names the host directory of a cache after its id, which usually is a
+/// path of the image.
+fn cache_dir_name(id: &str) -> String {
+    let name: String = id
+        .trim_matches('/')
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '.' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    match name.as_str() {
+        "" | "." | ".." => "root".to_string(),
+        _ => name,
+    }
+}
+
+/// Bind mounts set up for a chroot, unmounted when dropped or interrupted,
+/// along with the mount points they needed created in the image.
+struct BoundVolumes {
+    mounts: Vec<Mount>,
+    created: Vec<PathBuf>,
+    registration: Option<Registration>,
+}
+
+impl BoundVolumes {
+    fn new(root: &Path, volumes: &[BindMount]) -> Result<BoundVolumes, Error> {
+        let mut bound = BoundVolumes {
+            mounts: Vec::new(),
+            created: Vec::new(),
+            registration: None,
+        };
+
+        for volume in volumes {
+            let mount = volume.mount(root, &mut bound.created)?;
+            bound.mounts.push(mount);
+        }
+
+        let targets = bound
+            .mounts
             .iter()
-            .map(|(key, value)| format!("{}={}", key, value))
-            .collect::<Vec<String>>()
-            .join(" ");
+            .map(|mount| mount.target_path().to_path_buf())
+            .collect::<Vec<_>>();
+        let created = bound.created.clone();
+        bound.registration = Some(cleanup::register(move || {
+            for target in targets.iter().rev() {
+                let _ = sys_mount::unmount(target, UnmountFlags::DETACH);
+            }
+            remove_created(&created);
+        }));
+
+        Ok(bound)
+    }
+    fn claim(&mut self) -> bool {
+        match self.registration.take() {
+            Some(registration) => registration.release(),
+            None => true,
+        }
+    }
+    fn unmount(mut self) -> Result<(), Error> {
+        if !self.claim() {
+            self.mounts.clear();
+            return Ok(());
+        }
+
+        while let Some(mount) = self.mounts.pop() {
+            mount.unmount(UnmountFlags::DETACH)?;
+        }
+        remove_created(&std::mem::take(&mut self.created));
+
+        Ok(())
+    }
+}
+
+impl Drop for BoundVolumes {
+    fn drop(&mut self) {
+        if !self.claim() {
+            return;
+        }
+
+        while let Some(mount) = self.mounts.pop() {
+            let _ = mount.unmount(UnmountFlags::DETACH);
+        }
+        remove_created(&self.created);
+    }
+}
+
+fn references(value: &str, key: &str) -> bool {
+    value.contains(&format!("${{{}}}", key))
+        || value.contains(&format!("${{{}:", key))
+        || value
+            .match_indices(&format!("${}", key))
+            .any(|(index, reference)| {
+                !value[index + reference.len()..]
+                    .starts_with(|ch: char| ch.is_ascii_alphanumeric() || ch == '_')
+            })
+}
+
+/// Sets variables in definition order with the last definition winning. A
+/// previous definition is only kept when a later variable references it, so
+/// that the reference still expands to the value it had when defined.
+pub fn set_environment_variables(
+    environment_variables: &mut Vec<(String, String)>,
+    variables: Vec<(String, String)>,
+) {
+    for (key, value) in variables {
+        if let Some(index) = environment_variables
+            .iter()
+            .rposition(|(existing, _)| *existing == key)
+        {
+            let referenced = environment_variables[index + 1..]
+                .iter()
+                .any(|(_, existing)| references(existing, &key));
+
+            if !referenced {
+                environment_variables.remove(index);
+            }
+        }
+
+        environment_variables.push((key, value));
+    }
+}
+
+/// Interpreter commands run with until a SHELL instruction changes it.
+pub fn default_shell() -> Vec<String> {
+    vec!["/bin/sh".to_string(), "-c".to_string()]
+}
+
+/// Double quotes `value` so that it stays a single word while references to
+/// other variables, and their escaped `\$` form, still work.
+fn double_quote(value: &str) -> String {
+    let mut quoted = String::from('"');
+    let mut chars = value.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' | '`' => quoted.push('\\'),
+            '\\' if chars.peek() != Some(&'$') => quoted.push('\\'),
+            _ => {}
+        }
+        quoted.push(ch);
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Exports variables one statement at a time so that later values can
+/// expand earlier ones.
+pub(crate) fn export_environment_variables(environment_variables: &[(String, String)]) -> String {
+    environment_variables
+        .iter()
+        .map(|(key, value)| format!("export {}={}; ", key, double_quote(value)))
+        .collect()
+}
+
+impl RunEnvironment {
+    pub fn new(backend: Backend, kernel: Option<PathBuf>) -> Result<RunEnvironment, Error> {
+        match (backend, kernel) {
+            (Backend::Chroot, None) => Ok(RunEnvironment::Chroot),
+            (Backend::Nspawn, None) => Ok(RunEnvironment::SystemdNspawn),
+            (Backend::Vmspawn, Some(kernel)) => Ok(RunEnvironment::SystemdVmspawn(kernel)),
+            (Backend::Vmspawn, None) => Err(Error::Usage(
+                "The vmspawn run environment requires a kernel".to_string(),
+            )),
+            (_, Some(_)) => Err(Error::Usage(
+                "A kernel can only be used with the vmspawn run environment".to_string(),
+            )),
+        }
+    }
+    pub fn binary(&self) -> &'static str {
+        match self {
+            RunEnvironment::Chroot => "chroot",
+            RunEnvironment::SystemdNspawn => "systemd-nspawn",
+            RunEnvironment::SystemdVmspawn(_) => "systemd-vmspawn",
+        }
+    }
+    pub fn check_available(&self) -> Result<(), Error> {
+        if doctor::find_in_path(self.binary()).is_none() {
+            return Err(Error::Other(format!(
+                "{} was not found in PATH",
+                self.binary()
+            )));
+        }
 
-        if !environment_variables_str.is_empty() {
-            environment_variables_str.push_str("; ");
+        if let RunEnvironment::SystemdVmspawn(kernel) = self {
+            if !kernel.is_file() {
+                return Err(Error::Usage(format!(
+                    "Kernel {} does not exist",
+                    kernel.display()
+                )));
+            }
         }
 
-        match &self {
+        Ok(())
+    }
+    fn command(
+        &self,
+        mount_point: &str,
+        volumes: &[BindMount],
+        user: &str,
+        script: &str,
+    ) -> Command {
+        let mut command = Command::new(self.binary());
+
+        match self {
             RunEnvironment::Chroot => {
-                let status = std::process::Command::new("chroot")
-                    .arg(mount_point_str)
+                command
+                    .arg(mount_point)
                     .arg("su")
                     .arg("-")
                     .arg(user)
-                    .arg("-c")
-                    .arg(format!(
-                        "cd '{}' && sh -c '{}{}'",
-                        working_dir, environment_variables_str, command,
-                    ))
-                    .status()?;
-
-                if !status.success() {
-                    return Err("Failed to run command".into());
-                }
+                    .arg("-c");
             }
             RunEnvironment::SystemdNspawn => {
-                let status = std::process::Command::new("systemd-nspawn")
+                command
                     .arg("-q")
                     .arg("-D")
-                    .arg(mount_point_str)
+                    .arg(mount_point)
                     .arg("-u")
-                    .arg(user)
-                    .arg("sh")
-                    .arg("-c")
-                    .arg(format!(
-                        "cd '{}' && sh -c '{}{}'",
-                        working_dir, environment_variables_str, command,
-                    ))
-                    .status()?;
-
-                if !status.success() {
-                    return Err("Failed to run command".into());
-                }
+                    .arg(user);
+                command.args(volumes.iter().map(BindMount::systemd_arg));
+                command.arg("sh").arg("-c");
             }
             RunEnvironment::SystemdVmspawn(kernel_path) => {
-                let status = std::process::Command::new("systemd-vmspawn")
+                command
                     .arg("-q")
                     .arg("-D")
-                    .arg(mount_point_str)
+                    .arg(mount_point)
                     .arg("-u")
                     .arg(user)
                     .arg("--linux")
-                    .arg(kernel_path.as_os_str())
-                    .arg("sh")
-                    .arg("-c")
-                    .arg(format!(
-                        "cd '{}' && sh -c '{}{}'",
-                        working_dir, environment_variables_str, command,
-                    ))
-                    .status()?;
-
-                if !status.success() {
-                    return Err("Failed to run command".into());
-                }
+                    .arg(kernel_path.as_os_str());
+                command.args(volumes.iter().map(BindMount::systemd_arg));
+                command.arg("sh").arg("-c");
+            }
+        }
+
+        command.arg(script);
+        command
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn run(
+        &self,
+        mount_point: &PathBuf,
+        volumes: &[BindMount],
+        environment_variables: &[(String, String)],
+        user: &str,
+        working_dir: &str,
+        shell: &[String],
+        command: &str,
+        log: Option<&File>,
+    ) -> Result<(), Error> {
+        let mount_point_str = mount_point
+            .to_str()
+            .ok_or_else(|| Error::Other("Failed to convert path to string".to_string()))?;
+
+        let environment_variables_str = export_environment_variables(environment_variables);
+
+        let script = format!(
+            "cd {} && {} {}",
+            crate::system::shell_quote(working_dir),
+            quote_words(shell),
+            crate::system::shell_quote(&format!("{}{}", environment_variables_str, command)),
+        );
+
+        // Mount points created for file volumes must not be left in the image
+        let placeholders = volumes
+            .iter()
+            .filter(|volume| !volume.host.is_dir())
+            .filter_map(|volume| copy::resolve_in_root(mount_point, &volume.container).ok())
+            .filter(|target| fs::symlink_metadata(target).is_err())
+            .collect::<Vec<_>>();
+
+        // chroot has no notion of bind mounts, so they are mounted by hand
+        let bound = match self {
+            RunEnvironment::Chroot => Some(BoundVolumes::new(mount_point, volumes)?),
+            _ => None,
+        };
+
+        let status = run_logged(
+            &mut self.command(mount_point_str, volumes, user, &script),
+            log,
+        );
+
+        if let Some(bound) = bound {
+            bound.unmount()?;
+        }
+        for placeholder in placeholders {
+            if fs::metadata(&placeholder)
+                .is_ok_and(|metadata| metadata.is_file() && metadata.len() == 0)
+            {
+                fs::remove_file(&placeholder)?;
             }
         }
 
+        let status = status?;
+        if !status.success() {
+            return Err(Error::RunFailed {
+                code: status.code(),
+            });
+        }
+
         Ok(())
     }
 }
 
+/// Copies what is read to the terminal and to the log as it comes.
+fn tee(mut reader: impl Read, mut terminal: impl Write, mut log: &File) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        terminal.write_all(&buffer[..read])?;
+        terminal.flush()?;
+        log.write_all(&buffer[..read])?;
+    }
+}
+
+/// Runs `command`, what it prints also going to `log` if any.
+fn run_logged(command: &mut Command, log: Option<&File>) -> io::Result<ExitStatus> {
+    let Some(log) = log else {
+        return command.status();
+    };
+
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+    let copied = std::thread::scope(|scope| {
+        let stdout = scope.spawn(|| stdout.map_or(Ok(()), |out| tee(out, io::stdout(), log)));
+        let stderr = scope.spawn(|| stderr.map_or(Ok(()), |err| tee(err, io::stderr(), log)));
+        [stdout, stderr]
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or(Ok(())))
+            .collect::<io::Result<Vec<_>>>()
+    });
+    let status = child.wait()?;
+    copied?;
+
+    Ok(status)
+}
+
+fn quote_words(words: &[String]) -> String {
+    words
+        .iter()
+        .map(|word| crate::system::shell_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Writes a heredoc script under the `/var/tmp` of the image, which unlike
+/// `/tmp` is not hidden by a tmpfs in containers, returning the directory
+/// holding it and the command running it from inside the image. Scripts
+/// without a shebang are run by `shell`, minus its trailing `-c`.
+fn write_script(
+    mount_point: &Path,
+    script: &str,
+    shell: &[String],
+) -> Result<(tempdir::TempDir, String), Error> {
+    let tmp = copy::resolve_in_root(mount_point, Path::new("/var/tmp"))?;
+    fs::create_dir_all(&tmp)?;
+
+    let script_dir = tempdir::TempDir::new_in(&tmp, "baker")?;
+    fs::set_permissions(script_dir.path(), fs::Permissions::from_mode(0o755))?;
+
+    let script_path = script_dir.path().join("script");
+    fs::write(&script_path, script)?;
+    fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+
+    let container_path = Path::new("/").join(
+        script_path
+            .strip_prefix(mount_point)
+            .map_err(|_| Error::Other("Invalid script path".to_string()))?,
+    );
+    let command = if script.starts_with("#!") {
+        container_path.display().to_string()
+    } else {
+        let interpreter = match shell.split_last() {
+            Some((last, interpreter)) if last == "-c" => interpreter,
+            _ => shell,
+        };
+        format!("{} {}", quote_words(interpreter), container_path.display())
+    };
+
+    Ok((script_dir, command))
+}
+
 impl MountedImage {
+    #[allow(clippy::too_many_arguments)]
     pub fn run(
         &self,
         label: &str,
-        environment: RunEnvironment,
-        environment_variables: &HashMap<String, String>,
+        environment: &RunEnvironment,
+        volumes: &[BindMount],
+        environment_variables: &[(String, String)],
         user: &str,
         working_dir: &str,
-        command: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        shell: &[String],
+        command: &RunCommand,
+        log: Option<&File>,
+    ) -> Result<(), Error> {
         let mount_point = self.get_mount_point(label)?;
 
-        environment.run(
-            &mount_point,
-            environment_variables,
-            user,
-            working_dir,
-            command,
-        )?;
+        match command {
+            RunCommand::Shell(command) => environment.run(
+                &mount_point,
+                volumes,
+                environment_variables,
+                user,
+                working_dir,
+                shell,
+                command,
+                log,
+            )?,
+            RunCommand::Heredoc { script, .. } => {
+                let (script_dir, command) = write_script(&mount_point, script, shell)?;
+                let result = environment.run(
+                    &mount_point,
+                    volumes,
+                    environment_variables,
+                    user,
+                    working_dir,
+                    shell,
+                    &command,
+                    log,
+                );
+                script_dir.close()?;
+                result?
+            }
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_dir_name() {
+        assert_eq!(cache_dir_name("/var/cache/apt"), "var_cache_apt");
+        assert_eq!(cache_dir_name("pip"), "pip");
+        assert_eq!(cache_dir_name("/"), "root");
+        assert_eq!(cache_dir_name(".."), "root");
+    }
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_write_script() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let mount_point = tmp_dir.path();
+
+        let (script_dir, command) =
+            write_script(mount_point, "echo hello\n", &default_shell()).unwrap();
+        let path = command.strip_prefix("'/bin/sh' ").unwrap();
+        assert!(path.starts_with("/var/tmp/baker"));
+        let script_path = mount_point.join(path.trim_start_matches('/'));
+        assert_eq!(fs::read_to_string(&script_path).unwrap(), "echo hello\n");
+        assert_eq!(
+            fs::metadata(&script_path).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        script_dir.close().unwrap();
+        assert!(!script_path.exists());
+
+        let (_script_dir, command) =
+            write_script(mount_point, "#!/bin/bash\necho hi\n", &default_shell()).unwrap();
+        assert!(command.starts_with("/var/tmp/baker"));
+
+        let shell: Vec<String> = ["/bin/bash", "-euo", "pipefail", "-c"]
+            .iter()
+            .map(|word| word.to_string())
+            .collect();
+        let (_script_dir, command) = write_script(mount_point, "echo hi\n", &shell).unwrap();
+        assert!(command.starts_with("'/bin/bash' '-euo' 'pipefail' /var/tmp/baker"));
+    }
+
+    #[test]
+    fn test_run_environment_new() {
+        assert_eq!(
+            RunEnvironment::new(Backend::Nspawn, None).unwrap(),
+            RunEnvironment::SystemdNspawn
+        );
+        assert_eq!(
+            RunEnvironment::new(Backend::Chroot, None).unwrap(),
+            RunEnvironment::Chroot
+        );
+        assert_eq!(
+            RunEnvironment::new(Backend::Vmspawn, Some("/boot/vmlinuz".into())).unwrap(),
+            RunEnvironment::SystemdVmspawn("/boot/vmlinuz".into())
+        );
+        assert!(matches!(
+            RunEnvironment::new(Backend::Vmspawn, None),
+            Err(Error::Usage(_))
+        ));
+        assert!(RunEnvironment::new(Backend::Chroot, Some("/boot/vmlinuz".into())).is_err());
+    }
+
+    fn args(command: &Command) -> Vec<&str> {
+        command
+            .get_args()
+            .map(|arg| arg.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_bind_mount_parse() {
+        assert_eq!(
+            BindMount::parse("/var/cache/apt:/var/cache/apt").unwrap(),
+            BindMount {
+                host: "/var/cache/apt".into(),
+                container: "/var/cache/apt".into(),
+                read_only: false,
+            }
+        );
+        assert!(
+            BindMount::parse("./downloads:/opt/downloads:ro")
+                .unwrap()
+                .read_only
+        );
+        assert!(!BindMount::parse("/a:/b:rw").unwrap().read_only);
+        assert!(BindMount::parse("/a").is_err());
+        assert!(BindMount::parse("/a:b").is_err());
+        assert!(BindMount::parse(":/b").is_err());
+        assert!(BindMount::parse("/a:/b:rx").is_err());
+    }
+
+    #[test]
+    fn test_bind_mount_check() {
+        assert!(BindMount::parse("/:/host").unwrap().check().is_ok());
+        assert!(BindMount::parse("/nonexistent/baker:/b")
+            .unwrap()
+            .check()
+            .is_err());
+    }
+
+    #[test]
+    fn test_create_target_is_undone() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        fs::create_dir(tmp_dir.path().join("run")).unwrap();
+        let target = tmp_dir.path().join("run/secrets/apikey");
+
+        let mut created = Vec::new();
+        create_target(&target, false, &mut created).unwrap();
+        assert_eq!(
+            created,
+            vec![tmp_dir.path().join("run/secrets"), target.clone()]
+        );
+        assert!(target.is_file());
+
+        remove_created(&created);
+        assert!(!tmp_dir.path().join("run/secrets").exists());
+        assert!(tmp_dir.path().join("run").is_dir());
+
+        // Directories something else was put in are kept
+        let mut created = Vec::new();
+        create_target(&tmp_dir.path().join("mnt/cache"), true, &mut created).unwrap();
+        fs::write(tmp_dir.path().join("mnt/notes"), "kept").unwrap();
+        remove_created(&created);
+        assert!(!tmp_dir.path().join("mnt/cache").exists());
+        assert!(tmp_dir.path().join("mnt/notes").is_file());
+    }
+
+    #[test]
+    fn test_command_arguments() {
+        let volumes = vec![
+            BindMount::parse("/var/cache/apt:/var/cache/apt").unwrap(),
+            BindMount::parse("/srv/files:/opt/files:ro").unwrap(),
+        ];
+
+        let command = RunEnvironment::Chroot.command("/mnt", &volumes, "pi", "true");
+        assert_eq!(command.get_program(), "chroot");
+        assert_eq!(args(&command), vec!["/mnt", "su", "-", "pi", "-c", "true"]);
+
+        let command = RunEnvironment::SystemdNspawn.command("/mnt", &volumes, "pi", "true");
+        assert_eq!(command.get_program(), "systemd-nspawn");
+        assert_eq!(
+            args(&command),
+            vec![
+                "-q",
+                "-D",
+                "/mnt",
+                "-u",
+                "pi",
+                "--bind=/var/cache/apt:/var/cache/apt",
+                "--bind-ro=/srv/files:/opt/files",
+                "sh",
+                "-c",
+                "true"
+            ]
+        );
+
+        let command = RunEnvironment::SystemdVmspawn("/boot/vmlinuz".into()).command(
+            "/mnt",
+            &volumes[1..],
+            "pi",
+            "true",
+        );
+        assert_eq!(command.get_program(), "systemd-vmspawn");
+        assert_eq!(
+            args(&command),
+            vec![
+                "-q",
+                "-D",
+                "/mnt",
+                "-u",
+                "pi",
+                "--linux",
+                "/boot/vmlinuz",
+                "--bind-ro=/srv/files:/opt/files",
+                "sh",
+                "-c",
+                "true"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_environment_variables_last_wins() {
+        let mut environment_variables = Vec::new();
+        set_environment_variables(&mut environment_variables, vars(&[("A", "1"), ("B", "2")]));
+        set_environment_variables(&mut environment_variables, vars(&[("A", "3"), ("A", "4")]));
+
+        assert_eq!(environment_variables, vars(&[("B", "2"), ("A", "4")]));
+    }
+
+    #[test]
+    fn test_set_environment_variables_keeps_referenced_definitions() {
+        let mut environment_variables = Vec::new();
+        set_environment_variables(
+            &mut environment_variables,
+            vars(&[("A", "1"), ("B", "${A}2"), ("A", "3")]),
+        );
+
+        assert_eq!(
+            environment_variables,
+            vars(&[("A", "1"), ("B", "${A}2"), ("A", "3")])
+        );
+    }
+
+    #[test]
+    fn test_references() {
+        assert!(references("${A}", "A"));
+        assert!(references("x$A/y", "A"));
+        assert!(references("${A:-default}", "A"));
+        assert!(!references("$AB", "A"));
+        assert!(!references("A", "A"));
+    }
+
+    #[test]
+    fn test_export_environment_variables_quotes_values() {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "{}printf '%s|' \"$A\" \"$B\" \"$C\"",
+                export_environment_variables(&vars(&[
+                    ("A", "hello  world"),
+                    ("B", "say \"it's\" `x` \\$A"),
+                    ("C", "$A!"),
+                ]))
+            ))
+            .output()
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "hello  world|say \"it's\" `x` $A|hello  world!|"
+        );
+    }
+
+    #[test]
+    fn test_export_environment_variables_expands_in_order() {
+        let mut environment_variables = Vec::new();
+        set_environment_variables(
+            &mut environment_variables,
+            vars(&[("A", "1"), ("B", "${A}2"), ("A", "3")]),
+        );
+
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "{}echo $A $B",
+                export_environment_variables(&environment_variables)
+            ))
+            .output()
+            .unwrap();
+
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "3 12\n");
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::Error, run::BindMount};
+
+/// A file on the host whose content is only handed to the build, never
+/// written in the Bakerfile or the image history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Secret {
+    id: String,
+    source: PathBuf,
+}
+
+impl Secret {
+    /// Parses an `id=NAME,src=PATH` secret specification.
+    pub fn parse(spec: &str) -> Result<Secret, Error> {
+        let mut id = None;
+        let mut source = None;
+
+        for field in spec.split(',') {
+            match field.split_once('=') {
+                Some(("id", value)) if !value.is_empty() => id = Some(value.to_string()),
+                Some(("src" | "source", value)) if !value.is_empty() => {
+                    source = Some(PathBuf::from(value))
+                }
+                _ => {
+                    return Err(Error::Usage(format!(
+                        "Invalid secret {}, expected id=NAME,src=PATH",
+                        spec
+                    )))
+                }
+            }
+        }
+
+        match (id, source) {
+            (Some(id), Some(source)) => Ok(Secret { id, source }),
+            _ => Err(Error::Usage(format!(
+                "Invalid secret {}, expected id=NAME,src=PATH",
+                spec
+            ))),
+        }
+    }
+    pub fn check(&self) -> Result<(), Error> {
+        if !self.source.is_file() {
+            return Err(Error::Usage(format!(
+                "Secret {} source {} is not a file",
+                self.id,
+                self.source.display()
+            )));
+        }
+        Ok(())
+    }
+    /// Reads the secret, without the trailing newline editors tend to add.
+    pub fn read(&self) -> Result<String, Error> {
+        let content = fs::read_to_string(&self.source)?;
+        Ok(content.trim_end_matches(['\r', '\n']).to_string())
+    }
+    /// Read-only bind mount exposing the secret at `target` inside the image,
+    /// `/run/secrets/<id>` by default.
+    pub fn bind_mount(&self, target: Option<&str>) -> Result<BindMount, Error> {
+        let target = match target {
+            Some(target) => PathBuf::from(target),
+            None => Path::new("/run/secrets").join(&self.id),
+        };
+
+        Ok(BindMount::new(
+            fs::canonicalize(&self.source)?,
+            target,
+            true,
+        ))
+    }
+}
+
+pub fn find<'a>(secrets: &'a [Secret], id: &str) -> Result<&'a Secret, Error> {
+    secrets
+        .iter()
+        .find(|secret| secret.id == id)
+        .ok_or_else(|| Error::Usage(format!("Unknown secret {}, pass it with --secret", id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Secret::parse("id=apikey,src=./key.txt").unwrap(),
+            Secret {
+                id: "apikey".to_string(),
+                source: PathBuf::from("./key.txt")
+            }
+        );
+        assert!(Secret::parse("id=apikey").is_err());
+        assert!(Secret::parse("src=./key.txt").is_err());
+        assert!(Secret::parse("id=apikey,src=./key.txt,mode=0400").is_err());
+    }
+
+    #[test]
+    fn test_find_and_read() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let source = tmp_dir.path().join("psk");
+        fs::write(&source, "hunter22\n").unwrap();
+        let secrets = vec![Secret {
+            id: "psk".to_string(),
+            source,
+        }];
+
+        assert_eq!(find(&secrets, "psk").unwrap().read().unwrap(), "hunter22");
+        assert!(matches!(find(&secrets, "other"), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn test_bind_mount() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let source = tmp_dir.path().join("key.txt");
+        fs::write(&source, "secret").unwrap();
+        let secret = Secret {
+            id: "apikey".to_string(),
+            source,
+        };
+
+        assert_eq!(
+            secret.bind_mount(None).unwrap(),
+            BindMount::new(
+                fs::canonicalize(tmp_dir.path().join("key.txt")).unwrap(),
+                PathBuf::from("/run/secrets/apikey"),
+                true
+            )
+        );
+        assert_eq!(
+            secret.bind_mount(Some("/root/.netrc")).unwrap(),
+            BindMount::new(
+                fs::canonicalize(tmp_dir.path().join("key.txt")).unwrap(),
+                PathBuf::from("/root/.netrc"),
+                true
+            )
+        );
+    }
+}
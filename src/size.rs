@@ -0,0 +1,65 @@
+use crate::error::Error;
+
+const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Parses sizes such as `512`, `64K`, `2G` or `1.5GiB` using binary units.
+pub fn parse_size(size: &str) -> Result<u64, Error> {
+    let size = size.trim();
+    let split = size
+        .find(|ch: char| !ch.is_ascii_digit() && ch != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| Error::Usage(format!("Invalid size: {}", size)))?;
+
+    let exponent = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        _ => return Err(Error::Usage(format!("Invalid size unit: {}", size))),
+    };
+
+    Ok((number * 1024f64.powi(exponent)) as u64)
+}
+
+pub fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64K").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size("1.5GiB").unwrap(), 3 * 512 * 1024 * 1024);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("12X").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(2 * 1024 * 1024 * 1024), "2.0 GiB");
+    }
+}
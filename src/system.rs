@@ -0,0 +1,25 @@
+pub mod boot;
+pub mod command;
+pub mod fstab;
+pub mod locale;
+pub mod packages;
+pub mod services;
+pub mod ssh;
+pub mod users;
+pub mod wifi;
+
+/// Quotes `value` for a POSIX shell, for the scripts run inside images.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("$6$a"), "'$6$a'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+}
@@ -0,0 +1,360 @@
+use std::{fs, path::Path};
+
+use crate::{error::Error, parsing::parser::CmdlineEdit};
+
+const CONFIG_TXT: &str = "config.txt";
+const CMDLINE_TXT: &str = "cmdline.txt";
+/// Init programs growing the root partition on first boot, used by Bookworm
+/// and by older releases respectively.
+const RESIZE_INITS: [&str; 2] = [
+    "/usr/lib/raspberrypi-sys-mods/firstboot",
+    "/usr/lib/raspi-config/init_resize.sh",
+];
+
+/// What identifies a setting of config.txt: its key, plus the parameter or
+/// overlay name for `dtparam` and `dtoverlay` which may appear many times.
+fn setting_id(setting: &str) -> Option<String> {
+    let (key, value) = setting.trim().split_once('=')?;
+    let key = key.trim();
+
+    match key {
+        "dtparam" => Some(format!("{}={}", key, value.split('=').next()?.trim())),
+        "dtoverlay" => Some(format!("{}={}", key, value.split(',').next()?.trim())),
+        _ => Some(key.to_string()),
+    }
+}
+
+/// Sets `key=value` in the unconditional part of config.txt. An existing
+/// setting is updated, a commented out one is enabled and otherwise the
+/// setting is appended under `[all]`.
+pub fn set_config(content: &str, key: &str, value: &str) -> String {
+    let setting = format!("{}={}", key, value);
+    let id = setting_id(&setting);
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+
+    let mut unconditional = true;
+    let mut active = None;
+    let mut commented = None;
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            unconditional = trimmed == "[all]";
+        } else if unconditional {
+            if let Some(disabled) = trimmed.strip_prefix('#') {
+                if commented.is_none() && setting_id(disabled) == id {
+                    commented = Some(index);
+                }
+            } else if setting_id(trimmed) == id {
+                active = Some(index);
+            }
+        }
+    }
+
+    match active.or(commented) {
+        Some(index) => lines[index] = setting,
+        None => {
+            if !unconditional {
+                lines.push("[all]".to_string());
+            }
+            lines.push(setting);
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+pub fn configure(boot: &Path, key: &str, value: &str) -> Result<(), Error> {
+    let path = boot.join(CONFIG_TXT);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    fs::write(&path, set_config(&content, key, value))?;
+
+    Ok(())
+}
+
+/// Enables the overlay in config.txt, installing its `.dtbo` first when one
+/// is given. `overlay` is the name of the overlay and its parameters.
+pub fn add_overlay(boot: &Path, overlay: &str, dtbo: Option<&Path>) -> Result<(), Error> {
+    let name = overlay.split(',').next().unwrap_or(overlay);
+    if name.is_empty() || name.contains('/') {
+        return Err(Error::Usage(format!("Invalid overlay {}", overlay)));
+    }
+
+    if let Some(dtbo) = dtbo {
+        let overlays_dir = boot.join("overlays");
+        fs::create_dir_all(&overlays_dir)?;
+        fs::copy(dtbo, overlays_dir.join(format!("{}.dtbo", name)))?;
+    }
+
+    configure(boot, "dtoverlay", overlay)
+}
+
+/// Copies the kernel image to the boot partition and points `kernel=` at
+/// it, keeping the stock kernels around.
+pub fn install_kernel(boot: &Path, kernel: &Path) -> Result<(), Error> {
+    let name = kernel
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::Usage(format!("Invalid kernel {}", kernel.display())))?;
+
+    fs::copy(kernel, boot.join(name))?;
+
+    configure(boot, "kernel", name)
+}
+
+/// Applies the edit to the arguments of cmdline.txt. Appending skips the
+/// arguments already there, removing a bare key also removes its `key=value`
+/// forms.
+pub fn edit_cmdline(content: &str, edit: &CmdlineEdit) -> Result<String, Error> {
+    if content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .count()
+        > 1
+    {
+        return Err(Error::Other(
+            "cmdline.txt must hold a single line".to_string(),
+        ));
+    }
+
+    let mut arguments: Vec<&str> = content.split_whitespace().collect();
+    match edit {
+        CmdlineEdit::Append(appended) => {
+            for argument in appended {
+                if !arguments.contains(&argument.as_str()) {
+                    arguments.push(argument);
+                }
+            }
+        }
+        CmdlineEdit::Remove(removed) => arguments.retain(|argument| {
+            !removed.iter().any(|removed| {
+                argument == removed
+                    || (!removed.contains('=')
+                        && argument.split_once('=').map(|(key, _)| key) == Some(removed))
+            })
+        }),
+    }
+
+    Ok(arguments.join(" ") + "\n")
+}
+
+pub fn configure_cmdline(boot: &Path, edit: &CmdlineEdit) -> Result<(), Error> {
+    let path = boot.join(CMDLINE_TXT);
+    let content = fs::read_to_string(&path)?;
+
+    fs::write(&path, edit_cmdline(&content, edit)?)?;
+
+    Ok(())
+}
+
+/// Adds or removes the first boot resize from cmdline.txt, `init` being the
+/// resize program available in the image.
+fn set_first_boot_resize(
+    content: &str,
+    enabled: bool,
+    init: Option<&str>,
+) -> Result<String, Error> {
+    let resize_arguments: Vec<String> = RESIZE_INITS
+        .iter()
+        .map(|init| format!("init={}", init))
+        .collect();
+    if !enabled {
+        return edit_cmdline(content, &CmdlineEdit::Remove(resize_arguments));
+    }
+
+    let current = content
+        .split_whitespace()
+        .find(|argument| argument.starts_with("init="));
+    match current {
+        Some(current) if resize_arguments.iter().any(|argument| argument == current) => {
+            Ok(content.to_string())
+        }
+        Some(current) => Err(Error::Usage(format!(
+            "cmdline.txt already sets {}",
+            current
+        ))),
+        None => {
+            let init = init.ok_or_else(|| {
+                Error::Usage("This image does not support resizing on first boot".to_string())
+            })?;
+            edit_cmdline(
+                content,
+                &CmdlineEdit::Append(vec![format!("init={}", init)]),
+            )
+        }
+    }
+}
+
+/// Enables or disables the growth of the root partition on first boot.
+pub fn configure_first_boot_resize(root: &Path, boot: &Path, enabled: bool) -> Result<(), Error> {
+    let path = boot.join(CMDLINE_TXT);
+    let content = fs::read_to_string(&path)?;
+    let init = RESIZE_INITS
+        .iter()
+        .find(|init| root.join(init.trim_start_matches('/')).exists())
+        .copied();
+
+    fs::write(&path, set_first_boot_resize(&content, enabled, init)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str =
+        "# Uncomment some or all of these to enable the optional hardware interfaces\n\
+                          #dtparam=i2c_arm=on\n\
+                          #dtparam=spi=on\n\
+                          dtparam=audio=on\n\
+                          dtoverlay=vc4-kms-v3d\n\
+                          \n\
+                          [pi4]\n\
+                          arm_boost=1\n\
+                          gpu_mem=128\n";
+
+    #[test]
+    fn test_setting_id() {
+        assert_eq!(setting_id("gpu_mem=16").as_deref(), Some("gpu_mem"));
+        assert_eq!(
+            setting_id("dtparam=i2c_arm=on").as_deref(),
+            Some("dtparam=i2c_arm")
+        );
+        assert_eq!(
+            setting_id("dtoverlay=gpio-fan,gpiopin=14").as_deref(),
+            Some("dtoverlay=gpio-fan")
+        );
+        assert_eq!(setting_id("Uncomment some of these"), None);
+    }
+
+    #[test]
+    fn test_set_config_enables_commented_setting() {
+        let config = set_config(CONFIG, "dtparam", "i2c_arm=on");
+        assert!(config.contains("\ndtparam=i2c_arm=on\n#dtparam=spi=on\n"));
+        assert_eq!(set_config(&config, "dtparam", "i2c_arm=on"), config);
+    }
+
+    #[test]
+    fn test_set_config_updates_existing_setting() {
+        let config = set_config(CONFIG, "dtparam", "audio=off");
+        assert!(config.contains("\ndtparam=audio=off\n"));
+        assert!(!config.contains("audio=on"));
+    }
+
+    #[test]
+    fn test_set_config_appends_outside_conditional_sections() {
+        let config = set_config(CONFIG, "gpu_mem", "16");
+        assert!(config.ends_with("[pi4]\narm_boost=1\ngpu_mem=128\n[all]\ngpu_mem=16\n"));
+
+        let config = set_config(&config, "gpu_mem", "32");
+        assert!(config.ends_with("gpu_mem=128\n[all]\ngpu_mem=32\n"));
+    }
+
+    #[test]
+    fn test_edit_cmdline() {
+        let cmdline =
+            "console=serial0,115200 console=tty1 root=PARTUUID=1234-02 rootwait quiet splash\n";
+
+        assert_eq!(
+            edit_cmdline(
+                cmdline,
+                &CmdlineEdit::Remove(vec!["quiet".to_string(), "console".to_string()])
+            )
+            .unwrap(),
+            "root=PARTUUID=1234-02 rootwait splash\n"
+        );
+        assert_eq!(
+            edit_cmdline(
+                cmdline,
+                &CmdlineEdit::Append(vec![
+                    "console=tty1".to_string(),
+                    "net.ifnames=0".to_string()
+                ])
+            )
+            .unwrap(),
+            "console=serial0,115200 console=tty1 root=PARTUUID=1234-02 rootwait quiet splash net.ifnames=0\n"
+        );
+        assert!(edit_cmdline("rootwait\nquiet\n", &CmdlineEdit::Append(Vec::new())).is_err());
+    }
+
+    #[test]
+    fn test_add_overlay() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let boot = tmp_dir.path().join("bootfs");
+        fs::create_dir_all(&boot).unwrap();
+        fs::write(boot.join("config.txt"), "dtoverlay=my-hat\n").unwrap();
+        let dtbo = tmp_dir.path().join("my-hat-overlay.dtbo");
+        fs::write(&dtbo, b"\xd0\x0d\xfe\xed").unwrap();
+
+        add_overlay(&boot, "my-hat,addr=0x20", Some(&dtbo)).unwrap();
+
+        assert_eq!(
+            fs::read(boot.join("overlays/my-hat.dtbo")).unwrap(),
+            b"\xd0\x0d\xfe\xed"
+        );
+        assert_eq!(
+            fs::read_to_string(boot.join("config.txt")).unwrap(),
+            "dtoverlay=my-hat,addr=0x20\n"
+        );
+        assert!(matches!(
+            add_overlay(&boot, "../escape", None),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_install_kernel() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let boot = tmp_dir.path().join("bootfs");
+        fs::create_dir_all(&boot).unwrap();
+        fs::write(boot.join("config.txt"), "kernel=kernel8.img\narm_64bit=1\n").unwrap();
+        let kernel = tmp_dir.path().join("kernel8-rt.img");
+        fs::write(&kernel, b"kernel").unwrap();
+
+        install_kernel(&boot, &kernel).unwrap();
+
+        assert_eq!(fs::read(boot.join("kernel8-rt.img")).unwrap(), b"kernel");
+        assert_eq!(
+            fs::read_to_string(boot.join("config.txt")).unwrap(),
+            "kernel=kernel8-rt.img\narm_64bit=1\n"
+        );
+    }
+
+    #[test]
+    fn test_configure_creates_config() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+
+        configure(tmp_dir.path(), "enable_uart", "1").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("config.txt")).unwrap(),
+            "enable_uart=1\n"
+        );
+    }
+
+    #[test]
+    fn test_set_first_boot_resize() {
+        let cmdline = "console=tty1 root=PARTUUID=1234-02 rootwait init=/usr/lib/raspberrypi-sys-mods/firstboot\n";
+
+        let disabled = set_first_boot_resize(cmdline, false, None).unwrap();
+        assert_eq!(disabled, "console=tty1 root=PARTUUID=1234-02 rootwait\n");
+        assert_eq!(
+            set_first_boot_resize(&disabled, true, Some(RESIZE_INITS[0])).unwrap(),
+            cmdline
+        );
+        assert_eq!(
+            set_first_boot_resize(cmdline, true, Some(RESIZE_INITS[0])).unwrap(),
+            cmdline
+        );
+        assert!(set_first_boot_resize(&disabled, true, None).is_err());
+        assert!(matches!(
+            set_first_boot_resize("root=/dev/sda2 init=/bin/sh\n", true, Some(RESIZE_INITS[0])),
+            Err(Error::Usage(_))
+        ));
+    }
+}
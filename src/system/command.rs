@@ -0,0 +1,143 @@
+use std::{fs, path::Path};
+
+use crate::{copy, error::Error, parsing::parser::EntrypointOptions, run, system::services};
+
+/// Unit running the ENTRYPOINT and CMD of the Bakerfile on boot.
+pub const COMMAND_UNIT: &str = "baker-cmd.service";
+
+/// Quotes a word of a unit file command line, doubling `%` and `$` so that
+/// systemd expands neither specifiers nor variables and leaves them to the
+/// shell.
+fn systemd_quote(word: &str) -> String {
+    format!(
+        "\"{}\"",
+        word.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+            .replace('$', "$$")
+    )
+}
+
+/// Command line running `command` with `shell` once the variables are set,
+/// the way RUN does.
+pub fn exec_start(shell: &[String], envs: &[(String, String)], command: &str) -> String {
+    shell
+        .iter()
+        .map(|word| systemd_quote(word))
+        .chain([systemd_quote(&format!(
+            "{}{}",
+            run::export_environment_variables(envs),
+            command
+        ))])
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn unit(user: &str, workdir: &str, exec_start: &str, options: &EntrypointOptions) -> String {
+    let restart = match options.restart.as_deref() {
+        Some(restart) => format!("Restart={}\nRestartSec=5\n", restart),
+        None => String::new(),
+    };
+
+    format!(
+        "[Unit]\n\
+         Description=Bakerfile entrypoint\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         User={}\n\
+         WorkingDirectory={}\n\
+         ExecStart={}\n\
+         {}\
+         \n\
+         [Install]\n\
+         WantedBy={}\n",
+        user,
+        workdir,
+        exec_start,
+        restart,
+        options.wanted_by.as_deref().unwrap_or("multi-user.target")
+    )
+}
+
+/// Installs and enables the unit running `exec_start` on every boot.
+pub fn install(
+    root: &Path,
+    user: &str,
+    workdir: &str,
+    exec_start: &str,
+    options: &EntrypointOptions,
+) -> Result<(), Error> {
+    let path = copy::resolve_in_root(
+        root,
+        &Path::new(services::SYSTEM_CONFIG_DIR).join(COMMAND_UNIT),
+    )?;
+    fs::create_dir_all(
+        path.parent()
+            .ok_or_else(|| Error::Other("Invalid unit path".to_string()))?,
+    )?;
+    fs::write(&path, unit(user, workdir, exec_start, options))?;
+
+    services::enable(root, COMMAND_UNIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exec_start() {
+        let shell = vec!["/bin/sh".to_string(), "-c".to_string()];
+        let envs = vec![("URL".to_string(), "http://localhost:8080".to_string())];
+
+        assert_eq!(
+            exec_start(&shell, &envs, "chromium --kiosk \"$URL\" 100%"),
+            "\"/bin/sh\" \"-c\" \"export URL=\\\"http://localhost:8080\\\"; chromium --kiosk \\\"$$URL\\\" 100%%\""
+        );
+    }
+
+    #[test]
+    fn test_unit_restart_policy() {
+        let unit = unit(
+            "root",
+            "/",
+            "\"/opt/app/run\"",
+            &EntrypointOptions {
+                restart: Some("always".to_string()),
+                wanted_by: Some("graphical.target".to_string()),
+            },
+        );
+
+        assert!(unit.contains("\nExecStart=\"/opt/app/run\"\nRestart=always\nRestartSec=5\n"));
+        assert!(unit.ends_with("[Install]\nWantedBy=graphical.target\n"));
+    }
+
+    #[test]
+    fn test_install() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+
+        install(
+            tmp_dir.path(),
+            "pi",
+            "/home/pi",
+            "\"/bin/sh\" \"-c\" \"kiosk\"",
+            &EntrypointOptions::default(),
+        )
+        .unwrap();
+
+        let unit = fs::read_to_string(tmp_dir.path().join("etc/systemd/system/baker-cmd.service"))
+            .unwrap();
+        assert!(unit.contains(
+            "\nUser=pi\nWorkingDirectory=/home/pi\nExecStart=\"/bin/sh\" \"-c\" \"kiosk\"\n"
+        ));
+        assert!(fs::symlink_metadata(
+            tmp_dir
+                .path()
+                .join("etc/systemd/system/multi-user.target.wants/baker-cmd.service")
+        )
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    }
+}
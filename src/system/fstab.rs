@@ -0,0 +1,196 @@
+use std::{fs, path::Path};
+
+use crate::{error::Error, parsing::parser::FstabEntry};
+
+const FSTAB: &str = "etc/fstab";
+
+/// Sets the entry mounting `mount_point`, replacing the line that already
+/// mounts it if any.
+pub fn set_entry(content: &str, entry: &str, mount_point: &str) -> String {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+
+    match lines.iter().position(|line| {
+        !line.trim_start().starts_with('#') && line.split_whitespace().nth(1) == Some(mount_point)
+    }) {
+        Some(index) => lines[index] = entry.to_string(),
+        None => lines.push(entry.to_string()),
+    }
+
+    lines.join("\n") + "\n"
+}
+
+fn read_fstab(path: &Path) -> Result<String, Error> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Appends `entry`, skipping it when the same line is already there and
+/// failing when another line mounts the same mount point or, for swap, the
+/// same device.
+pub fn append_entry(content: &str, entry: &FstabEntry) -> Result<String, Error> {
+    let fields = entry.to_string();
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    let is_swap = entry.mount_point == "none" || entry.mount_point == "swap";
+
+    for line in content.lines() {
+        let existing: Vec<&str> = line.split_whitespace().collect();
+        if existing.first().is_none_or(|first| first.starts_with('#')) {
+            continue;
+        }
+        if existing == fields {
+            return Ok(content.to_string());
+        }
+        let conflicts = if is_swap {
+            existing.first() == Some(&entry.device.as_str())
+        } else {
+            existing.get(1) == Some(&entry.mount_point.as_str())
+        };
+        if conflicts {
+            return Err(Error::Usage(format!(
+                "/etc/fstab already has an entry for {}: {}",
+                if is_swap {
+                    &entry.device
+                } else {
+                    &entry.mount_point
+                },
+                line.trim()
+            )));
+        }
+    }
+
+    let mut content = content.to_string();
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    Ok(content + &entry.to_string() + "\n")
+}
+
+/// Adds `entry` to the fstab of the image, creating its mount point.
+pub fn add_entry(root: &Path, entry: &FstabEntry) -> Result<(), Error> {
+    let path = root.join(FSTAB);
+    let content = append_entry(&read_fstab(&path)?, entry)?;
+    fs::write(&path, content)?;
+    if entry.mount_point.starts_with('/') {
+        fs::create_dir_all(root.join(entry.mount_point.trim_start_matches('/')))?;
+    }
+
+    Ok(())
+}
+
+/// Mounts the filesystem labelled `label` at `mount_point` on boot, creating
+/// the mount point in the image.
+pub fn mount_label(
+    root: &Path,
+    label: &str,
+    mount_point: &str,
+    filesystem: &str,
+) -> Result<(), Error> {
+    if !mount_point.starts_with('/') || mount_point.split_whitespace().count() != 1 {
+        return Err(Error::Usage(format!("Invalid mount point {}", mount_point)));
+    }
+
+    let options = match filesystem {
+        "vfat" => "defaults",
+        _ => "defaults,noatime",
+    };
+    let entry = format!(
+        "LABEL={}  {}  {}  {}  0  2",
+        label, mount_point, filesystem, options
+    );
+
+    let path = root.join(FSTAB);
+    fs::write(&path, set_entry(&read_fstab(&path)?, &entry, mount_point))?;
+    fs::create_dir_all(root.join(mount_point.trim_start_matches('/')))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FSTAB_CONTENT: &str = "proc            /proc           proc    defaults          0       0\n\
+                                 PARTUUID=1234-01  /boot/firmware  vfat    defaults          0       2\n\
+                                 PARTUUID=1234-02  /               ext4    defaults,noatime  0       1\n";
+
+    #[test]
+    fn test_set_entry() {
+        let fstab = set_entry(
+            FSTAB_CONTENT,
+            "LABEL=data  /data  ext4  defaults  0  2",
+            "/data",
+        );
+        assert!(fstab.starts_with(FSTAB_CONTENT));
+        assert!(fstab.ends_with("\nLABEL=data  /data  ext4  defaults  0  2\n"));
+
+        let fstab = set_entry(&fstab, "LABEL=logs  /data  ext4  defaults  0  2", "/data");
+        assert!(!fstab.contains("LABEL=data"));
+        assert_eq!(fstab.lines().count(), 4);
+    }
+
+    fn entry(device: &str, mount_point: &str) -> FstabEntry {
+        FstabEntry {
+            device: device.to_string(),
+            mount_point: mount_point.to_string(),
+            filesystem: "ext4".to_string(),
+            options: "defaults,nofail".to_string(),
+            dump: 0,
+            pass: 2,
+        }
+    }
+
+    #[test]
+    fn test_append_entry() {
+        let fstab = append_entry(FSTAB_CONTENT, &entry("/dev/sda1", "/mnt/storage")).unwrap();
+        assert!(fstab.starts_with(FSTAB_CONTENT));
+        assert!(fstab.ends_with("\n/dev/sda1  /mnt/storage  ext4  defaults,nofail  0  2\n"));
+
+        assert_eq!(
+            append_entry(&fstab, &entry("/dev/sda1", "/mnt/storage")).unwrap(),
+            fstab
+        );
+        assert!(matches!(
+            append_entry(&fstab, &entry("/dev/sdb1", "/mnt/storage")),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            append_entry(FSTAB_CONTENT, &entry("/dev/sda1", "/")),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_add_entry_without_fstab() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        fs::create_dir_all(tmp_dir.path().join("etc")).unwrap();
+
+        add_entry(tmp_dir.path(), &entry("/dev/sda1", "/mnt/storage")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join(FSTAB)).unwrap(),
+            "/dev/sda1  /mnt/storage  ext4  defaults,nofail  0  2\n"
+        );
+        assert!(tmp_dir.path().join("mnt/storage").is_dir());
+    }
+
+    #[test]
+    fn test_mount_label() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        fs::create_dir_all(tmp_dir.path().join("etc")).unwrap();
+        fs::write(tmp_dir.path().join(FSTAB), FSTAB_CONTENT).unwrap();
+
+        mount_label(tmp_dir.path(), "data", "/data", "ext4").unwrap();
+
+        assert!(fs::read_to_string(tmp_dir.path().join(FSTAB))
+            .unwrap()
+            .ends_with("LABEL=data  /data  ext4  defaults,noatime  0  2\n"));
+        assert!(tmp_dir.path().join("data").is_dir());
+        assert!(matches!(
+            mount_label(tmp_dir.path(), "data", "data", "ext4"),
+            Err(Error::Usage(_))
+        ));
+    }
+}
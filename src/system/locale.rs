@@ -0,0 +1,225 @@
+use std::{
+    fs,
+    path::{Component, Path},
+};
+
+use crate::{copy, error::Error};
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+fn read_or_default(path: &Path) -> Result<String, Error> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn write(root: &Path, path: &str, content: &str) -> Result<(), Error> {
+    let path = copy::resolve_in_root(root, Path::new(path))?;
+    fs::create_dir_all(
+        path.parent()
+            .ok_or_else(|| Error::Other("Invalid configuration path".to_string()))?,
+    )?;
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Sets `KEY="value"` in a shell style configuration file, replacing the
+/// existing assignment if there is one.
+fn set_assignment(content: &str, key: &str, value: &str) -> String {
+    let assignment = format!("{}=\"{}\"", key, value);
+    let mut found = false;
+
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((line_key, _)) if line_key.trim() == key => {
+                found = true;
+                assignment.clone()
+            }
+            _ => line.to_string(),
+        })
+        .collect();
+    if !found {
+        lines.push(assignment);
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Points `/etc/localtime` at the zone, which must exist in the image.
+pub fn set_timezone(root: &Path, timezone: &str) -> Result<(), Error> {
+    let zone = Path::new(ZONEINFO_DIR).join(timezone);
+    let is_valid = Path::new(timezone)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+
+    if !is_valid || !copy::resolve_in_root(root, &zone)?.is_file() {
+        return Err(Error::Usage(format!("Unknown timezone {}", timezone)));
+    }
+
+    // Only the parent is resolved, /etc/localtime is itself a symlink
+    let localtime = copy::resolve_in_root(root, Path::new("/etc"))?.join("localtime");
+    if fs::symlink_metadata(&localtime).is_ok() {
+        fs::remove_file(&localtime)?;
+    }
+    std::os::unix::fs::symlink(&zone, &localtime)?;
+
+    write(root, "/etc/timezone", &format!("{}\n", timezone))
+}
+
+/// The `locale.gen` entry of a locale, with the charset from the list of
+/// supported locales when the image has it.
+fn locale_gen_entry(supported: &str, locale: &str) -> String {
+    supported
+        .lines()
+        .map(str::trim)
+        .find(|line| line.split_whitespace().next() == Some(locale))
+        .map(|line| line.to_string())
+        .unwrap_or_else(|| {
+            let charset = locale
+                .split_once('.')
+                .map_or("UTF-8", |(_, charset)| charset);
+            format!("{} {}", locale, charset)
+        })
+}
+
+/// Uncomments the locale in `locale.gen`, adding it when missing.
+fn enable_in_locale_gen(content: &str, entry: &str) -> String {
+    let mut found = false;
+
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| {
+            if line.trim_start_matches(['#', ' ']) == entry {
+                found = true;
+                entry.to_string()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        lines.push(entry.to_string());
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Enables the locale and makes it the default one, `locale-gen` still has
+/// to run inside the image afterwards.
+pub fn set_locale(root: &Path, locale: &str) -> Result<(), Error> {
+    let supported = read_or_default(&copy::resolve_in_root(
+        root,
+        Path::new("/usr/share/i18n/SUPPORTED"),
+    )?)?;
+    let locale_gen = read_or_default(&copy::resolve_in_root(root, Path::new("/etc/locale.gen"))?)?;
+
+    write(
+        root,
+        "/etc/locale.gen",
+        &enable_in_locale_gen(&locale_gen, &locale_gen_entry(&supported, locale)),
+    )?;
+    write(root, "/etc/default/locale", &format!("LANG={}\n", locale))
+}
+
+pub fn set_keyboard(root: &Path, layout: &str) -> Result<(), Error> {
+    let keyboard = read_or_default(&copy::resolve_in_root(
+        root,
+        Path::new("/etc/default/keyboard"),
+    )?)?;
+
+    write(
+        root,
+        "/etc/default/keyboard",
+        &set_assignment(&keyboard, "XKBLAYOUT", layout),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_assignment() {
+        let keyboard = "XKBMODEL=\"pc105\"\nXKBLAYOUT=\"gb\"\nXKBVARIANT=\"\"\n";
+        assert_eq!(
+            set_assignment(keyboard, "XKBLAYOUT", "be"),
+            "XKBMODEL=\"pc105\"\nXKBLAYOUT=\"be\"\nXKBVARIANT=\"\"\n"
+        );
+        assert_eq!(set_assignment("", "XKBLAYOUT", "be"), "XKBLAYOUT=\"be\"\n");
+    }
+
+    #[test]
+    fn test_enable_in_locale_gen() {
+        let locale_gen = "# en_GB ISO-8859-1\n# en_GB.UTF-8 UTF-8\nen_US.UTF-8 UTF-8\n";
+        assert_eq!(
+            enable_in_locale_gen(locale_gen, "en_GB.UTF-8 UTF-8"),
+            "# en_GB ISO-8859-1\nen_GB.UTF-8 UTF-8\nen_US.UTF-8 UTF-8\n"
+        );
+        assert_eq!(
+            enable_in_locale_gen(locale_gen, "fr_BE.UTF-8 UTF-8"),
+            format!("{}fr_BE.UTF-8 UTF-8\n", locale_gen)
+        );
+    }
+
+    #[test]
+    fn test_locale_gen_entry() {
+        let supported = "en_GB.UTF-8 UTF-8\nen_GB ISO-8859-1\n";
+        assert_eq!(locale_gen_entry(supported, "en_GB"), "en_GB ISO-8859-1");
+        assert_eq!(locale_gen_entry("", "nl_BE.UTF-8"), "nl_BE.UTF-8 UTF-8");
+    }
+
+    #[test]
+    fn test_set_timezone() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("usr/share/zoneinfo/Europe")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("usr/share/zoneinfo/Europe/Brussels"), "TZif").unwrap();
+        std::os::unix::fs::symlink("/usr/share/zoneinfo/Etc/UTC", root.join("etc/localtime"))
+            .unwrap();
+
+        set_timezone(root, "Europe/Brussels").unwrap();
+
+        assert_eq!(
+            fs::read_link(root.join("etc/localtime")).unwrap(),
+            Path::new("/usr/share/zoneinfo/Europe/Brussels")
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("etc/timezone")).unwrap(),
+            "Europe/Brussels\n"
+        );
+        assert!(matches!(
+            set_timezone(root, "Mars/Olympus"),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            set_timezone(root, "../../../etc/passwd"),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_set_locale_and_keyboard() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path();
+
+        set_locale(root, "en_GB.UTF-8").unwrap();
+        set_keyboard(root, "be").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(root.join("etc/locale.gen")).unwrap(),
+            "en_GB.UTF-8 UTF-8\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("etc/default/locale")).unwrap(),
+            "LANG=en_GB.UTF-8\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("etc/default/keyboard")).unwrap(),
+            "XKBLAYOUT=\"be\"\n"
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{error::Error, system::shell_quote};
+
+const APT_RETRIES: usize = 3;
+
+/// Script installing the packages with apt, without recommended packages
+/// and without leaving the package cache and lists in the image.
+pub fn install_script(packages: &[String]) -> Result<String, Error> {
+    if let Some(package) = packages.iter().find(|package| package.starts_with('-')) {
+        return Err(Error::Usage(format!("Invalid package name {}", package)));
+    }
+
+    let apt_get = format!("apt-get -o Acquire::Retries={}", APT_RETRIES);
+    let packages: Vec<String> = packages
+        .iter()
+        .map(|package| shell_quote(package))
+        .collect();
+
+    Ok(format!(
+        "set -e\n\
+         export DEBIAN_FRONTEND=noninteractive\n\
+         for attempt in $(seq {retries}); do\n\
+         \t{apt_get} update && break\n\
+         \t[ \"$attempt\" -lt {retries} ] || exit 1\n\
+         \tsleep 5\n\
+         done\n\
+         {apt_get} install -y --no-install-recommends {packages}\n\
+         apt-get clean\n\
+         rm -rf /var/lib/apt/lists/*\n",
+        retries = APT_RETRIES,
+        apt_get = apt_get,
+        packages = packages.join(" ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_script() {
+        let script = install_script(&["nginx".to_string(), "python3-pip".to_string()]).unwrap();
+
+        assert!(script.contains("export DEBIAN_FRONTEND=noninteractive\n"));
+        assert!(script.contains(
+            "apt-get -o Acquire::Retries=3 install -y --no-install-recommends 'nginx' 'python3-pip'\n"
+        ));
+        assert!(script.ends_with("apt-get clean\nrm -rf /var/lib/apt/lists/*\n"));
+    }
+
+    #[test]
+    fn test_install_script_rejects_options() {
+        assert!(matches!(
+            install_script(&["--allow-unauthenticated".to_string()]),
+            Err(Error::Usage(_))
+        ));
+    }
+}
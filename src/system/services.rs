@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{copy, error::Error};
+
+const UNIT_DIRS: &[&str] = &[
+    "/etc/systemd/system",
+    "/lib/systemd/system",
+    "/usr/lib/systemd/system",
+];
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/systemd/system";
+
+/// Completes a unit name like systemctl does, `ssh` meaning `ssh.service`.
+pub fn unit_name(unit: &str) -> Result<String, Error> {
+    if unit.is_empty() || unit.contains('/') {
+        return Err(Error::Usage(format!("Invalid unit {}", unit)));
+    }
+
+    if unit.contains('.') {
+        Ok(unit.to_string())
+    } else {
+        Ok(format!("{}.service", unit))
+    }
+}
+
+/// Path of the unit file inside the image, looked up like systemd does.
+fn find_unit(root: &Path, unit: &str) -> Result<Option<PathBuf>, Error> {
+    for dir in UNIT_DIRS {
+        let path = Path::new(dir).join(unit);
+        let resolved = copy::resolve_in_root(root, &path)?;
+        if resolved.is_file() {
+            return Ok(Some(path));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Values of `key` in the `[Install]` section of a unit file.
+fn install_values(content: &str, key: &str) -> Vec<String> {
+    let mut in_install = false;
+    let mut values = Vec::new();
+
+    for line in content.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_install = line == "[Install]";
+        } else if in_install {
+            if let Some((line_key, value)) = line.split_once('=') {
+                if line_key.trim() == key {
+                    values.extend(value.split_whitespace().map(str::to_string));
+                }
+            }
+        }
+    }
+
+    values
+}
+
+fn replace_symlink(target: &Path, link: &Path) -> Result<(), Error> {
+    fs::create_dir_all(
+        link.parent()
+            .ok_or_else(|| Error::Other("Invalid unit path".to_string()))?,
+    )?;
+    if fs::symlink_metadata(link).is_ok() {
+        fs::remove_file(link)?;
+    }
+    std::os::unix::fs::symlink(target, link)?;
+    Ok(())
+}
+
+/// Creates the symlinks `systemctl enable` would, without running systemd.
+pub fn enable(root: &Path, unit: &str) -> Result<(), Error> {
+    let path = find_unit(root, unit)?
+        .ok_or_else(|| Error::Usage(format!("Unit {} not found in the image", unit)))?;
+    let content = fs::read_to_string(copy::resolve_in_root(root, &path)?)?;
+
+    let targets = install_values(&content, "WantedBy");
+    if targets.is_empty() {
+        return Err(Error::Usage(format!(
+            "Unit {} has no WantedBy to be enabled for",
+            unit
+        )));
+    }
+
+    let config_dir = copy::resolve_in_root(root, Path::new(SYSTEM_CONFIG_DIR))?;
+    for target in targets {
+        replace_symlink(
+            &path,
+            &config_dir.join(format!("{}.wants", target)).join(unit),
+        )?;
+    }
+    for alias in install_values(&content, "Alias") {
+        replace_symlink(&path, &config_dir.join(alias))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the symlinks enabling `unit`, like `systemctl disable` would.
+pub fn disable(root: &Path, unit: &str) -> Result<(), Error> {
+    let config_dir = copy::resolve_in_root(root, Path::new(SYSTEM_CONFIG_DIR))?;
+    let Ok(entries) = fs::read_dir(&config_dir) else {
+        return Ok(());
+    };
+
+    let mut links = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() && path.to_string_lossy().ends_with(".wants") {
+            links.push(path.join(unit));
+        }
+    }
+
+    if let Some(path) = find_unit(root, unit)? {
+        let content = fs::read_to_string(copy::resolve_in_root(root, &path)?)?;
+        links.extend(
+            install_values(&content, "Alias")
+                .into_iter()
+                .map(|alias| config_dir.join(alias)),
+        );
+    }
+
+    for link in links {
+        if fs::symlink_metadata(&link).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            fs::remove_file(&link)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSH_UNIT: &str = "[Unit]\nDescription=OpenBSD Secure Shell server\n\n\
+                            [Service]\nExecStart=/usr/sbin/sshd -D\n\n\
+                            [Install]\nWantedBy=multi-user.target\nAlias=sshd.service\n";
+
+    #[test]
+    fn test_unit_name() {
+        assert_eq!(unit_name("ssh").unwrap(), "ssh.service");
+        assert_eq!(unit_name("fstrim.timer").unwrap(), "fstrim.timer");
+        assert!(matches!(unit_name("../ssh"), Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn test_install_values() {
+        assert_eq!(
+            install_values(SSH_UNIT, "WantedBy"),
+            vec!["multi-user.target"]
+        );
+        assert_eq!(install_values(SSH_UNIT, "Alias"), vec!["sshd.service"]);
+        assert!(install_values("[Service]\nWantedBy=nope\n", "WantedBy").is_empty());
+    }
+
+    #[test]
+    fn test_enable_and_disable() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("lib/systemd/system")).unwrap();
+        fs::write(root.join("lib/systemd/system/ssh.service"), SSH_UNIT).unwrap();
+
+        enable(root, "ssh.service").unwrap();
+
+        let wants = root.join("etc/systemd/system/multi-user.target.wants/ssh.service");
+        assert_eq!(
+            fs::read_link(&wants).unwrap(),
+            Path::new("/lib/systemd/system/ssh.service")
+        );
+        assert!(fs::symlink_metadata(root.join("etc/systemd/system/sshd.service")).is_ok());
+
+        disable(root, "ssh.service").unwrap();
+
+        assert!(fs::symlink_metadata(&wants).is_err());
+        assert!(fs::symlink_metadata(root.join("etc/systemd/system/sshd.service")).is_err());
+    }
+
+    #[test]
+    fn test_enable_missing_unit() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+
+        assert!(matches!(
+            enable(tmp_dir.path(), "missing.service"),
+            Err(Error::Usage(_))
+        ));
+    }
+}
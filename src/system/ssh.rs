@@ -0,0 +1,182 @@
+use std::{
+    fs,
+    os::unix::fs::{chown, PermissionsExt},
+    path::Path,
+};
+
+use crate::{copy, error::Error, system::services};
+
+const SSH_UNIT: &str = "ssh.service";
+const PASSWD: &str = "/etc/passwd";
+
+/// Turns SSH on or off both through the `ssh` flag file of the boot
+/// partition, which the first boot honours, and through `ssh.service`.
+pub fn configure(root: &Path, boot: Option<&Path>, enabled: bool) -> Result<(), Error> {
+    if let Some(boot) = boot {
+        let flag = boot.join("ssh");
+        if enabled {
+            fs::write(&flag, "")?;
+        } else if flag.exists() {
+            fs::remove_file(&flag)?;
+        }
+    }
+
+    if enabled {
+        match services::enable(root, SSH_UNIT) {
+            Err(Error::Usage(_)) if boot.is_some() => Ok(()),
+            result => result,
+        }
+    } else {
+        services::disable(root, SSH_UNIT)
+    }
+}
+
+/// Uid, gid and home directory of `user` in the image.
+fn passwd_entry(root: &Path, user: &str) -> Result<(u32, u32, String), Error> {
+    let passwd = fs::read_to_string(copy::resolve_in_root(root, Path::new(PASSWD))?)?;
+
+    passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 6 && fields[0] == user)
+        .and_then(|fields| {
+            Some((
+                fields[2].parse().ok()?,
+                fields[3].parse().ok()?,
+                fields[5].to_string(),
+            ))
+        })
+        .ok_or_else(|| Error::Usage(format!("User {} not found in the image", user)))
+}
+
+/// Appends the keys missing from `authorized_keys`, skipping blank lines and
+/// comments.
+fn append_keys(content: &str, keys: &[String]) -> String {
+    let mut lines: Vec<&str> = content.lines().collect();
+
+    for key in keys.iter().flat_map(|keys| keys.lines()).map(str::trim) {
+        if !key.is_empty() && !key.starts_with('#') && !lines.contains(&key) {
+            lines.push(key);
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Installs public keys in the `~/.ssh/authorized_keys` of `user`, owned by
+/// the user with the permissions sshd insists on.
+pub fn add_authorized_keys(root: &Path, user: &str, keys: &[String]) -> Result<(), Error> {
+    let (uid, gid, home) = passwd_entry(root, user)?;
+
+    let ssh_dir = copy::resolve_in_root(root, &Path::new(&home).join(".ssh"))?;
+    fs::create_dir_all(&ssh_dir)?;
+    fs::set_permissions(&ssh_dir, fs::Permissions::from_mode(0o700))?;
+    chown(&ssh_dir, Some(uid), Some(gid))?;
+
+    let path = ssh_dir.join("authorized_keys");
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    fs::write(&path, append_keys(&content, keys))?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    chown(&path, Some(uid), Some(gid))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configure() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path().join("rootfs");
+        let boot = tmp_dir.path().join("bootfs");
+        fs::create_dir_all(root.join("lib/systemd/system")).unwrap();
+        fs::create_dir_all(&boot).unwrap();
+        fs::write(
+            root.join("lib/systemd/system/ssh.service"),
+            "[Install]\nWantedBy=multi-user.target\n",
+        )
+        .unwrap();
+
+        let wants = root.join("etc/systemd/system/multi-user.target.wants/ssh.service");
+
+        configure(&root, Some(&boot), true).unwrap();
+        assert!(boot.join("ssh").exists());
+        assert!(fs::symlink_metadata(&wants).is_ok());
+
+        configure(&root, Some(&boot), false).unwrap();
+        assert!(!boot.join("ssh").exists());
+        assert!(fs::symlink_metadata(&wants).is_err());
+    }
+
+    #[test]
+    fn test_append_keys() {
+        let keys = vec!["# ops\nssh-ed25519 AAAA ops@host\n\nssh-rsa BBBB deploy\n".to_string()];
+
+        assert_eq!(
+            append_keys("ssh-ed25519 AAAA ops@host\n", &keys),
+            "ssh-ed25519 AAAA ops@host\nssh-rsa BBBB deploy\n"
+        );
+    }
+
+    #[test]
+    fn test_add_authorized_keys() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let metadata = fs::metadata(tmp_dir.path()).unwrap();
+        fs::create_dir_all(tmp_dir.path().join("etc")).unwrap();
+        fs::write(
+            tmp_dir.path().join("etc/passwd"),
+            format!(
+                "root:x:0:0:root:/root:/bin/bash\npi:x:{}:{}:,,,:/home/pi:/bin/bash\n",
+                metadata.uid(),
+                metadata.gid()
+            ),
+        )
+        .unwrap();
+
+        add_authorized_keys(
+            tmp_dir.path(),
+            "pi",
+            &["ssh-ed25519 AAAA ops@host\n".to_string()],
+        )
+        .unwrap();
+
+        let path = tmp_dir.path().join("home/pi/.ssh/authorized_keys");
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "ssh-ed25519 AAAA ops@host\n"
+        );
+        assert_eq!(fs::metadata(&path).unwrap().mode() & 0o777, 0o600);
+        assert_eq!(
+            fs::metadata(tmp_dir.path().join("home/pi/.ssh"))
+                .unwrap()
+                .mode()
+                & 0o777,
+            0o700
+        );
+        assert!(matches!(
+            add_authorized_keys(tmp_dir.path(), "kiosk", &[]),
+            Err(Error::Usage(_))
+        ));
+    }
+
+    #[test]
+    fn test_configure_without_ssh_server() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let boot = tmp_dir.path().join("bootfs");
+        fs::create_dir_all(&boot).unwrap();
+
+        assert!(configure(tmp_dir.path(), Some(&boot), true).is_ok());
+        assert!(matches!(
+            configure(tmp_dir.path(), None, true),
+            Err(Error::Usage(_))
+        ));
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fs, path::Path};
+
+use crate::{error::Error, parsing::parser::NewUser, system::shell_quote};
+
+/// Script creating the user with `useradd`, run inside the image.
+pub fn useradd_script(user: &NewUser) -> String {
+    let mut command = vec!["useradd".to_string(), "--create-home".to_string()];
+
+    if let Some(shell) = &user.shell {
+        command.push("--shell".to_string());
+        command.push(shell_quote(shell));
+    }
+    if !user.groups.is_empty() {
+        command.push("--groups".to_string());
+        command.push(shell_quote(&user.groups.join(",")));
+    }
+    if let Some(password_hash) = &user.password_hash {
+        command.push("--password".to_string());
+        command.push(shell_quote(password_hash));
+    }
+    command.push(shell_quote(&user.name));
+
+    format!("set -e\n{}\n", command.join(" "))
+}
+
+/// Writes the `userconf.txt` Raspberry Pi OS reads on first boot to rename
+/// the default user, as Raspberry Pi Imager does.
+pub fn write_userconf(boot: &Path, user: &NewUser) -> Result<(), Error> {
+    let password_hash = user
+        .password_hash
+        .as_ref()
+        .ok_or_else(|| Error::Usage("USERADD --userconf requires a --password-hash".to_string()))?;
+
+    fs::write(
+        boot.join("userconf.txt"),
+        format!("{}:{}\n", user.name, password_hash),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> NewUser {
+        NewUser {
+            name: "pi".to_string(),
+            password_hash: Some("$6$salt$hash".to_string()),
+            groups: vec!["sudo".to_string(), "gpio".to_string()],
+            shell: Some("/bin/bash".to_string()),
+            userconf: true,
+        }
+    }
+
+    #[test]
+    fn test_useradd_script() {
+        assert_eq!(
+            useradd_script(&user()),
+            "set -e\nuseradd --create-home --shell '/bin/bash' --groups 'sudo,gpio' --password '$6$salt$hash' 'pi'\n"
+        );
+        assert_eq!(
+            useradd_script(&NewUser {
+                name: "kiosk".to_string(),
+                ..Default::default()
+            }),
+            "set -e\nuseradd --create-home 'kiosk'\n"
+        );
+    }
+
+    #[test]
+    fn test_write_userconf() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+
+        write_userconf(tmp_dir.path(), &user()).unwrap();
+        assert_eq!(
+            fs::read_to_string(tmp_dir.path().join("userconf.txt")).unwrap(),
+            "pi:$6$salt$hash\n"
+        );
+
+        let without_hash = NewUser {
+            password_hash: None,
+            ..user()
+        };
+        assert!(matches!(
+            write_userconf(tmp_dir.path(), &without_hash),
+            Err(Error::Usage(_))
+        ));
+    }
+}
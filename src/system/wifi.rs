@@ -0,0 +1,367 @@
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{copy, error::Error, secrets::Secret};
+
+const REGULATORY_DOMAIN: &str = "cfg80211.ieee80211_regdom";
+
+/// Whether `psk` is a raw 256-bit key, given as 64 hex digits, rather than a
+/// passphrase it is derived from.
+fn is_raw_psk(psk: &str) -> bool {
+    psk.len() == 64 && psk.chars().all(|ch| ch.is_ascii_hexdigit())
+}
+
+/// Escapes a value of a GLib keyfile, which reads backslashes as escapes and
+/// drops the spaces around values.
+fn keyfile_value(value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\");
+    let start = escaped.trim_start_matches(' ');
+    let trimmed = start.trim_end_matches(' ');
+
+    format!(
+        "{}{}{}",
+        "\\s".repeat(escaped.len() - start.len()),
+        trimmed,
+        "\\s".repeat(start.len() - trimmed.len())
+    )
+}
+
+/// NetworkManager reads an ssid holding `;` as a list of bytes, such ssids
+/// are written as one.
+fn keyfile_ssid(ssid: &str) -> String {
+    if !ssid.contains(';') {
+        return keyfile_value(ssid);
+    }
+
+    ssid.bytes().map(|byte| format!("{};", byte)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WifiNetwork {
+    ssid: String,
+    psk: Option<String>,
+    country: String,
+}
+
+impl WifiNetwork {
+    /// Builds the network from the `WIFI` instruction, taking the PSK from
+    /// the `psk_secret` build secret when given instead of `psk`.
+    pub fn from_pairs(
+        pairs: &[(String, String)],
+        secrets: &[Secret],
+    ) -> Result<WifiNetwork, Error> {
+        let mut ssid = None;
+        let mut psk = None;
+        let mut country = None;
+
+        for (key, value) in pairs {
+            match key.as_str() {
+                "ssid" => ssid = Some(value.clone()),
+                "psk" => psk = Some(value.clone()),
+                "psk_secret" => psk = Some(crate::secrets::find(secrets, value)?.read()?),
+                "country" => country = Some(value.to_uppercase()),
+                _ => return Err(Error::Usage(format!("Unknown WIFI option {}", key))),
+            }
+        }
+
+        let ssid = ssid
+            .filter(|ssid| !ssid.is_empty())
+            .ok_or_else(|| Error::Usage("WIFI requires an ssid".to_string()))?;
+        let country = country
+            .filter(|country| {
+                country.len() == 2 && country.chars().all(|ch| ch.is_ascii_alphabetic())
+            })
+            .ok_or_else(|| Error::Usage("WIFI requires a two letter country code".to_string()))?;
+
+        if let Some(psk) = &psk {
+            if (psk.len() < 8 || psk.len() > 63) && !is_raw_psk(psk) {
+                return Err(Error::Usage(
+                    "WIFI psk must be between 8 and 63 characters, or 64 hex digits".to_string(),
+                ));
+            }
+        }
+
+        if ssid
+            .chars()
+            .chain(psk.iter().flat_map(|psk| psk.chars()))
+            .any(|ch| ch.is_control() || ch == '"')
+        {
+            return Err(Error::Usage(
+                "WIFI ssid and psk cannot contain quotes or control characters".to_string(),
+            ));
+        }
+
+        Ok(WifiNetwork { ssid, psk, country })
+    }
+    fn wpa_supplicant_conf(&self) -> String {
+        let security = match &self.psk {
+            // Raw keys are the ones left unquoted
+            Some(psk) if is_raw_psk(psk) => format!("\tpsk={}\n", psk),
+            Some(psk) => format!("\tpsk=\"{}\"\n", psk),
+            None => "\tkey_mgmt=NONE\n".to_string(),
+        };
+
+        format!(
+            "ctrl_interface=DIR=/var/run/wpa_supplicant GROUP=netdev\n\
+             update_config=1\n\
+             country={}\n\
+             \n\
+             network={{\n\
+             \tssid=\"{}\"\n\
+             {}}}\n",
+            self.country, self.ssid, security
+        )
+    }
+    fn nmconnection(&self) -> String {
+        let security = match &self.psk {
+            Some(psk) => format!(
+                "\n[wifi-security]\nkey-mgmt=wpa-psk\npsk={}\n",
+                keyfile_value(psk)
+            ),
+            None => String::new(),
+        };
+
+        format!(
+            "[connection]\n\
+             id={}\n\
+             type=wifi\n\
+             autoconnect=true\n\
+             \n\
+             [wifi]\n\
+             mode=infrastructure\n\
+             ssid={}\n\
+             {}\n\
+             [ipv4]\n\
+             method=auto\n\
+             \n\
+             [ipv6]\n\
+             method=auto\n",
+            keyfile_value(&self.ssid),
+            keyfile_ssid(&self.ssid),
+            security
+        )
+    }
+    fn connection_file_name(&self) -> String {
+        let name: String = self
+            .ssid
+            .chars()
+            .map(|ch| {
+                if ch.is_ascii_alphanumeric() || ch == '-' {
+                    ch
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}.nmconnection", name)
+    }
+}
+
+fn write_private(root: &Path, path: &str, content: &str) -> Result<PathBuf, Error> {
+    let path = copy::resolve_in_root(root, Path::new(path))?;
+    fs::create_dir_all(
+        path.parent()
+            .ok_or_else(|| Error::Other("Invalid configuration path".to_string()))?,
+    )?;
+    fs::write(&path, content)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+    Ok(path)
+}
+
+/// Sets the regulatory domain on the kernel command line, which is where
+/// NetworkManager based images read the WiFi country from.
+fn set_regulatory_domain(cmdline: &str, country: &str) -> String {
+    let mut arguments: Vec<String> = cmdline
+        .split_whitespace()
+        .filter(|argument| !argument.starts_with(&format!("{}=", REGULATORY_DOMAIN)))
+        .map(|argument| argument.to_string())
+        .collect();
+    arguments.push(format!("{}={}", REGULATORY_DOMAIN, country));
+
+    arguments.join(" ") + "\n"
+}
+
+/// Images ship with WiFi soft blocked until a country is set.
+fn unblock_wlan(root: &Path) -> Result<(), Error> {
+    let rfkill_dir = copy::resolve_in_root(root, Path::new("/var/lib/systemd/rfkill"))?;
+    let Ok(entries) = fs::read_dir(&rfkill_dir) else {
+        return Ok(());
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(":wlan") {
+            fs::write(path, "0\n")?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a NetworkManager connection when the image uses it, like Bookworm
+/// and later do, and a `wpa_supplicant.conf` otherwise.
+pub fn configure(root: &Path, boot: Option<&Path>, network: &WifiNetwork) -> Result<(), Error> {
+    if copy::resolve_in_root(root, Path::new("/etc/NetworkManager"))?.is_dir() {
+        write_private(
+            root,
+            &format!(
+                "/etc/NetworkManager/system-connections/{}",
+                network.connection_file_name()
+            ),
+            &network.nmconnection(),
+        )?;
+
+        if let Some(boot) = boot {
+            let cmdline = boot.join("cmdline.txt");
+            if cmdline.is_file() {
+                let content = fs::read_to_string(&cmdline)?;
+                fs::write(&cmdline, set_regulatory_domain(&content, &network.country))?;
+            }
+        }
+    } else {
+        write_private(
+            root,
+            "/etc/wpa_supplicant/wpa_supplicant.conf",
+            &network.wpa_supplicant_conf(),
+        )?;
+    }
+
+    unblock_wlan(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn network() -> WifiNetwork {
+        WifiNetwork::from_pairs(
+            &pairs(&[("ssid", "Home"), ("psk", "hunter22"), ("country", "be")]),
+            &[],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_pairs() {
+        assert_eq!(network().country, "BE");
+
+        for invalid in [
+            pairs(&[("psk", "hunter22"), ("country", "BE")]),
+            pairs(&[("ssid", "Home"), ("psk", "short"), ("country", "BE")]),
+            pairs(&[
+                ("ssid", "Home"),
+                ("psk", &"g".repeat(64)),
+                ("country", "BE"),
+            ]),
+            pairs(&[("ssid", "Home"), ("country", "Belgium")]),
+            pairs(&[("ssid", "Home"), ("country", "BE"), ("band", "5")]),
+            pairs(&[("ssid", "Home"), ("psk_secret", "psk"), ("country", "BE")]),
+        ] {
+            assert!(matches!(
+                WifiNetwork::from_pairs(&invalid, &[]),
+                Err(Error::Usage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_configure_wpa_supplicant() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path();
+        fs::create_dir_all(root.join("var/lib/systemd/rfkill")).unwrap();
+        fs::write(
+            root.join("var/lib/systemd/rfkill/platform-3f300000.mmcnr:wlan"),
+            "1\n",
+        )
+        .unwrap();
+
+        configure(root, None, &network()).unwrap();
+
+        let conf = root.join("etc/wpa_supplicant/wpa_supplicant.conf");
+        let content = fs::read_to_string(&conf).unwrap();
+        assert!(content.contains("country=BE\n"));
+        assert!(content.contains("\tssid=\"Home\"\n\tpsk=\"hunter22\"\n"));
+        assert_eq!(
+            fs::metadata(&conf).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("var/lib/systemd/rfkill/platform-3f300000.mmcnr:wlan"))
+                .unwrap(),
+            "0\n"
+        );
+    }
+
+    #[test]
+    fn test_raw_psk() {
+        let psk = "0123456789abcdef".repeat(4);
+        let raw = WifiNetwork::from_pairs(
+            &pairs(&[("ssid", "Home"), ("psk", &psk), ("country", "BE")]),
+            &[],
+        )
+        .unwrap();
+
+        assert!(raw
+            .wpa_supplicant_conf()
+            .contains(&format!("\tpsk={}\n", psk)));
+        assert!(network()
+            .wpa_supplicant_conf()
+            .contains("\tpsk=\"hunter22\"\n"));
+    }
+
+    #[test]
+    fn test_configure_network_manager() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path().join("rootfs");
+        let boot = tmp_dir.path().join("bootfs");
+        fs::create_dir_all(root.join("etc/NetworkManager")).unwrap();
+        fs::create_dir_all(&boot).unwrap();
+        fs::write(
+            boot.join("cmdline.txt"),
+            "console=tty1 root=PARTUUID=1234-02 cfg80211.ieee80211_regdom=GB\n",
+        )
+        .unwrap();
+
+        configure(&root, Some(&boot), &network()).unwrap();
+
+        let connection = fs::read_to_string(
+            root.join("etc/NetworkManager/system-connections/Home.nmconnection"),
+        )
+        .unwrap();
+        assert!(connection.contains("ssid=Home\n"));
+        assert!(connection.contains("key-mgmt=wpa-psk\npsk=hunter22\n"));
+        assert_eq!(
+            fs::read_to_string(boot.join("cmdline.txt")).unwrap(),
+            "console=tty1 root=PARTUUID=1234-02 cfg80211.ieee80211_regdom=BE\n"
+        );
+    }
+
+    #[test]
+    fn test_nmconnection_escapes_keyfile_values() {
+        let escaped = WifiNetwork::from_pairs(
+            &pairs(&[
+                ("ssid", " Café;Bar"),
+                ("psk", "a\\secret pass "),
+                ("country", "BE"),
+            ]),
+            &[],
+        )
+        .unwrap();
+
+        let connection = escaped.nmconnection();
+        assert!(connection.contains("id=\\sCafé;Bar\n"));
+        assert!(connection.contains("ssid=32;67;97;102;195;169;59;66;97;114;\n"));
+        assert!(connection.contains("psk=a\\\\secret pass\\s\n"));
+        assert!(network().nmconnection().contains("ssid=Home\n"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, SystemTime},
+};
+
+use path_absolutize::*;
+
+use crate::{
+    context::{BuildContext, SymlinkPolicy},
+    error::Error,
+};
+
+/// How often the files get looked at again.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Modification time and size of every watched file.
+pub type Snapshot = BTreeMap<PathBuf, (Option<SystemTime>, u64)>;
+
+fn stamp(path: &Path, snapshot: &mut Snapshot) -> Result<(), Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            snapshot.insert(
+                path.to_path_buf(),
+                (metadata.modified().ok(), metadata.len()),
+            );
+        }
+        // Deleted while walking, the next snapshot notices it
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+/// `path` made comparable with the walked paths, which are under the
+/// canonical context root, even when it does not exist yet.
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = match path.absolutize() {
+        Ok(absolute) => absolute.to_path_buf(),
+        Err(_) => return path.to_path_buf(),
+    };
+
+    for ancestor in absolute.ancestors() {
+        if let (Ok(canonical), Ok(rest)) =
+            (ancestor.canonicalize(), absolute.strip_prefix(ancestor))
+        {
+            return canonical.join(rest);
+        }
+    }
+    absolute
+}
+
+fn walk(
+    dir: &Path,
+    context: &BuildContext,
+    excluded: &[PathBuf],
+    snapshot: &mut Snapshot,
+) -> Result<(), Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if context.is_ignored(&path) || excluded.iter().any(|excluded| path.starts_with(excluded)) {
+            continue;
+        }
+        stamp(&path, snapshot)?;
+        if entry.file_type()?.is_dir() {
+            walk(&path, context, excluded, snapshot)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// State of the file to build and of the context files `.bakerignore` keeps.
+/// The `excluded` files and directories, the ones the build writes to, are
+/// left out so that building does not trigger another build.
+pub fn snapshot(file: &Path, context: &Path, excluded: &[PathBuf]) -> Result<Snapshot, Error> {
+    let context = BuildContext::new(context, SymlinkPolicy::Copy, u64::MAX, usize::MAX)?;
+    let excluded = excluded
+        .iter()
+        .map(|path| resolve(path))
+        .collect::<Vec<_>>();
+    let mut snapshot = Snapshot::new();
+
+    stamp(file, &mut snapshot)?;
+    walk(context.root(), &context, &excluded, &mut snapshot)?;
+
+    Ok(snapshot)
+}
+
+/// Waits until a file differs from `previous`, then for the changes to
+/// settle so that an editor saving several files triggers a single build.
+pub fn wait_for_change(
+    file: &Path,
+    context: &Path,
+    excluded: &[PathBuf],
+    previous: &Snapshot,
+) -> Result<(), Error> {
+    let mut current = snapshot(file, context, excluded)?;
+    while current == *previous {
+        sleep(POLL_INTERVAL);
+        current = snapshot(file, context, excluded)?;
+    }
+
+    loop {
+        sleep(POLL_INTERVAL);
+        let settled = snapshot(file, context, excluded)?;
+        if settled == current {
+            return Ok(());
+        }
+        current = settled;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_notices_changes() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path();
+        fs::write(root.join("Bakerfile"), "FROM raspios:bookworm\n").unwrap();
+        fs::create_dir(root.join("scripts")).unwrap();
+        fs::write(root.join("scripts/setup.sh"), "echo hello\n").unwrap();
+        fs::write(root.join(".bakerignore"), "build\n").unwrap();
+        fs::create_dir(root.join("build")).unwrap();
+
+        let file = root.join("Bakerfile");
+        let before = snapshot(&file, root, &[]).unwrap();
+        assert!(before.keys().any(|path| path.ends_with("scripts/setup.sh")));
+
+        // Ignored files do not trigger a build
+        fs::write(root.join("build/output"), "built").unwrap();
+        assert_eq!(snapshot(&file, root, &[]).unwrap(), before);
+
+        fs::write(root.join("scripts/setup.sh"), "echo hello world\n").unwrap();
+        assert_ne!(snapshot(&file, root, &[]).unwrap(), before);
+    }
+
+    #[test]
+    fn test_snapshot_leaves_out_excluded() {
+        let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+        let root = tmp_dir.path();
+        fs::write(root.join("Bakerfile"), "FROM raspios:bookworm\n").unwrap();
+        let file = root.join("Bakerfile");
+        let excluded = [root.join("out/kiosk.img"), root.join("report.json")];
+
+        let before = snapshot(&file, root, &excluded).unwrap();
+        fs::create_dir(root.join("out")).unwrap();
+        fs::write(root.join("out/kiosk.img"), "built").unwrap();
+        fs::write(root.join("report.json"), "{}").unwrap();
+        let after = snapshot(&file, root, &excluded).unwrap();
+
+        // Only the directory of the output is seen, created by the build
+        assert_eq!(after.len(), before.len() + 1);
+        assert!(!after.keys().any(|path| path.ends_with("kiosk.img")));
+    }
+}
@@ -0,0 +1,55 @@
+use std::process::Command;
+
+/// Runs baker with its config directory in `config_home`, keeping the
+/// images of the machine out of the way.
+fn baker(config_home: &std::path::Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_baker"))
+        .args(args)
+        .env("XDG_CONFIG_HOME", config_home)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_json_error_on_missing_image() {
+    let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+
+    let output = baker(tmp_dir.path(), &["--json", "inspect", "missing:tag"]);
+
+    assert_eq!(output.status.code(), Some(3));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let error: serde_json::Value = serde_json::from_str(stderr.trim_end()).unwrap();
+    assert_eq!(error["kind"], "not_found");
+    assert!(error["error"].as_str().unwrap().contains("missing:tag"));
+}
+
+#[test]
+fn test_usage_error_exit_code() {
+    let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+
+    let output = baker(tmp_dir.path(), &["inspect", "a:b:c"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .starts_with("Error: "));
+}
+
+#[test]
+fn test_json_error_on_invalid_arguments() {
+    let tmp_dir = tempdir::TempDir::new("baker").unwrap();
+
+    let output = baker(tmp_dir.path(), &["--json", "inspect", "--bogus"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr.lines().count(), 1);
+    let error: serde_json::Value = serde_json::from_str(stderr.trim_end()).unwrap();
+    assert_eq!(error["kind"], "usage");
+    assert!(error["error"].as_str().unwrap().contains("--bogus"));
+
+    let output = baker(tmp_dir.path(), &["inspect", "--bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(serde_json::from_slice::<serde_json::Value>(&output.stderr).is_err());
+}